@@ -0,0 +1,132 @@
+//! Compressed log streams, to cut the network bandwidth used when
+//! profiling remote clusters.
+//!
+//! The source computation compresses its log streams with a
+//! `CompressingWriter`, and tdiag decompresses them with
+//! `TcpStreamOrFile::decompressed` (with `--compression`). Requires the
+//! `compression` feature, except for `Compression::None`.
+
+use crate::ConnectError;
+
+use std::io::{Read, Write};
+
+/// How a log stream is compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Not compressed.
+    None,
+    /// gzip (deflate): widely available, slower.
+    Gzip,
+    /// zstd: faster, and usually smaller.
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    /// Parses `none`, `gzip` or `zstd`.
+    pub fn parse(name: &str) -> Result<Self, ConnectError> {
+        match name {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(ConnectError::Other(format!("Unknown compression: {} (expected none, gzip or zstd)", other))),
+        }
+    }
+
+    /// The name of the compression, as accepted by `parse`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Decompresses the bytes read from `reader`.
+    pub fn reader<R: Read + Send + 'static>(self, reader: R) -> Result<Box<dyn Read + Send>, ConnectError> {
+        match self {
+            Compression::None => Ok(Box::new(reader)),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(reader))),
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            #[cfg(not(feature = "compression"))]
+            other => Err(unsupported(other)),
+        }
+    }
+
+    /// Compresses the bytes written to `writer`, see `CompressingWriter`.
+    pub fn writer<W: Write>(self, writer: W) -> Result<CompressingWriter<W>, ConnectError> {
+        match self {
+            Compression::None => Ok(CompressingWriter::Plain(writer)),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => Ok(CompressingWriter::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::fast()))),
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Ok(CompressingWriter::Zstd(zstd::stream::write::Encoder::new(writer, 0)?)),
+            #[cfg(not(feature = "compression"))]
+            other => Err(unsupported(other)),
+        }
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn unsupported(compression: Compression) -> ConnectError {
+    ConnectError::Other(format!("{} compression requires the `compression` feature of tdiag-connect", compression.name()))
+}
+
+/// Compresses a log stream.
+///
+/// Compressed bytes are only sent once a block fills up or the writer is
+/// flushed, which `FlushingEventWriter` does after every batch of
+/// events; the compressed stream is finished when this is dropped.
+pub enum CompressingWriter<W: Write> {
+    /// Not compressed.
+    Plain(W),
+    /// gzip-compressed.
+    #[cfg(feature = "compression")]
+    Gzip(flate2::write::GzEncoder<W>),
+    /// zstd-compressed.
+    #[cfg(feature = "compression")]
+    Zstd(zstd::stream::write::Encoder<W>),
+}
+
+impl<W: Write> Write for CompressingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressingWriter::Plain(w) => w.write(buf),
+            #[cfg(feature = "compression")]
+            CompressingWriter::Gzip(w) => w.write(buf),
+            #[cfg(feature = "compression")]
+            CompressingWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressingWriter::Plain(w) => w.flush(),
+            #[cfg(feature = "compression")]
+            CompressingWriter::Gzip(w) => w.flush(),
+            #[cfg(feature = "compression")]
+            CompressingWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl<W: Write> Drop for CompressingWriter<W> {
+    fn drop(&mut self) {
+        // Errors can't be reported from here: the stream is cut short,
+        // as it would be without compression.
+        match self {
+            CompressingWriter::Plain(_) => (),
+            #[cfg(feature = "compression")]
+            CompressingWriter::Gzip(w) => { let _ = w.try_finish(); }
+            #[cfg(feature = "compression")]
+            CompressingWriter::Zstd(w) => { let _ = w.do_finish(); }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! The header a source worker sends when it connects to tdiag with
+//! `send::connect_timely_logging` or `send::connect_differential_logging`
+//! (each time it reconnects, with `send::ReconnectingSender`), before its
+//! log stream.
+//!
+//! The log events are abomonated structs, whose encoding depends on the
+//! versions of timely and abomonation: decoding a stream from another
+//! version would hang or produce garbage. The header lets tdiag reject
+//! such a stream with a clear error instead, and tell the kind of log
+//! stream that follows when both kinds are sent to the same port (see
+//! tdiag's `--multiplex`).
+//!
+//! The header is, in order:
+//!
+//! * `MAGIC`;
+//! * the protocol version, as a big-endian u16 (`PROTOCOL_VERSION`);
+//! * the `StreamKind`, as a byte (0 for timely, 1 for differential);
+//! * the source worker's index, as a big-endian u32;
+//! * the timely version the events are encoded with, as a byte for its
+//! length followed by its UTF-8 bytes (`TIMELY_VERSION`).
+//!
+//! tdiag answers with a `Reply`: the filter spec the source worker
+//! applies to its timely log stream (see `send::FilterSpec` and tdiag's
+//! `--source-filter`), as a big-endian u16 for its length (0 for none)
+//! followed by its UTF-8 bytes.
+//!
+//! The log stream follows right after the header, compressed or not. Streams
+//! without a header (e.g. sent by `timely::execute` to
+//! `TIMELY_WORKER_LOG_ADDR`, or over TLS) are still accepted.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::ConnectError;
+use crate::recording::StreamKind;
+
+/// The bytes a header starts with.
+pub const MAGIC: [u8; 4] = *b"TDIG";
+
+/// The version of the header and of the log stream that follows,
+/// incremented whenever either changes incompatibly.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The version of timely (major.minor) whose log events this crate
+/// encodes and decodes, as in its `Cargo.toml`.
+pub const TIMELY_VERSION: &str = "0.11";
+
+/// How long to wait for the start of a header before treating a
+/// connection as one without a header (`receive` reads the headers of
+/// the connections concurrently, so this is waited at most once).
+const HEADER_WAIT: Duration = Duration::from_secs(1);
+
+/// How long a connection may take to send the rest of its header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a source worker says about its log stream when it connects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The protocol version of the stream.
+    pub version: u16,
+    /// The kind of log stream that follows.
+    pub kind: StreamKind,
+    /// The index of the source worker.
+    pub worker: usize,
+    /// The timely version the events are encoded with.
+    pub timely_version: String,
+}
+
+impl Header {
+    /// The header of a log stream of `kind` sent by `worker`, with this
+    /// crate's versions.
+    pub fn new(kind: StreamKind, worker: usize) -> Self {
+        Header {
+            version: PROTOCOL_VERSION,
+            kind,
+            worker,
+            timely_version: TIMELY_VERSION.to_string(),
+        }
+    }
+}
+
+/// What tdiag answers the header of a connection with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reply {
+    /// The filter spec (see `send::FilterSpec`) the source worker applies
+    /// to its timely log stream, instead of its own.
+    pub filter: Option<String>,
+}
+
+/// Writes `header`.
+pub fn write<W: Write>(writer: &mut W, header: &Header) -> std::io::Result<()> {
+    let kind = match header.kind {
+        StreamKind::Timely => 0u8,
+        StreamKind::Differential => 1u8,
+    };
+    let timely_version = header.timely_version.as_bytes();
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&header.version.to_be_bytes())?;
+    writer.write_all(&[kind])?;
+    writer.write_all(&(header.worker as u32).to_be_bytes())?;
+    writer.write_all(&[timely_version.len() as u8])?;
+    writer.write_all(timely_version)?;
+    writer.flush()
+}
+
+/// Reads a header, and checks that the log stream that follows can be
+/// decoded by this crate.
+pub fn read<R: Read>(reader: &mut R) -> Result<Header, ConnectError> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(ConnectError::Other(
+            "Missing header, is the source computation sending its log streams with tdiag_connect::send?".to_string()));
+    }
+    let mut version = [0u8; 2];
+    reader.read_exact(&mut version)?;
+    let version = u16::from_be_bytes(version);
+    // The rest of the header may differ in other versions.
+    if version != PROTOCOL_VERSION {
+        return Err(ConnectError::Other(format!(
+            "The source computation sends its log streams with protocol version {}, but tdiag reads version {}: use matching versions of tdiag and tdiag-connect",
+            version, PROTOCOL_VERSION)));
+    }
+    let mut fields = [0u8; 6];
+    reader.read_exact(&mut fields)?;
+    let kind = match fields[0] {
+        0 => StreamKind::Timely,
+        1 => StreamKind::Differential,
+        kind => return Err(ConnectError::Other(format!("Unknown log stream kind {} in header", kind))),
+    };
+    let worker = u32::from_be_bytes([fields[1], fields[2], fields[3], fields[4]]) as usize;
+    let mut timely_version = vec![0u8; fields[5] as usize];
+    reader.read_exact(&mut timely_version)?;
+    let timely_version = String::from_utf8(timely_version)
+        .map_err(|_| ConnectError::Other("Invalid timely version in header".to_string()))?;
+    if timely_version != TIMELY_VERSION {
+        return Err(ConnectError::Other(format!(
+            "Source worker {} logs with timely {}, but tdiag decodes the events of timely {}: use a tdiag built with the same timely version",
+            worker, timely_version, TIMELY_VERSION)));
+    }
+    Ok(Header { version, kind, worker, timely_version })
+}
+
+/// Reads the header of a newly accepted (blocking) `socket`, if it
+/// starts with one; `None` if it doesn't, leaving the stream untouched.
+pub fn read_if_present(socket: &TcpStream) -> Result<Option<Header>, ConnectError> {
+    let deadline = Instant::now() + HEADER_WAIT;
+    let mut start = [0u8; 4];
+    loop {
+        socket.set_read_timeout(Some(HEADER_WAIT))?;
+        let peeked = match socket.peek(&mut start) {
+            Ok(peeked) => peeked,
+            // Nothing sent yet: a stream without a header is only sent
+            // once there are events to log.
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if peeked == start.len() {
+            break;
+        }
+        if !MAGIC.starts_with(&start[..peeked]) || Instant::now() >= deadline {
+            socket.set_read_timeout(None)?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    if start != MAGIC {
+        socket.set_read_timeout(None)?;
+        return Ok(None);
+    }
+    socket.set_read_timeout(Some(HEADER_TIMEOUT))?;
+    let header = read(&mut &*socket)?;
+    socket.set_read_timeout(None)?;
+    Ok(Some(header))
+}
+
+/// Writes `reply`.
+pub fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> Result<(), ConnectError> {
+    let filter = reply.filter.as_ref().map_or(&[][..], |filter| filter.as_bytes());
+    if filter.len() > u16::max_value() as usize {
+        return Err(ConnectError::Other(format!("Filter spec too long ({} bytes)", filter.len())));
+    }
+    writer.write_all(&(filter.len() as u16).to_be_bytes())?;
+    writer.write_all(filter)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a reply.
+pub fn read_reply<R: Read>(reader: &mut R) -> Result<Reply, ConnectError> {
+    let mut length = [0u8; 2];
+    reader.read_exact(&mut length)?;
+    let mut filter = vec![0u8; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut filter)?;
+    let filter = String::from_utf8(filter)
+        .map_err(|_| ConnectError::Other("Invalid filter spec in reply".to_string()))?;
+    Ok(Reply { filter: if filter.is_empty() { None } else { Some(filter) } })
+}
+
+/// Sends `header` on a newly established (blocking) `socket`, and waits
+/// for tdiag's reply.
+pub fn exchange(socket: &mut TcpStream, header: &Header) -> Result<Reply, ConnectError> {
+    write(socket, header)?;
+    socket.set_read_timeout(Some(HEADER_TIMEOUT))?;
+    let reply = read_reply(socket)?;
+    socket.set_read_timeout(None)?;
+    Ok(reply)
+}
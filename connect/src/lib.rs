@@ -13,4 +13,26 @@ impl From<std::io::Error> for ConnectError {
     }
 }
 
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectError::IoError(e) => write!(f, "io error: {}", e),
+            ConnectError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::IoError(e) => Some(e),
+            ConnectError::Other(_) => None,
+        }
+    }
+}
+
+pub mod compression;
+pub mod handshake;
 pub mod receive;
+pub mod recording;
+pub mod send;
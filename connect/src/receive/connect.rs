@@ -1,11 +1,13 @@
 use crate::ConnectError;
+use crate::handshake::{Header, Reply};
 
 use timely::dataflow::operators::capture::EventReader;
 
-use std::sync::{Arc, Mutex};
-use std::net::{TcpStream, TcpListener, ToSocketAddrs, IpAddr};
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::net::{TcpStream, TcpListener, ToSocketAddrs, IpAddr, SocketAddr};
 use std::fs::File;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 /// Listens on 127.0.0.1:8000 and opens `source_peers` sockets from the
 /// computations we're examining (one socket for every worker on the
@@ -31,6 +33,96 @@ pub fn bind(ip_addr: IpAddr, port: u16) -> Result<TcpListener, ConnectError> {
     }
 }
 
+/// A connection from a source worker, with what its header (see
+/// `handshake`) says about it.
+pub struct Connection {
+    /// The socket, in nonblocking mode, positioned after the header.
+    pub socket: TcpStream,
+    /// The address the source worker connected from.
+    pub addr: SocketAddr,
+    /// The header, `None` for streams without one (e.g. sent by
+    /// `timely::execute` to `TIMELY_WORKER_LOG_ADDR`).
+    pub header: Option<Header>,
+}
+
+/// Reads the header of a newly accepted `socket`, if any, failing if it
+/// tells of a stream this crate can't decode, and answers it with
+/// `reply`.
+pub(crate) fn connection(socket: TcpStream, addr: SocketAddr, reply: &Reply) -> Result<Connection, ConnectError> {
+    socket.set_nonblocking(false)?;
+    let header = crate::handshake::read_if_present(&socket)?;
+    if header.is_some() {
+        crate::handshake::write_reply(&mut &socket, reply)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(Connection { socket, addr, header })
+}
+
+/// Accepts connections on `listener`, reading the header of each on a
+/// thread of its own so that a slow or silent connection doesn't hold
+/// up the others, answers them with `reply`, and hands them to
+/// `accepted` until it returns
+/// `Ok(false)`. Stops accepting once `timeout` passes before the first
+/// connection, or `settle` passes without a new one (if set), and
+/// returns once the headers of the connections accepted so far are read.
+///
+/// Connections whose header can't be read, or that `accepted` refuses
+/// (with an error), are passed to `rejected` and closed.
+fn accept_connections<A, F>(
+    listener: TcpListener,
+    settle: Option<Duration>,
+    timeout: Option<Duration>,
+    reply: &Reply,
+    mut accepted: A,
+    mut rejected: F) -> Result<(), ConnectError>
+where A: FnMut(Connection) -> Result<bool, ConnectError>, F: FnMut(SocketAddr, ConnectError) {
+
+    let mut deadline = timeout.map(|timeout| Instant::now() + timeout);
+    listener.set_nonblocking(true)?;
+    let (headers_send, headers_recv) = std::sync::mpsc::channel();
+    // Connections whose header is being read.
+    let mut pending = 0;
+    loop {
+        while let Ok((addr, connection)) = headers_recv.try_recv() {
+            pending -= 1;
+            match connection.and_then(&mut accepted) {
+                Ok(true) => (),
+                // The connections still being read are closed.
+                Ok(false) => return Ok(()),
+                Err(e) => rejected(addr, e),
+            }
+        }
+        let expired = deadline.map_or(false, |deadline| Instant::now() >= deadline);
+        if expired && pending == 0 {
+            return Ok(());
+        }
+        if !expired {
+            match listener.accept() {
+                Ok((socket, addr)) => {
+                    let (headers_send, reply) = (headers_send.clone(), reply.clone());
+                    std::thread::Builder::new()
+                        .name("tdiag-connect-header".to_string())
+                        .spawn(move || {
+                            let _ = headers_send.send((addr, connection(socket, addr, &reply)));
+                        })?;
+                    pending += 1;
+                    if let Some(settle) = settle {
+                        deadline = Some(Instant::now() + settle);
+                    }
+                    continue;
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn into_sockets(connections: Vec<Connection>) -> Vec<Option<TcpStream>> {
+    connections.into_iter().map(|connection| Some(connection.socket)).collect()
+}
+
 /// Listens on the provided socket until `source_peers` connections
 /// from the computations we're examining have been established (one
 /// socket for every worker on the examined computation).
@@ -38,15 +130,144 @@ pub fn bind(ip_addr: IpAddr, port: u16) -> Result<TcpListener, ConnectError> {
 /// The sockets are wrapped in `Some(_)` because the result is
 /// commonly used as a an argument to `make_readers` in this module.
 ///
+/// Connections whose header tells of a stream this crate can't decode
+/// are closed, and don't count towards `source_peers`: use
+/// `await_connections` to be told about them.
+///
 /// The sockets are returned in nonblocking mode.
 pub fn await_sockets(listener: TcpListener, source_peers: usize) -> Result<Vec<Option<TcpStream>>, ConnectError> {
-    Ok((0..source_peers).map(|_| {
-        let socket = listener.incoming().next().expect("Socket unexpectedly unavailable");
-        if let Ok(ref s) = &socket {
-            s.set_nonblocking(true)?;
+    await_connections(listener, source_peers, None, &Reply::default(), |_, _| ()).map(into_sockets)
+}
+
+/// Like `await_sockets`, but stops waiting after `timeout`, returning
+/// the sockets connected so far (possibly fewer than `source_peers`).
+/// Rejected connections are passed to `rejected`, as with
+/// `await_connections`.
+///
+/// The sockets are returned in nonblocking mode.
+pub fn await_sockets_timeout<F>(
+    listener: TcpListener,
+    source_peers: usize,
+    timeout: Duration,
+    rejected: F) -> Result<Vec<Option<TcpStream>>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    await_connections(listener, source_peers, Some(timeout), &Reply::default(), rejected).map(into_sockets)
+}
+
+/// Like `await_sockets`, for when the number of workers in the source
+/// computation isn't known: waits for a first connection (for at most
+/// `timeout`, if set), then keeps accepting connections until none
+/// arrives for `settle`. Rejected connections are passed to
+/// `rejected`, as with `await_connections`.
+///
+/// The sockets are returned in nonblocking mode.
+pub fn await_sockets_settle<F>(
+    listener: TcpListener,
+    settle: Duration,
+    timeout: Option<Duration>,
+    rejected: F) -> Result<Vec<Option<TcpStream>>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    await_connections_settle(listener, settle, timeout, &Reply::default(), rejected).map(into_sockets)
+}
+
+/// Like `await_sockets` (or `await_sockets_timeout`, with `timeout`),
+/// but returns the connections with their header, answered with `reply`
+/// (see `handshake`).
+///
+/// The headers are read concurrently: a connection without one (e.g.
+/// from `TIMELY_WORKER_LOG_ADDR`, which sends nothing until there are
+/// events) is recognized after a short wait without delaying the others.
+/// Connections whose header tells of a stream this crate can't decode
+/// (e.g. logged with another timely version) are passed to `rejected`,
+/// closed, and don't count towards `source_peers`.
+pub fn await_connections<F>(
+    listener: TcpListener,
+    source_peers: usize,
+    timeout: Option<Duration>,
+    reply: &Reply,
+    rejected: F) -> Result<Vec<Connection>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    let mut connections = Vec::with_capacity(source_peers);
+    if source_peers == 0 {
+        return Ok(connections);
+    }
+    accept_connections(listener, None, timeout, reply, |connection| {
+        connections.push(connection);
+        Ok(connections.len() < source_peers)
+    }, rejected)?;
+    Ok(connections)
+}
+
+/// Like `await_sockets_settle`, but returns the connections with their
+/// header, answered with `reply`, as `await_connections`.
+pub fn await_connections_settle<F>(
+    listener: TcpListener,
+    settle: Duration,
+    timeout: Option<Duration>,
+    reply: &Reply,
+    rejected: F) -> Result<Vec<Connection>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    let mut connections = Vec::new();
+    accept_connections(listener, Some(settle), timeout, reply, |connection| {
+        connections.push(connection);
+        Ok(true)
+    }, rejected)?;
+    Ok(connections)
+}
+
+/// The connections accepted by `await_multiplexed_sockets`, by the kind
+/// of log stream their header announced.
+pub struct MultiplexedSockets {
+    /// The connections sending timely log streams.
+    pub timely: Vec<Connection>,
+    /// The connections sending differential log streams.
+    pub differential: Vec<Connection>,
+}
+
+/// Like `await_connections`, for source workers sending both their
+/// timely and differential log streams to the same port, told apart by
+/// their header (see `handshake`): waits for `source_peers` connections
+/// of each kind (with `differential`, or of timely ones only), or at
+/// most `timeout` if set.
+///
+/// Connections without a header (whose kind is unknown), with a header
+/// this crate can't decode, or that aren't expected (e.g. differential
+/// ones without `differential`) are passed to `rejected` and closed,
+/// which the source workers treat as a failed connection.
+pub fn await_multiplexed_sockets<F>(
+    listener: TcpListener,
+    source_peers: usize,
+    differential: bool,
+    timeout: Option<Duration>,
+    reply: &Reply,
+    rejected: F) -> Result<MultiplexedSockets, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    let differential_peers = if differential { source_peers } else { 0 };
+    let mut sockets = MultiplexedSockets { timely: Vec::with_capacity(source_peers), differential: Vec::with_capacity(differential_peers) };
+    if source_peers == 0 {
+        return Ok(sockets);
+    }
+    accept_connections(listener, None, timeout, reply, |connection| {
+        let kind = match &connection.header {
+            Some(header) => header.kind,
+            None => return Err(ConnectError::Other(
+                "No header, is the source computation sending its log streams with tdiag_connect::send?".to_string())),
+        };
+        match kind {
+            crate::recording::StreamKind::Timely if sockets.timely.len() < source_peers =>
+                sockets.timely.push(connection),
+            crate::recording::StreamKind::Differential if sockets.differential.len() < differential_peers =>
+                sockets.differential.push(connection),
+            kind => return Err(ConnectError::Other(format!("Unexpected {} log stream", kind.name()))),
         }
-        socket.map(Some)
-    }).collect::<Result<Vec<_>, _>>()?)
+        Ok(sockets.timely.len() < source_peers || sockets.differential.len() < differential_peers)
+    }, rejected)?;
+    Ok(sockets)
 }
 
 /// Types of Read created by `make_replayers`
@@ -55,6 +276,44 @@ pub enum TcpStreamOrFile {
     Tcp(TcpStream),
     /// a file-backed offline reader
     File(File),
+    /// a reader for a stream piped into this process
+    Stdin(std::io::Stdin),
+    /// a TLS-encrypted online reader
+    #[cfg(feature = "tls")]
+    Tls(super::TlsStream),
+    /// a reader decompressing one of the others
+    Decompressed(Box<dyn std::io::Read + Send>),
+    /// a reader of one of the others' bytes, read ahead on a dedicated thread
+    ReadAhead(super::ReadAheadReader),
+}
+
+impl TcpStreamOrFile {
+    /// Decompresses the bytes read from this reader with `compression`.
+    pub fn decompressed(self, compression: crate::compression::Compression) -> Result<Self, ConnectError> {
+        match compression {
+            crate::compression::Compression::None => Ok(self),
+            compression => Ok(TcpStreamOrFile::Decompressed(compression.reader(self)?)),
+        }
+    }
+
+    /// Makes sockets block on reads, for a short while (see
+    /// `blocking_socket`), for a reader read on a thread of its own
+    /// rather than polled.
+    pub fn blocking(self) -> Result<Self, ConnectError> {
+        match &self {
+            TcpStreamOrFile::Tcp(socket) => super::blocking_socket(socket)?,
+            #[cfg(feature = "tls")]
+            TcpStreamOrFile::Tls(stream) => super::blocking_socket(stream.get_ref())?,
+            _ => (),
+        }
+        Ok(self)
+    }
+
+    /// Reads this reader ahead on a dedicated thread, into a buffer of
+    /// `read_ahead`. Sockets are made blocking (see `blocking`).
+    pub fn read_ahead(self, read_ahead: &super::ReadAhead) -> Result<Self, ConnectError> {
+        Ok(TcpStreamOrFile::ReadAhead(read_ahead.reader(self.blocking()?)?))
+    }
 }
 
 impl std::io::Read for TcpStreamOrFile {
@@ -62,6 +321,11 @@ impl std::io::Read for TcpStreamOrFile {
         match self {
             TcpStreamOrFile::Tcp(x) => x.read(buf),
             TcpStreamOrFile::File(x) => x.read(buf),
+            TcpStreamOrFile::Stdin(x) => x.read(buf),
+            #[cfg(feature = "tls")]
+            TcpStreamOrFile::Tls(x) => x.read(buf),
+            TcpStreamOrFile::Decompressed(x) => x.read(buf),
+            TcpStreamOrFile::ReadAhead(x) => x.read(buf),
         }
     }
 }
@@ -71,6 +335,12 @@ impl std::io::Read for TcpStreamOrFile {
 pub enum ReplaySource {
     Tcp(Arc<Mutex<Vec<Option<TcpStream>>>>),
     Files(Arc<Mutex<Vec<Option<PathBuf>>>>),
+    /// A single stream read from this process' stdin, which is
+    /// replayed by worker 0.
+    Stdin,
+    /// TLS sessions established with `await_tls_sockets`.
+    #[cfg(feature = "tls")]
+    Tls(Arc<Mutex<Vec<Option<super::TlsStream>>>>),
 }
 
 /// Construct EventReaders that read data from sockets or file
@@ -81,13 +351,42 @@ pub fn make_readers<T, E>(
     worker_peers: usize,
     ) -> Result<Vec<EventReader<T, E, TcpStreamOrFile>>, ConnectError> {
 
+    Ok(open_streams(source, worker_index, worker_peers)?
+        .into_iter()
+        .map(|r| EventReader::<T, E, _>::new(r))
+        .collect::<Vec<_>>())
+}
+
+/// Like `make_readers`, but each reader reports to `end_of_streams`
+/// when it reaches the end of its stream.
+pub fn make_tracked_readers<T, E>(
+    source: ReplaySource,
+    worker_index: usize,
+    worker_peers: usize,
+    end_of_streams: &EndOfStreams,
+    ) -> Result<Vec<EventReader<T, E, TrackedReader<TcpStreamOrFile>>>, ConnectError> {
+
+    Ok(open_streams(source, worker_index, worker_peers)?
+        .into_iter()
+        .map(|r| EventReader::<T, E, _>::new(TrackedReader::new(r, end_of_streams.clone())))
+        .collect::<Vec<_>>())
+}
+
+/// Opens this worker's share of the streams in `source`, for callers
+/// that wrap them in their own readers.
+pub fn open_streams(
+    source: ReplaySource,
+    worker_index: usize,
+    worker_peers: usize,
+    ) -> Result<Vec<TcpStreamOrFile>, ConnectError> {
+
     match source {
         ReplaySource::Tcp(sockets) => 
             Ok(sockets.lock().unwrap()
                 .iter_mut().enumerate()
                 .filter(|(i, _)| *i % worker_peers == worker_index)
                 .map(|(_, s)| s.take().expect("socket missing, check the docs for make_replayers"))
-                .map(|r| EventReader::<T, E, _>::new(TcpStreamOrFile::Tcp(r)))
+                .map(TcpStreamOrFile::Tcp)
                 .collect::<Vec<_>>()),
         ReplaySource::Files(files) => {
             let open_files = files.lock().unwrap()
@@ -97,8 +396,74 @@ pub fn make_readers<T, E>(
                 .map(|p| File::open(&p))
                 .collect::<Result<Vec<File>, std::io::Error>>()?;
             Ok(open_files.into_iter()
-                .map(|f| EventReader::new(TcpStreamOrFile::File(f)))
+                .map(TcpStreamOrFile::File)
                 .collect::<Vec<_>>())
         }
+        #[cfg(feature = "tls")]
+        ReplaySource::Tls(streams) =>
+            Ok(streams.lock().unwrap()
+                .iter_mut().enumerate()
+                .filter(|(i, _)| *i % worker_peers == worker_index)
+                .map(|(_, s)| s.take().expect("stream missing, check the docs for make_replayers"))
+                .map(TcpStreamOrFile::Tls)
+                .collect::<Vec<_>>()),
+        ReplaySource::Stdin => {
+            if worker_index == 0 {
+                Ok(vec![TcpStreamOrFile::Stdin(std::io::stdin())])
+            } else {
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+/// Counts the readers that have reached the end of their stream
+/// (the sending side closed the connection, or the file was read
+/// entirely).
+#[derive(Clone, Default)]
+pub struct EndOfStreams(Arc<AtomicUsize>);
+
+impl EndOfStreams {
+    /// Creates a new counter, shared by all its clones.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of readers that have reached the end of their stream.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Counts one more reader as having reached the end of its stream.
+    pub(crate) fn reached(&self) {
+        self.0.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// A Read that reports to an `EndOfStreams` once the wrapped reader
+/// reaches the end of its stream.
+pub struct TrackedReader<R> {
+    reader: R,
+    end_of_streams: EndOfStreams,
+    ended: bool,
+}
+
+impl<R> TrackedReader<R> {
+    /// Wraps `reader`, reporting its end of stream to `end_of_streams`.
+    pub fn new(reader: R, end_of_streams: EndOfStreams) -> Self {
+        TrackedReader { reader, end_of_streams, ended: false }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for TrackedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = self.reader.read(buf);
+        if let Ok(0) = result {
+            if !buf.is_empty() && !self.ended {
+                self.ended = true;
+                self.end_of_streams.reached();
+            }
+        }
+        result
     }
 }
@@ -0,0 +1,229 @@
+use crate::ConnectError;
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::Event;
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+
+use super::{ReplaySource, TcpStreamOrFile, open_streams};
+
+/// The outcome of polling a nonblocking source.
+#[derive(Debug)]
+pub enum Polled<T> {
+    /// A value is available.
+    Ready(T),
+    /// Nothing is available yet, try again later.
+    Pending,
+    /// The source has ended.
+    Ended,
+}
+
+/// Decodes the events in a stream of bytes, in order.
+///
+/// Unlike `EventReader`, reports bytes at the end of the stream that
+/// don't form a complete event (e.g. because the stream was cut short)
+/// as an error. Note that the encoding carries no checksums: corruption
+/// that still decodes can't be detected.
+pub struct EventDecoder<R, T, D> {
+    reader: R,
+    buffer: Vec<u8>,
+    consumed: usize,
+    valid: usize,
+    offset: u64,
+    end_of_stream: bool,
+    phantom: PhantomData<(T, D)>,
+}
+
+impl<R: Read, T: Abomonation + Clone, D: Abomonation + Clone> EventDecoder<R, T, D> {
+    /// Decodes the events read from `reader`, which may be nonblocking.
+    pub fn new(reader: R) -> Self {
+        EventDecoder {
+            reader,
+            buffer: vec![0; 1 << 20],
+            consumed: 0,
+            valid: 0,
+            offset: 0,
+            end_of_stream: false,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The offset in the stream of the next event.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Decodes the next event, or returns `None` at the end of the
+    /// stream; waits for more bytes if the reader is nonblocking.
+    pub fn next_event(&mut self) -> Result<Option<Event<T, D>>, ConnectError> {
+        loop {
+            match self.poll_event()? {
+                Polled::Ready(event) => return Ok(Some(event)),
+                Polled::Ended => return Ok(None),
+                Polled::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    /// Decodes the next event if enough bytes are available without
+    /// blocking.
+    pub fn poll_event(&mut self) -> Result<Polled<Event<T, D>>, ConnectError> {
+        loop {
+            let available = self.valid - self.consumed;
+            let decoded = unsafe { abomonation::decode::<Event<T, D>>(&mut self.buffer[self.consumed..self.valid]) }
+                .map(|(event, rest)| {
+                    let event = match event {
+                        Event::Progress(updates) => Event::Progress(updates.clone()),
+                        Event::Messages(time, data) => Event::Messages(time.clone(), data.clone()),
+                    };
+                    (event, available - rest.len())
+                });
+
+            if let Some((event, length)) = decoded {
+                self.consumed += length;
+                self.offset += length as u64;
+                return Ok(Polled::Ready(event));
+            }
+
+            if self.end_of_stream {
+                if available > 0 {
+                    return Err(ConnectError::Other(format!(
+                        "{} trailing bytes at offset {} don't form a complete event (truncated or corrupt stream)",
+                        available, self.offset)));
+                }
+                return Ok(Polled::Ended);
+            }
+
+            // Make room for more bytes, growing the buffer if a single
+            // event doesn't fit.
+            if self.consumed > 0 {
+                self.buffer.copy_within(self.consumed..self.valid, 0);
+                self.valid -= self.consumed;
+                self.consumed = 0;
+            }
+            if self.valid == self.buffer.len() {
+                let length = self.buffer.len();
+                self.buffer.resize(2 * length, 0);
+            }
+
+            match self.reader.read(&mut self.buffer[self.valid..]) {
+                Ok(0) => self.end_of_stream = true,
+                Ok(read) => self.valid += read,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Polled::Pending),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// The decoded log records of all streams in a `ReplaySource`, without
+/// replaying them into a timely computation.
+///
+/// As an `Iterator`, blocks until the next record is available and ends
+/// once all streams have ended; an error ends the iteration and is
+/// available from `take_error`. Use `poll_next` instead to integrate
+/// with an event loop.
+///
+/// Records of different streams are interleaved in the order in which
+/// they're read, so they're only ordered by time within a stream.
+pub struct LogEvents<E = TimelyEvent> {
+    decoders: Vec<EventDecoder<TcpStreamOrFile, Duration, (Duration, WorkerIdentifier, E)>>,
+    pending: VecDeque<(Duration, WorkerIdentifier, E)>,
+    next_decoder: usize,
+    error: Option<ConnectError>,
+}
+
+impl<E: Abomonation + Clone> LogEvents<E> {
+    /// Opens all streams in `source`.
+    pub fn open(source: ReplaySource) -> Result<Self, ConnectError> {
+        let decoders = open_streams(source, 0, 1)?
+            .into_iter()
+            .map(EventDecoder::new)
+            .collect();
+        Ok(LogEvents {
+            decoders,
+            pending: VecDeque::new(),
+            next_decoder: 0,
+            error: None,
+        })
+    }
+
+    /// Returns the next record if one is available without blocking.
+    ///
+    /// Streams that ended are closed; the first error ends all streams.
+    pub fn poll_next(&mut self) -> Result<Polled<(Duration, WorkerIdentifier, E)>, ConnectError> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Polled::Ready(record));
+            }
+            if self.decoders.is_empty() {
+                return Ok(Polled::Ended);
+            }
+
+            // Poll each stream at most once per call, starting after
+            // the last one that produced records, so that no stream is
+            // starved.
+            let mut progress = false;
+            for _ in 0..self.decoders.len() {
+                let index = self.next_decoder % self.decoders.len();
+                let polled = self.decoders[index].poll_event();
+                match polled {
+                    Ok(Polled::Ready(Event::Messages(_, data))) => {
+                        self.pending.extend(data);
+                        self.next_decoder = index + 1;
+                        progress = true;
+                        break;
+                    }
+                    Ok(Polled::Ready(Event::Progress(_))) => {
+                        self.next_decoder = index;
+                        progress = true;
+                        break;
+                    }
+                    Ok(Polled::Pending) => self.next_decoder = index + 1,
+                    Ok(Polled::Ended) => {
+                        self.decoders.remove(index);
+                        self.next_decoder = index;
+                        progress = true;
+                        break;
+                    }
+                    Err(e) => {
+                        self.decoders.clear();
+                        return Err(e);
+                    }
+                }
+            }
+            if !progress {
+                return Ok(Polled::Pending);
+            }
+        }
+    }
+
+    /// The error that ended the iteration, if any.
+    pub fn take_error(&mut self) -> Option<ConnectError> {
+        self.error.take()
+    }
+}
+
+impl<E: Abomonation + Clone> Iterator for LogEvents<E> {
+    type Item = (Duration, WorkerIdentifier, E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.poll_next() {
+                Ok(Polled::Ready(record)) => return Some(record),
+                Ok(Polled::Pending) => std::thread::sleep(Duration::from_millis(1)),
+                Ok(Polled::Ended) => return None,
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,25 @@
 mod connect;
 pub use connect::*;
 
+mod events;
+pub use events::*;
+
+mod pipeline;
+pub use pipeline::*;
+
+mod read_ahead;
+pub use read_ahead::*;
+
+mod reconnect;
+pub use reconnect::{Reconnections, Resume};
+
 mod replaywithshutdown;
 pub use replaywithshutdown::*;
+
+mod throttle;
+pub use throttle::*;
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::*;
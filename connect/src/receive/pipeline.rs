@@ -0,0 +1,411 @@
+use crate::ConnectError;
+
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::{Event, EventIterator};
+
+use super::{EndOfStreams, EventDecoder, Resume};
+use super::reconnect::Splice;
+
+/// The number of decoded events buffered per stream by `spawn_decoder`
+/// when it's not told otherwise.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// The most bytes read from a stream at once by its reading thread.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of chunks read ahead of the decoding thread, per stream.
+const READ_QUEUE_CHUNKS: usize = 16;
+
+/// Statistics of a `DecodedEvents` queue, shared with its clones.
+#[derive(Clone, Default)]
+pub struct QueueStats {
+    queued: Arc<AtomicUsize>,
+    decoded: Arc<AtomicUsize>,
+    replayed: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+}
+
+impl QueueStats {
+    /// The number of decoded events waiting to be replayed.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    /// The number of records decoded so far.
+    pub fn decoded_records(&self) -> usize {
+        self.decoded.load(Ordering::Acquire)
+    }
+
+    /// The number of records replayed so far.
+    pub fn replayed_records(&self) -> usize {
+        self.replayed.load(Ordering::Acquire)
+    }
+
+    /// Whether the decoding thread stopped reading the stream.
+    pub fn closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Events decoded on a separate thread (see `spawn_decoder`), to be
+/// replayed into a timely computation.
+///
+/// Unlike `EventReader`, never blocks or decodes while the replay
+/// operator is scheduled: it only takes the events that are ready.
+pub struct DecodedEvents<T, D> {
+    receiver: Receiver<Event<T, D>>,
+    current: Option<Event<T, D>>,
+    stats: QueueStats,
+    end_of_streams: Option<EndOfStreams>,
+    ended: bool,
+    drain: Arc<AtomicBool>,
+}
+
+impl<T, D> DecodedEvents<T, D> {
+    /// The statistics of this stream's queue.
+    pub fn stats(&self) -> QueueStats {
+        self.stats.clone()
+    }
+
+    /// Keeps reading (and discarding) the stream once this is dropped,
+    /// until the sender closes it, rather than closing the connection
+    /// under the sender's feet.
+    pub fn drain_on_drop(&self) {
+        self.drain.store(true, Ordering::Release);
+    }
+
+    /// Whether all the stream's events were taken, and it ended.
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+}
+
+impl<T, D> EventIterator<T, D> for DecodedEvents<T, D> {
+    fn next(&mut self) -> Option<&Event<T, D>> {
+        match self.receiver.try_recv() {
+            Ok(event) => {
+                self.stats.queued.fetch_sub(1, Ordering::AcqRel);
+                if let Event::Messages(_, data) = &event {
+                    self.stats.replayed.fetch_add(data.len(), Ordering::AcqRel);
+                }
+                self.current = Some(event);
+                self.current.as_ref()
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                // All events were replayed: only now the stream counts
+                // as ended, rather than when the decoder reached its end.
+                if !self.ended {
+                    self.ended = true;
+                    if let Some(end_of_streams) = &self.end_of_streams {
+                        end_of_streams.reached();
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Reads the events of `reader` on a new thread, and decodes them on
+/// another, buffering at most `capacity` events until they're replayed.
+///
+/// The stream is reported to `end_of_streams` (if any) once all its
+/// events have been taken from the returned `DecodedEvents`. A decoding
+/// or read error ends the stream, and is passed to `on_error`. The
+/// thread stops when the `DecodedEvents` is dropped, unless told to
+/// drain the stream (see `DecodedEvents::drain_on_drop`).
+///
+/// Sockets should be made blocking first (see
+/// `TcpStreamOrFile::blocking`), so that the reading thread waits for
+/// them rather than polling them.
+pub fn spawn_decoder<R, T, D, F>(
+    reader: R,
+    capacity: usize,
+    end_of_streams: Option<EndOfStreams>,
+    on_error: F) -> Result<DecodedEvents<T, D>, ConnectError>
+where
+    R: Read + Send + 'static,
+    T: Abomonation + Clone + Send + 'static,
+    D: Abomonation + Clone + Send + 'static,
+    F: FnOnce(ConnectError) + Send + 'static {
+
+    spawn_decoder_with(reader, capacity, end_of_streams, on_error, |_| true)
+}
+
+/// Like `spawn_decoder`, but passes each decoded record to `map` on the
+/// decoding thread before it's queued, e.g. to tag it with where it
+/// came from; records for which `map` returns `false` are dropped.
+pub fn spawn_decoder_with<R, T, D, F, M>(
+    reader: R,
+    capacity: usize,
+    end_of_streams: Option<EndOfStreams>,
+    on_error: F,
+    map: M) -> Result<DecodedEvents<T, D>, ConnectError>
+where
+    R: Read + Send + 'static,
+    T: Abomonation + Clone + Send + 'static,
+    D: Abomonation + Clone + Send + 'static,
+    F: FnOnce(ConnectError) + Send + 'static,
+    M: FnMut(&mut D) -> bool + Send + 'static {
+
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let stats = QueueStats::default();
+    let stats_d = stats.clone();
+    let drain = Arc::new(AtomicBool::new(false));
+    let drain_d = drain.clone();
+
+    let reader = spawn_reader(reader)?;
+    std::thread::Builder::new()
+        .name("tdiag-decode".to_string())
+        .spawn(move || {
+            decode(EventDecoder::new(reader), sender, &stats_d, &drain_d, on_error, map);
+            stats_d.closed.store(true, Ordering::Release);
+        })?;
+
+    Ok(DecodedEvents { receiver, current: None, stats, end_of_streams, ended: false, drain })
+}
+
+/// Like `spawn_decoder_with`, but once the connection drops (the stream
+/// ends or fails while still holding capabilities), waits for the source
+/// worker to reconnect as described by `resume`, and carries on with
+/// the events of the new connection.
+///
+/// The source worker has to send the capabilities it holds when it
+/// reconnects, as `send::ReconnectingSender` does.
+pub fn spawn_resumable_decoder<R, T, D, F, M>(
+    reader: R,
+    capacity: usize,
+    end_of_streams: Option<EndOfStreams>,
+    on_error: F,
+    map: M,
+    resume: Resume<R>) -> Result<DecodedEvents<T, D>, ConnectError>
+where
+    R: Read + Send + 'static,
+    T: Abomonation + Ord + Default + Clone + Send + 'static,
+    D: Abomonation + Clone + Send + 'static,
+    F: FnOnce(ConnectError) + Send + 'static,
+    M: FnMut(&mut D) -> bool + Send + 'static {
+
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let stats = QueueStats::default();
+    let stats_d = stats.clone();
+    let drain = Arc::new(AtomicBool::new(false));
+    let drain_d = drain.clone();
+
+    let reader = spawn_reader(reader)?;
+    std::thread::Builder::new()
+        .name("tdiag-decode".to_string())
+        .spawn(move || {
+            decode_resumable(EventDecoder::new(reader), sender, &stats_d, &drain_d, on_error, map, resume);
+            stats_d.closed.store(true, Ordering::Release);
+        })?;
+
+    Ok(DecodedEvents { receiver, current: None, stats, end_of_streams, ended: false, drain })
+}
+
+/// Queues a decoded event; returns whether to keep decoding. Once the
+/// replay is dropped, `sender` is cleared, and the stream is only read
+/// on if it's being drained.
+fn forward<T, D, M: FnMut(&mut D) -> bool>(
+    sender: &mut Option<SyncSender<Event<T, D>>>,
+    mut event: Event<T, D>,
+    stats: &QueueStats,
+    drain: &AtomicBool,
+    map: &mut M) -> bool {
+
+    if let Some(events) = sender {
+        if let Event::Messages(_, data) = &mut event {
+            stats.decoded.fetch_add(data.len(), Ordering::AcqRel);
+            let decoded = std::mem::replace(data, Vec::new());
+            data.extend(decoded.into_iter().filter_map(|mut record| if map(&mut record) { Some(record) } else { None }));
+        }
+        stats.queued.fetch_add(1, Ordering::AcqRel);
+        if events.send(event).is_err() {
+            // The replay was dropped.
+            stats.queued.fetch_sub(1, Ordering::AcqRel);
+            if !drain.load(Ordering::Acquire) {
+                return false;
+            }
+            *sender = None;
+        }
+    }
+    true
+}
+
+/// The bytes of a stream read on a separate thread (see `spawn_reader`),
+/// so that decoding doesn't wait for reads and reading doesn't wait for
+/// decoding. Reads block until bytes are available.
+struct ChunkReader {
+    chunks: Receiver<std::io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    consumed: usize,
+    ended: bool,
+    /// Set once this is dropped, so that the reading thread stops.
+    dropped: Arc<AtomicBool>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.consumed == self.current.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            match self.chunks.recv() {
+                // The end of the stream.
+                Ok(Ok(chunk)) if chunk.is_empty() => self.ended = true,
+                Ok(Ok(chunk)) => {
+                    self.current = chunk;
+                    self.consumed = 0;
+                }
+                Ok(Err(e)) => {
+                    self.ended = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.ended = true;
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "the reading thread stopped"));
+                }
+            }
+        }
+        let read = std::cmp::min(buf.len(), self.current.len() - self.consumed);
+        buf[..read].copy_from_slice(&self.current[self.consumed..self.consumed + read]);
+        self.consumed += read;
+        Ok(read)
+    }
+}
+
+impl Drop for ChunkReader {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Release);
+    }
+}
+
+/// Reads `reader` on a new thread, at most `READ_QUEUE_CHUNKS` chunks
+/// ahead of the returned `ChunkReader`. The thread stops at the end of
+/// the stream, after a read error, or once the `ChunkReader` is dropped.
+///
+/// Reads should block until bytes are available, for a short while: a
+/// reader failing with `WouldBlock` is retried right away. Sockets
+/// should be made blocking with `TcpStreamOrFile::blocking`, whose read
+/// timeout lets the thread notice the `ChunkReader` being dropped.
+fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Result<ChunkReader, ConnectError> {
+    let (chunks_send, chunks) = mpsc::sync_channel(READ_QUEUE_CHUNKS);
+    let dropped = Arc::new(AtomicBool::new(false));
+    let dropped_r = dropped.clone();
+    std::thread::Builder::new()
+        .name("tdiag-read".to_string())
+        .spawn(move || {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            while !dropped_r.load(Ordering::Acquire) {
+                let read = match reader.read(&mut chunk) {
+                    Ok(read) => Ok(chunk[..read].to_vec()),
+                    // The read timed out without bytes available.
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let last = read.as_ref().map_or(true, |read| read.is_empty());
+                if chunks_send.send(read).is_err() || last {
+                    return;
+                }
+            }
+        })?;
+    Ok(ChunkReader { chunks, current: Vec::new(), consumed: 0, ended: false, dropped })
+}
+
+fn decode<R: Read, T: Abomonation + Clone, D: Abomonation + Clone, F: FnOnce(ConnectError), M: FnMut(&mut D) -> bool>(
+    mut decoder: EventDecoder<R, T, D>,
+    sender: SyncSender<Event<T, D>>,
+    stats: &QueueStats,
+    drain: &AtomicBool,
+    on_error: F,
+    mut map: M) {
+
+    let mut sender = Some(sender);
+    loop {
+        match decoder.next_event() {
+            Ok(Some(event)) => {
+                if !forward(&mut sender, event, stats, drain, &mut map) {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                if sender.is_some() {
+                    on_error(e);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn decode_resumable<R, T, D, F, M>(
+    mut decoder: EventDecoder<ChunkReader, T, D>,
+    sender: SyncSender<Event<T, D>>,
+    stats: &QueueStats,
+    drain: &AtomicBool,
+    on_error: F,
+    mut map: M,
+    mut resume: Resume<R>)
+where
+    R: Read + Send + 'static,
+    T: Abomonation + Ord + Default + Clone,
+    D: Abomonation + Clone,
+    F: FnOnce(ConnectError),
+    M: FnMut(&mut D) -> bool {
+
+    let mut sender = Some(sender);
+    let mut splice = Splice::new();
+    loop {
+        let error = match decoder.next_event() {
+            Ok(Some(event)) => {
+                let event = splice.advance(event);
+                if !forward(&mut sender, event, stats, drain, &mut map) {
+                    return;
+                }
+                continue;
+            }
+            Ok(None) => None,
+            Err(e) => Some(e),
+        };
+
+        // Not worth waiting for if no one is replaying the stream.
+        if sender.is_some() && splice.holds_capabilities() {
+            (resume.notify)(false);
+            match resume.reconnections.accept(resume.header.as_ref(), resume.timeout) {
+                Ok(Some(socket)) => {
+                    (resume.notify)(true);
+                    decoder = match spawn_reader((resume.wrap)(socket)) {
+                        Ok(reader) => EventDecoder::new(reader),
+                        Err(e) => {
+                            on_error(e);
+                            return;
+                        }
+                    };
+                    let swap = Event::Progress(splice.resume());
+                    if !forward(&mut sender, swap, stats, drain, &mut map) {
+                        return;
+                    }
+                    continue;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    on_error(e);
+                    return;
+                }
+            }
+        }
+        if let (Some(e), Some(_)) = (error, &sender) {
+            on_error(e);
+        }
+        return;
+    }
+}
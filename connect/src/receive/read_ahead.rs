@@ -0,0 +1,395 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use crate::ConnectError;
+
+/// The most bytes moved from a stream to its buffer at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size after which a stream's disk segment is sealed, and a new
+/// one started, so that replayed bytes can be freed.
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How long a read-ahead thread waits for a socket to be readable
+/// before checking whether its `ReadAheadReader` was dropped, and how
+/// long a `ReadAheadReader` waits for buffered bytes before failing
+/// with `WouldBlock`.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Reads log streams on dedicated threads into buffers, so that a slow
+/// analysis doesn't slow down the source computation through TCP
+/// backpressure: either bounded in-memory buffers (until one fills up),
+/// or segments appended to disk (see `ReadAhead::on_disk`).
+///
+/// Clones share the statistics.
+#[derive(Clone)]
+pub struct ReadAhead {
+    storage: Storage,
+    buffered: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+    stalls: Arc<AtomicUsize>,
+}
+
+#[derive(Clone)]
+enum Storage {
+    Memory { capacity: usize },
+    /// Segments are named after `streams`, counting the streams read so far.
+    Disk { dir: PathBuf, streams: Arc<AtomicUsize> },
+}
+
+impl ReadAhead {
+    /// Creates a read-ahead that buffers up to `capacity` bytes per
+    /// stream in memory.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_storage(Storage::Memory { capacity: std::cmp::max(capacity, 1) })
+    }
+
+    /// Creates a read-ahead that appends the bytes of each stream to
+    /// segment files in `dir` (created if needed), deleting them once
+    /// they're read, so that streams are never read slower than they're
+    /// sent however far behind the analysis is.
+    pub fn on_disk(dir: PathBuf) -> Self {
+        Self::with_storage(Storage::Disk { dir, streams: Arc::new(AtomicUsize::new(0)) })
+    }
+
+    fn with_storage(storage: Storage) -> Self {
+        ReadAhead {
+            storage,
+            buffered: Arc::new(AtomicUsize::new(0)),
+            peak: Arc::new(AtomicUsize::new(0)),
+            stalls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Starts reading `reader` into a buffer on a new thread, which
+    /// stops once the returned `ReadAheadReader` is dropped.
+    ///
+    /// Reads should block until bytes are available: a reader failing
+    /// with `WouldBlock` is retried right away. Sockets should be made
+    /// blocking with `blocking_socket`, whose read timeout lets the
+    /// thread notice the `ReadAheadReader` being dropped.
+    pub fn reader<R: Read + Send + 'static>(&self, reader: R) -> Result<ReadAheadReader, ConnectError> {
+        let segments = match &self.storage {
+            Storage::Memory { .. } => None,
+            Storage::Disk { dir, streams } => {
+                std::fs::create_dir_all(dir)?;
+                let prefix = format!("tdiag-{}-{}", std::process::id(), streams.fetch_add(1, Ordering::AcqRel));
+                Some(Segments { dir: dir.clone(), prefix, next: 0 })
+            }
+        };
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(Buffer { chunks: VecDeque::new(), segments: VecDeque::new(), bytes: 0, end: None, dropped: false }),
+            has_room: Condvar::new(),
+            has_bytes: Condvar::new(),
+        });
+        let (shared_d, read_ahead_d) = (shared.clone(), self.clone());
+        std::thread::Builder::new()
+            .name("tdiag-read-ahead".to_string())
+            .spawn(move || match segments {
+                Some(segments) => read_ahead_d.spill(reader, &shared_d, segments),
+                None => read_ahead_d.fill(reader, &shared_d),
+            })?;
+        Ok(ReadAheadReader { shared, read_ahead: self.clone(), current: Vec::new(), consumed: 0, segment: None })
+    }
+
+    /// The number of bytes currently buffered (in memory or on disk),
+    /// across all streams.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered.load(Ordering::Acquire)
+    }
+
+    /// The most bytes that were buffered at once, across all streams.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak.load(Ordering::Acquire)
+    }
+
+    /// The number of times a stream's in-memory buffer filled up, so
+    /// that the stream was read only as fast as it was analysed.
+    pub fn stalls(&self) -> usize {
+        self.stalls.load(Ordering::Acquire)
+    }
+
+    fn buffered(&self, bytes: usize) {
+        let buffered = self.buffered.fetch_add(bytes, Ordering::AcqRel) + bytes;
+        self.peak.fetch_max(buffered, Ordering::AcqRel);
+    }
+
+    /// Moves the bytes of `reader` to the in-memory buffer until the
+    /// stream ends, fails, or the `ReadAheadReader` is dropped.
+    fn fill<R: Read>(&self, mut reader: R, shared: &Shared) {
+        let capacity = match self.storage {
+            Storage::Memory { capacity } => capacity,
+            Storage::Disk { .. } => unreachable!("disk segments are filled by `spill`"),
+        };
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            {
+                let mut buffer = shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+                if buffer.bytes >= capacity && !buffer.dropped {
+                    self.stalls.fetch_add(1, Ordering::AcqRel);
+                    while buffer.bytes >= capacity && !buffer.dropped {
+                        buffer = shared.has_room.wait(buffer).expect("cannot lock the read-ahead buffer");
+                    }
+                }
+                if buffer.dropped {
+                    return;
+                }
+            }
+
+            let end = match read_chunk(&mut reader, &mut chunk) {
+                Ok(Some(0)) => Ok(()),
+                Ok(Some(read)) => {
+                    let mut buffer = shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+                    if buffer.dropped {
+                        return;
+                    }
+                    buffer.chunks.push_back(chunk[..read].to_vec());
+                    buffer.bytes += read;
+                    self.buffered(read);
+                    shared.has_bytes.notify_one();
+                    continue;
+                }
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            shared.buffer.lock().expect("cannot lock the read-ahead buffer").end = Some(end);
+            shared.has_bytes.notify_one();
+            return;
+        }
+    }
+
+    /// Appends the bytes of `reader` to disk segments until the stream
+    /// ends, fails, or the `ReadAheadReader` is dropped.
+    fn spill<R: Read>(&self, mut reader: R, shared: &Shared, mut segments: Segments) {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut file: Option<File> = None;
+        let end = loop {
+            if file.is_none() {
+                let mut buffer = shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+                if buffer.dropped {
+                    return;
+                }
+                // Created while holding the lock, so that the reader
+                // deletes it if it's dropped.
+                let path = segments.next_path();
+                match File::create(&path) {
+                    Ok(created) => file = Some(created),
+                    Err(e) => break Err(e),
+                }
+                buffer.segments.push_back(Segment { path, len: 0, sealed: false });
+            }
+
+            match read_chunk(&mut reader, &mut chunk) {
+                Ok(Some(0)) => break Ok(()),
+                Ok(Some(read)) => {
+                    if let Err(e) = file.as_mut().expect("no open segment").write_all(&chunk[..read]) {
+                        break Err(e);
+                    }
+                    let mut buffer = shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+                    if buffer.dropped {
+                        return;
+                    }
+                    let segment = buffer.segments.back_mut().expect("no open segment");
+                    segment.len += read as u64;
+                    if segment.len >= SEGMENT_SIZE {
+                        segment.sealed = true;
+                        file = None;
+                    }
+                    buffer.bytes += read;
+                    self.buffered(read);
+                    shared.has_bytes.notify_one();
+                }
+                Ok(None) => (),
+                Err(e) => break Err(e),
+            }
+        };
+        let mut buffer = shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+        if let Some(segment) = buffer.segments.back_mut() {
+            segment.sealed = true;
+        }
+        buffer.end = Some(end);
+        shared.has_bytes.notify_one();
+    }
+}
+
+/// Makes `socket` (e.g. as returned by `await_sockets`, nonblocking)
+/// block on reads, for at most `READ_TIMEOUT`, so that a thread reading
+/// it (e.g. a read-ahead thread) waits for it to be readable rather
+/// than polling it, and still notices when it should stop.
+pub fn blocking_socket(socket: &std::net::TcpStream) -> std::io::Result<()> {
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(Some(READ_TIMEOUT))
+}
+
+/// Reads a chunk of `reader`, or returns `None` if no bytes are
+/// available yet (once the read timed out).
+fn read_chunk<R: Read>(reader: &mut R, chunk: &mut [u8]) -> std::io::Result<Option<usize>> {
+    match reader.read(chunk) {
+        Ok(read) => Ok(Some(read)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Names the disk segments of a stream.
+struct Segments {
+    dir: PathBuf,
+    prefix: String,
+    next: usize,
+}
+
+impl Segments {
+    fn next_path(&mut self) -> PathBuf {
+        let path = self.dir.join(format!("{}-{}.seg", self.prefix, self.next));
+        self.next += 1;
+        path
+    }
+}
+
+/// A segment file, appended to until it's sealed.
+struct Segment {
+    path: PathBuf,
+    len: u64,
+    sealed: bool,
+}
+
+struct Buffer {
+    /// In memory: the chunks read but not replayed yet.
+    chunks: VecDeque<Vec<u8>>,
+    /// On disk: the segments not entirely replayed yet.
+    segments: VecDeque<Segment>,
+    bytes: usize,
+    /// Set once the stream ended or failed.
+    end: Option<std::io::Result<()>>,
+    /// Set once the reader is dropped.
+    dropped: bool,
+}
+
+struct Shared {
+    buffer: Mutex<Buffer>,
+    /// Notified when the reader frees room in the in-memory buffer.
+    has_room: Condvar,
+    /// Notified when the read-ahead thread buffers bytes or ends.
+    has_bytes: Condvar,
+}
+
+/// A Read over the bytes buffered by a `ReadAhead`.
+///
+/// When the buffer is empty, reads wait up to `READ_TIMEOUT` for the
+/// read-ahead thread to read more, then fail with `WouldBlock` (which
+/// `EventReader` treats like a nonblocking socket with no data
+/// available).
+pub struct ReadAheadReader {
+    shared: Arc<Shared>,
+    read_ahead: ReadAhead,
+    current: Vec<u8>,
+    consumed: usize,
+    /// The disk segment being replayed, and how much of it was read.
+    segment: Option<(File, u64)>,
+}
+
+impl ReadAheadReader {
+    /// Reads from the front disk segment, deleting it once it's sealed
+    /// and entirely read.
+    ///
+    /// The buffer is only locked to look at the segment and to account
+    /// for the bytes read, not while reading the file, so that the
+    /// read-ahead thread can keep appending to it.
+    fn read_segment(&mut self, buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+        loop {
+            let (len, sealed, path) = {
+                let buffer = self.shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+                match buffer.segments.front() {
+                    // The path is only needed to open the segment.
+                    Some(segment) => (segment.len, segment.sealed, if self.segment.is_none() { Some(segment.path.clone()) } else { None }),
+                    None => return Ok(None),
+                }
+            };
+            if let Some(path) = path {
+                self.segment = Some((File::open(&path)?, 0));
+            }
+            let (file, offset) = self.segment.as_mut().expect("no segment being replayed");
+            if len > *offset {
+                let read = file.read(&mut buf[..std::cmp::min(buf.len() as u64, len - *offset) as usize])?;
+                *offset += read as u64;
+                self.shared.buffer.lock().expect("cannot lock the read-ahead buffer").bytes -= read;
+                self.read_ahead.buffered.fetch_sub(read, Ordering::AcqRel);
+                return Ok(Some(read));
+            }
+            if !sealed {
+                return Ok(None);
+            }
+            self.segment = None;
+            let segment = self.shared.buffer.lock().expect("cannot lock the read-ahead buffer")
+                .segments.pop_front().expect("no segment being replayed");
+            std::fs::remove_file(&segment.path)?;
+        }
+    }
+}
+
+impl Read for ReadAheadReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut waited = false;
+        while self.consumed == self.current.len() {
+            if let Some(read) = self.read_segment(buf)? {
+                return Ok(read);
+            }
+            let shared = self.shared.clone();
+            let mut buffer = shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+            match buffer.chunks.pop_front() {
+                Some(chunk) => {
+                    buffer.bytes -= chunk.len();
+                    self.read_ahead.buffered.fetch_sub(chunk.len(), Ordering::AcqRel);
+                    self.current = chunk;
+                    self.consumed = 0;
+                    shared.has_room.notify_one();
+                }
+                None => match buffer.end.take() {
+                    // Ended: keep returning the end of the stream.
+                    Some(Ok(())) => {
+                        buffer.end = Some(Ok(()));
+                        return Ok(0);
+                    }
+                    Some(Err(e)) => {
+                        buffer.end = Some(Ok(()));
+                        return Err(e);
+                    }
+                    None if waited => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "no buffered bytes")),
+                    None => {
+                        // Bytes appended to a disk segment are looked
+                        // for again too.
+                        let _ = shared.has_bytes.wait_timeout(buffer, READ_TIMEOUT).expect("cannot lock the read-ahead buffer");
+                        waited = true;
+                    }
+                },
+            }
+        }
+
+        let read = std::cmp::min(buf.len(), self.current.len() - self.consumed);
+        buf[..read].copy_from_slice(&self.current[self.consumed..self.consumed + read]);
+        self.consumed += read;
+        Ok(read)
+    }
+}
+
+impl Drop for ReadAheadReader {
+    fn drop(&mut self) {
+        let mut buffer = self.shared.buffer.lock().expect("cannot lock the read-ahead buffer");
+        buffer.dropped = true;
+        self.read_ahead.buffered.fetch_sub(buffer.bytes, Ordering::AcqRel);
+        buffer.chunks.clear();
+        for segment in buffer.segments.drain(..) {
+            let _ = std::fs::remove_file(&segment.path);
+        }
+        buffer.bytes = 0;
+        self.shared.has_room.notify_one();
+    }
+}
@@ -0,0 +1,209 @@
+use crate::ConnectError;
+use crate::handshake::{Header, Reply};
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use timely::dataflow::operators::capture::event::Event;
+use timely::progress::ChangeBatch;
+
+use super::connect::{Connection, connection};
+
+/// Accepts the connections of source workers that reconnect after their
+/// connection dropped, on the listener their first connection was
+/// accepted on.
+///
+/// Clones share the listener: each new connection is handed to the
+/// stream waiting for it, told by the source worker and kind of log
+/// stream in its header (see `handshake`). Connections accepted before
+/// their stream asks for them are kept until it does. Streams without a
+/// header are resumed by connections without one, in the order in which
+/// they ask.
+#[derive(Clone)]
+pub struct Reconnections {
+    shared: Arc<Mutex<Accepted>>,
+}
+
+struct Accepted {
+    listener: TcpListener,
+    reply: Reply,
+    /// Connections no stream asked for yet.
+    parked: Vec<Connection>,
+}
+
+impl Reconnections {
+    /// Keeps accepting connections on `listener` (e.g. a clone of the
+    /// one passed to `await_connections`, see `TcpListener::try_clone`),
+    /// answering them with `reply` as the first ones were.
+    pub fn new(listener: TcpListener, reply: Reply) -> Result<Self, ConnectError> {
+        listener.set_nonblocking(true)?;
+        Ok(Reconnections { shared: Arc::new(Mutex::new(Accepted { listener, reply, parked: Vec::new() })) })
+    }
+
+    /// Waits for up to `timeout` for the source worker whose dropped
+    /// connection started with `header` to reconnect.
+    ///
+    /// The socket is returned in nonblocking mode, positioned after its
+    /// header.
+    pub fn accept(&self, header: Option<&Header>, timeout: Duration) -> Result<Option<TcpStream>, ConnectError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (accepted, reply) = {
+                let mut shared = self.shared.lock().expect("cannot lock reconnections");
+                if let Some(index) = shared.parked.iter().position(|c| resumes(header, c)) {
+                    return Ok(Some(shared.parked.swap_remove(index).socket));
+                }
+                (shared.listener.accept(), shared.reply.clone())
+            };
+            match accepted {
+                // The header is read without holding the lock, so that a
+                // slow connection doesn't hold up the other streams.
+                Ok((socket, addr)) => match connection(socket, addr, &reply) {
+                    Ok(connection) if resumes(header, &connection) => return Ok(Some(connection.socket)),
+                    Ok(connection) => self.shared.lock().expect("cannot lock reconnections").parked.push(connection),
+                    // Not a stream this crate can decode, so not one to resume.
+                    Err(_) => (),
+                },
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Whether `connection` resumes the stream whose dropped connection
+/// started with `header`.
+fn resumes(header: Option<&Header>, connection: &Connection) -> bool {
+    match (header, &connection.header) {
+        (Some(header), Some(resumed)) => header.kind == resumed.kind && header.worker == resumed.worker,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// How a decoded stream resumes once its connection drops (see
+/// `spawn_resumable_decoder`).
+pub struct Resume<R> {
+    /// Where the source worker reconnects.
+    pub reconnections: Reconnections,
+    /// The header of the stream's first connection, which the source
+    /// worker sends again when it reconnects.
+    pub header: Option<Header>,
+    /// Turns a new connection into the stream's reader.
+    pub wrap: Box<dyn FnMut(TcpStream) -> R + Send>,
+    /// How long to wait for the source worker to reconnect before
+    /// treating the stream as ended.
+    pub timeout: Duration,
+    /// Told when the connection drops (`false`), and when it's resumed
+    /// (`true`).
+    pub notify: Box<dyn FnMut(bool) + Send>,
+}
+
+/// Splices the events of successive connections of a source worker into
+/// a single consistent stream.
+///
+/// A reconnected worker starts a new stream, with its own implicit
+/// capability at the minimal time (and its current capabilities
+/// acquired right away, see `send::ReconnectingSender`). The times of
+/// its events are advanced to the frontier at which the dropped
+/// connection was left, so that the replay never moves backwards.
+pub(crate) struct Splice<T: Ord> {
+    capabilities: ChangeBatch<T>,
+    floor: Option<T>,
+}
+
+impl<T: Ord + Clone + Default> Splice<T> {
+    pub(crate) fn new() -> Self {
+        // Every stream implicitly starts with a capability at the minimal time.
+        Splice { capabilities: ChangeBatch::new_from(T::default(), 1), floor: None }
+    }
+
+    /// Whether the stream still holds capabilities, i.e. it was cut
+    /// short rather than ended by the source worker.
+    pub(crate) fn holds_capabilities(&mut self) -> bool {
+        !self.capabilities.is_empty()
+    }
+
+    /// Advances the times of `event` past the floor, and tracks the
+    /// capabilities it acquires and releases.
+    pub(crate) fn advance<D>(&mut self, event: Event<T, D>) -> Event<T, D> {
+        let event = match (event, &self.floor) {
+            (Event::Progress(updates), Some(floor)) => Event::Progress(
+                updates.into_iter().map(|(t, diff)| (std::cmp::max(t, floor.clone()), diff)).collect()),
+            (Event::Messages(t, data), Some(floor)) => Event::Messages(std::cmp::max(t, floor.clone()), data),
+            (event, None) => event,
+        };
+        if let Event::Progress(updates) = &event {
+            self.capabilities.extend(updates.iter().cloned());
+        }
+        event
+    }
+
+    /// Starts splicing in a new connection: returns the progress update
+    /// that swaps the capabilities still held for the new stream's
+    /// implicit one, at the frontier of the dropped connection.
+    pub(crate) fn resume(&mut self) -> Vec<(T, i64)> {
+        let floor = self.capabilities.iter().map(|(t, _)| t.clone()).min().unwrap_or_default();
+        let mut swap = ChangeBatch::new_from(floor.clone(), 1);
+        swap.extend(self.capabilities.iter().map(|(t, diff)| (t.clone(), -diff)));
+        self.capabilities = ChangeBatch::new_from(floor.clone(), 1);
+        self.floor = Some(floor);
+        swap.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(event: Event<u64, ()>) -> Vec<(u64, i64)> {
+        match event {
+            Event::Progress(mut updates) => {
+                updates.sort();
+                updates
+            }
+            Event::Messages(..) => panic!("expected progress"),
+        }
+    }
+
+    #[test]
+    fn splice_passes_the_first_stream_through() {
+        let mut splice = Splice::<u64>::new();
+        assert_eq!(progress(splice.advance(Event::Progress(vec![(0, -1), (3, 1)]))), vec![(0, -1), (3, 1)]);
+        match splice.advance(Event::Messages(3, vec![()])) {
+            Event::Messages(time, data) => assert_eq!((time, data.len()), (3, 1)),
+            Event::Progress(_) => panic!("expected messages"),
+        }
+        assert!(splice.holds_capabilities());
+        splice.advance(Event::Progress(vec![(3, -1)]));
+        assert!(!splice.holds_capabilities());
+    }
+
+    #[test]
+    fn splice_resumes_at_the_dropped_frontier() {
+        let mut splice = Splice::<u64>::new();
+        splice.advance(Event::Progress(vec![(0, -1), (3, 1), (5, 1)]));
+        // The new stream's implicit capability at 3 stands in for the
+        // one held there, and the one at 5 is released.
+        let mut swap = splice.resume();
+        swap.sort();
+        assert_eq!(swap, vec![(5, -1)]);
+
+        // The new stream acquires the capabilities its worker holds, and
+        // its times are advanced past the floor.
+        assert_eq!(progress(splice.advance(Event::Progress(vec![(0, -1), (7, 1)]))), vec![(3, -1), (7, 1)]);
+        match splice.advance(Event::Messages(1, vec![()])) {
+            Event::Messages(time, _) => assert_eq!(time, 3),
+            Event::Progress(_) => panic!("expected messages"),
+        }
+        assert!(splice.holds_capabilities());
+        splice.advance(Event::Progress(vec![(7, -1)]));
+        assert!(!splice.holds_capabilities());
+    }
+}
@@ -25,6 +25,7 @@
 // SOFTWARE.
 
 use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
+use std::time::{Duration, Instant};
 
 use timely::{Data, dataflow::{Scope, Stream}, progress::Timestamp};
 use timely::dataflow::channels::pushers::{Counter as PushCounter, buffer::Buffer as PushBuffer};
@@ -33,16 +34,69 @@ use timely::progress::frontier::MutableAntichain;
 
 use timely::dataflow::operators::capture::event::{Event, EventIterator};
 
+/// Signals a replay to stop, at which point it releases its
+/// capabilities so that the downstream dataflow can complete.
+///
+/// Each worker checks its own token every time the replay operator is
+/// scheduled, so a token shared by the workers must be cloneable into
+/// each of them (as an `Arc<AtomicBool>` or a `Deadline` are), or be
+/// wrapped in a `ShutdownFn` over shared state. Once a token reported a
+/// shutdown, the replay stays shut down.
+pub trait ShutdownToken: 'static {
+    /// Whether the replay should stop.
+    fn is_shutdown(&self) -> bool;
+}
+
+/// A flag that is `true` while the replay should keep running.
+impl ShutdownToken for Arc<AtomicBool> {
+    fn is_shutdown(&self) -> bool {
+        !self.load(Ordering::Acquire)
+    }
+}
+
+/// Shuts down when the wrapped function returns `true`, e.g. to check a
+/// cancellation token of an async runtime:
+///
+/// ```ignore
+/// let token = cancellation_token.clone();
+/// readers.replay_with_shutdown_into(scope, ShutdownFn(move || token.is_cancelled()))
+/// ```
+pub struct ShutdownFn<F>(pub F);
+
+impl<F: Fn() -> bool + 'static> ShutdownToken for ShutdownFn<F> {
+    fn is_shutdown(&self) -> bool {
+        (self.0)()
+    }
+}
+
+/// Shuts down once a point in time has passed.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    /// A deadline `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        Deadline(Instant::now() + duration)
+    }
+}
+
+impl ShutdownToken for Deadline {
+    fn is_shutdown(&self) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
 /// Replay a capture stream into a scope with the same timestamp.
 pub trait ReplayWithShutdown<T: Timestamp, D: Data> {
-    /// Replays `self` into the provided scope, as a `Stream<S, D>`.
-    fn replay_with_shutdown_into<S: Scope<Timestamp=T>>(self, scope: &mut S, is_running: Arc<AtomicBool>) -> Stream<S, D>;
+    /// Replays `self` into the provided scope, as a `Stream<S, D>`,
+    /// until `shutdown` signals to stop.
+    fn replay_with_shutdown_into<S: Scope<Timestamp=T>, K: ShutdownToken>(self, scope: &mut S, shutdown: K) -> Stream<S, D>;
 }
 
 impl<T: Timestamp, D: Data, I> ReplayWithShutdown<T, D> for I
 where I : IntoIterator,
       <I as IntoIterator>::Item: EventIterator<T, D>+'static {
-    fn replay_with_shutdown_into<S: Scope<Timestamp=T>>(self, scope: &mut S, is_running: Arc<AtomicBool>) -> Stream<S, D> {
+    fn replay_with_shutdown_into<S: Scope<Timestamp=T>, K: ShutdownToken>(self, scope: &mut S, shutdown: K) -> Stream<S, D> {
 
         let mut builder = OperatorBuilder::new("Replay".to_owned(), scope.clone());
 
@@ -54,6 +108,7 @@ where I : IntoIterator,
         let mut output = PushBuffer::new(PushCounter::new(targets));
         let mut event_streams = self.into_iter().collect::<Vec<_>>();
         let mut started = false;
+        let mut stopped = false;
 
         let mut antichain = MutableAntichain::new();
 
@@ -69,7 +124,9 @@ where I : IntoIterator,
                     started = true;
                 }
 
-                if is_running.load(Ordering::Acquire) {
+                stopped = stopped || shutdown.is_shutdown();
+
+                if !stopped {
 
                     for event_stream in event_streams.iter_mut() {
                         while let Some(event) = event_stream.next() {
@@ -0,0 +1,139 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::time::{Duration, Instant};
+
+/// How long a read refused by a `ThrottledReader` waits for the limits
+/// to allow more bytes before failing.
+const THROTTLE_WAIT: Duration = Duration::from_millis(10);
+
+/// Limits the rate at which log streams are read, per connection and
+/// across all connections, so that attaching to a production
+/// computation can't saturate its network.
+///
+/// Clones share the global limit and the statistics.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    global: Option<Arc<Mutex<TokenBucket>>>,
+    per_connection: Option<u64>,
+    throttled_bytes: Arc<AtomicUsize>,
+}
+
+impl Throttle {
+    /// Creates a throttle with the provided limits (in bytes per
+    /// second); `None` means unlimited.
+    pub fn new(global: Option<u64>, per_connection: Option<u64>) -> Self {
+        Throttle {
+            global: global.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+            per_connection,
+            throttled_bytes: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Wraps `reader` so that it's subject to this throttle's limits.
+    pub fn reader<R>(&self, reader: R) -> ThrottledReader<R> {
+        ThrottledReader {
+            reader,
+            global: self.global.clone(),
+            per_connection: self.per_connection.map(TokenBucket::new),
+            throttled_bytes: self.throttled_bytes.clone(),
+            refused: false,
+        }
+    }
+
+    /// The number of bytes that were read later than they would have
+    /// been without the limits.
+    pub fn throttled_bytes(&self) -> usize {
+        self.throttled_bytes.load(Ordering::Acquire)
+    }
+}
+
+/// Allows `rate` bytes per second, with bursts of up to a second's
+/// worth of bytes.
+struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket { rate, tokens: rate, last_refill: Instant::now() }
+    }
+
+    fn available(&mut self) -> u64 {
+        let now = Instant::now();
+        let earned = ((now - self.last_refill).as_secs_f64() * self.rate as f64) as u64;
+        if earned > 0 {
+            self.tokens = std::cmp::min(self.rate, self.tokens.saturating_add(earned));
+            // Keep the fraction of a token earned since the last refill.
+            self.last_refill = if self.tokens == self.rate {
+                now
+            } else {
+                self.last_refill + Duration::from_secs_f64(earned as f64 / self.rate as f64)
+            };
+        }
+        self.tokens
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        self.tokens = self.tokens.saturating_sub(bytes);
+    }
+}
+
+/// A Read subject to the limits of a `Throttle`.
+///
+/// When a limit is reached, reads wait for `THROTTLE_WAIT` and fail
+/// with `WouldBlock` (which `EventReader` treats like a nonblocking
+/// socket with no data available) until enough time has passed, so
+/// that a thread retrying them doesn't spin.
+pub struct ThrottledReader<R> {
+    reader: R,
+    global: Option<Arc<Mutex<TokenBucket>>>,
+    per_connection: Option<TokenBucket>,
+    throttled_bytes: Arc<AtomicUsize>,
+    refused: bool,
+}
+
+impl<R: std::io::Read> std::io::Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.global.is_none() && self.per_connection.is_none() {
+            return self.reader.read(buf);
+        }
+
+        let mut global = self.global.as_ref().map(|g| g.lock().expect("cannot lock the global throttle"));
+
+        let mut allowed = buf.len() as u64;
+        if let Some(bucket) = self.per_connection.as_mut() {
+            allowed = std::cmp::min(allowed, bucket.available());
+        }
+        if let Some(bucket) = global.as_mut() {
+            allowed = std::cmp::min(allowed, bucket.available());
+        }
+
+        if allowed == 0 && !buf.is_empty() {
+            self.refused = true;
+            // Not while holding the global limit, which other
+            // connections share.
+            drop(global);
+            std::thread::sleep(THROTTLE_WAIT);
+            return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "log stream throttled"));
+        }
+
+        let read = self.reader.read(&mut buf[..allowed as usize])?;
+
+        if let Some(bucket) = self.per_connection.as_mut() {
+            bucket.consume(read as u64);
+        }
+        if let Some(bucket) = global.as_mut() {
+            bucket.consume(read as u64);
+        }
+
+        // Bytes read after a refusal, or in a read cut short by a
+        // limit, were held back by the throttle.
+        if self.refused || (allowed as usize) < buf.len() {
+            self.refused = false;
+            self.throttled_bytes.fetch_add(read, Ordering::AcqRel);
+        }
+
+        Ok(read)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::ConnectError;
+
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+
+/// A TLS session with a source computation's worker.
+pub type TlsStream = SslStream<TcpStream>;
+
+/// How long a connection may take to complete the TLS handshake, so
+/// that a silent one doesn't hold up the others.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates used to accept TLS-encrypted log streams.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain presented to sources.
+    pub cert: PathBuf,
+    /// PEM file with the private key for `cert`.
+    pub key: PathBuf,
+    /// If set, sources must present a client certificate signed by
+    /// one of the CAs in this PEM file.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Builds the acceptor used to establish the TLS sessions.
+    pub fn acceptor(&self) -> Result<SslAcceptor, ConnectError> {
+        let tls_error = |e: openssl::error::ErrorStack| ConnectError::Other(format!("TLS configuration error: {}", e));
+
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(tls_error)?;
+        builder.set_certificate_chain_file(&self.cert).map_err(tls_error)?;
+        builder.set_private_key_file(&self.key, SslFiletype::PEM).map_err(tls_error)?;
+        builder.check_private_key().map_err(tls_error)?;
+        if let Some(client_ca) = &self.client_ca {
+            builder.set_ca_file(client_ca).map_err(tls_error)?;
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Like `await_sockets`, but establishes a TLS session on each
+/// connection.
+///
+/// Connections that fail the handshake (e.g. because they don't present
+/// an authorized client certificate) are passed to `rejected` and
+/// don't count towards `source_peers`.
+///
+/// With a `timeout`, stops waiting after that long, returning the
+/// streams established so far (possibly fewer than `source_peers`).
+///
+/// The streams are returned in nonblocking mode.
+pub fn await_tls_sockets<F>(
+    listener: TcpListener,
+    source_peers: usize,
+    acceptor: &SslAcceptor,
+    timeout: Option<Duration>,
+    rejected: F) -> Result<Vec<Option<TlsStream>>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    accept_tls(listener, Some(source_peers), None, acceptor, timeout, rejected)
+}
+
+/// Like `await_sockets_settle`, but establishes a TLS session on each
+/// connection; connections that fail the handshake are passed to
+/// `rejected`, as with `await_tls_sockets`.
+///
+/// The streams are returned in nonblocking mode.
+pub fn await_tls_sockets_settle<F>(
+    listener: TcpListener,
+    settle: Duration,
+    acceptor: &SslAcceptor,
+    timeout: Option<Duration>,
+    rejected: F) -> Result<Vec<Option<TlsStream>>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    accept_tls(listener, None, Some(settle), acceptor, timeout, rejected)
+}
+
+/// Accepts TLS sessions until there are `source_peers` of them (if
+/// set), `timeout` passes before the first one, or `settle` passes
+/// without a new one (if set).
+fn accept_tls<F>(
+    listener: TcpListener,
+    source_peers: Option<usize>,
+    settle: Option<Duration>,
+    acceptor: &SslAcceptor,
+    timeout: Option<Duration>,
+    mut rejected: F) -> Result<Vec<Option<TlsStream>>, ConnectError>
+where F: FnMut(SocketAddr, ConnectError) {
+
+    let mut deadline = timeout.map(|timeout| Instant::now() + timeout);
+    if deadline.is_some() || settle.is_some() {
+        listener.set_nonblocking(true)?;
+    }
+
+    let mut streams = Vec::with_capacity(source_peers.unwrap_or(0));
+    while source_peers.map_or(true, |source_peers| streams.len() < source_peers) {
+        let (socket, addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // The handshake is performed in blocking mode.
+        socket.set_nonblocking(false)?;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        match acceptor.accept(socket) {
+            Ok(stream) => {
+                stream.get_ref().set_read_timeout(None)?;
+                stream.get_ref().set_write_timeout(None)?;
+                stream.get_ref().set_nonblocking(true)?;
+                streams.push(Some(stream));
+                if let Some(settle) = settle {
+                    deadline = Some(Instant::now() + settle);
+                }
+            }
+            Err(err) => rejected(addr, ConnectError::Other(format!("TLS handshake failed: {}", err))),
+        }
+    }
+    Ok(streams)
+}
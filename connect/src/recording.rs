@@ -0,0 +1,189 @@
+//! Recordings of log streams, for offline analysis.
+//!
+//! A recording is a directory with one file of encoded events per
+//! recorded stream (as written by timely's `EventWriter`), and a
+//! `MANIFEST` listing them:
+//!
+//! ```text
+//! # tdiag recording
+//! version 1
+//! stream timely 0 timely-0.events
+//! stream timely 1 timely-1.events
+//! stream differential 0 differential-0.events
+//! ```
+//!
+//! A single file of encoded timely events (e.g. saved from `tdiag tee`)
+//! is also accepted as a recording with one stream.
+
+use crate::ConnectError;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use abomonation::Abomonation;
+
+use crate::receive::{EventDecoder, ReplaySource};
+
+/// The name of the file listing a recording's streams.
+pub const MANIFEST: &str = "MANIFEST";
+
+/// The version of the recording format written by this crate.
+pub const VERSION: usize = 1;
+
+/// The kind of events in a recorded stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum StreamKind {
+    /// `TimelyEvent`s
+    Timely,
+    /// `DifferentialEvent`s
+    Differential,
+}
+
+impl StreamKind {
+    /// The name of the kind, as written in the manifest.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamKind::Timely => "timely",
+            StreamKind::Differential => "differential",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "timely" => Some(StreamKind::Timely),
+            "differential" => Some(StreamKind::Differential),
+            _ => None,
+        }
+    }
+}
+
+/// A recorded stream.
+#[derive(Clone, Debug)]
+pub struct RecordedStream {
+    /// The kind of events in the stream.
+    pub kind: StreamKind,
+    /// The index of the stream among the streams of its kind (the
+    /// order in which the source workers connected).
+    pub index: usize,
+    /// The file the stream is recorded in.
+    pub path: PathBuf,
+}
+
+/// A recording's manifest.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    /// The recording's directory, if any.
+    pub dir: Option<PathBuf>,
+    /// The recorded streams.
+    pub streams: Vec<RecordedStream>,
+}
+
+impl Recording {
+    /// Opens the recording at `path`, a directory with a manifest or a
+    /// single file of timely events.
+    pub fn open(path: &Path) -> Result<Self, ConnectError> {
+        if !path.is_dir() {
+            if !path.exists() {
+                return Err(ConnectError::Other(format!("{} does not exist", path.display())));
+            }
+            return Ok(Recording {
+                dir: None,
+                streams: vec![RecordedStream { kind: StreamKind::Timely, index: 0, path: path.to_path_buf() }],
+            });
+        }
+
+        let manifest_path = path.join(MANIFEST);
+        let manifest = File::open(&manifest_path)
+            .map_err(|e| ConnectError::Other(format!("cannot open {}: {}", manifest_path.display(), e)))?;
+        let invalid = |line: usize, reason: &str| ConnectError::Other(
+            format!("invalid manifest {} (line {}): {}", manifest_path.display(), line + 1, reason));
+
+        let mut version = None;
+        let mut streams = Vec::new();
+        for (line_number, line) in BufReader::new(manifest).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields[..] {
+                ["version", v] => {
+                    let v = v.parse::<usize>().map_err(|_| invalid(line_number, "invalid version"))?;
+                    if v > VERSION {
+                        return Err(invalid(line_number, &format!("unsupported version {} (at most {} is supported)", v, VERSION)));
+                    }
+                    version = Some(v);
+                }
+                ["stream", kind, index, file] => {
+                    let kind = StreamKind::parse(kind).ok_or_else(|| invalid(line_number, &format!("unknown stream kind {}", kind)))?;
+                    let index = index.parse::<usize>().map_err(|_| invalid(line_number, "invalid stream index"))?;
+                    streams.push(RecordedStream { kind, index, path: path.join(file) });
+                }
+                _ => return Err(invalid(line_number, &format!("unexpected line {:?}", line))),
+            }
+        }
+
+        if version.is_none() {
+            return Err(ConnectError::Other(format!("invalid manifest {}: missing version", manifest_path.display())));
+        }
+
+        Ok(Recording { dir: Some(path.to_path_buf()), streams })
+    }
+
+    /// The recorded streams of a kind, in index order.
+    pub fn streams_of(&self, kind: StreamKind) -> Vec<&RecordedStream> {
+        let mut streams = self.streams.iter().filter(|s| s.kind == kind).collect::<Vec<_>>();
+        streams.sort_by_key(|s| s.index);
+        streams
+    }
+
+    /// A source replaying the recorded streams of a kind.
+    pub fn replay_source(&self, kind: StreamKind) -> ReplaySource {
+        let files = self.streams_of(kind).into_iter().map(|s| Some(s.path.clone())).collect();
+        ReplaySource::Files(Arc::new(Mutex::new(files)))
+    }
+}
+
+/// The name of the file a stream is recorded in.
+pub fn stream_file_name(kind: StreamKind, index: usize) -> String {
+    format!("{}-{}.events", kind.name(), index)
+}
+
+/// Creates the directory for a new recording, which must not exist or
+/// be empty.
+pub fn create_dir(dir: &Path) -> Result<(), ConnectError> {
+    if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        return Err(ConnectError::Other(format!("{} already exists and is not empty", dir.display())));
+    }
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// Writes the manifest for `streams`, which must be in `dir`.
+pub fn write_manifest(dir: &Path, streams: &[RecordedStream]) -> Result<(), ConnectError> {
+    let mut manifest = File::create(dir.join(MANIFEST))?;
+    writeln!(manifest, "# tdiag recording")?;
+    writeln!(manifest, "version {}", VERSION)?;
+    for stream in streams.iter() {
+        let file = stream.path.strip_prefix(dir)
+            .map_err(|_| ConnectError::Other(format!("{} is not in {}", stream.path.display(), dir.display())))?;
+        writeln!(manifest, "stream {} {} {}", stream.kind.name(), stream.index, file.display())?;
+    }
+    manifest.sync_all()?;
+    Ok(())
+}
+
+/// Decodes the events in a recorded stream, in order.
+pub type EventFileReader<T, D> = EventDecoder<File, T, D>;
+
+impl<T: Abomonation + Clone, D: Abomonation + Clone> EventDecoder<File, T, D> {
+    /// Opens the file at `path`.
+    pub fn open(path: &Path) -> Result<Self, ConnectError> {
+        let file = File::open(path)
+            .map_err(|e| ConnectError::Other(format!("cannot open {}: {}", path.display(), e)))?;
+        Ok(EventDecoder::new(file))
+    }
+}
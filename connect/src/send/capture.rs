@@ -0,0 +1,279 @@
+use crate::ConnectError;
+use crate::recording::{self, RecordedStream, StreamKind};
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use abomonation::Abomonation;
+
+use timely::communication::Allocate;
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+use timely::logging::{BatchLogger, TimelyEvent, WorkerIdentifier};
+use timely::progress::ChangeBatch;
+use timely::worker::Worker;
+
+use super::FlushingEventWriter;
+
+/// When a `RotatingCapture` starts a new segment, and how many it keeps.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+    /// Start a new segment once the current one has this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Start a new segment once the current one is this old.
+    pub max_age: Option<Duration>,
+    /// Delete the oldest segments beyond this many.
+    pub max_segments: Option<usize>,
+}
+
+/// Writes a worker's log stream to disk, as a sequence of recordings
+/// (segments) in a directory, so that the events leading up to an
+/// incident are available even if no `tdiag` was connected at the time.
+///
+/// Each segment is a complete recording with a single stream, named
+/// `<kind>-<worker>-<sequence>`: capabilities still held when a segment
+/// ends are released in it, and acquired again at the start of the next
+/// one, and the events that describe the dataflow (see `with_prelude`)
+/// are written again at its start, so that it can be analysed on its
+/// own. Each segment is flushed once per batch of events, so that little
+/// is lost if the computation crashes. Use `tdiag merge` to combine the
+/// segments of all workers that cover the time of interest into one
+/// recording.
+///
+/// Write errors disable the capture (with a message on stderr) rather
+/// than disrupting the source computation.
+pub struct RotatingCapture<E> {
+    dir: PathBuf,
+    kind: StreamKind,
+    worker: WorkerIdentifier,
+    rotation: Rotation,
+    next_sequence: usize,
+    segments: VecDeque<PathBuf>,
+    current: Option<Segment<E>>,
+    capabilities: ChangeBatch<Duration>,
+    is_prelude: fn(&E) -> bool,
+    /// The events logged so far for which `is_prelude` holds.
+    prelude: Vec<(Duration, WorkerIdentifier, E)>,
+    failed: bool,
+}
+
+struct Segment<E> {
+    writer: FlushingEventWriter<Duration, (Duration, WorkerIdentifier, E), CountingWriter>,
+    bytes: Rc<Cell<u64>>,
+    error: Rc<Cell<Option<std::io::ErrorKind>>>,
+    opened: Instant,
+}
+
+impl<E: Abomonation + Clone> RotatingCapture<E> {
+    /// Captures the `kind` log stream of `worker` into `dir`, which is
+    /// created if needed. Existing segments are kept (and count towards
+    /// `rotation.max_segments`).
+    pub fn new(dir: &Path, kind: StreamKind, worker: WorkerIdentifier, rotation: Rotation) -> Result<Self, ConnectError> {
+        std::fs::create_dir_all(dir)?;
+
+        let prefix = format!("{}-{}-", kind.name(), worker);
+        let mut existing = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if let Some(sequence) = name.to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|sequence| sequence.parse::<usize>().ok()) {
+                existing.push((sequence, entry.path()));
+            }
+        }
+        existing.sort();
+
+        Ok(RotatingCapture {
+            dir: dir.to_path_buf(),
+            kind,
+            worker,
+            rotation,
+            next_sequence: existing.last().map(|(sequence, _)| sequence + 1).unwrap_or(0),
+            segments: existing.into_iter().map(|(_, path)| path).collect(),
+            current: None,
+            // Every stream implicitly starts with a capability at the minimal time.
+            capabilities: ChangeBatch::new_from(Duration::default(), 1),
+            is_prelude: |_| false,
+            prelude: Vec::new(),
+            failed: false,
+        })
+    }
+
+    /// Writes the events for which `is_prelude` holds (e.g. timely's
+    /// `Operates` and `Channels` events, see `is_timely_prelude`) again
+    /// at the start of each segment. None are by default.
+    pub fn with_prelude(mut self, is_prelude: fn(&E) -> bool) -> Self {
+        self.is_prelude = is_prelude;
+        self
+    }
+
+    fn should_rotate(&self, segment: &Segment<E>) -> bool {
+        self.rotation.max_bytes.map_or(false, |max| segment.bytes.get() >= max)
+            || self.rotation.max_age.map_or(false, |max| segment.opened.elapsed() >= max)
+    }
+
+    /// Ends the current segment, releasing the capabilities still held.
+    fn close_segment(&mut self) {
+        if let Some(mut segment) = self.current.take() {
+            let held = self.capabilities.iter().map(|(t, diff)| (*t, -diff)).collect::<Vec<_>>();
+            if !held.is_empty() {
+                segment.writer.push(Event::Progress(held));
+            }
+        }
+    }
+
+    /// Starts a new segment with the prelude, while it holds its implicit
+    /// capability at the minimal time, then acquires the capabilities
+    /// currently held.
+    fn open_segment(&mut self) -> Result<(), ConnectError> {
+        let dir = self.dir.join(format!("{}-{}-{:06}", self.kind.name(), self.worker, self.next_sequence));
+        self.next_sequence += 1;
+
+        recording::create_dir(&dir)?;
+        let path = dir.join(recording::stream_file_name(self.kind, self.worker));
+        let file = File::create(&path)?;
+        recording::write_manifest(&dir, &[RecordedStream { kind: self.kind, index: self.worker, path }])?;
+
+        let bytes = Rc::new(Cell::new(0));
+        let error = Rc::new(Cell::new(None));
+        let mut writer = FlushingEventWriter::new(CountingWriter {
+            inner: BufWriter::new(file),
+            bytes: bytes.clone(),
+            error: error.clone(),
+        });
+
+        if !self.prelude.is_empty() {
+            writer.push(Event::Messages(Duration::default(), self.prelude.clone()));
+        }
+        let mut acquired = ChangeBatch::new_from(Duration::default(), -1);
+        acquired.extend(self.capabilities.iter().cloned());
+        let acquired = acquired.into_inner();
+        if !acquired.is_empty() {
+            writer.push(Event::Progress(acquired));
+        }
+
+        self.segments.push_back(dir);
+        if let Some(max_segments) = self.rotation.max_segments {
+            while self.segments.len() > max_segments.max(1) {
+                let oldest = self.segments.pop_front().expect("segments can't be empty");
+                std::fs::remove_dir_all(&oldest)?;
+            }
+        }
+
+        self.current = Some(Segment { writer, bytes, error, opened: Instant::now() });
+        Ok(())
+    }
+
+    fn fail(&mut self, error: ConnectError) {
+        eprintln!("tdiag-connect: disabling the capture to {} after an error: {:?}", self.dir.display(), error);
+        self.current = None;
+        self.failed = true;
+    }
+}
+
+impl<E: Abomonation + Clone> EventPusher<Duration, (Duration, WorkerIdentifier, E)> for RotatingCapture<E> {
+    fn push(&mut self, event: Event<Duration, (Duration, WorkerIdentifier, E)>) {
+        if self.failed {
+            return;
+        }
+
+        let rotate = self.current.as_ref().map_or(true, |segment| self.should_rotate(segment));
+        if rotate {
+            self.close_segment();
+            if let Err(e) = self.open_segment() {
+                self.fail(e);
+                return;
+            }
+        }
+
+        match &event {
+            Event::Progress(updates) => self.capabilities.extend(updates.iter().cloned()),
+            Event::Messages(_, data) => {
+                let is_prelude = self.is_prelude;
+                self.prelude.extend(data.iter().filter(|(_, _, event)| is_prelude(event)).cloned());
+            }
+        }
+
+        let segment = self.current.as_mut().expect("a segment is open");
+        segment.writer.push(event);
+        if let Some(kind) = segment.error.get() {
+            self.fail(ConnectError::IoError(kind.into()));
+        }
+    }
+}
+
+/// Counts the bytes written through it, and swallows write errors so
+/// that `FlushingEventWriter` doesn't panic; they're reported through `error`.
+struct CountingWriter {
+    inner: BufWriter<File>,
+    bytes: Rc<Cell<u64>>,
+    error: Rc<Cell<Option<std::io::ErrorKind>>>,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(written) => {
+                self.bytes.set(self.bytes.get() + written as u64);
+                Ok(written)
+            }
+            Err(e) => {
+                self.error.set(Some(e.kind()));
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Err(e) = self.inner.flush() {
+            self.error.set(Some(e.kind()));
+        }
+        Ok(())
+    }
+}
+
+/// Captures `worker`'s timely (`StreamKind::Timely`) or differential
+/// (`StreamKind::Differential`) log stream to rotating segments in
+/// `dir`, see `RotatingCapture`.
+///
+/// Call this when setting up the worker, instead of (or in addition to)
+/// connecting the log stream to `tdiag`:
+///
+/// ```ignore
+/// if let Ok(dir) = std::env::var("TIMELY_WORKER_LOG_DIR") {
+///     let rotation = Rotation { max_bytes: Some(64 << 20), max_segments: Some(16), ..Default::default() };
+///     tdiag_connect::send::capture_to_disk::<_, TimelyEvent>(worker, StreamKind::Timely, dir.as_ref(), rotation)
+///         .expect("cannot capture the log stream");
+/// }
+/// ```
+pub fn capture_to_disk<A: Allocate, E: Abomonation + Clone + 'static>(
+    worker: &mut Worker<A>,
+    kind: StreamKind,
+    dir: &Path,
+    rotation: Rotation) -> Result<(), ConnectError> {
+
+    let capture = RotatingCapture::<E>::new(dir, kind, worker.index(), rotation)?.with_prelude(is_timely_prelude::<E>);
+    let mut logger = BatchLogger::new(capture);
+    let name = match kind {
+        StreamKind::Timely => "timely",
+        StreamKind::Differential => "differential/arrange",
+    };
+    worker.log_register().insert::<E, _>(name, move |time, data| logger.publish_batch(time, data));
+    Ok(())
+}
+
+/// Whether `event` is a timely event that describes the dataflow
+/// (`Operates` or `Channels`), needed to make sense of later events;
+/// differential events never are.
+pub fn is_timely_prelude<E: 'static>(event: &E) -> bool {
+    match (event as &dyn Any).downcast_ref::<TimelyEvent>() {
+        Some(TimelyEvent::Operates(_)) | Some(TimelyEvent::Channels(_)) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::Write;
+
+/// A writer for a log stream that survives the diagnostic tool going
+/// away: once a write fails (e.g. because tdiag closed the connection),
+/// everything is discarded rather than failing, so that `EventWriter`
+/// doesn't panic and take the source computation down with it.
+///
+/// ```ignore
+/// let stream = std::net::TcpStream::connect(addr)?;
+/// let writer = EventWriter::new(DetachableWriter::new(stream));
+/// ```
+pub struct DetachableWriter<W> {
+    inner: Option<W>,
+}
+
+impl<W: Write> DetachableWriter<W> {
+    /// Writes to `inner` until the first error.
+    pub fn new(inner: W) -> Self {
+        DetachableWriter { inner: Some(inner) }
+    }
+
+    /// Whether a write failed, so that the stream is being discarded.
+    pub fn is_detached(&self) -> bool {
+        self.inner.is_none()
+    }
+
+    fn detach(&mut self, error: std::io::Error) {
+        eprintln!("tdiag-connect: no longer sending the log stream after an error: {}", error);
+        self.inner = None;
+    }
+}
+
+impl<W: Write> Write for DetachableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(inner) = &mut self.inner {
+            match inner.write(buf) {
+                Ok(written) => return Ok(written),
+                // Retried by `write_all`.
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => self.detach(e),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(inner) = &mut self.inner {
+            if let Err(e) = inner.flush() {
+                self.detach(e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use crate::ConnectError;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+
+/// The name of an event's type, as used in a `FilterSpec`.
+pub fn event_type(event: &TimelyEvent) -> &'static str {
+    match event {
+        TimelyEvent::Operates(_) => "operates",
+        TimelyEvent::Channels(_) => "channels",
+        TimelyEvent::PushProgress(_) => "push_progress",
+        TimelyEvent::Messages(_) => "messages",
+        TimelyEvent::Schedule(_) => "schedule",
+        TimelyEvent::Shutdown(_) => "shutdown",
+        TimelyEvent::Application(_) => "application",
+        TimelyEvent::GuardedMessage(_) => "guarded_message",
+        TimelyEvent::GuardedProgress(_) => "guarded_progress",
+        TimelyEvent::CommChannels(_) => "comm_channels",
+        TimelyEvent::Input(_) => "input",
+        TimelyEvent::Park(_) => "park",
+        TimelyEvent::Text(_) => "text",
+    }
+}
+
+const EVENT_TYPES: &[&str] = &[
+    "operates", "channels", "push_progress", "messages", "schedule", "shutdown", "application",
+    "guarded_message", "guarded_progress", "comm_channels", "input", "park", "text",
+];
+
+/// Which timely log events a source computation sends, to reduce the
+/// volume of the log stream before it's serialized.
+///
+/// Written as `;`-separated clauses, e.g.
+/// `types=schedule,messages;operators=Join,Reduce;sample=messages:100`:
+///
+/// * `types=...`: only send events of these types (default: all);
+/// * `operators=...`: only send `schedule` and `shutdown` events of
+///   operators whose name contains one of these strings, or that are
+///   nested in such a scope (default: all);
+/// * `sample=type:n,...`: only send every n-th event of a type (for
+///   `schedule`, every n-th activation, with both its start and stop).
+///
+/// `operates` and `channels` events are always sent, as they describe
+/// the dataflow's structure which all analyses need.
+///
+/// The spec is set in the source computation (e.g. with
+/// `TIMELY_WORKER_LOG_FILTER`), or sent by tdiag in its reply to the
+/// header (see `handshake::Reply` and `connect_timely_logging`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterSpec {
+    /// The event types to send, or `None` for all.
+    pub types: Option<HashSet<String>>,
+    /// Substrings of the names of operators to send events of, or
+    /// `None` for all.
+    pub operators: Option<Vec<String>>,
+    /// Send only one in this many events of a type.
+    pub sample: HashMap<String, u64>,
+}
+
+impl FilterSpec {
+    /// Parses a spec (see `FilterSpec`).
+    pub fn parse(spec: &str) -> Result<Self, ConnectError> {
+        let invalid = |reason: String| ConnectError::Other(format!("invalid filter spec {:?}: {}", spec, reason));
+        let check_type = |name: &str| if EVENT_TYPES.contains(&name) {
+            Ok(name.to_string())
+        } else {
+            Err(invalid(format!("unknown event type {} (expected one of {})", name, EVENT_TYPES.join(", "))))
+        };
+
+        let mut filter = FilterSpec::default();
+        for clause in spec.split(';').map(str::trim).filter(|c| !c.is_empty()) {
+            let mut parts = clause.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let values = parts.next().ok_or_else(|| invalid(format!("expected key=value, got {}", clause)))?
+                .split(',').map(str::trim).filter(|v| !v.is_empty());
+            match key {
+                "types" => filter.types = Some(values.map(check_type).collect::<Result<_, _>>()?),
+                "operators" => filter.operators = Some(values.map(String::from).collect()),
+                "sample" => for value in values {
+                    let mut parts = value.splitn(2, ':');
+                    let name = check_type(parts.next().unwrap_or("").trim())?;
+                    let every = parts.next()
+                        .and_then(|n| n.trim().parse::<u64>().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| invalid(format!("expected type:n with n > 0, got {}", value)))?;
+                    filter.sample.insert(name, every);
+                },
+                _ => return Err(invalid(format!("unknown key {}", key))),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Reads the spec from the `TIMELY_WORKER_LOG_FILTER` environment
+    /// variable, if set.
+    pub fn from_env() -> Result<Option<Self>, ConnectError> {
+        match std::env::var("TIMELY_WORKER_LOG_FILTER") {
+            Ok(spec) => Self::parse(&spec).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl std::fmt::Display for FilterSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut clauses = Vec::new();
+        if let Some(types) = &self.types {
+            let mut types = types.iter().cloned().collect::<Vec<_>>();
+            types.sort();
+            clauses.push(format!("types={}", types.join(",")));
+        }
+        if let Some(operators) = &self.operators {
+            clauses.push(format!("operators={}", operators.join(",")));
+        }
+        if !self.sample.is_empty() {
+            let mut sample = self.sample.iter().map(|(t, n)| format!("{}:{}", t, n)).collect::<Vec<_>>();
+            sample.sort();
+            clauses.push(format!("sample={}", sample.join(",")));
+        }
+        write!(f, "{}", clauses.join(";"))
+    }
+}
+
+/// Applies a `FilterSpec` to the timely log events pushed through it,
+/// before they reach `pusher` (e.g. an `EventWriter` on the connection
+/// to tdiag, or a `RotatingCapture`):
+///
+/// ```ignore
+/// let spec = FilterSpec::from_env()?.unwrap_or_default();
+/// let mut logger = BatchLogger::new(EventFilter::new(EventWriter::new(stream), spec));
+/// worker.log_register().insert::<TimelyEvent, _>("timely", move |time, data| logger.publish_batch(time, data));
+/// ```
+///
+/// The default `FilterSpec` sends all events.
+pub struct EventFilter<P> {
+    pusher: P,
+    spec: FilterSpec,
+    /// Addresses of the scopes and operators selected by `operators`.
+    selected_addrs: Vec<Vec<usize>>,
+    /// Ids of the operators selected by `operators`.
+    selected_ids: HashSet<usize>,
+    /// Events seen, per sampled type.
+    seen: HashMap<&'static str, u64>,
+    /// Ids of the operators whose ongoing activation was sampled, so
+    /// that its stop is sent along with its start.
+    sampled_activations: HashSet<usize>,
+}
+
+impl<P> EventFilter<P> {
+    /// Filters the events pushed to `pusher` according to `spec`.
+    pub fn new(pusher: P, spec: FilterSpec) -> Self {
+        EventFilter {
+            pusher,
+            spec,
+            selected_addrs: Vec::new(),
+            selected_ids: HashSet::new(),
+            seen: HashMap::new(),
+            sampled_activations: HashSet::new(),
+        }
+    }
+
+    fn keep(&mut self, event: &TimelyEvent) -> bool {
+        let operator = match event {
+            TimelyEvent::Operates(operates) => {
+                if let Some(operators) = &self.spec.operators {
+                    let selected = operators.iter().any(|o| operates.name.contains(o.as_str()))
+                        || self.selected_addrs.iter().any(|addr| operates.addr.starts_with(addr));
+                    if selected {
+                        self.selected_addrs.push(operates.addr.clone());
+                        self.selected_ids.insert(operates.id);
+                    }
+                }
+                return true;
+            }
+            TimelyEvent::Channels(_) => return true,
+            TimelyEvent::Schedule(schedule) => Some(schedule.id),
+            TimelyEvent::Shutdown(shutdown) => Some(shutdown.id),
+            _ => None,
+        };
+
+        if let (Some(id), Some(_)) = (operator, &self.spec.operators) {
+            if !self.selected_ids.contains(&id) {
+                return false;
+            }
+        }
+
+        let name = event_type(event);
+        if let Some(types) = &self.spec.types {
+            if !types.contains(name) {
+                return false;
+            }
+        }
+        let every = match self.spec.sample.get(name) {
+            Some(every) => *every,
+            None => return true,
+        };
+        // The filter is per worker: an operator's activations alternate
+        // between a start and its stop.
+        if let TimelyEvent::Schedule(schedule) = event {
+            if let timely::logging::StartStop::Stop = schedule.start_stop {
+                return self.sampled_activations.remove(&schedule.id);
+            }
+        }
+        let seen = self.seen.entry(name).or_insert(0);
+        *seen += 1;
+        let keep = (*seen - 1) % every == 0;
+        if let (true, TimelyEvent::Schedule(schedule)) = (keep, event) {
+            self.sampled_activations.insert(schedule.id);
+        }
+        keep
+    }
+}
+
+impl<P: EventPusher<Duration, (Duration, WorkerIdentifier, TimelyEvent)>> EventPusher<Duration, (Duration, WorkerIdentifier, TimelyEvent)> for EventFilter<P> {
+    fn push(&mut self, event: Event<Duration, (Duration, WorkerIdentifier, TimelyEvent)>) {
+        match event {
+            Event::Progress(updates) => self.pusher.push(Event::Progress(updates)),
+            Event::Messages(time, data) => {
+                let data = data.into_iter().filter(|(_, _, event)| self.keep(event)).collect::<Vec<_>>();
+                if !data.is_empty() {
+                    self.pusher.push(Event::Messages(time, data));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        let spec = FilterSpec::parse("types=schedule, messages;operators=Join,Reduce;sample=messages:100").expect("valid spec");
+        assert_eq!(spec.types, Some(vec!["schedule".to_string(), "messages".to_string()].into_iter().collect()));
+        assert_eq!(spec.operators, Some(vec!["Join".to_string(), "Reduce".to_string()]));
+        assert_eq!(spec.sample.get("messages"), Some(&100));
+        assert_eq!(FilterSpec::parse(&spec.to_string()).expect("valid spec"), spec);
+    }
+
+    #[test]
+    fn parse_empty_spec() {
+        assert_eq!(FilterSpec::parse("").expect("valid spec"), FilterSpec::default());
+        assert_eq!(FilterSpec::parse(" ; ").expect("valid spec"), FilterSpec::default());
+        assert_eq!(FilterSpec::default().to_string(), "");
+    }
+
+    #[test]
+    fn parse_invalid_spec() {
+        for spec in &["types=schedule,nothing", "kinds=schedule", "types", "sample=schedule", "sample=schedule:0", "sample=schedule:x", "sample=nothing:2"] {
+            assert!(FilterSpec::parse(spec).is_err(), "{:?} should be invalid", spec);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::Write;
+use std::marker::PhantomData;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+
+/// Like timely's `EventWriter`, but flushes the stream after every
+/// progress update, i.e. once per batch published by a `BatchLogger`,
+/// so that the events of a buffering writer (e.g. a `CompressingWriter`)
+/// reach tdiag as they're logged.
+///
+/// ```ignore
+/// let writer = Compression::Zstd.writer(stream)?;
+/// let mut logger = BatchLogger::new(FlushingEventWriter::new(DetachableWriter::new(writer)));
+/// ```
+pub struct FlushingEventWriter<T, D, W: Write> {
+    stream: W,
+    buffer: Vec<u8>,
+    phantom: PhantomData<(T, D)>,
+}
+
+impl<T, D, W: Write> FlushingEventWriter<T, D, W> {
+    /// Writes the events to `stream`.
+    pub fn new(stream: W) -> Self {
+        FlushingEventWriter {
+            stream,
+            buffer: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: Abomonation, D: Abomonation, W: Write> EventPusher<T, D> for FlushingEventWriter<T, D, W> {
+    fn push(&mut self, event: Event<T, D>) {
+        let progress = matches!(event, Event::Progress(_));
+        unsafe { abomonation::encode(&event, &mut self.buffer).expect("Event abomonation/write failed"); }
+        self.stream.write_all(&self.buffer[..]).expect("Event write failed.");
+        self.buffer.clear();
+        if progress {
+            self.stream.flush().expect("Event flush failed.");
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::ConnectError;
+use crate::compression::Compression;
+use crate::handshake::Reply;
+use crate::recording::StreamKind;
+
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use abomonation::Abomonation;
+
+use timely::communication::Allocate;
+use timely::dataflow::operators::capture::event::EventPusher;
+use timely::logging::{BatchLogger, TimelyEvent, WorkerIdentifier};
+use timely::worker::Worker;
+
+use super::{DetachableWriter, EventFilter, FilterSpec, FlushingEventWriter, ReconnectingSender};
+
+/// The address of tdiag's port for timely log streams, read by
+/// `connect_timely_logging`. Not `TIMELY_WORKER_LOG_ADDR`: `timely::execute`
+/// already connects to that one by itself.
+pub const TIMELY_LOG_ADDR_VAR: &str = "TDIAG_TIMELY_LOG_ADDR";
+
+/// The address of tdiag's port for differential log streams, read by
+/// `connect_differential_logging`.
+pub const DIFFERENTIAL_LOG_ADDR_VAR: &str = "DIFFERENTIAL_LOG_ADDR";
+
+/// The address of tdiag's port for both timely and differential log
+/// streams, with `--multiplex`, read by `connect_timely_logging` and
+/// `connect_differential_logging` when their own variable isn't set.
+/// tdiag tells the streams apart by their header (see `handshake`).
+pub const LOG_ADDR_VAR: &str = "TDIAG_LOG_ADDR";
+
+/// If set (to `gzip` or `zstd`), the log streams are compressed, for
+/// tdiag's `--compression`.
+pub const COMPRESSION_VAR: &str = "TDIAG_LOG_COMPRESSION";
+
+/// If set (to a number of seconds), the log streams are sent with a
+/// `ReconnectingSender` trying to reconnect that often, for tdiag's
+/// `--reconnect-timeout`.
+pub const RECONNECT_VAR: &str = "TDIAG_LOG_RECONNECT";
+
+/// How long to keep trying to connect, e.g. while tdiag is starting up.
+const CONNECT_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait between attempts to connect.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Sends `worker`'s timely log stream to the tdiag at the address in
+/// `TDIAG_TIMELY_LOG_ADDR` (or `TDIAG_LOG_ADDR`), if set; returns
+/// whether it is.
+///
+/// The connection is retried for a few seconds, in case tdiag isn't
+/// listening yet. The events are filtered according to the spec tdiag
+/// answers the header with (see `FilterSpec` and tdiag's
+/// `--source-filter`), or else `TIMELY_WORKER_LOG_FILTER`, compressed if
+/// `TDIAG_LOG_COMPRESSION` is set, and the connection is re-established
+/// if `TDIAG_LOG_RECONNECT` is set. Once the connection fails, the log
+/// stream is discarded rather than crashing the worker.
+///
+/// The stream starts with a header (see `handshake`) telling tdiag the
+/// worker's index and the versions the events are encoded with, so that
+/// a tdiag built with another timely version reports it rather than
+/// decoding garbage. When reconnecting, the connection is established in
+/// the background (see `ReconnectingSender`), so only
+/// `TIMELY_WORKER_LOG_FILTER` applies.
+///
+/// ```ignore
+/// timely::execute_from_args(std::env::args(), |worker| {
+///     tdiag_connect::send::connect_timely_logging(worker).expect("cannot connect to tdiag");
+///     // ...
+/// })
+/// ```
+pub fn connect_timely_logging<A: Allocate>(worker: &mut Worker<A>) -> Result<bool, ConnectError> {
+    let (addr, multiplexed) = match addr_from_env(TIMELY_LOG_ADDR_VAR) {
+        Some(addr) => addr,
+        None => return Ok(false),
+    };
+    let spec = FilterSpec::from_env()?.unwrap_or_default();
+    let compression = compression_from_env()?;
+    match reconnect_from_env(multiplexed)? {
+        Some(retry) => {
+            let sender = ReconnectingSender::<TimelyEvent>::new(resolve(&addr)?, StreamKind::Timely, worker.index(), retry).with_compression(compression);
+            register(worker, "timely", EventFilter::new(sender, spec));
+        }
+        None => {
+            let (stream, reply) = connect_stream(&addr, StreamKind::Timely, worker.index())?;
+            let spec = match reply.filter {
+                Some(filter) => FilterSpec::parse(&filter)?,
+                None => spec,
+            };
+            let writer = compression.writer(stream)?;
+            register(worker, "timely", EventFilter::new(FlushingEventWriter::new(DetachableWriter::new(writer)), spec));
+        }
+    }
+    Ok(true)
+}
+
+/// Sends `worker`'s differential log stream (of `E`, i.e.
+/// `differential_dataflow::logging::DifferentialEvent`) to the tdiag at
+/// the address in `DIFFERENTIAL_LOG_ADDR` (or `TDIAG_LOG_ADDR`), if set;
+/// returns whether it is. Like `connect_timely_logging`, but without
+/// filtering.
+///
+/// ```ignore
+/// tdiag_connect::send::connect_differential_logging::<_, DifferentialEvent>(worker)
+///     .expect("cannot connect to tdiag");
+/// ```
+pub fn connect_differential_logging<A: Allocate, E: Abomonation + Clone + 'static>(worker: &mut Worker<A>) -> Result<bool, ConnectError> {
+    let (addr, multiplexed) = match addr_from_env(DIFFERENTIAL_LOG_ADDR_VAR) {
+        Some(addr) => addr,
+        None => return Ok(false),
+    };
+    let compression = compression_from_env()?;
+    match reconnect_from_env(multiplexed)? {
+        Some(retry) => {
+            let sender = ReconnectingSender::<E>::new(resolve(&addr)?, StreamKind::Differential, worker.index(), retry).with_compression(compression);
+            register(worker, "differential/arrange", sender);
+        }
+        None => {
+            // Differential log streams aren't filtered.
+            let (stream, _reply) = connect_stream(&addr, StreamKind::Differential, worker.index())?;
+            let writer = compression.writer(stream)?;
+            register(worker, "differential/arrange", FlushingEventWriter::<_, (_, _, E), _>::new(DetachableWriter::new(writer)));
+        }
+    }
+    Ok(true)
+}
+
+fn register<A, E, P>(worker: &mut Worker<A>, name: &str, pusher: P)
+where
+    A: Allocate,
+    E: Clone + 'static,
+    P: EventPusher<Duration, (Duration, WorkerIdentifier, E)> + 'static {
+
+    let mut logger = BatchLogger::new(pusher);
+    worker.log_register().insert::<E, _>(name, move |time, data| logger.publish_batch(time, data));
+}
+
+fn compression_from_env() -> Result<Compression, ConnectError> {
+    match std::env::var(COMPRESSION_VAR) {
+        Ok(name) => Compression::parse(&name),
+        Err(_) => Ok(Compression::None),
+    }
+}
+
+/// The address in `var`, or else in `TDIAG_LOG_ADDR`, and whether it's
+/// the latter (multiplexed).
+fn addr_from_env(var: &str) -> Option<(String, bool)> {
+    match (std::env::var(var), std::env::var(LOG_ADDR_VAR)) {
+        (Ok(addr), _) => Some((addr, false)),
+        (Err(_), Ok(addr)) => Some((addr, true)),
+        (Err(_), Err(_)) => None,
+    }
+}
+
+fn reconnect_from_env(multiplexed: bool) -> Result<Option<Duration>, ConnectError> {
+    match std::env::var(RECONNECT_VAR) {
+        // tdiag doesn't resume multiplexed streams.
+        Ok(_) if multiplexed => Err(ConnectError::Other(format!("{} is not supported with {}", RECONNECT_VAR, LOG_ADDR_VAR))),
+        Ok(secs) => secs.parse::<u64>()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|e| ConnectError::Other(format!("Invalid {}: {}", RECONNECT_VAR, e))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr, ConnectError> {
+    addr.to_socket_addrs()?.next()
+        .ok_or_else(|| ConnectError::Other(format!("Invalid address: {}", addr)))
+}
+
+/// Connects to `addr`, starting with the header of a stream of `kind`
+/// sent by `worker`, and returns tdiag's reply.
+fn connect_stream(addr: &str, kind: StreamKind, worker: usize) -> Result<(TcpStream, Reply), ConnectError> {
+    let mut stream = connect_with_retries(addr)?;
+    let reply = crate::handshake::exchange(&mut stream, &crate::handshake::Header::new(kind, worker))?;
+    Ok((stream, reply))
+}
+
+fn connect_with_retries(addr: &str) -> Result<TcpStream, ConnectError> {
+    let deadline = Instant::now() + CONNECT_RETRY_TIMEOUT;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                if Instant::now() >= deadline {
+                    return Err(ConnectError::Other(format!("Cannot connect to tdiag at {}: {}", addr, e)));
+                }
+                std::thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+        }
+    }
+}
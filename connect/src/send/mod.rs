@@ -0,0 +1,22 @@
+mod capture;
+pub use capture::*;
+
+mod filter;
+pub use filter::*;
+
+mod detachable;
+pub use detachable::*;
+
+mod flushing;
+pub use flushing::*;
+
+mod reconnect;
+pub use reconnect::*;
+
+mod logging;
+pub use logging::*;
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::*;
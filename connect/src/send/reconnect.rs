@@ -0,0 +1,297 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use abomonation::Abomonation;
+
+use timely::communication::Allocate;
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+use timely::logging::{BatchLogger, WorkerIdentifier};
+use timely::progress::ChangeBatch;
+use timely::worker::Worker;
+
+use crate::ConnectError;
+use crate::compression::{Compression, CompressingWriter};
+use crate::handshake::Header;
+use crate::recording::StreamKind;
+
+use super::FlushingEventWriter;
+
+/// Sends a worker's log stream to tdiag, reconnecting if the connection
+/// drops, so that long-running monitoring survives transient network
+/// failures.
+///
+/// Each connection starts with the header of the stream (see
+/// `handshake`), by which tdiag tells which of its streams it resumes.
+/// Connecting is tried at most every `retry`, on a thread of its own, so
+/// that an unreachable tdiag doesn't hold up the worker.
+///
+/// While disconnected, the events logged (and the batch whose write
+/// failed) are kept in a backlog of up to `DEFAULT_BACKLOG` records (see
+/// `with_backlog`), dropping the oldest ones once it's full, and sent
+/// once reconnected. The capabilities currently held are then acquired
+/// at the start of the new stream, so that tdiag (with
+/// `--reconnect-timeout`) can resume the stream where it was left.
+///
+/// Connection errors never disrupt the source computation.
+pub struct ReconnectingSender<E> {
+    addr: SocketAddr,
+    header: Header,
+    retry: Duration,
+    compression: Compression,
+    current: Option<Connection<E>>,
+    connecting: Option<Receiver<std::io::Result<TcpStream>>>,
+    was_connected: bool,
+    last_attempt: Option<Instant>,
+    capabilities: ChangeBatch<Duration>,
+    backlog: VecDeque<(Duration, Vec<(Duration, WorkerIdentifier, E)>)>,
+    /// The number of records in `backlog`.
+    backlogged: usize,
+    max_backlog: usize,
+    /// The records dropped from the full backlog since the last connection.
+    dropped: usize,
+}
+
+struct Connection<E> {
+    writer: FlushingEventWriter<Duration, (Duration, WorkerIdentifier, E), ErrorFlaggingWriter>,
+    error: Rc<Cell<Option<std::io::ErrorKind>>>,
+}
+
+/// How long to wait for a connection to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of records kept while disconnected, by default.
+pub const DEFAULT_BACKLOG: usize = 100_000;
+
+impl<E: Abomonation + Clone> ReconnectingSender<E> {
+    /// Sends `worker`'s log stream of `kind` to tdiag at `addr`, which
+    /// doesn't need to be reachable yet.
+    pub fn new(addr: SocketAddr, kind: StreamKind, worker: usize, retry: Duration) -> Self {
+        ReconnectingSender {
+            addr,
+            header: Header::new(kind, worker),
+            retry,
+            compression: Compression::None,
+            current: None,
+            connecting: None,
+            was_connected: false,
+            last_attempt: None,
+            // Every stream implicitly starts with a capability at the minimal time.
+            capabilities: ChangeBatch::new_from(Duration::default(), 1),
+            backlog: VecDeque::new(),
+            backlogged: 0,
+            max_backlog: DEFAULT_BACKLOG,
+            dropped: 0,
+        }
+    }
+
+    /// Compresses the log stream, for tdiag's `--compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Keeps up to `records` records while disconnected, rather than
+    /// `DEFAULT_BACKLOG`; 0 discards them.
+    pub fn with_backlog(mut self, records: usize) -> Self {
+        self.max_backlog = records;
+        self
+    }
+
+    /// Whether the log stream is currently being sent.
+    pub fn is_connected(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Takes up the connection being established, if it is, or starts
+    /// establishing one if it's time to retry.
+    fn poll_connect(&mut self) {
+        if let Some(connecting) = &self.connecting {
+            let connected = match connecting.try_recv() {
+                Ok(connected) => connected,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Err(std::io::Error::new(std::io::ErrorKind::Other, "connecting thread failed")),
+            };
+            self.connecting = None;
+            match connected.and_then(|stream| self.start(stream)) {
+                Ok(()) if self.was_connected => eprintln!("tdiag-connect: reconnected to {}", self.addr),
+                Ok(()) => (),
+                Err(e) => eprintln!("tdiag-connect: cannot connect to {}: {}", self.addr, e),
+            }
+            if self.current.is_some() {
+                self.was_connected = true;
+                if self.dropped > 0 {
+                    eprintln!("tdiag-connect: dropped {} records logged while disconnected from {}", self.dropped, self.addr);
+                    self.dropped = 0;
+                }
+            }
+            return;
+        }
+
+        if self.last_attempt.map_or(true, |t| t.elapsed() >= self.retry) {
+            self.last_attempt = Some(Instant::now());
+            let (addr, header) = (self.addr, self.header.clone());
+            let (send, receive) = mpsc::channel();
+            let spawned = std::thread::Builder::new()
+                .name("tdiag-connect".to_string())
+                .spawn(move || {
+                    // The reply is read so that the connection isn't reset
+                    // with unread bytes; its filter spec comes too late for
+                    // the events already filtered.
+                    let connected = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                        .map_err(ConnectError::from)
+                        .and_then(|mut stream| crate::handshake::exchange(&mut stream, &header).map(|_| stream))
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+                    let _ = send.send(connected);
+                });
+            match spawned {
+                Ok(_) => self.connecting = Some(receive),
+                Err(e) => eprintln!("tdiag-connect: cannot connect to {}: {}", self.addr, e),
+            }
+        }
+    }
+
+    /// Starts a new stream on `stream` (after its header): sends the
+    /// backlog, while the stream holds its implicit capability at the
+    /// minimal time, then acquires the capabilities currently held.
+    fn start(&mut self, stream: TcpStream) -> std::io::Result<()> {
+        let stream = self.compression.writer(stream)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))?;
+        let error = Rc::new(Cell::new(None));
+        let mut writer = FlushingEventWriter::new(ErrorFlaggingWriter { inner: stream, error: error.clone() });
+
+        for (time, data) in self.backlog.iter() {
+            writer.push(Event::Messages(*time, data.clone()));
+        }
+        let mut acquired = ChangeBatch::new_from(Duration::default(), -1);
+        acquired.extend(self.capabilities.iter().cloned());
+        let acquired = acquired.into_inner();
+        if !acquired.is_empty() {
+            writer.push(Event::Progress(acquired));
+        }
+        if let Some(kind) = error.get() {
+            return Err(kind.into());
+        }
+        self.backlog.clear();
+        self.backlogged = 0;
+        self.current = Some(Connection { writer, error });
+        Ok(())
+    }
+
+    /// Keeps `data` until reconnected, dropping the oldest records if the
+    /// backlog is full.
+    fn keep(&mut self, time: Duration, data: Vec<(Duration, WorkerIdentifier, E)>) {
+        self.backlogged += data.len();
+        self.backlog.push_back((time, data));
+        while self.backlogged > self.max_backlog {
+            match self.backlog.pop_front() {
+                Some((_, data)) => {
+                    self.backlogged -= data.len();
+                    self.dropped += data.len();
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<E: Abomonation + Clone> EventPusher<Duration, (Duration, WorkerIdentifier, E)> for ReconnectingSender<E> {
+    fn push(&mut self, event: Event<Duration, (Duration, WorkerIdentifier, E)>) {
+        // Progress is only kept as the capabilities held, which a new
+        // stream acquires at once.
+        if let Event::Progress(updates) = &event {
+            self.capabilities.extend(updates.iter().cloned());
+        }
+
+        if self.current.is_none() {
+            self.poll_connect();
+        }
+
+        let connection = match &mut self.current {
+            Some(connection) => connection,
+            None => {
+                if let Event::Messages(time, data) = event {
+                    self.keep(time, data);
+                }
+                return;
+            }
+        };
+        // Kept until written, to be sent again if the connection drops.
+        let resend = match &event {
+            Event::Messages(time, data) if self.max_backlog > 0 => Some((*time, data.clone())),
+            _ => None,
+        };
+        connection.writer.push(event);
+        if let Some(kind) = connection.error.get() {
+            eprintln!("tdiag-connect: lost the connection to {} ({:?}), reconnecting every {:?}", self.addr, kind, self.retry);
+            self.current = None;
+            self.last_attempt = Some(Instant::now());
+            if let Some((time, data)) = resend {
+                self.keep(time, data);
+            }
+        }
+    }
+}
+
+/// Swallows write errors so that `FlushingEventWriter` doesn't panic; they're
+/// reported through `error`, and nothing is written after the first one.
+struct ErrorFlaggingWriter {
+    inner: CompressingWriter<TcpStream>,
+    error: Rc<Cell<Option<std::io::ErrorKind>>>,
+}
+
+impl Write for ErrorFlaggingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.error.get().is_some() {
+            return Ok(buf.len());
+        }
+        match self.inner.write(buf) {
+            Ok(written) => Ok(written),
+            // Retried by `write_all`.
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Err(e),
+            Err(e) => {
+                self.error.set(Some(e.kind()));
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.error.get().is_none() {
+            if let Err(e) = self.inner.flush() {
+                self.error.set(Some(e.kind()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends `worker`'s timely (`StreamKind::Timely`) or differential
+/// (`StreamKind::Differential`) log stream to tdiag at `addr`,
+/// reconnecting every `retry` if the connection drops, see
+/// `ReconnectingSender`.
+///
+/// Call this when setting up the worker, instead of setting
+/// `TIMELY_WORKER_LOG_ADDR`:
+///
+/// ```ignore
+/// let addr = "127.0.0.1:51317".parse().unwrap();
+/// tdiag_connect::send::connect_reconnecting::<_, TimelyEvent>(worker, StreamKind::Timely, addr, Duration::from_secs(1));
+/// ```
+pub fn connect_reconnecting<A: Allocate, E: Abomonation + Clone + 'static>(
+    worker: &mut Worker<A>,
+    kind: StreamKind,
+    addr: SocketAddr,
+    retry: Duration) {
+
+    let mut logger = BatchLogger::new(ReconnectingSender::<E>::new(addr, kind, worker.index(), retry));
+    let name = match kind {
+        StreamKind::Timely => "timely",
+        StreamKind::Differential => "differential/arrange",
+    };
+    worker.log_register().insert::<E, _>(name, move |time, data| logger.publish_batch(time, data));
+}
@@ -0,0 +1,61 @@
+use crate::ConnectError;
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslStream};
+
+/// How a source computation establishes TLS sessions with tdiag (see
+/// `connect_tls`).
+#[derive(Clone, Debug, Default)]
+pub struct TlsClientConfig {
+    /// PEM file with the CAs that tdiag's certificate is verified
+    /// against, in addition to the system's.
+    pub ca: Option<PathBuf>,
+    /// PEM file with the client certificate chain presented to tdiag,
+    /// for endpoints that require one (`--tls-client-ca`).
+    pub cert: Option<PathBuf>,
+    /// PEM file with the private key for `cert`.
+    pub key: Option<PathBuf>,
+}
+
+impl TlsClientConfig {
+    /// Builds the connector used to establish the TLS sessions.
+    pub fn connector(&self) -> Result<SslConnector, ConnectError> {
+        let tls_error = |e: openssl::error::ErrorStack| ConnectError::Other(format!("TLS configuration error: {}", e));
+
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+        if let Some(ca) = &self.ca {
+            builder.set_ca_file(ca).map_err(tls_error)?;
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                builder.set_certificate_chain_file(cert).map_err(tls_error)?;
+                builder.set_private_key_file(key, SslFiletype::PEM).map_err(tls_error)?;
+                builder.check_private_key().map_err(tls_error)?;
+            }
+            (None, None) => (),
+            _ => return Err(ConnectError::Other("A client certificate needs both a certificate and a key file".to_string())),
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Connects to tdiag at `addr` (started with `--tls-cert` and
+/// `--tls-key`), verifying that its certificate is valid for `domain`.
+///
+/// Use the stream in place of the plain `TcpStream` when registering
+/// the loggers:
+///
+/// ```ignore
+/// let config = TlsClientConfig { ca: Some("tdiag-ca.pem".into()), ..Default::default() };
+/// let stream = tdiag_connect::send::connect_tls("tdiag.example.com:51317", "tdiag.example.com", &config)?;
+/// let mut logger = BatchLogger::new(EventWriter::new(DetachableWriter::new(stream)));
+/// worker.log_register().insert::<TimelyEvent, _>("timely", move |time, data| logger.publish_batch(time, data));
+/// ```
+pub fn connect_tls<A: ToSocketAddrs>(addr: A, domain: &str, config: &TlsClientConfig) -> Result<SslStream<TcpStream>, ConnectError> {
+    let connector = config.connector()?;
+    let stream = TcpStream::connect(addr)?;
+    connector.connect(domain, stream)
+        .map_err(|e| ConnectError::Other(format!("TLS handshake failed: {}", e)))
+}
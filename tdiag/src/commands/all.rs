@@ -0,0 +1,110 @@
+//! "all" subcommand: runs several analyses over a single capture of the
+//! log streams, rather than a capture per subcommand.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::DiagError;
+use crate::commands::graph::{GraphFormat, GraphOptions};
+use crate::commands::vega::OutputFormat;
+use crate::session::SessionBuilder;
+
+/// The analyses `listen` runs, and where their results go.
+pub struct AllOptions {
+    /// Render the dataflow graph to this path.
+    pub graph_path: Option<PathBuf>,
+    /// How to write the graph.
+    pub graph_format: GraphFormat,
+    /// Write the time spent in each operator to this path, as `profile
+    /// --format json` does.
+    pub profile_path: Option<PathBuf>,
+    /// Write the arrangement sizes to this path, as `differential
+    /// arrangements --format json` does (needs the differential log
+    /// streams).
+    pub arrangements_path: Option<PathBuf>,
+    /// The interval (in ms) at which arrangement sizes are measured.
+    pub output_interval_ms: u64,
+}
+
+/// Runs the requested analyses as dataflows in the same workers, which
+/// share a single replay of the log streams, and writes their results
+/// once the analyses stop (see `await_stop`).
+///
+/// Each subcommand captures the log streams on its own, and the source
+/// computation only logs to a single tdiag: this gets the graph, the
+/// profile and the arrangement sizes of a single run.
+pub fn listen(config: &crate::config::DiagConfig, options: AllOptions) -> Result<(), DiagError> {
+    let AllOptions { graph_path, graph_format, profile_path, arrangements_path, output_interval_ms } = options;
+
+    if graph_path.is_none() && profile_path.is_none() && arrangements_path.is_none() {
+        return Err(DiagError::InvalidArgs("Nothing to do, pass at least one of --graph, --profile and --arrangements".to_string()));
+    }
+
+    // Before listening, so that an unusable path is reported right away.
+    let create = |path: &PathBuf| std::fs::File::create(path)
+        .map(std::io::BufWriter::new)
+        .map_err(|e| DiagError::io(format!("cannot create {}", path.display()), e));
+    let profile_out = profile_path.as_ref().map(create).transpose()?;
+    let arrangements_out = arrangements_path.as_ref().map(create).transpose()?;
+
+    let mut builder = SessionBuilder::new(config.clone());
+    if graph_path.is_some() {
+        builder = builder.graph();
+    }
+    if profile_path.is_some() {
+        builder = builder.profile();
+    }
+    if arrangements_path.is_some() {
+        builder = builder.arrangements(output_interval_ms, Default::default());
+    }
+    let mut session = builder.start()?;
+
+    let printer = match (arrangements_out, session.arrangement_sizes()) {
+        (Some(out), Some(sizes)) => {
+            // A failed printer stops the session; its error is returned
+            // once the other results are written.
+            let stop = session.stop_handle();
+            Some(std::thread::Builder::new()
+                .name("tdiag-arrangements".to_string())
+                .spawn(move || {
+                    let printed = crate::commands::arrangements::print_sizes(Box::new(out), OutputFormat::Json, None, None, sizes);
+                    if printed.is_err() {
+                        stop.stop();
+                    }
+                    printed
+                })?)
+        }
+        _ => None,
+    };
+
+    session.await_stop("Press enter to stop the analyses and write their results");
+
+    let results = match session.finish() {
+        Ok(results) => results,
+        Err(e) => {
+            // The workers are gone, which ends the printer.
+            if let Some(printer) = printer {
+                let _ = printer.join();
+            }
+            return Err(e);
+        }
+    };
+
+    if let (Some(path), Some(graph)) = (&graph_path, &results.graph) {
+        let options = GraphOptions { format: graph_format, ..Default::default() };
+        crate::commands::graph::render(config, graph, path, &options)?;
+    }
+
+    if let (Some(mut out), Some(path), Some(entries)) = (profile_out, &profile_path, &results.profile) {
+        crate::commands::profile::write_entries(&mut out, config, entries)?;
+        out.flush()?;
+        crate::status::info("profile_written", &format!("Profile written to {}", path.display()));
+    }
+
+    if let (Some(printer), Some(path)) = (printer, &arrangements_path) {
+        printer.join().map_err(|_| DiagError::Internal("the printer thread panicked".to_string()))??;
+        crate::status::info("arrangements_written", &format!("Arrangement sizes written to {}", path.display()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! "anonymize" subcommand: rewrites a recording without operator
+//! names, so that captures of proprietary systems can be shared (e.g.
+//! when reporting bugs).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::capture::EventWriter;
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+use timely::logging::TimelyEvent;
+
+use tdiag_connect::recording::{self, EventFileReader, Recording, RecordedStream, StreamKind};
+
+/// Writes a copy of the recording at `input` to `output` where operator
+/// names are replaced with generated ones (`op-1`, `op-2`, ...), and
+/// the contents of `Text` events are dropped.
+///
+/// The mapping from generated to original names is written to
+/// `mapping_path` as a name map, so that the owner of the recording can
+/// still analyse it with the original names (`--name-map`).
+pub fn anonymize(input: &Path, output: &Path, mapping_path: &Path) -> Result<(), DiagError> {
+    if mapping_path.starts_with(output) {
+        return Err(DiagError::InvalidArgs("the mapping must not be written into the anonymized recording".to_string()));
+    }
+
+    let recording = Recording::open(input)?;
+    recording::create_dir(output)?;
+
+    // original name -> generated name
+    let mut names = HashMap::new();
+
+    let mut streams = Vec::new();
+    for stream in recording.streams.iter() {
+        let path = output.join(recording::stream_file_name(stream.kind, stream.index));
+        match stream.kind {
+            StreamKind::Timely => anonymize_stream(&stream.path, &path, &mut names)?,
+            // Differential events only refer to operators by id.
+            StreamKind::Differential => { std::fs::copy(&stream.path, &path)?; },
+        }
+        streams.push(RecordedStream { kind: stream.kind, index: stream.index, path });
+    }
+
+    recording::write_manifest(output, &streams)?;
+
+    let count = names.len();
+    let mut mapping = toml::value::Table::new();
+    for (name, generated) in names.into_iter() {
+        mapping.insert(generated, toml::Value::String(name));
+    }
+    let mut name_map = toml::value::Table::new();
+    name_map.insert("names".to_string(), toml::Value::Table(mapping));
+    let contents = toml::to_string(&toml::Value::Table(name_map))
+        .map_err(|e| DiagError::Internal(format!("cannot encode the name mapping: {}", e)))?;
+
+    let mut file = File::create(mapping_path)
+        .map_err(|e| DiagError::io(format!("cannot create {}", mapping_path.display()), e))?;
+    writeln!(file, "# Generated by `tdiag anonymize`: maps the generated operator names back to the original ones.")?;
+    writeln!(file, "# Keep this file private; use it with --name-map to analyse the anonymized recording.")?;
+    file.write_all(contents.as_bytes())?;
+
+    println!("Anonymized {} operator name(s); the mapping is in {}", count, mapping_path.display());
+
+    Ok(())
+}
+
+/// Copies a timely stream, renaming operators through `names`.
+fn anonymize_stream(input: &Path, output: &Path, names: &mut HashMap<String, String>) -> Result<(), DiagError> {
+    let mut reader = EventFileReader::<Duration, LoggingTuple>::open(input)?;
+    let file = File::create(output).map_err(|e| DiagError::io(format!("cannot create {}", output.display()), e))?;
+    let mut writer = EventWriter::<Duration, LoggingTuple, _>::new(BufWriter::new(file));
+
+    while let Some(event) = reader.next_event()? {
+        match event {
+            Event::Progress(updates) => writer.push(Event::Progress(updates)),
+            Event::Messages(time, data) => {
+                let data = data.into_iter().map(|(t, worker, event)| {
+                    let event = match event {
+                        TimelyEvent::Operates(mut operates) => {
+                            let next = names.len() + 1;
+                            operates.name = names.entry(operates.name).or_insert_with(|| format!("op-{}", next)).clone();
+                            TimelyEvent::Operates(operates)
+                        }
+                        TimelyEvent::Text(_) => TimelyEvent::Text(String::new()),
+                        event => event,
+                    };
+                    (t, worker, event)
+                }).collect();
+                writer.push(Event::Messages(time, data));
+            }
+        }
+    }
+
+    Ok(())
+}
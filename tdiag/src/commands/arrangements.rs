@@ -1,133 +1,567 @@
 //! "arrangements" subcommand: cli tool to extract logical arrangement
 //! sizes over time.
+//!
+//! Besides tuple counts, the number of batches in each trace and the
+//! rate of completed merges are reported: compaction stalls tend to show
+//! up as a growing batch count long before the logical size explodes.
+//!
+//! The number of distinct logical times retained by an arrangement (the
+//! signature of a trace whose logical compaction isn't advanced) isn't
+//! reported: it would need the lower/upper bounds of each batch, which
+//! differential's `Batch` and `Merge` events don't carry as of
+//! differential-dataflow 0.11.
+//!
+//! This module includes `arrangements/arrangement-sizes.html` as a
+//! static resource, for `--render`.
 
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::convert::TryFrom;
 
 use crate::DiagError;
+use crate::report::Value;
+use crate::commands::vega::OutputFormat;
 
 use timely::dataflow::operators::{Filter, Map};
 use timely::logging::{TimelyEvent, WorkerIdentifier};
 use TimelyEvent::Operates;
 
 use differential_dataflow::collection::AsCollection;
+use differential_dataflow::difference::DiffPair;
 use differential_dataflow::logging::DifferentialEvent;
-use differential_dataflow::operators::{Count, Join};
+use differential_dataflow::operators::{Count, Join, Reduce, Threshold};
 use DifferentialEvent::{Batch, Merge, MergeShortfall, TraceShare};
 
-use tdiag_connect::receive::ReplayWithShutdown;
+use tdiag_connect::receive::{ReplayWithShutdown, ShutdownFn};
+
+static SIZES_HTML: &str = include_str!("arrangements/arrangement-sizes.html");
 
 /// Prints the number of tuples maintained in each arrangement.
 ///
 /// 1. Listens to incoming connections from a differential-dataflow
 /// program with timely and differential logging enabled;
 /// 2. runs a differential-dataflow program to track batching and
-/// compaction events and derive number of tuples for each trace;
-/// 3. prints the current size alongside arrangement names;
+/// compaction events and derive the number of tuples and batches of,
+/// and merges completed by, each trace;
+/// 3. prints the current size, batch count and merge rate (merges per
+/// second since the previous measurement) alongside arrangement names,
+/// dataflow indices and scope paths (as a table,
+/// or CSV rows with `OutputFormat::Csv`) as it's measured, or once the
+/// streams end, a JSON array of the measurements (`OutputFormat::Json`)
+/// or a Vega-Lite spec of the sizes over time (`OutputFormat::Vega`).
+///
+/// See `ArrangementsOptions` for where the output goes, and what else
+/// is reported.
 pub fn listen(
-    timely_configuration: timely::Configuration,
-    timely_sockets: Vec<Option<std::net::TcpStream>>,
-    differential_sockets: Vec<Option<std::net::TcpStream>>,
-    output_interval_ms: u64, 
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: crate::source::LogStreams,
+    options: ArrangementsOptions,
+) -> Result<(), crate::DiagError> {
+    let ArrangementsOptions { output_interval_ms, format, out_path, render_path, statsd, record_bytes } = options;
+
+    // Before listening, so that an unusable path is reported right away.
+    let out: Box<dyn Write + Send> = match &out_path {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)
+            .map_err(|e| DiagError::io(format!("cannot create {}", path.display()), e))?)),
+        None => Box::new(std::io::stdout()),
+    };
+    let render = match &render_path {
+        Some(path) => Some((path.to_path_buf(), std::fs::File::create(path)
+            .map_err(|e| DiagError::io(format!("cannot create {}", path.display()), e))?)),
+        None => None,
+    };
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+
+    // Set if the printer stops early (e.g. on a broken pipe), which
+    // stops the measurement too.
+    let printer_failed = Arc::new(AtomicBool::new(false));
+    let printer_failed_p = printer_failed.clone();
+    let printer = std::thread::Builder::new()
+        .name("tdiag-arrangements".to_string())
+        .spawn(move || {
+            let printed = print_sizes(out, format, render, statsd, output_recv);
+            if printed.is_err() {
+                printer_failed_p.store(true, Ordering::Release);
+            }
+            printed
+        })?;
+
+    let measured = measure(config, timely_streams, differential_streams, output_interval_ms, record_bytes, output_send, printer_failed);
+
+    printer.join().map_err(|_| DiagError::Internal("the arrangements printer thread panicked".to_string()))??;
+    measured
+}
+
+/// Measures the arrangement sizes, as `listen` does, and returns them
+/// (in the order in which they were measured) once the streams end.
+pub fn listen_and_collect(
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: crate::source::LogStreams,
+    output_interval_ms: u64,
+    record_bytes: RecordBytes,
+) -> Result<Vec<ArrangementSize>, crate::DiagError> {
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    measure(config, timely_streams, differential_streams, output_interval_ms, record_bytes, output_send, Default::default())?;
+    Ok(output_recv.into_iter().collect())
+}
+
+/// Runs the dataflow measuring the arrangement sizes every
+/// `output_interval_ms`, sending them to `output_send`, until the
+/// streams end or `stopped` is set.
+fn measure(
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: crate::source::LogStreams,
+    output_interval_ms: u64,
+    record_bytes: RecordBytes,
+    output_send: std::sync::mpsc::Sender<ArrangementSize>,
+    stopped: Arc<AtomicBool>,
 ) -> Result<(), crate::DiagError> {
-    let timely_sockets = Arc::new(Mutex::new(timely_sockets));
-    let differential_sockets = Arc::new(Mutex::new(differential_sockets));
-
-    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
-    let is_running_w = is_running.clone();
-
-    timely::execute(timely_configuration, move |worker| {
-        let timely_sockets = timely_sockets.clone();
-        let differential_sockets = differential_sockets.clone();
-
-        let timely_replayer = tdiag_connect::receive::make_readers::<
-            Duration,
-            (Duration, WorkerIdentifier, TimelyEvent),
-        >(
-            tdiag_connect::receive::ReplaySource::Tcp(timely_sockets),
-            worker.index(),
-            worker.peers(),
-        )
-        .expect("failed to open timely tcp readers");
-
-        let differential_replayer = tdiag_connect::receive::make_readers::<
-            Duration,
-            (Duration, WorkerIdentifier, DifferentialEvent),
-        >(
-            tdiag_connect::receive::ReplaySource::Tcp(differential_sockets),
-            worker.index(),
-            worker.peers(),
-        )
-            .expect("failed to open differential tcp readers");
+    let measurement = Measurement::new(config, output_interval_ms, Arc::new(record_bytes))?;
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    // Runs until the streams end, the capture duration elapses, tdiag is
+    // interrupted, or the memory budget is exceeded.
+    let resources = config.resources().clone();
+    let timer = timely_streams.clone();
+    crate::signals::arm();
+
+    timely::execute(config.timely_configuration(), move |worker| {
+        let measurement = measurement.clone();
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+        let resources_d = resources.clone();
+        let resources_t = resources.clone();
+        let (timer_t, timer_d) = (timer.clone(), timer.clone());
+        let (stopped_t, stopped_d) = (stopped.clone(), stopped.clone());
+
+        let timely_replayer = timely_streams
+            .readers::<Duration, (Duration, WorkerIdentifier, TimelyEvent)>(worker.index(), worker.peers())
+            .expect("failed to open timely readers");
+
+        let differential_replayer = differential_streams
+            .readers::<Duration, (Duration, WorkerIdentifier, DifferentialEvent)>(worker.index(), worker.peers())
+            .expect("failed to open differential readers");
 
         worker.dataflow::<Duration, _, _>(|scope| {
-            let operates = timely_replayer
-                .replay_with_shutdown_into(scope, is_running_w.clone())
-                .flat_map(|(t, worker, x)| {
-                    if let Operates(event) = x {
-                        Some((
-                            (
-                                (worker, event.id),
-                                format!("{} ({:?})", event.name, event.addr),
-                            ),
-                            t,
-                            1 as isize,
-                        ))
-                    } else {
-                        None
-                    }
-                })
-                .as_collection();
-
-            let events =
-                differential_replayer.replay_with_shutdown_into(scope, is_running_w.clone());
-
-            // Print output header.
-            println!("ms\tWorker\tOp. Id\tName\t# of tuples");
-
-            // Track sizes.
-            events
-                .flat_map(|(t, worker, x)| match x {
-                    Batch(x) => Some(((worker, x.operator), t, x.length as isize)),
-                    Merge(x) => match x.complete {
-                        None => None,
-                        Some(complete_size) => {
-                            let size_diff =
-                                (complete_size as isize) - (x.length1 + x.length2) as isize;
-
-                            Some(((worker, x.operator), t, size_diff as isize))
-                        }
-                    },
-                    MergeShortfall(x) => {
-                        eprintln!("MergeShortfall {:?}", x);
-                        None
-                    },
-                    DifferentialEvent::Drop(x) => Some(((worker, x.operator), t, -(x.length as isize))),
-                    TraceShare(_x) => None,
-                })
-                .as_collection()
-                .delay(move |t| {
-                    let timestamp: u64 = u64::try_from(t.as_millis())
-                        .expect("Why are the timestamps larger than humans are old?");
-
-                    let window_idx = (timestamp / output_interval_ms) + 1;
-
-                    Duration::from_millis(window_idx * output_interval_ms)
-                })
-                .count()
-                .inner
-                // We do not bother with retractions here, because the
-                // user is only interested in the current count.
-                .filter(|(_, _, count)| count >= &0)
-                .as_collection()
-                .join(&operates)
-                .inspect(|(((worker, operator), (count, name)), t, _diff)| {
-                    println!("{}\t{}\t{}\t{}\t{}", t.as_millis(), worker, operator, name, count);
-                });
+            let timely_events = timely_replayer
+                .replay_with_shutdown_into(scope, ShutdownFn(move || resources_t.memory_exhausted() || timer_t.timed_out() || crate::signals::interrupted() || stopped_t.load(Ordering::Acquire)));
+            let differential_events = differential_replayer
+                .replay_with_shutdown_into(scope, ShutdownFn(move || resources_d.memory_exhausted() || timer_d.timed_out() || crate::signals::interrupted() || stopped_d.load(Ordering::Acquire)));
+            build(&timely_events, &differential_events, measurement, output_send);
         })
     })
-    .map_err(|x| DiagError(format!("error in the timely computation: {}", x)))?;
+    .map_err(DiagError::Timely)?;
 
     Ok(())
 }
+
+/// How `build` measures the arrangement sizes, and what it reports
+/// them with besides its output.
+#[derive(Clone)]
+pub(crate) struct Measurement {
+    output_interval_ms: u64,
+    record_bytes: Arc<RecordBytes>,
+    name_map: crate::names::NameMap,
+    report: Option<crate::report::Report>,
+    resources: crate::resources::Resources,
+}
+
+impl Measurement {
+    /// Measures every `output_interval_ms`, with the names, report and
+    /// memory budget of `config`.
+    ///
+    /// Fails if the selected source workers belong to several sessions,
+    /// as operators are named by id (see `DiagConfig::structure_worker`).
+    pub(crate) fn new(config: &crate::config::DiagConfig, output_interval_ms: u64, record_bytes: Arc<RecordBytes>) -> Result<Self, crate::DiagError> {
+        config.structure_worker()?;
+        Ok(Measurement {
+            output_interval_ms,
+            record_bytes,
+            name_map: config.names().clone(),
+            report: config.reports().cloned(),
+            resources: config.resources().clone(),
+        })
+    }
+}
+
+/// Builds the dataflow measuring the arrangement sizes as set by
+/// `measurement` from the replayed timely and differential events,
+/// sending them to `output_send`.
+pub(crate) fn build<G: timely::dataflow::Scope<Timestamp=Duration>>(
+    timely_events: &timely::dataflow::Stream<G, (Duration, WorkerIdentifier, TimelyEvent)>,
+    differential_events: &timely::dataflow::Stream<G, (Duration, WorkerIdentifier, DifferentialEvent)>,
+    measurement: Measurement,
+    output_send: std::sync::mpsc::Sender<ArrangementSize>,
+) {
+    let Measurement { output_interval_ms, record_bytes, name_map, report, resources } = measurement;
+
+    // Operators are named after the `Operates` events of any
+    // source worker, as all workers build the same dataflows: a
+    // worker's arrangements are named even if its own timely
+    // stream is missing or handled by another diagnostic worker.
+    let operates = timely_events
+        .flat_map(move |(t, _worker, x)| {
+            if let Operates(event) = x {
+                let label = name_map.label(&event.addr, &event.name);
+                Some(((event.id, (event.addr, label)), t, 1 as isize))
+            } else {
+                None
+            }
+        })
+        .as_collection()
+        .distinct();
+
+    // The names of the scopes enclosing each operator, outermost
+    // (its dataflow) first. The operator itself is included, so
+    // that operators outside of any scope get an (empty) path.
+    let named = operates
+        .flat_map(|(id, (addr, _))| (1..=addr.len()).map(move |depth| (addr[..depth].to_vec(), (id, depth))))
+        .join_map(&operates.map(|(_, (addr, label))| (addr, label)), |addr, (id, depth), label| (*id, (*depth, addr.clone(), label.clone())))
+        .reduce(|_, prefixes, output| {
+            // Sorted by depth: the operator itself comes last.
+            let ((_, addr, label), _) = &prefixes[prefixes.len() - 1];
+            let path = prefixes[..prefixes.len() - 1].iter()
+                .map(|((_, _, name), _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" / ");
+            output.push(((addr[0], path, format!("{} ({:?})", label, addr)), 1));
+        });
+
+    // Track sizes, batch counts and completed merges.
+    differential_events
+        .flat_map(|(t, worker, x)| match x {
+            Batch(x) => Some(((worker, x.operator), t, stats(x.length as isize, 1, 0))),
+            Merge(x) => match x.complete {
+                None => None,
+                Some(complete_size) => {
+                    let size_diff =
+                        (complete_size as isize) - (x.length1 + x.length2) as isize;
+
+                    // Two batches are merged into one.
+                    Some(((worker, x.operator), t, stats(size_diff, -1, 1)))
+                }
+            },
+            MergeShortfall(x) => {
+                crate::status::warn("merge_shortfall", &format!("Arrangement {} fell behind merging its batches: {:?}", x.operator, x));
+                None
+            },
+            DifferentialEvent::Drop(x) => Some(((worker, x.operator), t, stats(-(x.length as isize), -1, 0))),
+            TraceShare(_x) => None,
+        })
+        .as_collection()
+        .delay(move |t| {
+            let timestamp: u64 = u64::try_from(t.as_millis())
+                .expect("Why are the timestamps larger than humans are old?");
+
+            // Wider windows when approaching the memory budget.
+            let interval_ms = output_interval_ms * resources.coarsening();
+            let window_idx = (timestamp / interval_ms) + 1;
+
+            Duration::from_millis(window_idx * interval_ms)
+        })
+        .count()
+        .inner
+        // We do not bother with retractions here, because the
+        // user is only interested in the current count.
+        .filter(|(_, _, count)| count >= &0)
+        .as_collection()
+        .map(|((worker, operator), stats)| (operator, (worker, stats)))
+        .join(&named)
+        .inspect(move |((operator, ((worker, stats), (dataflow, scope, name))), t, _diff)| {
+            let (tuples, batches, merges) = (stats.element1, stats.element2.element1, stats.element2.element2);
+            let bytes = record_bytes.get(*operator).map(|record| std::cmp::max(tuples, 0) as u64 * record);
+            crate::telemetry::arrangement_size(*worker, *operator, name, tuples);
+            crate::telemetry::arrangement_batches(*worker, *operator, name, batches, merges);
+            if let Some(report) = &report {
+                report.record("arrangement_size", vec![
+                    ("t_ms", (t.as_millis() as u64).into()),
+                    ("worker", (*worker).into()),
+                    ("dataflow", (*dataflow).into()),
+                    ("operator", (*operator).into()),
+                    ("scope", scope.as_str().into()),
+                    ("name", name.as_str().into()),
+                    ("tuples", tuples.into()),
+                    ("batches", batches.into()),
+                    ("merges", merges.into()),
+                    ("bytes", bytes.map_or(Value::Null, Value::from)),
+                ]);
+            }
+            let size = ArrangementSize {
+                t_ms: t.as_millis() as u64,
+                worker: *worker,
+                dataflow: *dataflow,
+                operator: *operator,
+                scope: scope.clone(),
+                name: name.clone(),
+                tuples,
+                batches,
+                merges,
+                bytes,
+            };
+            // Fails once the receiver is gone (e.g. its printer stopped
+            // on an error, which it reports): the sizes are dropped.
+            let _ = output_send.send(size);
+        });
+}
+
+/// The tuples, batches and completed merges of a trace, accumulated by
+/// `count`.
+type Stats = DiffPair<isize, DiffPair<isize, isize>>;
+
+fn stats(tuples: isize, batches: isize, merges: isize) -> Stats {
+    DiffPair::new(tuples, DiffPair::new(batches, merges))
+}
+
+/// The size of an arrangement on a worker, measured when it changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArrangementSize {
+    /// The end of the interval in which it was measured, in ms.
+    pub t_ms: u64,
+    /// The source worker.
+    pub worker: WorkerIdentifier,
+    /// The index of the dataflow the arrangement is in (the first
+    /// element of its address).
+    pub dataflow: usize,
+    /// The id of the operator maintaining the arrangement.
+    pub operator: usize,
+    /// The names of the scopes enclosing the arrangement, outermost
+    /// first, separated by " / ".
+    pub scope: String,
+    /// The arrangement's name and address.
+    pub name: String,
+    /// The number of tuples in its trace.
+    pub tuples: isize,
+    /// The number of batches in its trace.
+    pub batches: isize,
+    /// The number of merges it completed so far.
+    pub merges: isize,
+    /// The estimated size of its trace, if the size of its records was
+    /// provided (see `RecordBytes`).
+    pub bytes: Option<u64>,
+}
+
+/// Where `listen` prints the arrangement sizes, and what else it
+/// reports.
+pub struct ArrangementsOptions {
+    /// The interval (in ms) at which sizes are measured.
+    pub output_interval_ms: u64,
+    /// How to print the sizes.
+    pub format: OutputFormat,
+    /// Print the sizes to this file rather than stdout.
+    pub out_path: Option<PathBuf>,
+    /// Also write an html file plotting the sizes over time (one line
+    /// per arrangement) to this path, once the streams end.
+    pub render_path: Option<PathBuf>,
+    /// Also send each measurement as the gauges
+    /// `<prefix>.arrangements.<name>.worker<worker>.{tuples,batches}`.
+    pub statsd: Option<crate::statsd::StatsdSink>,
+    /// The size of the records of arrangements, to estimate their size
+    /// in bytes.
+    pub record_bytes: RecordBytes,
+}
+
+/// User-provided sizes (in bytes) of the records of arrangements, by
+/// operator id, to estimate their size in memory from their number of
+/// tuples. Tuples of different arrangements differ wildly in size,
+/// and their log events don't say.
+///
+/// Sizes are read from `--record-bytes <op_id>=<bytes>` options, or
+/// from a toml file:
+///
+/// ```toml
+/// [operators]
+/// 18 = 64
+/// 20 = "1K"
+/// ```
+#[derive(Clone, Default, Debug)]
+pub struct RecordBytes {
+    by_operator: HashMap<usize, u64>,
+}
+
+impl RecordBytes {
+    /// Reads record sizes from a toml file.
+    pub fn from_file(path: &Path) -> Result<Self, DiagError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| DiagError::io(format!("cannot read {}", path.display()), e))?;
+        let value = contents.parse::<toml::Value>()
+            .map_err(|e| DiagError::parse(format!("Invalid record sizes {}", path.display()), e))?;
+
+        let mut record_bytes = RecordBytes::default();
+        if let Some(operators) = value.get("operators").and_then(|v| v.as_table()) {
+            for (operator, bytes) in operators.iter() {
+                let invalid = || DiagError::Parse {
+                    context: format!("Invalid record sizes {}: size for {} is not a number of bytes", path.display(), operator),
+                    source: None,
+                };
+                let bytes = match bytes {
+                    toml::Value::Integer(bytes) if *bytes >= 0 => *bytes as u64,
+                    toml::Value::String(bytes) => crate::parse_bytes(bytes)?,
+                    _ => return Err(invalid()),
+                };
+                let operator = operator.parse()
+                    .map_err(|e| DiagError::parse(format!("Invalid record sizes {}: invalid operator id {}", path.display(), operator), e))?;
+                record_bytes.by_operator.insert(operator, bytes);
+            }
+        }
+        Ok(record_bytes)
+    }
+
+    /// Adds a size given as `<op_id>=<bytes>` (e.g. `18=64`, `20=1K`),
+    /// which overrides any size read from a file.
+    pub fn parse_hint(&mut self, hint: &str) -> Result<(), DiagError> {
+        let mut parts = hint.splitn(2, '=');
+        let (operator, bytes) = match (parts.next(), parts.next()) {
+            (Some(operator), Some(bytes)) => (operator, bytes),
+            _ => return Err(DiagError::InvalidArgs(format!("Invalid --record-bytes {} (expected <op_id>=<bytes>)", hint))),
+        };
+        let operator = operator.trim().parse()
+            .map_err(|e| DiagError::parse(format!("Invalid --record-bytes {}: invalid operator id", hint), e))?;
+        self.by_operator.insert(operator, crate::parse_bytes(bytes)?);
+        Ok(())
+    }
+
+    /// The size of the records of the arrangement maintained by
+    /// `operator`, if provided.
+    pub fn get(&self, operator: usize) -> Option<u64> {
+        self.by_operator.get(&operator).cloned()
+    }
+}
+
+/// Prints the sizes received from `sizes` (and sends them to `statsd`,
+/// if provided) until all senders are dropped, then renders them to
+/// `render`, if provided.
+pub(crate) fn print_sizes(
+    mut out: Box<dyn Write + Send>,
+    format: OutputFormat,
+    render: Option<(PathBuf, std::fs::File)>,
+    statsd: Option<crate::statsd::StatsdSink>,
+    sizes: std::sync::mpsc::Receiver<ArrangementSize>) -> Result<(), DiagError> {
+
+    match format {
+        OutputFormat::Text => writeln!(out, "ms\tWorker\tDataflow\tOp. Id\tScope\tName\t# of tuples\tEst. bytes\t# of batches\tMerges/s")?,
+        OutputFormat::Csv => writeln!(out, "ms,worker,dataflow,operator,scope,name,tuples,bytes,batches,merges_per_s")?,
+        OutputFormat::Json | OutputFormat::Vega => (),
+    }
+    out.flush()?;
+
+    let mut rows: Vec<Vec<Value>> = Vec::new();
+    let mut rendered: Vec<ArrangementSize> = Vec::new();
+    // The previous measurement (time in ms, merges so far) of each trace.
+    let mut previous: HashMap<(WorkerIdentifier, usize), (u64, isize)> = HashMap::new();
+    for size in sizes {
+        if render.is_some() {
+            rendered.push(size.clone());
+        }
+        let ArrangementSize { t_ms, worker, dataflow, operator, scope, name, tuples: count, batches, merges, bytes } = size;
+        if let Some(statsd) = &statsd {
+            // StatsD takes negative gauge values as decrements.
+            let worker_path = format!("worker{}", worker);
+            statsd.gauge(&["arrangements", &name, &worker_path, "tuples"], std::cmp::max(count, 0) as u64);
+            statsd.gauge(&["arrangements", &name, &worker_path, "batches"], std::cmp::max(batches, 0) as u64);
+        }
+        let (previous_ms, previous_merges) = previous.insert((worker, operator), (t_ms, merges)).unwrap_or((0, 0));
+        let merge_rate = if t_ms > previous_ms {
+            (merges - previous_merges) as f64 * 1000.0 / (t_ms - previous_ms) as f64
+        } else {
+            0.0
+        };
+        match format {
+            OutputFormat::Text => writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2}", t_ms, worker, dataflow, operator, scope, name, count,
+                bytes.map_or("-".to_string(), |bytes| bytes.to_string()), batches, merge_rate)?,
+            OutputFormat::Csv => writeln!(out, "{},{},{},{},{},{},{},{},{},{:.2}", t_ms, worker, dataflow, operator, csv_field(&scope), csv_field(&name), count,
+                bytes.map_or(String::new(), |bytes| bytes.to_string()), batches, merge_rate)?,
+            OutputFormat::Json | OutputFormat::Vega => {
+                rows.push(vec![t_ms.into(), worker.into(), dataflow.into(), operator.into(), scope.into(), name.into(), count.into(),
+                    bytes.map_or(Value::Null, Value::from), batches.into(), merge_rate.into()]);
+                continue;
+            }
+        }
+        out.flush()?;
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let fields = ["ms", "worker", "dataflow", "operator", "scope", "name", "tuples", "bytes", "batches", "merges_per_s"];
+            writeln!(out, "[")?;
+            for (i, row) in rows.iter().enumerate() {
+                let row: Vec<String> = fields.iter().zip(row.iter())
+                    .map(|(field, value)| format!("\"{}\": {}", field, value.to_json()))
+                    .collect();
+                writeln!(out, "  {{{}}}{}", row.join(", "), if i + 1 < rows.len() { "," } else { "" })?;
+            }
+            writeln!(out, "]")?;
+        }
+        OutputFormat::Vega => crate::commands::vega::write_spec(
+            &mut out,
+            "Tuples maintained in each arrangement",
+            r#"{"type": "line", "interpolate": "step-after"}"#,
+            r#"{
+    "x": {"field": "ms", "type": "quantitative", "title": "Time (ms)"},
+    "y": {"field": "tuples", "type": "quantitative", "title": "# of tuples"},
+    "color": {"field": "arrangement", "type": "nominal"},
+    "detail": {"field": "worker", "type": "nominal"}
+  }"#,
+            &["ms", "worker", "dataflow", "operator", "scope", "arrangement", "tuples", "bytes", "batches", "merges_per_s"],
+            &rows)?,
+        OutputFormat::Text | OutputFormat::Csv => (),
+    }
+    out.flush()?;
+
+    if let Some((path, file)) = render {
+        write_chart(std::io::BufWriter::new(file), &rendered)?;
+        crate::status::info("chart_written", &format!("Chart generated in file://{}", std::fs::canonicalize(path).expect("invalid path").to_string_lossy()));
+    }
+    Ok(())
+}
+
+/// Writes a self-contained html file plotting the total number of tuples
+/// of each arrangement (summed over the workers) over time.
+fn write_chart<W: Write>(mut file: W, sizes: &[ArrangementSize]) -> Result<(), DiagError> {
+    // Sizes are only measured when they change, so each worker's latest
+    // size carries over to the following measurements.
+    let mut series: BTreeMap<&str, (HashMap<WorkerIdentifier, isize>, Vec<(u64, isize)>)> = BTreeMap::new();
+    let mut sorted: Vec<&ArrangementSize> = sizes.iter().collect();
+    sorted.sort_by_key(|size| (size.t_ms, size.worker));
+    for size in sorted {
+        let (current, points) = series.entry(size.name.as_str()).or_default();
+        current.insert(size.worker, size.tuples);
+        let total: isize = current.values().sum();
+        match points.last_mut() {
+            Some((last, tuples)) if *last == size.t_ms => *tuples = total,
+            _ => points.push((size.t_ms, total)),
+        }
+    }
+
+    writeln!(file, "<body>")?;
+    writeln!(file, "{}", SIZES_HTML)?;
+    writeln!(file, "<script type=\"text/javascript\">")?;
+    writeln!(file, "let series = [")?;
+    for (name, (_, points)) in series.iter() {
+        writeln!(
+            file,
+            "{{ \"name\": {}, \"points\": [{}] }},",
+            crate::status::json_string(name),
+            points.iter().map(|(t_ms, size)| format!("[{}, {}]", t_ms, size)).collect::<Vec<_>>().join(", "))?;
+    }
+    writeln!(file, "];")?;
+    writeln!(file, "run(series);")?;
+    writeln!(file, "</script>")?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Quotes a CSV field if it contains separators, quotes or newlines.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,214 @@
+//! "batches" subcommand: reports the distribution of the sizes of the
+//! messages sent over each channel, to find channels that send many
+//! tiny batches.
+
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::{Filter, Map, capture::{Capture, extract::Extract}};
+
+use differential_dataflow::collection::AsCollection;
+use differential_dataflow::difference::DiffPair;
+use differential_dataflow::operators::Consolidate;
+
+use timely::logging::TimelyEvent::{Channels, Messages, Operates};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+use crate::commands::channels::endpoint_label;
+
+/// The bucket of a batch of `length` records: 0 for empty batches, then
+/// one per power of two (1, 2-3, 4-7, ...).
+fn bucket(length: usize) -> u32 {
+    (std::mem::size_of::<usize>() * 8) as u32 - length.leading_zeros()
+}
+
+/// Describes the batch sizes in `bucket`.
+fn bucket_label(bucket: u32) -> String {
+    match bucket {
+        0 => "0".to_string(),
+        1 => "1".to_string(),
+        bucket => format!("{}-{}", 1usize << (bucket - 1), (1usize << bucket) - 1),
+    }
+}
+
+/// The sizes of the messages sent over a channel.
+#[derive(Default)]
+struct Batches {
+    messages: isize,
+    records: isize,
+    /// Messages of at most `tiny` records.
+    tiny: isize,
+    /// bucket -> # of messages
+    buckets: BTreeMap<u32, isize>,
+}
+
+impl Batches {
+    /// The bucket of the median message.
+    fn median_bucket(&self) -> u32 {
+        let mut seen = 0;
+        for (bucket, messages) in self.buckets.iter() {
+            seen += messages;
+            if 2 * seen >= self.messages {
+                return *bucket;
+            }
+        }
+        0
+    }
+}
+
+/// Prints, for each channel, the number of messages sent over it, their
+/// mean and median size, and the share of messages of at most `tiny`
+/// records, followed by the distribution of message sizes per channel.
+///
+/// Timely sends records in batches: many tiny batches mean that the
+/// per-message overhead (allocation, serialization, scheduling the
+/// receiving operator) dominates the work, a common performance
+/// problem. Channels where most messages are tiny are flagged, and
+/// listed first.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a differential-dataflow program to count the messages sent
+/// over each channel by size bucket, from the length of `Messages`
+/// events;
+/// 3. prints the batch sizes of each channel, with the operators at its
+/// endpoints.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    tiny: usize) -> Result<(), crate::DiagError> {
+
+    let name_map = config.names();
+    let streams_w = streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let (operators_send, operators_recv) = ::std::sync::mpsc::channel();
+    let operators_send = Arc::new(Mutex::new(operators_send));
+
+    let (channels_send, channels_recv) = ::std::sync::mpsc::channel();
+    let channels_send = Arc::new(Mutex::new(channels_send));
+
+    let structure_worker = config.structure_worker()?;
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+        let operators_send: std::sync::mpsc::Sender<_> = operators_send.lock().expect("cannot lock operators_send").clone();
+        let channels_send: std::sync::mpsc::Sender<_> = channels_send.lock().expect("cannot lock channels_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
+
+            let structure = stream.filter(move |(_, w, _)| *w == structure_worker);
+            structure
+                .flat_map(|(_, _, x)| if let Operates(event) = x { Some((event.addr, event.name)) } else { None })
+                .capture_into(operators_send);
+            structure
+                .flat_map(|(_, _, x)| if let Channels(event) = x { Some((event.id, (event.scope_addr, event.source, event.target))) } else { None })
+                .capture_into(channels_send);
+
+            stream
+                .flat_map(move |(t, _, x)| if let Messages(event) = x {
+                    if event.is_send {
+                        let ts_clip = std::time::Duration::from_secs(t.as_secs() + 1);
+                        Some(((event.channel, bucket(event.length), event.length <= tiny), ts_clip, DiffPair::new(1, event.length as isize)))
+                    } else {
+                        None
+                    }
+                } else { None })
+                .as_collection()
+                .consolidate()
+                .inner
+                .capture_into(output_send);
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting batch sizes");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    // channel -> batch sizes
+    let mut channels = HashMap::<usize, Batches>::new();
+    for ((channel, bucket, is_tiny), _t, diff) in output_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        let batches = channels.entry(channel).or_insert_with(Batches::default);
+        batches.messages += diff.element1;
+        batches.records += diff.element2;
+        if is_tiny {
+            batches.tiny += diff.element1;
+        }
+        *batches.buckets.entry(bucket).or_insert(0) += diff.element1;
+    }
+
+    let names = operators_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<HashMap<_, _>>();
+    let endpoints = channels_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<HashMap<_, _>>();
+
+    let share = |part: isize, whole: isize| if whole > 0 { 100.0 * part as f64 / whole as f64 } else { 0.0 };
+    // Most messages tiny.
+    let flagged = |batches: &Batches| 2 * batches.tiny > batches.messages;
+
+    let mut data = channels.into_iter().collect::<Vec<_>>();
+    data.sort_unstable_by_key(|(channel, batches)| (std::cmp::Reverse(flagged(batches)), std::cmp::Reverse(batches.tiny), *channel));
+
+    println!("Channel\tSource\tTarget\t# of messages\t# of records\tMean batch\tMedian batch\tBatches of <= {} records (%)\tNote", tiny);
+    for (channel, batches) in data.iter() {
+        let (source, target) = match endpoints.get(channel) {
+            Some((scope_addr, source, target)) => (endpoint_label(&names, name_map, scope_addr, *source), endpoint_label(&names, name_map, scope_addr, *target)),
+            // Created before the log streams were opened.
+            None => ("?".to_string(), "?".to_string()),
+        };
+        let mean = batches.records as f64 / std::cmp::max(batches.messages, 1) as f64;
+        let median = bucket_label(batches.median_bucket());
+        let tiny_share = share(batches.tiny, batches.messages);
+        if let Some(report) = config.reports() {
+            report.record("channel_batches", vec![
+                ("channel", (*channel).into()),
+                ("source", source.as_str().into()),
+                ("target", target.as_str().into()),
+                ("messages", batches.messages.into()),
+                ("records", batches.records.into()),
+                ("mean_batch", mean.into()),
+                ("median_batch", median.as_str().into()),
+                ("tiny_messages", batches.tiny.into()),
+            ]);
+        }
+        println!("{}\t{}\t{}\t{}\t{}\t{:.1}\t{}\t{:.1}\t{}",
+            channel, source, target, batches.messages, batches.records, mean, median, tiny_share,
+            if flagged(batches) { "many tiny batches" } else { "" });
+    }
+    let flagged_channels = data.iter().filter(|(_, batches)| flagged(batches)).count();
+    println!("{} channels used, {} sending mostly batches of <= {} records", data.len(), flagged_channels, tiny);
+
+    // The distribution of batch sizes, a column per bucket.
+    let min_bucket = data.iter().flat_map(|(_, batches)| batches.buckets.keys().cloned()).min();
+    let max_bucket = data.iter().flat_map(|(_, batches)| batches.buckets.keys().cloned()).max();
+    if let (Some(min_bucket), Some(max_bucket)) = (min_bucket, max_bucket) {
+        println!();
+        print!("Channel");
+        for bucket in min_bucket..=max_bucket {
+            print!("\t{}", bucket_label(bucket));
+        }
+        println!();
+        for (channel, batches) in data.iter() {
+            print!("{}", channel);
+            for bucket in min_bucket..=max_bucket {
+                print!("\t{}", batches.buckets.get(&bucket).cloned().unwrap_or(0));
+            }
+            println!();
+        }
+    }
+
+    Ok(())
+}
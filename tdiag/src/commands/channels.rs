@@ -0,0 +1,227 @@
+//! "channels" subcommand: reports the endpoints of each channel and the
+//! number of messages and records sent over it, or the records
+//! exchanged between each pair of source workers.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::{Filter, Map, capture::{Capture, extract::Extract}};
+
+use differential_dataflow::collection::AsCollection;
+use differential_dataflow::difference::DiffPair;
+use differential_dataflow::operators::Consolidate;
+
+use timely::logging::TimelyEvent::{Channels, Messages, Operates};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Prints every channel with the operators at its endpoints and the
+/// number of messages and records sent over it, or (with `matrix`) the
+/// number of records each source worker sent to every other source
+/// worker.
+///
+/// Channels that no message was ever sent over are listed too (last),
+/// which makes dead or misrouted edges obvious.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a differential-dataflow program to count all sent messages
+/// and sum their length, keyed by channel, sender and receiver, and
+/// collects the channels and operators of the dataflow;
+/// 3. prints the totals, or an NxN matrix followed by the hottest
+/// sender/receiver pairs;
+/// 4. optionally writes a worker × channel heatmap of the records sent
+/// to `heatmap_path`.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    matrix: bool,
+    heatmap_path: Option<&std::path::Path>) -> Result<(), crate::DiagError> {
+
+    let name_map = config.names();
+    let streams_w = streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let (operators_send, operators_recv) = ::std::sync::mpsc::channel();
+    let operators_send = Arc::new(Mutex::new(operators_send));
+
+    let (channels_send, channels_recv) = ::std::sync::mpsc::channel();
+    let channels_send = Arc::new(Mutex::new(channels_send));
+
+    let structure_worker = config.structure_worker()?;
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+        let operators_send: std::sync::mpsc::Sender<_> = operators_send.lock().expect("cannot lock operators_send").clone();
+        let channels_send: std::sync::mpsc::Sender<_> = channels_send.lock().expect("cannot lock channels_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
+
+            let structure = stream.filter(move |(_, w, _)| *w == structure_worker);
+            structure
+                .flat_map(|(_, _, x)| if let Operates(event) = x { Some((event.addr, event.name)) } else { None })
+                .capture_into(operators_send);
+            structure
+                .flat_map(|(_, _, x)| if let Channels(event) = x { Some((event.id, (event.scope_addr, event.source, event.target))) } else { None })
+                .capture_into(channels_send);
+
+            stream
+                .flat_map(|(t, _, x)| if let Messages(event) = x {
+                    if event.is_send {
+                        let ts_clip = std::time::Duration::from_secs(t.as_secs() + 1);
+                        Some(((event.channel, event.source, event.target), ts_clip, DiffPair::new(1, event.length as isize)))
+                    } else {
+                        None
+                    }
+                } else { None })
+                .as_collection()
+                .consolidate()
+                .inner
+                .capture_into(output_send);
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting channel data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    // (channel, sender, receiver) -> (# of messages, # of records)
+    let mut volumes = HashMap::new();
+    for (key, _t, diff) in output_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        let entry = volumes.entry(key).or_insert((0, 0));
+        entry.0 += diff.element1;
+        entry.1 += diff.element2;
+    }
+    let totals = volumes.iter().map(|(key, (_, records))| (*key, *records)).collect::<HashMap<_, _>>();
+
+    if let Some(heatmap_path) = heatmap_path {
+        let mut values = HashMap::new();
+        let mut channels = HashMap::new();
+        for (&(channel, source, _target), &records) in totals.iter() {
+            *values.entry((source, channel)).or_insert(0f64) += records as f64;
+            *channels.entry(channel).or_insert(0) += records;
+        }
+        let mut columns = channels.into_iter().collect::<Vec<_>>();
+        columns.sort_unstable_by_key(|&(_, records)| std::cmp::Reverse(records));
+        let columns = columns.into_iter().map(|(channel, _)| (channel, format!("channel {}", channel))).collect::<Vec<_>>();
+        crate::commands::heatmap::write_heatmap(heatmap_path, "Records sent by each worker", "records", &columns, &values)?;
+    }
+
+    if let Some(report) = config.reports() {
+        for (&(channel, source, target), &records) in totals.iter() {
+            report.record("channel_records", vec![
+                ("channel", channel.into()),
+                ("source", source.into()),
+                ("target", target.into()),
+                ("records", records.into()),
+            ]);
+        }
+    }
+
+    if matrix {
+        let mut pairs = HashMap::new();
+        for ((_channel, source, target), records) in totals.into_iter() {
+            *pairs.entry((source, target)).or_insert(0) += records;
+        }
+        print_matrix(&pairs);
+    } else {
+        let names = operators_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<HashMap<_, _>>();
+        let endpoints = channels_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<HashMap<_, _>>();
+        let endpoint = |scope_addr: &Vec<usize>, port: (usize, usize)| endpoint_label(&names, name_map, scope_addr, port);
+
+        let mut channels = endpoints.keys().map(|channel| (*channel, (0, 0))).collect::<HashMap<_, _>>();
+        for ((channel, _source, _target), (messages, records)) in volumes.into_iter() {
+            let entry = channels.entry(channel).or_insert((0, 0));
+            entry.0 += messages;
+            entry.1 += records;
+        }
+        let mut data = channels.into_iter().collect::<Vec<_>>();
+        data.sort_unstable_by_key(|&(channel, (messages, records))| (std::cmp::Reverse(messages > 0), std::cmp::Reverse(records), channel));
+        println!("Channel\tScope\tSource\tTarget\tUsed\t# of messages\t# of records");
+        let unused = data.iter().filter(|(_, (messages, _))| *messages == 0).count();
+        for (channel, (messages, records)) in data.iter() {
+            let (scope, source, target) = match endpoints.get(channel) {
+                Some((scope_addr, source, target)) => (format!("{:?}", scope_addr), endpoint(scope_addr, *source), endpoint(scope_addr, *target)),
+                // Created before the log streams were opened.
+                None => ("?".to_string(), "?".to_string(), "?".to_string()),
+            };
+            if let Some(report) = config.reports() {
+                report.record("channel_volume", vec![
+                    ("channel", (*channel).into()),
+                    ("scope", scope.as_str().into()),
+                    ("source", source.as_str().into()),
+                    ("target", target.as_str().into()),
+                    ("messages", (*messages).into()),
+                    ("records", (*records).into()),
+                ]);
+            }
+            println!("{}\t{}\t{}\t{}\t{}\t{}\t{}", channel, scope, source, target, if *messages > 0 { "yes" } else { "no" }, messages, records);
+        }
+        println!("{} channels, {} never used", data.len(), unused);
+    }
+
+    Ok(())
+}
+
+/// Describes the endpoint (node, port) of a channel in the scope at
+/// `scope_addr` as "name addr:port", given the names of the operators by
+/// address.
+pub(crate) fn endpoint_label(
+    names: &HashMap<Vec<usize>, String>,
+    name_map: &crate::names::NameMap,
+    scope_addr: &[usize],
+    (node, port): (usize, usize)) -> String {
+
+    // Node 0 stands for the scope's own inputs and outputs.
+    let mut addr = scope_addr.to_vec();
+    if node != 0 {
+        addr.push(node);
+    }
+    let name = names.get(&addr).map_or("?", |name| name_map.label(&addr, name));
+    format!("{} {:?}:{}", name, addr, port)
+}
+
+/// Prints an NxN matrix of records exchanged between source workers
+/// (rows are senders, columns are receivers), followed by the pairs
+/// carrying the largest share of all records.
+fn print_matrix(totals: &HashMap<(usize, usize), isize>) {
+    let peers = totals.keys().map(|&(s, t)| std::cmp::max(s, t) + 1).max().unwrap_or(0);
+
+    print!("from\\to");
+    for target in 0..peers {
+        print!("\t{}", target);
+    }
+    println!();
+    for source in 0..peers {
+        print!("{}", source);
+        for target in 0..peers {
+            print!("\t{}", totals.get(&(source, target)).cloned().unwrap_or(0));
+        }
+        println!();
+    }
+
+    let total: isize = totals.values().sum();
+    if total > 0 {
+        let mut pairs = totals.iter().collect::<Vec<_>>();
+        pairs.sort_unstable_by_key(|&(_, records)| std::cmp::Reverse(*records));
+        println!();
+        println!("Hottest pairs:");
+        for ((source, target), records) in pairs.into_iter().take(5) {
+            println!("{} -> {}\t{}\t({:.1}%)", source, target, records, 100.0 * (*records as f64) / (total as f64));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! "epochs" subcommand: reports the number of records produced for
+//! each epoch (logical timestamp) of the source computation.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::{Map, Inspect};
+
+use timely::logging::TimelyEvent::Progress;
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Prints, for each scope and epoch, the number of records produced
+/// at that epoch and the resulting throughput.
+///
+/// `Messages` events don't carry the timestamp of the data they
+/// describe, so records are counted from the message counts broadcast
+/// by progress tracking (`Progress` events), which are bucketed by
+/// timestamp. Records produced and consumed by the same source worker
+/// before it broadcasts its progress updates cancel out and are not
+/// counted.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to sum the records produced at
+/// each epoch, and to track when each epoch was first and last seen;
+/// 3. prints the records and records/s for each epoch, in the order in
+/// which the epochs were first seen.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams) -> Result<(), crate::DiagError> {
+
+    let streams_w = streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        // (scope addr, epoch) -> (first seen, last seen, # of records)
+        let epochs = Rc::new(RefCell::new(HashMap::new()));
+        let epochs_w = epochs.clone();
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .flat_map(|(t, _, x)| if let Progress(event) = x {
+                    // Each update is logged once by its sender, and once
+                    // by each of its receivers.
+                    if event.is_send { Some((t, event)) } else { None }
+                } else { None })
+                .inspect(move |(t, event)| {
+                    let mut epochs = epochs_w.borrow_mut();
+                    for (_node, _port, time, diff) in event.messages.iter() {
+                        if *diff > 0 {
+                            let entry = epochs.entry((event.addr.clone(), time.clone())).or_insert((*t, *t, 0));
+                            entry.0 = std::cmp::min(entry.0, *t);
+                            entry.1 = std::cmp::max(entry.1, *t);
+                            entry.2 += *diff;
+                        }
+                    }
+                });
+        });
+
+        while worker.step() { }
+
+        for (key, value) in epochs.borrow_mut().drain() {
+            output_send.send((key, value)).expect("failed to send output to mpsc channel");
+        }
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting epoch data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut epochs: HashMap<(Vec<usize>, String), (Duration, Duration, i64)> = HashMap::new();
+    for (key, (first, last, records)) in output_recv.into_iter() {
+        let entry = epochs.entry(key).or_insert((first, last, 0));
+        entry.0 = std::cmp::min(entry.0, first);
+        entry.1 = std::cmp::max(entry.1, last);
+        entry.2 += records;
+    }
+
+    let mut data = epochs.into_iter().collect::<Vec<_>>();
+    data.sort_unstable_by_key(|((addr, _), (first, _, _))| (addr.clone(), *first));
+
+    println!("Scope\tEpoch\tFirst (ms)\tLast (ms)\t# of records\trecords/s");
+    for ((addr, epoch), (first, last, records)) in data.into_iter() {
+        // Epochs seen only once are assumed to have lasted a millisecond.
+        let elapsed = std::cmp::max(last - first, Duration::from_millis(1));
+        if let Some(report) = config.reports() {
+            report.record("epoch", vec![
+                ("scope", format!("{:?}", addr).into()),
+                ("epoch", epoch.clone().into()),
+                ("first_ms", (first.as_millis() as u64).into()),
+                ("last_ms", (last.as_millis() as u64).into()),
+                ("records", records.into()),
+            ]);
+        }
+        println!("{:?}\t{}\t{}\t{}\t{}\t{:.0}",
+            addr,
+            epoch,
+            first.as_millis(),
+            last.as_millis(),
+            records,
+            (records as f64) / elapsed.as_secs_f64());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Flamegraphs of the time spent in operators, folded by address so
+//! that each scope is drawn below the operators (and scopes) it
+//! contains.
+//!
+//! The SVG is rendered here rather than with an external tool, so that
+//! it only needs a browser to be viewed.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::DiagError;
+
+/// Height of a frame, in pixels.
+const FRAME_HEIGHT: usize = 17;
+/// Width of the image, in pixels.
+const WIDTH: f64 = 1200.0;
+/// Frames narrower than this (in pixels) aren't drawn.
+const MIN_WIDTH: f64 = 0.1;
+
+/// A node of the address hierarchy.
+struct Frame {
+    label: String,
+    /// The time attributed to the frame, which covers its children.
+    ns: u64,
+    children: Vec<Vec<usize>>,
+}
+
+/// Writes an SVG flamegraph of `operators` (address, label, time spent
+/// in ns), where each scope spans the operators it contains.
+///
+/// A scope's time is the larger of its own measurement and the sum of
+/// its children's; the difference is time spent in the scope itself
+/// (e.g. scheduling its children).
+pub fn write_flamegraph(
+    output_path: &std::path::Path,
+    title: &str,
+    operators: &[(Vec<usize>, String, u64)]) -> Result<(), DiagError> {
+
+    let mut frames = operators.iter()
+        .map(|(addr, label, ns)| (addr.clone(), Frame { label: label.clone(), ns: *ns, children: Vec::new() }))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut roots = Vec::new();
+    for addr in frames.keys().cloned().collect::<Vec<_>>() {
+        let parent = addr[..addr.len().saturating_sub(1)].to_vec();
+        match frames.get_mut(&parent) {
+            Some(frame) if !addr.is_empty() => frame.children.push(addr),
+            _ => roots.push(addr),
+        }
+    }
+
+    // Addresses sort after their prefixes: in reverse order, children
+    // are fixed up before their parents.
+    for addr in frames.keys().rev().cloned().collect::<Vec<_>>() {
+        let children_ns = frames[&addr].children.iter().map(|child| frames[child].ns).sum::<u64>();
+        let frame = frames.get_mut(&addr).expect("frame missing");
+        frame.ns = std::cmp::max(frame.ns, children_ns);
+    }
+
+    let total_ns = roots.iter().map(|root| frames[root].ns).sum::<u64>();
+    let depth = frames.keys().map(|addr| addr.len()).max().unwrap_or(0) + 1;
+    let height = (depth + 1) * FRAME_HEIGHT + 40;
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(output_path)
+        .map_err(|e| DiagError::io(format!("cannot create {}", output_path.display()), e))?);
+
+    writeln!(file, "<?xml version=\"1.0\" standalone=\"no\"?>")?;
+    writeln!(file, "<svg version=\"1.1\" width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">", WIDTH, height)?;
+    writeln!(file, "<style>text {{ font-family: Verdana, sans-serif; font-size: 12px; }} rect:hover {{ stroke: black; }}</style>")?;
+    writeln!(file, "<rect x=\"0\" y=\"0\" width=\"100%\" height=\"100%\" fill=\"#f8f8f8\"/>")?;
+    writeln!(file, "<text x=\"{}\" y=\"24\" text-anchor=\"middle\" font-size=\"17\">{}</text>", WIDTH / 2.0, escape(title))?;
+
+    let scale = if total_ns > 0 { WIDTH / total_ns as f64 } else { 0.0 };
+    let y_of = |level: usize| height - (level + 1) * FRAME_HEIGHT;
+
+    write_frame(&mut file, 0.0, y_of(0), WIDTH, "all", total_ns, total_ns)?;
+
+    // (address, x, level)
+    let mut todo = Vec::new();
+    let mut x = 0.0;
+    for root in roots.iter() {
+        todo.push((root.clone(), x, 1));
+        x += frames[root].ns as f64 * scale;
+    }
+    while let Some((addr, x, level)) = todo.pop() {
+        let frame = &frames[&addr];
+        let width = frame.ns as f64 * scale;
+        if width < MIN_WIDTH {
+            continue;
+        }
+        write_frame(&mut file, x, y_of(level), width, &format!("{} {:?}", frame.label, addr), frame.ns, total_ns)?;
+
+        let mut child_x = x;
+        for child in frame.children.iter() {
+            todo.push((child.clone(), child_x, level + 1));
+            child_x += frames[child].ns as f64 * scale;
+        }
+    }
+
+    writeln!(file, "</svg>")?;
+    file.flush()?;
+
+    Ok(())
+}
+
+fn write_frame<W: Write>(out: &mut W, x: f64, y: usize, width: f64, label: &str, ns: u64, total_ns: u64) -> Result<(), DiagError> {
+    let share = if total_ns > 0 { ns as f64 * 100.0 / total_ns as f64 } else { 0.0 };
+    writeln!(out, "<g><title>{} ({:.3} s, {:.2}%)</title>", escape(label), ns as f64 / 1_000_000_000f64, share)?;
+    writeln!(out, "<rect x=\"{:.2}\" y=\"{}\" width=\"{:.2}\" height=\"{}\" fill=\"{}\" rx=\"2\"/>",
+             x, y, width, FRAME_HEIGHT - 1, color(label))?;
+    // About 7px per character.
+    let chars = ((width - 6.0) / 7.0) as usize;
+    if chars >= 3 {
+        let text = if label.chars().count() <= chars {
+            label.to_string()
+        } else {
+            format!("{}..", label.chars().take(chars - 2).collect::<String>())
+        };
+        writeln!(out, "<text x=\"{:.2}\" y=\"{}\">{}</text>", x + 3.0, y + FRAME_HEIGHT - 5, escape(&text))?;
+    }
+    writeln!(out, "</g>")?;
+    Ok(())
+}
+
+/// A warm color, stable for a label.
+fn color(label: &str) -> String {
+    let hash = label.bytes().fold(0u32, |hash, byte| hash.wrapping_mul(31).wrapping_add(u32::from(byte)));
+    format!("rgb({},{},{})", 205 + hash % 50, (hash / 50) % 180 + 50, (hash / 9000) % 55)
+}
+
+/// Escapes text for SVG (and XML) content and attributes.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
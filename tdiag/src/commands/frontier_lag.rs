@@ -0,0 +1,152 @@
+//! "frontier-lag" subcommand: reports, for each operator, how long it
+//! holds back the frontier after its inputs have been drained.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::{Map, Filter, capture::{Capture, extract::Extract}};
+
+use differential_dataflow::collection::AsCollection;
+use differential_dataflow::operators::reduce::Threshold;
+
+use timely::logging::TimelyEvent::{Operates, Progress};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Prints the lag between each operator's input frontier and its output
+/// frontier, aggregated over all epochs.
+///
+/// Progress updates describe, for each operator and epoch, the messages
+/// outstanding at the operator's inputs and the capabilities held at its
+/// outputs. The lag for an epoch is the wall-clock time between the last
+/// input message at that epoch being consumed and the last capability
+/// at that epoch being released. Cheap operators that nonetheless hold
+/// back frontier propagation have a large lag.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a differential-dataflow program to track when each
+/// operator's outstanding messages and capabilities at each epoch
+/// drop to zero;
+/// 3. prints the number of epochs, mean and maximum lag for each
+/// operator, sorted by total lag.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams) -> Result<(), crate::DiagError> {
+
+    let name_map = config.names();
+    let streams_w = streams.clone();
+
+    let (operators_send, operators_recv) = ::std::sync::mpsc::channel();
+    let operators_send = Arc::new(Mutex::new(operators_send));
+
+    let (pointstamps_send, pointstamps_recv) = ::std::sync::mpsc::channel();
+    let pointstamps_send = Arc::new(Mutex::new(pointstamps_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let operators_send: std::sync::mpsc::Sender<_> = operators_send.lock().expect("cannot lock operators_send").clone();
+        let pointstamps_send: std::sync::mpsc::Sender<_> = pointstamps_send.lock().expect("cannot lock pointstamps_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
+
+            stream
+                .filter(move |(_, w, _)| *w == structure_worker)
+                .flat_map(|(_, _, x)| if let Operates(event) = x { Some((event.addr, event.name)) } else { None })
+                .capture_into(operators_send);
+
+            // Global counts of outstanding messages (false) and held
+            // capabilities (true), keyed by (scope addr, node, epoch).
+            // Each update is logged once by its sender, and once by each
+            // of its receivers.
+            stream
+                .flat_map(|(t, _, x)| match x {
+                    Progress(event) if event.is_send => {
+                        let addr = event.addr;
+                        let messages = event.messages.into_iter()
+                            .map(|(node, _port, time, diff)| ((addr.clone(), node, time, false), t, diff as isize));
+                        let internal = event.internal.into_iter()
+                            .map(|(node, _port, time, diff)| ((addr.clone(), node, time, true), t, diff as isize));
+                        messages.chain(internal).collect::<Vec<_>>()
+                    },
+                    _ => Vec::new(),
+                })
+                .as_collection()
+                .distinct()
+                .inner
+                // Only the times at which counts drop to zero are of interest.
+                .filter(|(_, _, diff)| *diff < 0)
+                .map(|(key, t, _)| (key, t))
+                .capture_into(pointstamps_send);
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting progress data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let names = operators_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<HashMap<_, _>>();
+
+    // (scope addr, node, epoch, is_capability) -> last time the count dropped to zero
+    let mut released = HashMap::new();
+    for ((addr, node, epoch, is_capability), t) in pointstamps_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        let entry = released.entry((addr, node, epoch, is_capability)).or_insert(t);
+        *entry = std::cmp::max(*entry, t);
+    }
+
+    // operator addr -> (# of epochs, total lag, maximum lag)
+    let mut lags: HashMap<Vec<usize>, (usize, Duration, Duration)> = HashMap::new();
+    for ((addr, node, epoch, is_capability), capabilities_released) in released.iter() {
+        // Node 0 represents the scope's own inputs and outputs.
+        if !is_capability || *node == 0 {
+            continue;
+        }
+        if let Some(inputs_drained) = released.get(&(addr.clone(), *node, epoch.clone(), false)) {
+            let lag = if capabilities_released > inputs_drained { *capabilities_released - *inputs_drained } else { Duration::from_secs(0) };
+            let mut operator_addr = addr.clone();
+            operator_addr.push(*node);
+            let entry = lags.entry(operator_addr).or_insert((0, Duration::from_secs(0), Duration::from_secs(0)));
+            entry.0 += 1;
+            entry.1 += lag;
+            entry.2 = std::cmp::max(entry.2, lag);
+        }
+    }
+
+    let mut data = lags.into_iter().collect::<Vec<_>>();
+    data.sort_unstable_by_key(|(_, (_, total, _))| std::cmp::Reverse(*total));
+
+    println!("Name\tAddr\tEpochs\tMean lag (ms)\tMax lag (ms)");
+    for (addr, (epochs, total, max)) in data.into_iter() {
+        if let Some(report) = config.reports() {
+            report.record("frontier_lag", vec![
+                ("name", names.get(&addr).map(|n| name_map.label(&addr, n)).unwrap_or("<unknown>").into()),
+                ("addr", format!("{:?}", addr).into()),
+                ("epochs", epochs.into()),
+                ("mean_lag_ms", (total.as_secs_f64() * 1000.0 / (epochs as f64)).into()),
+                ("max_lag_ms", (max.as_secs_f64() * 1000.0).into()),
+            ]);
+        }
+        println!("{}\t{:?}\t{}\t{:.3}\t{:.3}",
+            names.get(&addr).map(|n| name_map.label(&addr, n)).unwrap_or("<unknown>"),
+            addr,
+            epochs,
+            total.as_secs_f64() * 1000.0 / (epochs as f64),
+            max.as_secs_f64() * 1000.0);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! "frontiers" subcommand: reconstructs each operator's frontier from
+//! the progress updates, and reports how far it lags behind the
+//! frontier of the dataflow's sources.
+//!
+//! Progress events carry timestamps as strings (their `Debug` form), so
+//! they're ordered by the integers they contain, in order: exact for
+//! integer timestamps, and lexicographic for products (e.g. nested
+//! scopes' `(outer, inner)` timestamps). Lags are counted in epochs of
+//! the first (outermost) coordinate.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+use crate::names::NameMap;
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::TimelyEvent::{Operates, Channels, Progress};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// What the diagnostic workers report to the printer.
+enum Update {
+    /// An operator was created: (addr, name).
+    Operator(Vec<usize>, String),
+    /// A channel was created in a scope: (scope addr, target node).
+    Channel(Vec<usize>, usize),
+    /// Changes to the messages outstanding at, and capabilities held by,
+    /// operators, as (operator addr, time, diff), logged at a time.
+    Pointstamps(Duration, Vec<(Vec<usize>, String, i64)>),
+}
+
+/// A timestamp, ordered by the integers in its `Debug` form.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Timestamp {
+    coordinates: Vec<u64>,
+    /// The timestamp's `Debug` form.
+    pub(crate) text: String,
+}
+
+impl Timestamp {
+    pub(crate) fn parse(text: &str) -> Self {
+        let coordinates = text.split(|c: char| !c.is_ascii_digit())
+            .filter(|x| !x.is_empty())
+            .filter_map(|x| x.parse().ok())
+            .collect();
+        Timestamp { coordinates, text: text.to_string() }
+    }
+
+    /// The number of epochs (of the outermost coordinate) `self` is
+    /// behind `other`.
+    fn epochs_behind(&self, other: &Timestamp) -> Option<u64> {
+        match (self.coordinates.first(), other.coordinates.first()) {
+            (Some(mine), Some(theirs)) => Some(theirs.saturating_sub(*mine)),
+            _ => None,
+        }
+    }
+}
+
+/// Prints, every `interval_ms` (of the source computation's time), the
+/// operators whose frontier lags behind the frontier of their dataflow's
+/// sources, with both frontiers and the lag in epochs, the most lagging
+/// first.
+///
+/// An operator's frontier is the earliest time at which it has messages
+/// outstanding at its inputs or holds a capability. The sources of a
+/// dataflow are its top-level operators without incoming channels (e.g.
+/// inputs); once they dropped all their capabilities, every operator
+/// with a frontier lags behind.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to forward the progress updates
+/// sent by each worker;
+/// 3. accumulates them into per-operator pointstamp counts, and prints
+/// the lagging operators at the end of each interval.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    interval_ms: u64) -> Result<(), DiagError> {
+
+    let streams_w = streams.clone();
+
+    let (updates_send, updates_recv) = ::std::sync::mpsc::channel();
+    let updates_send = Arc::new(Mutex::new(updates_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let name_map = config.names().clone();
+    let report = config.reports().cloned();
+    let printer = std::thread::Builder::new()
+        .name("tdiag-frontiers".to_string())
+        .spawn(move || print_frontiers(updates_recv, interval_ms, &name_map, report.as_ref()))?;
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let updates: std::sync::mpsc::Sender<_> = updates_send.lock().expect("cannot lock updates_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(t, w, x)| match x {
+                    Operates(event) if *w == structure_worker => {
+                        let _ = updates.send(Update::Operator(event.addr.clone(), event.name.clone()));
+                    }
+                    Channels(event) if *w == structure_worker => {
+                        let _ = updates.send(Update::Channel(event.scope_addr.clone(), event.target.0));
+                    }
+                    // Each update is logged once by its sender, and once by
+                    // each of its receivers.
+                    Progress(event) if event.is_send => {
+                        let pointstamps = event.messages.iter().chain(event.internal.iter())
+                            // Node 0 is the scope's own inputs and outputs.
+                            .filter(|(node, _, _, _)| *node != 0)
+                            .map(|(node, _port, time, diff)| {
+                                let mut addr = event.addr.clone();
+                                addr.push(*node);
+                                (addr, time.clone(), *diff)
+                            })
+                            .collect::<Vec<_>>();
+                        if !pointstamps.is_empty() {
+                            let _ = updates.send(Update::Pointstamps(*t, pointstamps));
+                        }
+                    }
+                    _ => (),
+                });
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop tracking frontiers");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    printer.join().map_err(|_| DiagError::Internal("the printer thread panicked".to_string()))?;
+
+    Ok(())
+}
+
+/// The pointstamp counts, and the dataflow graph they're interpreted in.
+#[derive(Default)]
+struct Frontiers {
+    names: HashMap<Vec<usize>, String>,
+    /// Nodes with incoming channels, by scope address.
+    targets: HashMap<Vec<usize>, HashSet<usize>>,
+    /// Outstanding messages and capabilities, by operator addr and time.
+    counts: HashMap<Vec<usize>, BTreeMap<Timestamp, i64>>,
+}
+
+impl Frontiers {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Operator(addr, name) => { self.names.insert(addr, name); }
+            Update::Channel(scope_addr, target) => { self.targets.entry(scope_addr).or_insert_with(HashSet::new).insert(target); }
+            Update::Pointstamps(_, pointstamps) => for (addr, time, diff) in pointstamps {
+                let counts = self.counts.entry(addr).or_insert_with(BTreeMap::new);
+                let time = Timestamp::parse(&time);
+                let count = counts.entry(time.clone()).or_insert(0);
+                *count += diff;
+                if *count == 0 {
+                    counts.remove(&time);
+                }
+            },
+        }
+    }
+
+    /// The earliest time with a positive count at an operator.
+    fn frontier(&self, addr: &[usize]) -> Option<&Timestamp> {
+        self.counts.get(addr)?.iter().find(|(_, count)| **count > 0).map(|(time, _)| time)
+    }
+
+    /// Whether the operator is a top-level operator without incoming
+    /// channels.
+    fn is_source(&self, addr: &[usize]) -> bool {
+        addr.len() == 2 && !self.targets.get(&addr[..1]).map_or(false, |targets| targets.contains(&addr[1]))
+    }
+
+    /// The lagging operators, as (addr, frontier, source frontier, lag in
+    /// epochs), the most lagging first.
+    fn lagging(&self) -> Vec<(&[usize], &Timestamp, Option<&Timestamp>, Option<u64>)> {
+        // The sources' frontier, by dataflow.
+        let mut sources: HashMap<usize, Option<&Timestamp>> = HashMap::new();
+        for addr in self.names.keys().filter(|addr| self.is_source(addr)) {
+            let frontier = sources.entry(addr[0]).or_insert(None);
+            if let Some(time) = self.frontier(addr) {
+                if frontier.map_or(true, |frontier| time < frontier) {
+                    *frontier = Some(time);
+                }
+            }
+        }
+
+        let mut lagging = self.counts.keys()
+            .filter(|addr| !self.is_source(addr))
+            .filter_map(|addr| {
+                let frontier = self.frontier(addr)?;
+                let source = sources.get(&addr[0]).cloned().flatten();
+                match source {
+                    Some(source) if frontier >= source => None,
+                    Some(source) => Some((addr.as_slice(), frontier, Some(source), frontier.epochs_behind(source))),
+                    None => Some((addr.as_slice(), frontier, None, None)),
+                }
+            })
+            .collect::<Vec<_>>();
+        lagging.sort_by(|a, b| b.3.cmp(&a.3).then_with(|| a.1.cmp(b.1)).then_with(|| a.0.cmp(b.0)));
+        lagging
+    }
+}
+
+/// Applies the updates received from `updates` until all senders are
+/// dropped, printing the lagging operators whenever the updates' time
+/// passes the end of an interval, and once at the end.
+fn print_frontiers(updates: Receiver<Update>, interval_ms: u64, name_map: &NameMap, report: Option<&crate::report::Report>) {
+    let mut frontiers = Frontiers::default();
+    let mut window = 0;
+
+    let print = |frontiers: &Frontiers, t_ms: u64| {
+        for (addr, frontier, source, lag) in frontiers.lagging() {
+            let name = frontiers.names.get(addr).map(|name| name_map.label(addr, name)).unwrap_or("<unknown>");
+            let source_text = source.map(|source| source.text.as_str()).unwrap_or("(done)");
+            if let Some(report) = report {
+                let mut fields = vec![
+                    ("t_ms", t_ms.into()),
+                    ("addr", format!("{:?}", addr).into()),
+                    ("name", name.into()),
+                    ("frontier", frontier.text.as_str().into()),
+                    ("source_frontier", source_text.into()),
+                ];
+                if let Some(lag) = lag {
+                    fields.push(("lag_epochs", lag.into()));
+                }
+                report.record("frontier", fields);
+            }
+            println!("{}\t{:?}\t{}\t{}\t{}\t{}",
+                t_ms,
+                addr,
+                name,
+                frontier.text,
+                source_text,
+                lag.map(|lag| lag.to_string()).unwrap_or_else(|| "-".to_string()));
+        }
+    };
+
+    println!("ms\tAddr\tName\tFrontier\tSource frontier\tLag (epochs)");
+    for update in updates {
+        if let Update::Pointstamps(t, _) = &update {
+            let update_window = t.as_millis() as u64 / interval_ms;
+            if update_window > window {
+                print(&frontiers, update_window * interval_ms);
+                window = update_window;
+            }
+        }
+        frontiers.apply(update);
+    }
+    print(&frontiers, (window + 1) * interval_ms);
+}
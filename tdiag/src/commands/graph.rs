@@ -1,256 +1,976 @@
-//! "graph" subcommand: browser-based tool to display the dataflow graph.
+//! "graph" subcommand: browser-based tool to display the dataflow graph,
+//! or export it for other tools.
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
 use std::sync::{Arc, Mutex};
 
 use crate::{DiagError, LoggingTuple};
 
-use timely::dataflow::operators::{Filter, capture::{Capture, extract::Extract}};
+use timely::dataflow::operators::{Filter, Inspect};
 use timely::dataflow::operators::map::Map;
 
 use differential_dataflow::collection::AsCollection;
 use differential_dataflow::operators::{Join, reduce::Threshold, Consolidate};
 
-use timely::logging::TimelyEvent::{Operates, Channels};
+use timely::logging::TimelyEvent::{Operates, Channels, Messages};
 
 use tdiag_connect::receive::ReplayWithShutdown;
 
 static GRAPH_HTML: &str = include_str!("graph/dataflow-graph.html");
 
-/// Creates an html file that renders a timely dataflow graph.
+/// How `graph` writes the dataflow graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// An html file that renders the graph, fetched from a JSON file
+    /// next to it (see `data_path`).
+    Html,
+    /// A Graphviz file, with a cluster per scope.
+    Dot,
+    /// The operators, channels and scopes, as the html file fetches them.
+    Json,
+    /// An SVG image of the graph, laid out without a browser (see
+    /// `graph_svg`).
+    Svg,
+}
+
+impl GraphFormat {
+    /// Parses a format name, as passed to `--format`.
+    pub fn parse(name: &str) -> Result<Self, DiagError> {
+        match name {
+            "html" => Ok(GraphFormat::Html),
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            "svg" => Ok(GraphFormat::Svg),
+            _ => Err(DiagError::InvalidArgs(format!("Invalid --format {} (expected html, dot, json or svg)", name))),
+        }
+    }
+}
+
+/// How `listen_and_render` writes the graph, and what it checks.
+#[derive(Clone, Debug)]
+pub struct GraphOptions {
+    /// How to write the graph.
+    pub format: GraphFormat,
+    /// Only write the data the html file fetches (html format only).
+    pub data_only: bool,
+    /// Also sum the time spent in each operator, and shade the
+    /// operators by it.
+    pub with_profile: bool,
+    /// Build the graph from the operators and channels of all source
+    /// workers, and report those only some of them logged.
+    pub all_workers: bool,
+    /// Fail if the structural fingerprint of the graph doesn't match
+    /// this one.
+    pub expect_fingerprint: Option<String>,
+}
+
+impl Default for GraphOptions {
+    fn default() -> Self {
+        GraphOptions {
+            format: GraphFormat::Html,
+            data_only: false,
+            with_profile: false,
+            all_workers: false,
+            expect_fingerprint: None,
+        }
+    }
+}
+
+/// A leaf operator of the dataflow graph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OperatorNode {
+    /// The operator's address.
+    pub addr: Vec<usize>,
+    /// The operator's name, as logged (see `NameMap::label`).
+    pub name: String,
+    /// The time spent in the operator over all workers, if profiled.
+    pub elapsed_ns: Option<isize>,
+}
+
+/// A scope of the dataflow graph: it looks like an operator from the
+/// outside, but contains operators and other scopes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScopeNode {
+    /// The scope's address.
+    pub addr: Vec<usize>,
+    /// The scope's name, as logged (see `NameMap::label`).
+    pub name: String,
+    /// The address of the scope containing this one, or `None` for a
+    /// dataflow.
+    pub parent: Option<Vec<usize>>,
+    /// How deeply the scope is nested: 0 for a dataflow, 1 for the
+    /// scopes in a dataflow, and so on.
+    pub depth: usize,
+}
+
+/// A channel of the dataflow graph, re-wired across scope boundaries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelEdge {
+    /// The channel's id; a channel that crosses scope boundaries is
+    /// made of a channel per scope it goes through, in order.
+    pub ids: Vec<usize>,
+    /// Whether the channel crosses a scope boundary.
+    pub subgraph: bool,
+    /// The address of the operator the channel leaves.
+    pub from_addr: Vec<usize>,
+    /// The address of the operator the channel enters.
+    pub to_addr: Vec<usize>,
+    /// The output port the channel leaves.
+    pub from_port: usize,
+    /// The input port the channel enters.
+    pub to_port: usize,
+    /// The records sent over the channel, over all workers.
+    pub records: isize,
+}
+
+/// An operator or channel that only some source workers logged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The operator (`operator <name> <addr>`) or channel (`channel
+    /// <scope addr> <source> -> <target>`).
+    pub what: String,
+    /// The source workers that logged it.
+    pub present: Vec<usize>,
+    /// The source workers that logged other operators and channels, but
+    /// not this one.
+    pub missing: Vec<usize>,
+}
+
+/// The reconstructed dataflow graph, and what was measured on it.
+#[derive(Clone, Debug, Default)]
+pub struct DataflowGraph {
+    /// The leaf operators.
+    pub operators: Vec<OperatorNode>,
+    /// The channels between them.
+    pub channels: Vec<ChannelEdge>,
+    /// The scopes that contain operators, from which the scope tree
+    /// can be rebuilt.
+    pub scopes: Vec<ScopeNode>,
+    /// The operators and channels only some workers logged, if the
+    /// graph was built from all workers.
+    pub divergences: Vec<Divergence>,
+}
+
+impl DataflowGraph {
+    /// The most records sent over a channel.
+    fn max_records(&self) -> isize {
+        self.channels.iter().map(|channel| channel.records).max().unwrap_or(0)
+    }
+
+    /// Computes a stable hash of the structure of the graph: operator
+    /// addresses and names, and the endpoints of each channel. Channel
+    /// ids are excluded as they're not stable across runs.
+    ///
+    /// FNV-1a is used (rather than `std`'s hashers) because its output
+    /// is guaranteed not to change between Rust versions.
+    pub fn fingerprint(&self) -> u64 {
+
+        let mut operators = self.operators.iter().map(|op| format!("{:?} {}", op.addr, op.name)).collect::<Vec<_>>();
+        operators.sort();
+        let mut channels = self.channels.iter()
+            .map(|ch| format!("{:?}:{} -> {:?}:{} {}", ch.from_addr, ch.from_port, ch.to_addr, ch.to_port, ch.subgraph))
+            .collect::<Vec<_>>();
+        channels.sort();
+
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for line in operators.iter().chain(channels.iter()) {
+            for byte in line.bytes().chain(std::iter::once(b'\n')) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        hash
+    }
+}
+
+/// Creates a file that renders a timely dataflow graph.
 ///
-/// 1. Listens to incoming connection from a timely-dataflow program with
-/// logging enabled;
-/// 2. runs a differential-dataflow program to reconstruct the source
-/// computation's dataflow graph;
-/// 3. writes an html file that renders the dataflow graph.
+/// 1. Reconstructs the source computation's dataflow graph (see
+/// `listen_and_collect`);
+/// 2. writes an html file that renders the dataflow graph, with the
+/// records sent over each channel, or a file in another format;
+/// 3. prints a structural fingerprint of the graph and, if
+/// `expect_fingerprint` is provided, fails if it doesn't match.
+///
+/// The html file fetches the graph from a JSON file next to it (see
+/// `data_path`), with the same contents as `--format json`. With
+/// `data_only`, only that file is written, so that an html file from
+/// a newer version of this tool can render an existing capture.
+///
+/// With `with_profile`, also shades each operator by the share of the
+/// time spent in the hottest one, so that the graph doubles as a heat
+/// map.
+///
+/// With `all_workers`, builds the graph from the operators and channels
+/// of all source workers, and reports those only some of them logged.
 ///
 /// This module includes `graph/dataflow-graph.html` as a static resource.
 pub fn listen_and_render(
-    timely_configuration: timely::Configuration,
-    sockets: Vec<Option<std::net::TcpStream>>,
-    output_path: &std::path::Path) -> Result<(), crate::DiagError> {
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    output_path: &std::path::Path,
+    options: &GraphOptions) -> Result<(), crate::DiagError> {
 
-    let sockets = Arc::new(Mutex::new(sockets));
+    if options.data_only && options.format != GraphFormat::Html {
+        return Err(DiagError::InvalidArgs("--data-only only applies to --format html".to_string()));
+    }
+
+    let graph = listen_and_collect(config, streams, options.with_profile, options.all_workers)?;
+    render(config, &graph, output_path, options)
+}
 
-    let (operators_send, operators_recv) = ::std::sync::mpsc::channel();
-    let operators_send = Arc::new(Mutex::new(operators_send));
+/// Writes a graph collected with `listen_and_collect` (or by a
+/// `SessionBuilder`) as `listen_and_render` does, and checks its
+/// fingerprint.
+pub fn render(
+    config: &crate::config::DiagConfig,
+    graph: &DataflowGraph,
+    output_path: &std::path::Path,
+    options: &GraphOptions) -> Result<(), crate::DiagError> {
 
-    let (channels_send, channels_recv) = ::std::sync::mpsc::channel();
-    let channels_send = Arc::new(Mutex::new(channels_send));
+    let GraphOptions { format, data_only, all_workers, ref expect_fingerprint, .. } = *options;
+
+    if data_only && format != GraphFormat::Html {
+        return Err(DiagError::InvalidArgs("--data-only only applies to --format html".to_string()));
+    }
+
+    let name_map = config.names();
+
+    let fingerprint = format!("{:016x}", graph.fingerprint());
+
+    let create = |path: &std::path::Path| std::fs::File::create(path)
+        .map(std::io::BufWriter::new)
+        .map_err(|e| DiagError::io(format!("cannot create {}", path.display()), e));
+
+    let json_path = data_path(output_path);
+    if format == GraphFormat::Html {
+        let mut data = create(&json_path)?;
+        write_json(&mut data, graph, name_map)?;
+        data.flush()?;
+        println!("Graph data generated in file://{}", std::fs::canonicalize(&json_path).expect("invalid path").to_string_lossy());
+    }
+    if !data_only {
+        let mut file = create(output_path)?;
+        match format {
+            GraphFormat::Html => write_html(&mut file, &json_path)?,
+            GraphFormat::Dot => write_dot(&mut file, graph, name_map)?,
+            GraphFormat::Json => write_json(&mut file, graph, name_map)?,
+            GraphFormat::Svg => crate::commands::graph_svg::write_svg(&mut file, graph, name_map)?,
+        }
+        file.flush()?;
+
+        println!("Graph generated in file://{}", std::fs::canonicalize(output_path).expect("invalid path").to_string_lossy());
+    }
+
+    println!("Graph fingerprint: {}", fingerprint);
+    if let Some(report) = config.reports() {
+        let path = if data_only { json_path.as_path() } else { output_path };
+        report.record("graph", vec![
+            ("path", path.display().to_string().into()),
+            ("fingerprint", fingerprint.clone().into()),
+        ]);
+    }
+    if all_workers {
+        print_divergences(&graph.divergences, config.reports());
+    }
+    if let Some(expected) = expect_fingerprint {
+        if !expected.trim().eq_ignore_ascii_case(&fingerprint) {
+            return Err(DiagError::CheckFailed(format!("graph fingerprint {} does not match the expected fingerprint {}", fingerprint, expected)));
+        }
+    }
+
+    Ok(())
+}
+
+/// The JSON file the html file at `output_path` fetches the graph
+/// from: `graph-data.json` for `graph.html`, in the same directory.
+pub fn data_path(output_path: &std::path::Path) -> std::path::PathBuf {
+    let stem = output_path.file_stem().map_or_else(|| "graph".into(), |stem| stem.to_string_lossy());
+    output_path.with_file_name(format!("{}-data.json", stem))
+}
+
+/// Reconstructs a timely dataflow graph.
+///
+/// 1. Listens to incoming connection from a timely-dataflow program with
+/// logging enabled;
+/// 2. runs a differential-dataflow program to reconstruct the source
+/// computation's dataflow graph, and count the records sent over each
+/// channel;
+/// 3. returns the graph once the analysis stops (see `await_stop`).
+///
+/// With `with_profile`, also sums the time spent in each operator, as
+/// `profile` does.
+///
+/// The graph is built from the events of a single source worker (see
+/// `DiagConfig::structure_worker`), or with `all_workers` from those of
+/// all of them: then the graph has the operators and channels any worker
+/// logged, and `DataflowGraph::divergences` lists those only some did,
+/// e.g. because of conditionally constructed dataflows.
+///
+/// Only the operators matching the configured filter are kept (see
+/// `DiagConfig::operator_filter`), with the scopes containing them and
+/// the channels between them (or to and from scope boundaries).
+pub fn listen_and_collect(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    with_profile: bool,
+    all_workers: bool) -> Result<DataflowGraph, crate::DiagError> {
+
+    let streams_w = streams.clone();
+
+    let (updates_send, collector) = spawn_collector()?;
+    let updates_send = Arc::new(Mutex::new(updates_send));
 
     let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
     let is_running_w = is_running.clone();
 
-    let worker_handles = timely::execute(timely_configuration, move |worker| {
-        let operators_send: std::sync::mpsc::Sender<_> = operators_send.lock().expect("cannot lock operators_send").clone();
-        let channels_send: std::sync::mpsc::Sender<_> = channels_send.lock().expect("cannot lock channels_send").clone();
+    let structure_worker = config.structure_worker()?;
 
-        let sockets = sockets.clone();
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let updates_send: std::sync::mpsc::Sender<Update> = updates_send.lock().expect("cannot lock updates_send").clone();
 
         // create replayer from disjoint partition of source worker identifiers.
-        let replayer = tdiag_connect::receive::make_readers::<std::time::Duration, LoggingTuple>(
-            tdiag_connect::receive::ReplaySource::Tcp(sockets), worker.index(), worker.peers())
-            .expect("failed to open tcp readers");
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
 
         worker.dataflow(|scope| {
-            let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone())
-                .filter(|(_, worker, _)| *worker == 0);
-
-            let operates = stream
-                .flat_map(|(t, _, x)| if let Operates(event) = x { Some((event, t, 1 as isize)) } else { None })
-                .as_collection();
-
-            let channels = stream
-                .flat_map(|(t, _, x)| if let Channels(event) = x { Some((event, t, 1 as isize)) } else { None })
-                .as_collection();
-
-            // == Re-construct the dataflow graph (re-wire channels crossing a scope boundary) ==
-            //
-            // A timely dataflow graph has a hierarchical structure: a "scope" looks like an
-            // operator to the outside but can contain a subgraph of operators (and other scopes)
-            //
-            // We flatten this hierarchy to display it as a simple directed graph, but preserve the
-            // information on scope boundaries so that they can be drawn as graph cuts.
-
-            let operates = operates.map(|event| (event.addr, event.name));
-
-            // Addresses of potential scopes (excluding leaf operators)
-            let scopes = operates.map(|(mut addr, _)| {
-                addr.pop();
-                addr
-            }).distinct();
-
-            // Exclusively leaf operators
-            let operates_without_subg = operates.antijoin(&scopes);
-
-            // Retain only subscopes that correspond to scopes observed in the logs (remove empty [] addrs)
-            let subgraphs = operates.map(|(addr, _)| (addr, ())).semijoin(&scopes).map(|(addr, ())| addr);
-
-            let channels = channels.map(|event| (event.id, (event.scope_addr, event.source, event.target)));
-
-            // Output leaf operators
-            {
-                operates_without_subg
-                    .consolidate()
-                    .inner
-                    .map(move |((addr, name), _, _)| (addr, name))
-                    .capture_into(operators_send);
+            let events = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
+            build(&events, structure_worker, with_profile, all_workers, updates_send);
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to generate graph");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let collected = collector.join()
+        .map_err(|_| DiagError::Internal("the graph collector thread panicked".to_string()))?;
+
+    Ok(assemble(config, collected, with_profile, all_workers))
+}
+
+/// Spawns the thread folding the updates `build` derives into the graph
+/// collected so far, which it returns once all senders are dropped.
+///
+/// Updates are folded as they're derived, rather than at the end of the
+/// capture, so that per-second measurements don't pile up.
+pub(crate) fn spawn_collector() -> Result<(std::sync::mpsc::Sender<Update>, std::thread::JoinHandle<Collected>), DiagError> {
+    let (updates_send, updates_recv) = ::std::sync::mpsc::channel();
+    let collector = std::thread::Builder::new()
+        .name("tdiag-graph".to_string())
+        .spawn(move || {
+            let mut collected = Collected::default();
+            for update in updates_recv {
+                collected.fold(update);
             }
-            
-            // Output channels
-            {
-                // Channels that enter a subscope (as seen from outside the subscope)
-                // Their source is the operator representing the subscope
-                let subg_channels_outside_ingress = channels
-                    .map(|(id, (scope_addr, from, to))| {
-                        let mut subscope_addr = scope_addr.clone();
-                        subscope_addr.push(to.0);
-                        (subscope_addr, (id, (scope_addr, from), to.1))
-                    })
-                    .semijoin(&subgraphs);
-
-                // Channels that leave a subscope (as seen from outside the subscope)
-                // Their destination is the operator representing the subscope
-                let subg_channels_outside_egress = channels
-                    .map(|(id, (scope_addr, from, to))| {
-                        let mut subscope_addr = scope_addr.clone();
-                        subscope_addr.push(from.0);
-                        (subscope_addr, (id, from.1, (scope_addr, to)))
-                    })
-                    .semijoin(&subgraphs);
-
-                // Join the external and internal representation of channels that enter a subscope
-                //
-                //                          subscope [0, 1, 4]
-                //                        +-----------------------+
-                //     ([0, 1], 3)        |       ([0, 1, 4], 1)  |
-                //         o------------->|------------->o        |
-                //               external | internal              |
-                //                        +-----------------------+
-                //
-                // The external channel has addr [0, 1], source 3, destination 4 ([0, 1, 4] is the subscope).
-                // The internal channel has addr [0, 1, 4], source 0 (special!), destination 1.
-                let subg_ingress = subg_channels_outside_ingress
-                    .map(|(subscope_addr, (id, orig, subscope_port))| ((subscope_addr, (0, subscope_port)), (id, orig)))
-                    .join_map(
-                        &channels.map(|(id, (scope_addr, from, to))| ((scope_addr, from), (id, to))),
-                        |(scope_addr, _from), (id1, (orig_addr, orig_from)), (id2, to)| {
-                            let mut orig_addr = orig_addr.clone();
-                            orig_addr.push(orig_from.0);
-                            let mut to_addr = scope_addr.clone();
-                            to_addr.push(to.0);
-                            (vec![*id1, *id2], true, orig_addr, to_addr, orig_from.1, to.1)
-                        });
-
-                // Join the external and internal representation of channels that leave a subscope
-                //
-                // The structure depicted above is inverted for channels leaving a subscope
-                let subg_egress = subg_channels_outside_egress
-                    .map(|(subscope_addr, (id, subscope_port, dest))| ((subscope_addr, (0, subscope_port)), (id, dest)))
-                    .join_map(
-                        &channels.map(|(id, (scope_addr, from, to))| ((scope_addr, to), (id, from))),
-                        |(scope_addr, to), (id2, (dest_addr, dest_to)), (id1, from)| {
-                            let mut from_addr = scope_addr.clone();
-                            from_addr.push(from.0);
-                            let mut dest_addr = dest_addr.clone();
-                            dest_addr.push(dest_to.0);
-                            (vec![*id1, *id2], true, from_addr, dest_addr, to.1, dest_to.1)
-                        });
-
-                // Select all other channels (those that don't enter/leave a subscope)
-                let non_subg = channels
-                    .map(|(id, (scope_addr, from, to))| {
-                        let mut subscope_addr = scope_addr.clone();
-                        subscope_addr.push(from.0);
-                        (subscope_addr, (id, scope_addr, from, to))
-                    })
-                    .antijoin(&subgraphs)
-                    .map(|(_, (id, scope_addr, from, to))| {
-                        let mut subscope_addr = scope_addr.clone();
-                        subscope_addr.push(to.0);
-                        (subscope_addr, (id, scope_addr, from, to))
-                    })
-                    .antijoin(&subgraphs)
-                    .map(|(_, (id, scope_addr, from, to))| {
-                        let mut from_addr = scope_addr.clone();
-                        from_addr.push(from.0);
-                        let mut to_addr = scope_addr.clone();
-                        to_addr.push(to.0);
-                        (vec![id], false, from_addr, to_addr, from.1, to.1)
-                    });
-
-                subg_ingress
-                    .concat(&subg_egress)
-                    .concat(&non_subg)
-                    .consolidate()
-                    .inner
-                    .map(|(x, _, _)| x)
-                    .capture_into(channels_send);
+            collected
+        })?;
+    Ok((updates_send, collector))
+}
+
+/// Builds the dataflow reconstructing the graph from the replayed
+/// `events`, sending its parts to the collector (see `spawn_collector`).
+pub(crate) fn build<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    events: &timely::dataflow::Stream<G, LoggingTuple>,
+    structure_worker: usize,
+    with_profile: bool,
+    all_workers: bool,
+    updates_send: std::sync::mpsc::Sender<Update>) {
+
+    let (operators_send, channels_send, scopes_send) = (updates_send.clone(), updates_send.clone(), updates_send.clone());
+    let (elapsed_send, records_send, structure_send) = (updates_send.clone(), updates_send.clone(), updates_send);
+
+    let stream = events.filter(move |(_, worker, _)| all_workers || *worker == structure_worker);
+
+    // The operators and channels each worker logged
+    if all_workers {
+        events
+            .flat_map(|(_, worker, x)| match x {
+                Operates(event) => Some(((false, event.addr, event.name, (0, 0), (0, 0)), worker)),
+                Channels(event) => Some(((true, event.scope_addr, String::new(), event.source, event.target), worker)),
+                _ => None,
+            })
+            .inspect(move |(what, worker)| send(&structure_send, Update::Logged(what.clone(), *worker)));
+    }
 
+    // Records sent over each channel, over all workers
+    events
+        .flat_map(|(t, _, x)| if let Messages(event) = x {
+            if event.is_send {
+                let ts_clip = std::time::Duration::from_secs(t.as_secs() + 1);
+                Some((event.channel, ts_clip, event.length as isize))
+            } else {
+                None
             }
+        } else { None })
+        .as_collection()
+        .consolidate()
+        .inner
+        .inspect(move |(channel, _, records)| send(&records_send, Update::Records(*channel, *records)));
+
+    // Time spent in each operator, over all workers (operator
+    // ids are the same on all workers)
+    if with_profile {
+        let ids = stream
+            .flat_map(|(t, _, x)| if let Operates(event) = x { Some(((event.id, event.addr), t, 1 as isize)) } else { None })
+            .as_collection();
+        let ids = if all_workers { ids.distinct() } else { ids };
+
+        crate::commands::profile::activations(events)
+            .map(|((_, id), start, elapsed)| {
+                let ts_clip = std::time::Duration::from_secs((start + elapsed).as_secs() + 1);
+                let elapsed_ns = (elapsed.as_secs() as isize) * 1_000_000_000 + (elapsed.subsec_nanos() as isize);
+                ((id, ()), ts_clip, elapsed_ns)
+            })
+            .as_collection()
+            .join_map(&ids, |_, (), addr| addr.clone())
+            .consolidate()
+            .inner
+            .inspect(move |(addr, _, ns)| send(&elapsed_send, Update::Elapsed(addr.clone(), *ns)));
+    }
+
+    // The operators and channels are logged at distinct times; rounding
+    // them up to the second, as the measurements above, lets the
+    // arrangements below (in `distinct`, `semijoin` and `antijoin`)
+    // compact them into one update each as the frontier advances, rather
+    // than keeping one per log event over a long capture.
+    let operates = stream
+        .flat_map(|(t, _, x)| if let Operates(event) = x {
+            Some((event, std::time::Duration::from_secs(t.as_secs() + 1), 1 as isize))
+        } else { None })
+        .as_collection();
+
+    let channels = stream
+        .flat_map(|(t, _, x)| if let Channels(event) = x {
+            Some((event, std::time::Duration::from_secs(t.as_secs() + 1), 1 as isize))
+        } else { None })
+        .as_collection();
+
+    // == Re-construct the dataflow graph ==
+    //
+    // A timely dataflow graph has a hierarchical structure: a "scope" looks like an
+    // operator to the outside but can contain a subgraph of operators (and other scopes)
+    //
+    // We collect the leaf operators, the scope tree and each scope's own channels;
+    // `rewire` then follows the channels crossing scope boundaries, however deeply
+    // nested, so that the graph can be displayed flat or with collapsible scopes.
+
+    let operates = operates.map(|event| (event.addr, event.name));
+    let operates = if all_workers { operates.distinct() } else { operates };
+
+    // Addresses of potential scopes (excluding leaf operators)
+    let scopes = operates.map(|(mut addr, _)| {
+        addr.pop();
+        addr
+    }).distinct();
+
+    // Exclusively leaf operators
+    let operates_without_subg = operates.antijoin(&scopes);
+
+    // Output scopes that correspond to scopes observed in the logs (this removes
+    // empty [] addrs), with their names
+    operates
+        .semijoin(&scopes)
+        .consolidate()
+        .inner
+        .inspect(move |((addr, name), _, _)| send(&scopes_send, Update::Scope(addr.clone(), name.clone())));
+
+    let channels = channels.map(|event| (event.id, event.scope_addr, event.source, event.target));
+    let channels = if all_workers { channels.distinct() } else { channels };
+
+    // Output leaf operators
+    operates_without_subg
+        .consolidate()
+        .inner
+        .inspect(move |((addr, name), _, _)| send(&operators_send, Update::Operator(addr.clone(), name.clone())));
+
+    // Output channels, as logged in their scope
+    channels
+        .consolidate()
+        .inner
+        .inspect(move |(channel, _, _)| send(&channels_send, Update::Channel(channel.clone())));
+}
+
+/// Turns what the collector gathered into the graph, keeping the
+/// operators matching the configured filter.
+pub(crate) fn assemble(
+    config: &crate::config::DiagConfig,
+    collected: Collected,
+    with_profile: bool,
+    all_workers: bool) -> DataflowGraph {
+
+    let Collected { operators, scopes, channels: logged, records, elapsed, logged_by } = collected;
+
+    let operators = operators.into_iter()
+        .map(|(addr, name)| OperatorNode {
+            elapsed_ns: if with_profile { Some(elapsed.get(&addr).cloned().unwrap_or(0)) } else { None },
+            addr,
+            name,
+        })
+        .collect();
+    let scopes = scopes.into_iter()
+        .map(|(addr, name)| ScopeNode {
+            parent: if addr.len() > 1 { Some(addr[..addr.len() - 1].to_vec()) } else { None },
+            depth: addr.len() - 1,
+            addr,
+            name,
         })
-    }).map_err(|x| DiagError(format!("error in the timely computation: {}", x)))?;
+        .collect::<Vec<_>>();
+    let subgraphs = scopes.iter().map(|scope| scope.addr.clone()).collect();
+    let mut channels = rewire(&logged, &subgraphs).into_iter()
+        .map(|(ids, subgraph, from_addr, to_addr, from_port, to_port)| ChannelEdge {
+            // A channel that crosses scope boundaries is made of
+            // channels carrying the same records.
+            records: ids.iter().map(|id| records.get(id).cloned().unwrap_or(0)).max().unwrap_or(0),
+            ids,
+            subgraph,
+            from_addr,
+            to_addr,
+            from_port,
+            to_port,
+        })
+        .collect::<Vec<_>>();
+    if all_workers {
+        channels = merge_channels(channels);
+    }
+    let divergences = if all_workers {
+        divergences(logged_by)
+    } else {
+        Vec::new()
+    };
+
+    let graph = DataflowGraph { operators, channels, scopes, divergences };
+    if config.filter().is_empty() {
+        graph
+    } else {
+        filter_graph(graph, config.filter(), config.names())
+    }
+}
 
-    {
-        use std::io;
-        use std::io::prelude::*;
+/// A channel as logged in its scope: (id, scope address, source,
+/// target), where sources and targets are (index in the scope, port)
+/// and index 0 is the scope's own boundary.
+type LoggedChannel = (usize, Vec<usize>, (usize, usize), (usize, usize));
+
+/// An operator or channel as logged by a worker: (is_channel, addr or
+/// scope addr, name, source, target).
+type Logged = (bool, Vec<usize>, String, (usize, usize), (usize, usize));
+
+/// A part of the graph, sent to the collector as it's derived.
+pub(crate) enum Update {
+    /// A leaf operator (addr, name).
+    Operator(Vec<usize>, String),
+    /// A scope (addr, name).
+    Scope(Vec<usize>, String),
+    /// A channel, as logged in its scope.
+    Channel(LoggedChannel),
+    /// Records sent over a channel since the previous update.
+    Records(usize, isize),
+    /// Time spent in an operator (by addr) since the previous update,
+    /// with `with_profile`.
+    Elapsed(Vec<usize>, isize),
+    /// An operator or channel a worker logged, with `all_workers`.
+    Logged(Logged, usize),
+}
+
+/// The graph, as collected so far.
+#[derive(Default)]
+pub(crate) struct Collected {
+    operators: BTreeMap<Vec<usize>, String>,
+    scopes: BTreeMap<Vec<usize>, String>,
+    channels: Vec<LoggedChannel>,
+    /// channel id -> records sent
+    records: HashMap<usize, isize>,
+    /// operator addr -> ns
+    elapsed: HashMap<Vec<usize>, isize>,
+    /// What each worker logged, and which workers did.
+    logged_by: BTreeMap<Logged, BTreeSet<usize>>,
+}
 
-        let mut stdin = io::stdin();
-        let mut stdout = io::stdout();
+impl Collected {
+    fn fold(&mut self, update: Update) {
+        match update {
+            Update::Operator(addr, name) => { self.operators.insert(addr, name); }
+            Update::Scope(addr, name) => { self.scopes.insert(addr, name); }
+            Update::Channel(channel) => self.channels.push(channel),
+            Update::Records(channel, records) => *self.records.entry(channel).or_insert(0) += records,
+            Update::Elapsed(addr, ns) => *self.elapsed.entry(addr).or_insert(0) += ns,
+            Update::Logged(what, worker) => { self.logged_by.entry(what).or_insert_with(Default::default).insert(worker); }
+        }
+    }
+}
+
+/// Sends an update to the collector, which only stops once all workers
+/// are done.
+fn send(updates: &std::sync::mpsc::Sender<Update>, update: Update) {
+    updates.send(update).expect("the graph collector stopped");
+}
 
-        write!(stdout, "Press enter to generate graph (this will crash the source computation if it hasn't terminated).")
-            .expect("failed to write to stdout");
-        stdout.flush().unwrap();
+/// A scope boundary a channel leads to: (scope address, port, whether
+/// it's one of the scope's inputs).
+type Crossing = (Vec<usize>, usize, bool);
 
-        // Read a single byte and discard
-        let _ = stdin.read(&mut [0u8]).expect("failed to read from stdin");
+/// Re-wires the channels crossing scope boundaries, as (ids, subgraph,
+/// from_addr, to_addr, from_port, to_port).
+///
+/// Channels that don't enter or leave a `subgraphs` scope are kept as
+/// they are, including those to and from their own scope's boundary.
+/// Then, from each leaf operator, channels are followed in and out of
+/// scopes, through any number of nesting levels, until they reach
+/// another leaf operator (or a dataflow's boundary):
+///
+///                          subscope [0, 1, 4]
+///                        +-----------------------+
+///     ([0, 1], 3)        |       ([0, 1, 4], 1)  |
+///         o------------->|------------->o        |
+///               external | internal              |
+///                        +-----------------------+
+///
+/// The external channel has addr [0, 1], source 3, destination 4 ([0, 1, 4] is the subscope).
+/// The internal channel has addr [0, 1, 4], source 0 (special!), destination 1.
+/// Channels leaving a subscope are the other way around.
+fn rewire(
+    logged: &[LoggedChannel],
+    subgraphs: &std::collections::HashSet<Vec<usize>>) -> Vec<(Vec<usize>, bool, Vec<usize>, Vec<usize>, usize, usize)> {
+
+    fn child(scope: &[usize], index: usize) -> Vec<usize> {
+        let mut addr = scope.to_vec();
+        addr.push(index);
+        addr
     }
 
-    is_running.store(false, std::sync::atomic::Ordering::Release);
+    // The boundary a channel's target leads to, if it crosses one
+    let crossing = |(_, scope, _, to): &LoggedChannel| -> Option<Crossing> {
+        let target = child(scope, to.0);
+        if subgraphs.contains(&target) {
+            Some((target, to.1, true))
+        } else if to.0 == 0 && scope.len() > 1 {
+            Some((scope.clone(), to.1, false))
+        } else {
+            None
+        }
+    };
+
+    // The channels continuing from each boundary
+    let mut continuing = HashMap::<Crossing, Vec<&LoggedChannel>>::new();
+    for channel in logged.iter() {
+        let (_, scope, from, _) = channel;
+        let source = child(scope, from.0);
+        if from.0 == 0 {
+            continuing.entry((scope.clone(), from.1, true)).or_default().push(channel);
+        } else if subgraphs.contains(&source) {
+            continuing.entry((source, from.1, false)).or_default().push(channel);
+        }
+    }
 
-    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+    let mut rewired = Vec::new();
+    for channel in logged.iter() {
+        let (id, scope, from, to) = channel;
+        let (source, target) = (child(scope, from.0), child(scope, to.0));
+        if !subgraphs.contains(&source) && !subgraphs.contains(&target) {
+            rewired.push((vec![*id], false, source.clone(), target, from.1, to.1));
+        }
+        if from.0 == 0 || subgraphs.contains(&source) {
+            continue;
+        }
+        // Boundaries already crossed are skipped, in case a loop goes
+        // around a scope.
+        let mut paths = vec![(vec![*id], channel, Vec::<Crossing>::new())];
+        while let Some((ids, last, crossed)) = paths.pop() {
+            match crossing(last) {
+                Some(next) => {
+                    if crossed.contains(&next) {
+                        continue;
+                    }
+                    for channel in continuing.get(&next).map_or(&[][..], |channels| channels.as_slice()) {
+                        let mut ids = ids.clone();
+                        ids.push(channel.0);
+                        let mut crossed = crossed.clone();
+                        crossed.push(next.clone());
+                        paths.push((ids, *channel, crossed));
+                    }
+                }
+                None if ids.len() > 1 => {
+                    let (_, last_scope, _, last_to) = last;
+                    rewired.push((ids, true, source.clone(), child(last_scope, last_to.0), from.1, last_to.1));
+                }
+                None => {}
+            }
+        }
+    }
+    rewired
+}
 
-    let mut file = std::fs::File::create(output_path).map_err(|e| DiagError(format!("io error: {}", e)))?;
+/// Keeps the operators matching `filter`, the scopes containing them,
+/// and the channels between them or to and from scope boundaries.
+fn filter_graph(graph: DataflowGraph, filter: &crate::filter::OperatorFilter, name_map: &crate::names::NameMap) -> DataflowGraph {
+    let leaves = graph.operators.iter().map(|op| op.addr.clone()).collect::<std::collections::HashSet<_>>();
+    let operators = graph.operators.into_iter()
+        .filter(|op| filter.matches(&op.addr, &op.name, name_map))
+        .collect::<Vec<_>>();
+    let kept = operators.iter().map(|op| &op.addr).collect::<std::collections::HashSet<_>>();
+
+    // A channel endpoint that isn't a leaf operator is a scope boundary.
+    let channels = graph.channels.into_iter()
+        .filter(|ch| {
+            let (from, to) = (kept.contains(&ch.from_addr), kept.contains(&ch.to_addr));
+            (from || to) && (from || !leaves.contains(&ch.from_addr)) && (to || !leaves.contains(&ch.to_addr))
+        })
+        .collect();
+    let scopes = graph.scopes.into_iter()
+        .filter(|scope| operators.iter().any(|op| op.addr.starts_with(&scope.addr)))
+        .collect();
 
-    use std::io::Write;
+    DataflowGraph { operators, channels, scopes, divergences: graph.divergences }
+}
 
-    fn expect_write(e: Result<(), std::io::Error>) {
-        e.expect("write failed");
+/// Merges the channels with the same endpoints, which workers that built
+/// their dataflows differently may have logged with different ids.
+fn merge_channels(channels: Vec<ChannelEdge>) -> Vec<ChannelEdge> {
+    let mut merged: std::collections::BTreeMap<_, ChannelEdge> = std::collections::BTreeMap::new();
+    for channel in channels {
+        let key = (channel.from_addr.clone(), channel.from_port, channel.to_addr.clone(), channel.to_port, channel.subgraph);
+        match merged.get_mut(&key) {
+            Some(existing) => {
+                existing.ids.extend(channel.ids);
+                existing.ids.sort();
+                existing.ids.dedup();
+                existing.records += channel.records;
+            }
+            None => { merged.insert(key, channel); }
+        }
     }
+    merged.into_iter().map(|(_, channel)| channel).collect()
+}
 
-    expect_write(writeln!(file, "<body>"));
-    expect_write(writeln!(file, "{}", GRAPH_HTML));
-    expect_write(writeln!(file, "<script type=\"text/javascript\">"));
+/// The operators and channels not logged by all the workers that logged
+/// any, from the workers that logged each of them.
+fn divergences(logged_by: BTreeMap<Logged, BTreeSet<usize>>) -> Vec<Divergence> {
+    let workers = logged_by.values().flatten().cloned().collect::<BTreeSet<_>>();
+
+    logged_by.into_iter()
+        .filter(|(_, present)| present.len() < workers.len())
+        .map(|((is_channel, addr, name, source, target), present)| Divergence {
+            what: if is_channel {
+                format!("channel {:?} {:?} -> {:?}", addr, source, target)
+            } else {
+                format!("operator {} {:?}", name, addr)
+            },
+            missing: workers.difference(&present).cloned().collect(),
+            present: present.into_iter().collect(),
+        })
+        .collect()
+}
 
-    expect_write(writeln!(file, "let operate = ["));
-    for (addr, name) in operators_recv.extract().into_iter().flat_map(|(_t, v)| v) {
-        expect_write(writeln!(
-            file,
-            "{{ \"name\": \"{}\", \"addr\": [{}] }},",
-            name,
-            addr.into_iter().map(|x| format!("{}, ", x)).collect::<Vec<_>>().concat()));
+/// Prints the operators and channels only some workers logged, and
+/// whether there are any.
+fn print_divergences(divergences: &[Divergence], report: Option<&crate::report::Report>) {
+    if divergences.is_empty() {
+        crate::status::info("graph_consistent", "All source workers logged the same operators and channels");
+        return;
     }
-    expect_write(writeln!(file, "];"));
+    crate::status::warn("graph_divergence", &format!("{} operators or channels were only logged by some source workers", divergences.len()));
+    println!("Diverging\tLogged by workers\tMissing on workers");
+    for Divergence { what, present, missing } in divergences.iter() {
+        if let Some(report) = report {
+            report.record("graph_divergence", vec![
+                ("what", what.as_str().into()),
+                ("present", format!("{:?}", present).into()),
+                ("missing", format!("{:?}", missing).into()),
+            ]);
+        }
+        println!("{}\t{:?}\t{:?}", what, present, missing);
+    }
+}
 
-    expect_write(writeln!(file, "let channel = ["));
-    for (id, subgraph, from_addr, to_addr, from_port, to_port) in channels_recv.extract().into_iter().flat_map(|(_t, v)| v) {
-        expect_write(writeln!(
-            file,
-            "{{ \"id\": [{}], \"subgraph\": {}, \"from_addr\": [{}], \"to_addr\": [{}], \"from_port\": {}, \"to_port\": {} }},",
-            id.into_iter().map(|x| format!("{}, ", x)).collect::<Vec<_>>().concat(),
-            subgraph,
-            from_addr.into_iter().map(|x| format!("{}, ", x)).collect::<Vec<_>>().concat(),
-            to_addr.into_iter().map(|x| format!("{}, ", x)).collect::<Vec<_>>().concat(),
-            from_port,
-            to_port));
+/// Writes an html file that renders the graph with the bundled script,
+/// fetching it from `data_path` (relative to the html file).
+fn write_html<W: Write>(file: &mut W, data_path: &std::path::Path) -> Result<(), DiagError> {
+    let data_file = data_path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+
+    writeln!(file, "<body>")?;
+    writeln!(file, "{}", GRAPH_HTML)?;
+    writeln!(file, "<script type=\"text/javascript\">")?;
+    writeln!(file, "load({});", crate::status::json_string(&data_file))?;
+    writeln!(file, "</script>")?;
+    Ok(())
+}
+
+/// Writes the operators and channels as a standalone JSON document, as
+/// the html file fetches it:
+/// `{"operators": [{"name", "addr"}], "channels": [{"id", "subgraph",
+/// "from_addr", "to_addr", "from_port", "to_port", "records"}],
+/// "scopes": [{"name", "addr", "parent", "depth"}]}`, and the time
+/// spent in each operator (`"elapsed_ns"`) if profiled.
+fn write_json<W: Write>(file: &mut W, graph: &DataflowGraph, name_map: &crate::names::NameMap) -> Result<(), DiagError> {
+    let operators = &graph.operators;
+    let channels = &graph.channels;
+    let scopes = &graph.scopes;
+
+    let addr = |addr: &[usize]| format!("[{}]", addr.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "));
+
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"operators\": [")?;
+    for (i, op) in operators.iter().enumerate() {
+        let separator = if i + 1 < operators.len() { "," } else { "" };
+        writeln!(file, "    {{\"name\": {}, \"addr\": {}{}}}{}",
+            crate::status::json_string(name_map.label(&op.addr, &op.name)),
+            addr(&op.addr),
+            op.elapsed_ns.map_or(String::new(), |ns| format!(", \"elapsed_ns\": {}", ns)),
+            separator)?;
+    }
+    writeln!(file, "  ],")?;
+    writeln!(file, "  \"channels\": [")?;
+    for (i, ch) in channels.iter().enumerate() {
+        let separator = if i + 1 < channels.len() { "," } else { "" };
+        writeln!(file, "    {{\"id\": {}, \"subgraph\": {}, \"from_addr\": {}, \"to_addr\": {}, \"from_port\": {}, \"to_port\": {}, \"records\": {}}}{}",
+            addr(&ch.ids),
+            ch.subgraph,
+            addr(&ch.from_addr),
+            addr(&ch.to_addr),
+            ch.from_port,
+            ch.to_port,
+            ch.records,
+            separator)?;
     }
-    expect_write(writeln!(file, "];"));
+    writeln!(file, "  ],")?;
+    writeln!(file, "  \"scopes\": [")?;
+    for (i, scope) in scopes.iter().enumerate() {
+        let separator = if i + 1 < scopes.len() { "," } else { "" };
+        writeln!(file, "    {{\"name\": {}, \"addr\": {}, \"parent\": {}, \"depth\": {}}}{}",
+            crate::status::json_string(name_map.label(&scope.addr, &scope.name)),
+            addr(&scope.addr),
+            scope.parent.as_ref().map_or("null".to_string(), |parent| addr(parent)),
+            scope.depth,
+            separator)?;
+    }
+    writeln!(file, "  ]")?;
+    writeln!(file, "}}")?;
+    Ok(())
+}
 
-    expect_write(writeln!(file, "run(operate, channel);"));
+/// Writes a Graphviz file of the graph, with a cluster per scope. Nodes
+/// are identified by their address; channel endpoints that aren't leaf
+/// operators (e.g. the boundary of a dataflow) are drawn as points.
+/// Channels are labelled with, and as thick as, the records sent over
+/// them. If profiled, operators are filled in shades of red by the time
+/// spent in them.
+fn write_dot<W: Write>(file: &mut W, graph: &DataflowGraph, name_map: &crate::names::NameMap) -> Result<(), DiagError> {
+    let operators = &graph.operators;
+
+    let node_id = |addr: &[usize]| dot_string(&format!("{:?}", addr));
+
+    // scope addr -> leaf operators directly in it
+    let mut nodes = std::collections::BTreeMap::<Vec<usize>, Vec<&OperatorNode>>::new();
+    for operator in operators.iter() {
+        let mut parent = operator.addr.clone();
+        parent.pop();
+        nodes.entry(parent).or_insert_with(Vec::new).push(operator);
+    }
+    // Every scope on the way to an operator is a cluster, even without
+    // operators of its own.
+    let mut clusters = std::collections::BTreeSet::new();
+    for addr in nodes.keys() {
+        for len in 1..=addr.len() {
+            clusters.insert(addr[..len].to_vec());
+        }
+    }
 
-    expect_write(writeln!(file, "</script>"));
+    /// What's needed to write the nested clusters.
+    struct Clusters<'a> {
+        nodes: std::collections::BTreeMap<Vec<usize>, Vec<&'a OperatorNode>>,
+        clusters: std::collections::BTreeSet<Vec<usize>>,
+        scope_names: HashMap<Vec<usize>, &'a String>,
+        hottest: isize,
+        name_map: &'a crate::names::NameMap,
+    }
 
-    println!("Graph generated in file://{}", std::fs::canonicalize(output_path).expect("invalid path").to_string_lossy());
+    impl<'a> Clusters<'a> {
+        fn write_scope<W: Write>(&self, file: &mut W, scope: &[usize], depth: usize) -> Result<(), DiagError> {
+            let indent = "  ".repeat(depth);
+            for op in self.nodes.get(scope).map(|nodes| nodes.as_slice()).unwrap_or(&[]).iter() {
+                let (addr, label) = (&op.addr, format!("{}\n{:?}", self.name_map.label(&op.addr, &op.name), op.addr));
+                match op.elapsed_ns {
+                    Some(ns) => {
+                        writeln!(file, "{}{} [label={}, style=filled, fillcolor={}];",
+                            indent,
+                            dot_string(&format!("{:?}", addr)),
+                            dot_string(&format!("{}\n{:.3} s", label, ns as f64 / 1_000_000_000f64)),
+                            dot_string(&heat_color(ns, self.hottest)))?;
+                    }
+                    None => {
+                        writeln!(file, "{}{} [label={}];", indent, dot_string(&format!("{:?}", addr)), dot_string(&label))?;
+                    }
+                }
+            }
+            for child in self.clusters.iter().filter(|c| c.len() == scope.len() + 1 && c.starts_with(scope)) {
+                let label = match self.scope_names.get(child) {
+                    Some(name) => format!("{} {:?}", self.name_map.label(child, name), child),
+                    None => format!("{:?}", child),
+                };
+                writeln!(file, "{}subgraph {} {{", indent, dot_string(&format!("cluster_{:?}", child)))?;
+                writeln!(file, "{}  label={};", indent, dot_string(&label))?;
+                self.write_scope(file, child, depth + 1)?;
+                writeln!(file, "{}}}", indent)?;
+            }
+            Ok(())
+        }
+    }
 
+    let clusters = Clusters {
+        nodes,
+        clusters,
+        scope_names: graph.scopes.iter().map(|scope| (scope.addr.clone(), &scope.name)).collect(),
+        hottest: operators.iter().filter_map(|op| op.elapsed_ns).max().unwrap_or(0),
+        name_map,
+    };
+
+    writeln!(file, "digraph dataflow {{")?;
+    writeln!(file, "  node [shape=box];")?;
+    clusters.write_scope(file, &[], 1)?;
+
+    let known = operators.iter().map(|op| &op.addr).collect::<std::collections::HashSet<_>>();
+    let mut boundaries = std::collections::BTreeSet::new();
+    for ch in graph.channels.iter() {
+        for addr in [&ch.from_addr, &ch.to_addr].iter() {
+            if !known.contains(addr) {
+                boundaries.insert((*addr).clone());
+            }
+        }
+    }
+    for addr in boundaries.iter() {
+        writeln!(file, "  {} [shape=point, xlabel={}];", node_id(addr), dot_string(&format!("{:?}", addr)))?;
+    }
+
+    let max_records = graph.max_records();
+    for ChannelEdge { ids, subgraph, from_addr, to_addr, from_port, to_port, records } in graph.channels.iter() {
+        let penwidth = if max_records > 0 { 1.0 + 7.0 * *records as f64 / max_records as f64 } else { 1.0 };
+        writeln!(file, "  {} -> {} [label=\"{}\", taillabel=\"{}\", headlabel=\"{}\", penwidth={:.1}, tooltip={}{}];",
+            node_id(from_addr),
+            node_id(to_addr),
+            records,
+            from_port,
+            to_port,
+            penwidth,
+            dot_string(&format!("channel {:?}", ids)),
+            if *subgraph { ", style=dashed" } else { "" })?;
+    }
+    writeln!(file, "}}")?;
     Ok(())
 }
+
+/// A shade of red, from white (no time spent) to red (as much time as
+/// the hottest operator), as an RGB hex color.
+pub(crate) fn heat_color(ns: isize, hottest: isize) -> String {
+    let share = if hottest > 0 { (ns as f64 / hottest as f64).max(0.0).min(1.0) } else { 0.0 };
+    let other = (255.0 * (1.0 - share)).round() as u8;
+    format!("#ff{:02x}{:02x}", other, other)
+}
+
+/// Quotes a string as a DOT identifier, with newlines as line breaks.
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
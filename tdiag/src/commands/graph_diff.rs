@@ -0,0 +1,209 @@
+//! "graph-diff" subcommand: compares two dataflow graphs written by
+//! `graph --format json` (or the data files of html graphs), to review
+//! how a code change alters the constructed dataflow.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::DiagError;
+use crate::json::Json;
+
+/// A channel's endpoints: (from_addr, from_port, to_addr, to_port).
+type Endpoints = (Vec<usize>, usize, Vec<usize>, usize);
+
+/// The leaf operators (address, name) and channels of a graph.
+struct Graph {
+    operators: BTreeMap<Vec<usize>, String>,
+    channels: BTreeSet<Endpoints>,
+}
+
+impl Graph {
+    fn read(path: &Path) -> Result<Self, DiagError> {
+        let json = Json::read(path)?;
+        let invalid = |what: &str| DiagError::Parse {
+            context: format!("{} is not a graph written by graph --format json ({})", path.display(), what),
+            source: None,
+        };
+
+        let mut operators = BTreeMap::new();
+        for operator in json.get("operators").and_then(Json::as_array).ok_or_else(|| invalid("no operators"))? {
+            let addr = operator.get("addr").and_then(Json::as_addr).ok_or_else(|| invalid("operator without addr"))?;
+            let name = operator.get("name").and_then(Json::as_str).ok_or_else(|| invalid("operator without name"))?;
+            operators.insert(addr, name.to_string());
+        }
+        let mut channels = BTreeSet::new();
+        for channel in json.get("channels").and_then(Json::as_array).ok_or_else(|| invalid("no channels"))? {
+            let field = |key: &str| channel.get(key).ok_or_else(|| invalid(&format!("channel without {}", key)));
+            channels.insert((
+                field("from_addr")?.as_addr().ok_or_else(|| invalid("invalid from_addr"))?,
+                field("from_port")?.as_usize().ok_or_else(|| invalid("invalid from_port"))?,
+                field("to_addr")?.as_addr().ok_or_else(|| invalid("invalid to_addr"))?,
+                field("to_port")?.as_usize().ok_or_else(|| invalid("invalid to_port"))?,
+            ));
+        }
+        Ok(Graph { operators, channels })
+    }
+}
+
+/// Prints the operators and channels added, removed or renamed from the
+/// graph at `before` to the one at `after`.
+///
+/// Operators are matched within each scope (by their scope's address)
+/// by aligning the sequences of their names, so that an operator added
+/// in the middle of a scope shows up as such rather than as all the
+/// following ones being renamed. Unmatched operators at the same
+/// address in both graphs are renamed. Channels are then compared by
+/// their endpoints, as matched; channel ids and record counts aren't
+/// compared.
+///
+/// Fails if `fail_on_changes` is set and the graphs differ.
+pub fn diff(before: &Path, after: &Path, fail_on_changes: bool, report: Option<&crate::report::Report>) -> Result<(), DiagError> {
+    let before = Graph::read(before)?;
+    let after = Graph::read(after)?;
+
+    let mut scopes = BTreeMap::<Vec<usize>, (Vec<(&Vec<usize>, &String)>, Vec<(&Vec<usize>, &String)>)>::new();
+    for (addr, name) in before.operators.iter() {
+        scopes.entry(addr[..addr.len().saturating_sub(1)].to_vec()).or_default().0.push((addr, name));
+    }
+    for (addr, name) in after.operators.iter() {
+        scopes.entry(addr[..addr.len().saturating_sub(1)].to_vec()).or_default().1.push((addr, name));
+    }
+
+    // before address -> after address, for the matched operators
+    let mut matched = HashMap::new();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut renamed = Vec::new();
+    for (before_ops, after_ops) in scopes.values() {
+        let pairs = align(
+            &before_ops.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>(),
+            &after_ops.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>());
+        let before_matched = pairs.iter().map(|(i, _)| *i).collect::<BTreeSet<_>>();
+        let after_matched = pairs.iter().map(|(_, j)| *j).collect::<BTreeSet<_>>();
+        for (i, j) in pairs {
+            matched.insert(before_ops[i].0.clone(), after_ops[j].0.clone());
+        }
+        let mut unmatched_after = after_ops.iter().enumerate()
+            .filter(|(j, _)| !after_matched.contains(j))
+            .map(|(_, (addr, name))| (*addr, *name))
+            .collect::<BTreeMap<_, _>>();
+        for (_, (addr, name)) in before_ops.iter().enumerate().filter(|(i, _)| !before_matched.contains(i)) {
+            match unmatched_after.remove(addr) {
+                Some(new_name) => {
+                    matched.insert((*addr).clone(), (*addr).clone());
+                    renamed.push((*addr, *name, new_name));
+                }
+                None => removed.push((*addr, *name)),
+            }
+        }
+        added.extend(unmatched_after);
+    }
+
+    // Endpoints that aren't matched operators (e.g. scope boundaries)
+    // are compared as they are.
+    let translate = |addr: &Vec<usize>| matched.get(addr).cloned().unwrap_or_else(|| addr.clone());
+    let translated = before.channels.iter()
+        .map(|(from, from_port, to, to_port)| ((translate(from), *from_port, translate(to), *to_port), (from, to)))
+        .collect::<BTreeMap<_, _>>();
+    let added_channels = after.channels.iter().filter(|ch| !translated.contains_key(*ch)).collect::<Vec<_>>();
+    let removed_channels = translated.iter()
+        .filter(|(ch, _)| !after.channels.contains(*ch))
+        .map(|(ch, (from, to))| (*from, ch.1, *to, ch.3))
+        .collect::<Vec<_>>();
+
+    println!("Operators: {} added, {} removed, {} renamed, {} unchanged",
+        added.len(), removed.len(), renamed.len(), matched.len() - renamed.len());
+    for (addr, name) in added.iter() {
+        println!("+ operator {} {:?}", name, addr);
+    }
+    for (addr, name) in removed.iter() {
+        println!("- operator {} {:?}", name, addr);
+    }
+    for (addr, old_name, new_name) in renamed.iter() {
+        println!("~ operator {:?}: {} -> {}", addr, old_name, new_name);
+    }
+    // Added channels are described in the graph after, removed ones in
+    // the graph before, whose addresses may differ: the names tell them
+    // apart.
+    let added_channels = added_channels.iter()
+        .map(|(from, from_port, to, to_port)| format!("channel {} -> {}",
+            endpoint(&after.operators, from, *from_port), endpoint(&after.operators, to, *to_port)))
+        .collect::<Vec<_>>();
+    let removed_channels = removed_channels.iter()
+        .map(|(from, from_port, to, to_port)| format!("channel {} -> {}",
+            endpoint(&before.operators, from, *from_port), endpoint(&before.operators, to, *to_port)))
+        .collect::<Vec<_>>();
+    println!("Channels: {} added, {} removed", added_channels.len(), removed_channels.len());
+    for channel in added_channels.iter() {
+        println!("+ {}", channel);
+    }
+    for channel in removed_channels.iter() {
+        println!("- {}", channel);
+    }
+
+    if let Some(report) = report {
+        let record = |change: &str, what: String| report.record("graph_change", vec![
+            ("change", change.into()),
+            ("what", what.into()),
+        ]);
+        for (addr, name) in added.iter() {
+            record("added", format!("operator {} {:?}", name, addr));
+        }
+        for (addr, name) in removed.iter() {
+            record("removed", format!("operator {} {:?}", name, addr));
+        }
+        for (addr, old_name, new_name) in renamed.iter() {
+            record("renamed", format!("operator {:?}: {} -> {}", addr, old_name, new_name));
+        }
+        for channel in added_channels.iter() {
+            record("added", channel.clone());
+        }
+        for channel in removed_channels.iter() {
+            record("removed", channel.clone());
+        }
+    }
+
+    let changes = added.len() + removed.len() + renamed.len() + added_channels.len() + removed_channels.len();
+    if fail_on_changes && changes > 0 {
+        return Err(DiagError::CheckFailed(format!("the graphs differ ({} changes)", changes)));
+    }
+    Ok(())
+}
+
+/// A channel endpoint as "name addr:port", or "addr:port" if it isn't a
+/// leaf operator of the graph (e.g. a scope boundary).
+fn endpoint(operators: &BTreeMap<Vec<usize>, String>, addr: &[usize], port: usize) -> String {
+    match operators.get(addr) {
+        Some(name) => format!("{} {:?}:{}", name, addr, port),
+        None => format!("{:?}:{}", addr, port),
+    }
+}
+
+/// The pairs of indices of a longest common subsequence of `a` and `b`.
+fn align(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    // lengths[i][j]: the length of a longest common subsequence of a[i..] and b[j..]
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(lengths[i + 1][j], lengths[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut pairs = Vec::new();
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
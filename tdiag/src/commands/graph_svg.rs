@@ -0,0 +1,336 @@
+//! SVG rendering of the dataflow graph, for headless environments and
+//! documentation pipelines.
+//!
+//! The flattened graph (see `DataflowGraph`) is laid out here rather
+//! than with an external tool, in layers as Graphviz's `dot` does:
+//!
+//! 1. channels that close a cycle (e.g. the feedback of an iterative
+//! scope) are reversed, so that the graph is acyclic;
+//! 2. each node goes on the layer below its deepest predecessor;
+//! 3. channels spanning several layers go through a dummy node on each
+//! layer in between;
+//! 4. the nodes of each layer are ordered by the average position of
+//! their neighbors on the adjacent layer, sweeping down and up, to
+//! reduce crossings;
+//! 5. nodes are moved horizontally towards their neighbors, keeping
+//! their order.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::DiagError;
+use crate::commands::flamegraph::escape;
+use crate::commands::graph::{heat_color, DataflowGraph};
+
+/// Height of a line of text in a node, in pixels.
+const LINE_HEIGHT: f64 = 16.0;
+/// Vertical space between layers, in pixels.
+const LAYER_SPACING: f64 = 50.0;
+/// Minimum horizontal space between nodes, in pixels.
+const NODE_SPACING: f64 = 20.0;
+/// About 7px per character.
+const CHAR_WIDTH: f64 = 7.0;
+/// Space around the graph, in pixels.
+const MARGIN: f64 = 20.0;
+/// Passes to order the nodes of each layer.
+const ORDER_SWEEPS: usize = 8;
+/// Passes to move nodes towards their neighbors.
+const PLACE_SWEEPS: usize = 8;
+
+/// A node of the layout.
+enum Kind {
+    /// A leaf operator, with its label's lines.
+    Operator(Vec<String>, String),
+    /// A channel endpoint that isn't a leaf operator (e.g. the boundary
+    /// of a dataflow), drawn as a point.
+    Boundary(Vec<usize>),
+    /// Where a channel crosses a layer.
+    Dummy,
+}
+
+struct Node {
+    kind: Kind,
+    width: f64,
+    layer: usize,
+    /// The horizontal center.
+    x: f64,
+}
+
+/// A channel, as a path through the layers.
+struct Edge {
+    channel: usize,
+    /// The nodes the channel goes through, from layer to layer.
+    path: Vec<usize>,
+    /// Whether the path goes against the channel (to break a cycle).
+    reversed: bool,
+}
+
+/// Writes an SVG rendering of `graph`: operators are labelled with their
+/// names (see `NameMap::label`) and addresses, and filled in shades of
+/// red by the time spent in them if profiled; channels are labelled
+/// with, and as thick as, the records sent over them, and dashed if they
+/// cross a scope boundary.
+pub fn write_svg<W: Write>(file: &mut W, graph: &DataflowGraph, name_map: &crate::names::NameMap) -> Result<(), DiagError> {
+    let hottest = graph.operators.iter().filter_map(|op| op.elapsed_ns).max().unwrap_or(0);
+    let profiled = graph.operators.iter().any(|op| op.elapsed_ns.is_some());
+    let node_height = LINE_HEIGHT * if profiled { 3.0 } else { 2.0 } + 8.0;
+
+    // == Nodes and edges ==
+
+    let mut operators = graph.operators.iter().collect::<Vec<_>>();
+    operators.sort_by(|a, b| a.addr.cmp(&b.addr));
+    let mut nodes = Vec::new();
+    let mut index = HashMap::new();
+    for op in operators {
+        let mut lines = vec![name_map.label(&op.addr, &op.name).to_string(), format!("{:?}", op.addr)];
+        if let Some(ns) = op.elapsed_ns {
+            lines.push(format!("{:.3} s", ns as f64 / 1_000_000_000f64));
+        }
+        let fill = op.elapsed_ns.map_or_else(|| "white".to_string(), |ns| heat_color(ns, hottest));
+        let chars = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        index.insert(op.addr.clone(), nodes.len());
+        nodes.push(Node { kind: Kind::Operator(lines, fill), width: chars as f64 * CHAR_WIDTH + 16.0, layer: 0, x: 0.0 });
+    }
+    let mut endpoints = Vec::new();
+    for ch in graph.channels.iter() {
+        let mut node_of = |addr: &[usize]| *index.entry(addr.to_vec()).or_insert_with(|| {
+            nodes.push(Node { kind: Kind::Boundary(addr.to_vec()), width: 6.0, layer: 0, x: 0.0 });
+            nodes.len() - 1
+        });
+        endpoints.push((node_of(&ch.from_addr[..]), node_of(&ch.to_addr[..])));
+    }
+
+    // == 1. Break cycles ==
+
+    let mut outgoing = vec![Vec::new(); nodes.len()];
+    for (channel, (from, to)) in endpoints.iter().enumerate() {
+        if from != to {
+            outgoing[*from].push(channel);
+        }
+    }
+    let mut reversed = vec![false; endpoints.len()];
+    // 0: not visited yet, 1: on the depth-first search's stack, 2: done
+    let mut state = vec![0u8; nodes.len()];
+    for root in 0..nodes.len() {
+        if state[root] != 0 {
+            continue;
+        }
+        state[root] = 1;
+        let mut stack = vec![(root, 0)];
+        while let Some((node, next)) = stack.pop() {
+            if next < outgoing[node].len() {
+                stack.push((node, next + 1));
+                let channel = outgoing[node][next];
+                let to = endpoints[channel].1;
+                match state[to] {
+                    0 => {
+                        state[to] = 1;
+                        stack.push((to, 0));
+                    }
+                    1 => reversed[channel] = true,
+                    _ => {}
+                }
+            } else {
+                state[node] = 2;
+            }
+        }
+    }
+    // (channel, upper node, lower node)
+    let oriented = endpoints.iter().enumerate()
+        .filter(|(_, (from, to))| from != to)
+        .map(|(channel, &(from, to))| if reversed[channel] { (channel, to, from) } else { (channel, from, to) })
+        .collect::<Vec<_>>();
+
+    // == 2. Assign layers (longest path, in topological order) ==
+
+    let mut below = vec![Vec::new(); nodes.len()];
+    let mut in_degree = vec![0; nodes.len()];
+    for &(_, upper, lower) in oriented.iter() {
+        below[upper].push(lower);
+        in_degree[lower] += 1;
+    }
+    let mut ready = (0..nodes.len()).filter(|node| in_degree[*node] == 0).collect::<Vec<_>>();
+    while let Some(node) = ready.pop() {
+        for &lower in below[node].iter() {
+            nodes[lower].layer = std::cmp::max(nodes[lower].layer, nodes[node].layer + 1);
+            in_degree[lower] -= 1;
+            if in_degree[lower] == 0 {
+                ready.push(lower);
+            }
+        }
+    }
+
+    // == 3. Dummy nodes ==
+
+    let mut edges = Vec::new();
+    for &(channel, upper, lower) in oriented.iter() {
+        let mut path = vec![upper];
+        for layer in nodes[upper].layer + 1..nodes[lower].layer {
+            nodes.push(Node { kind: Kind::Dummy, width: 0.0, layer, x: 0.0 });
+            path.push(nodes.len() - 1);
+        }
+        path.push(lower);
+        edges.push(Edge { channel, path, reversed: reversed[channel] });
+    }
+    let mut up = vec![Vec::new(); nodes.len()];
+    let mut down = vec![Vec::new(); nodes.len()];
+    for edge in edges.iter() {
+        for pair in edge.path.windows(2) {
+            down[pair[0]].push(pair[1]);
+            up[pair[1]].push(pair[0]);
+        }
+    }
+
+    // == 4. Order the nodes of each layer ==
+
+    let depth = nodes.iter().map(|node| node.layer + 1).max().unwrap_or(0);
+    let mut layers = vec![Vec::new(); depth];
+    for (node, Node { layer, .. }) in nodes.iter().enumerate() {
+        layers[*layer].push(node);
+    }
+    let mut position = vec![0.0; nodes.len()];
+    for layer in layers.iter() {
+        for (i, node) in layer.iter().enumerate() {
+            position[*node] = i as f64;
+        }
+    }
+    for sweep in 0..ORDER_SWEEPS {
+        let (order, neighbors): (Vec<usize>, _) = if sweep % 2 == 0 {
+            ((1..depth).collect(), &up)
+        } else {
+            ((0..depth.saturating_sub(1)).rev().collect(), &down)
+        };
+        for layer in order {
+            let mut keyed = layers[layer].iter()
+                .map(|&node| (barycenter(&neighbors[node], &position).unwrap_or(position[node]), node))
+                .collect::<Vec<_>>();
+            // Stable, so that ties keep their order.
+            keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+            layers[layer] = keyed.into_iter().map(|(_, node)| node).collect();
+            for (i, node) in layers[layer].iter().enumerate() {
+                position[*node] = i as f64;
+            }
+        }
+    }
+
+    // == 5. Place the nodes ==
+
+    for layer in layers.iter() {
+        let mut x = 0.0;
+        for &node in layer.iter() {
+            nodes[node].x = x + nodes[node].width / 2.0;
+            x += nodes[node].width + NODE_SPACING;
+        }
+    }
+    for sweep in 0..PLACE_SWEEPS {
+        let (order, neighbors): (Vec<usize>, _) = if sweep % 2 == 0 {
+            ((1..depth).collect(), &up)
+        } else {
+            ((0..depth.saturating_sub(1)).rev().collect(), &down)
+        };
+        let centers = nodes.iter().map(|node| node.x).collect::<Vec<_>>();
+        for layer in order {
+            let layer = &layers[layer];
+            let desired = layer.iter()
+                .map(|&node| barycenter(&neighbors[node], &centers).unwrap_or(centers[node]))
+                .collect::<Vec<_>>();
+            let gap = |i: usize| (nodes[layer[i - 1]].width + nodes[layer[i]].width) / 2.0 + NODE_SPACING;
+            // As close as possible to the desired centers, packed to the
+            // left then to the right; their average keeps the order too.
+            let mut left = desired.clone();
+            for i in 1..layer.len() {
+                left[i] = left[i].max(left[i - 1] + gap(i));
+            }
+            let mut right = desired;
+            for i in (1..layer.len()).rev() {
+                right[i - 1] = right[i - 1].min(right[i] - gap(i));
+            }
+            for (i, &node) in layer.iter().enumerate() {
+                nodes[node].x = (left[i] + right[i]) / 2.0;
+            }
+        }
+    }
+    let min_x = nodes.iter().map(|node| node.x - node.width / 2.0).fold(std::f64::INFINITY, f64::min);
+    let shift = if min_x.is_finite() { MARGIN - min_x } else { MARGIN };
+    for node in nodes.iter_mut() {
+        node.x += shift;
+    }
+    let width = nodes.iter().map(|node| node.x + node.width / 2.0).fold(0.0, f64::max) + MARGIN;
+    let height = depth as f64 * (node_height + LAYER_SPACING) - LAYER_SPACING + 2.0 * MARGIN;
+    let y_of = |node: &Node| MARGIN + node_height / 2.0 + node.layer as f64 * (node_height + LAYER_SPACING);
+    let half_height = |node: &Node| match node.kind {
+        Kind::Operator(..) => node_height / 2.0,
+        Kind::Boundary(_) => 3.0,
+        Kind::Dummy => 0.0,
+    };
+
+    // == Render ==
+
+    writeln!(file, "<?xml version=\"1.0\" standalone=\"no\"?>")?;
+    writeln!(file, "<svg version=\"1.1\" width=\"{:.0}\" height=\"{:.0}\" xmlns=\"http://www.w3.org/2000/svg\">", width, height.max(2.0 * MARGIN))?;
+    writeln!(file, "<style>text {{ font-family: Verdana, sans-serif; font-size: 12px; }} .records {{ font-size: 10px; fill: #555; }}</style>")?;
+    writeln!(file, "<defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"6\" markerHeight=\"6\" markerUnits=\"userSpaceOnUse\" orient=\"auto\"><path d=\"M 0 0 L 10 5 L 0 10 z\" fill=\"#333\"/></marker></defs>")?;
+    writeln!(file, "<rect x=\"0\" y=\"0\" width=\"100%\" height=\"100%\" fill=\"white\"/>")?;
+
+    let max_records = graph.channels.iter().map(|channel| channel.records).max().unwrap_or(0);
+    for Edge { channel, path, reversed } in edges.iter() {
+        let ch = &graph.channels[*channel];
+        let (first, last) = (&nodes[path[0]], &nodes[path[path.len() - 1]]);
+        let mut points = Vec::with_capacity(path.len());
+        points.push((first.x, y_of(first) + half_height(first)));
+        points.extend(path[1..path.len() - 1].iter().map(|node| (nodes[*node].x, y_of(&nodes[*node]))));
+        points.push((last.x, y_of(last) - half_height(last)));
+        if *reversed {
+            points.reverse();
+        }
+        let d = points.iter().enumerate()
+            .map(|(i, (x, y))| format!("{} {:.1} {:.1}", if i == 0 { "M" } else { "L" }, x, y))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let stroke_width = if max_records > 0 { 1.0 + 7.0 * ch.records as f64 / max_records as f64 } else { 1.0 };
+        writeln!(file, "<g><title>{}</title>", escape(&format!("channel {:?} [{} -> {}]: {} records", ch.ids, ch.from_port, ch.to_port, ch.records)))?;
+        writeln!(file, "<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{:.1}\"{} marker-end=\"url(#arrow)\"/>",
+            d,
+            if ch.subgraph { "#33f" } else { "#333" },
+            stroke_width,
+            if ch.subgraph { " stroke-dasharray=\"5,2\"" } else { "" })?;
+        let (mid_x, mid_y) = {
+            let (a, b) = (points[(points.len() - 1) / 2], points[points.len() / 2]);
+            ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0)
+        };
+        writeln!(file, "<text class=\"records\" x=\"{:.1}\" y=\"{:.1}\">{}</text>", mid_x + 4.0, mid_y, ch.records)?;
+        writeln!(file, "</g>")?;
+    }
+
+    for node in nodes.iter() {
+        let y = y_of(node);
+        match &node.kind {
+            Kind::Operator(lines, fill) => {
+                writeln!(file, "<g><title>{}</title>", escape(&lines.join(" ")))?;
+                writeln!(file, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" rx=\"5\" fill=\"{}\" stroke=\"#333\"/>",
+                    node.x - node.width / 2.0, y - node_height / 2.0, node.width, node_height, fill)?;
+                for (i, line) in lines.iter().enumerate() {
+                    writeln!(file, "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>",
+                        node.x, y - node_height / 2.0 + 4.0 + LINE_HEIGHT * (i as f64 + 0.75), escape(line))?;
+                }
+                writeln!(file, "</g>")?;
+            }
+            Kind::Boundary(addr) => {
+                writeln!(file, "<g><title>{:?}</title><circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"#333\"/></g>", addr, node.x, y)?;
+            }
+            Kind::Dummy => {}
+        }
+    }
+
+    writeln!(file, "</svg>")?;
+    Ok(())
+}
+
+/// The average of the positions of `neighbors`, if any.
+fn barycenter(neighbors: &[usize], position: &[f64]) -> Option<f64> {
+    if neighbors.is_empty() {
+        None
+    } else {
+        Some(neighbors.iter().map(|node| position[*node]).sum::<f64>() / neighbors.len() as f64)
+    }
+}
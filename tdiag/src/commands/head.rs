@@ -0,0 +1,73 @@
+//! "head" subcommand: prints the first events of a recording, as a
+//! quick check that it contains what it should.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::DiagError;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::Event;
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::recording::{EventFileReader, Recording, StreamKind};
+
+/// Prints a summary of the recording at `path`, followed by the first
+/// `events` events of each source worker in each of its streams.
+pub fn head(path: &Path, events: usize) -> Result<(), DiagError> {
+    let recording = Recording::open(path)?;
+
+    println!("Kind\tIndex\tFile\tBytes");
+    for stream in recording.streams.iter() {
+        let bytes = std::fs::metadata(&stream.path).map(|m| m.len().to_string()).unwrap_or_else(|_| "missing".to_string());
+        println!("{}\t{}\t{}\t{}", stream.kind.name(), stream.index, stream.path.display(), bytes);
+    }
+
+    for stream in recording.streams.iter() {
+        println!();
+        println!("== {} stream {} ==", stream.kind.name(), stream.index);
+        match stream.kind {
+            StreamKind::Timely => print_head::<TimelyEvent>(&stream.path, events)?,
+            StreamKind::Differential => print_head::<DifferentialEvent>(&stream.path, events)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the first `events` events of each source worker in a stream.
+///
+/// Stops reading as soon as all workers seen so far have `events`
+/// events, so that looking at large recordings stays fast.
+fn print_head<E: Abomonation + Clone + Debug>(path: &Path, events: usize) -> Result<(), DiagError> {
+    let mut reader = EventFileReader::<Duration, (Duration, WorkerIdentifier, E)>::open(path)?;
+
+    let mut per_worker: BTreeMap<WorkerIdentifier, Vec<(Duration, E)>> = BTreeMap::new();
+    while let Some(event) = reader.next_event()? {
+        if let Event::Messages(_, data) = event {
+            for (t, worker, event) in data.into_iter() {
+                let head = per_worker.entry(worker).or_insert_with(Vec::new);
+                if head.len() < events {
+                    head.push((t, event));
+                }
+            }
+            if per_worker.values().all(|head| head.len() >= events) {
+                break;
+            }
+        }
+    }
+
+    println!("Worker\tTime (ms)\tEvent");
+    for (worker, head) in per_worker.into_iter() {
+        for (t, event) in head.into_iter() {
+            println!("{}\t{:.3}\t{:?}", worker, t.as_secs_f64() * 1000.0, event);
+        }
+    }
+
+    Ok(())
+}
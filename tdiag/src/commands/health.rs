@@ -0,0 +1,176 @@
+//! "health" subcommand: reports whether the log streams themselves look
+//! sound, before trusting any analysis of them.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::Inspect;
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Statistics gathered for the events of a single source worker.
+#[derive(Default, Clone, Debug)]
+struct WorkerHealth {
+    events: usize,
+    first: Option<Duration>,
+    last: Option<Duration>,
+    gaps: usize,
+    longest_gap: Duration,
+    regressions: usize,
+}
+
+impl WorkerHealth {
+    fn observe(&mut self, t: Duration, gap_threshold: Duration) {
+        self.events += 1;
+        if let Some(last) = self.last {
+            if t < last {
+                self.regressions += 1;
+            } else {
+                let gap = t - last;
+                if gap > gap_threshold {
+                    self.gaps += 1;
+                }
+                self.longest_gap = std::cmp::max(self.longest_gap, gap);
+            }
+        }
+        self.first = Some(self.first.map_or(t, |first| std::cmp::min(first, t)));
+        self.last = Some(self.last.map_or(t, |last| std::cmp::max(last, t)));
+    }
+
+    fn merge(&mut self, other: WorkerHealth) {
+        self.events += other.events;
+        self.first = match (self.first, other.first) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        self.last = match (self.last, other.last) {
+            (Some(a), Some(b)) => Some(std::cmp::max(a, b)),
+            (a, b) => a.or(b),
+        };
+        self.gaps += other.gaps;
+        self.longest_gap = std::cmp::max(self.longest_gap, other.longest_gap);
+        self.regressions += other.regressions;
+    }
+}
+
+/// Prints, for each source worker, the number of events received, gaps
+/// (periods longer than `gap_threshold_ms` without events), and
+/// timestamp regressions, followed by an overall verdict.
+///
+/// Events are decoded without validation, so corrupt streams can't be
+/// detected directly; they typically show up as regressions or as
+/// events attributed to workers that don't exist in the source
+/// computation.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to inspect the timestamps of all
+/// events, per source worker;
+/// 3. prints the per-worker statistics and the verdict.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    gap_threshold_ms: u64) -> Result<(), crate::DiagError> {
+
+    let source_peers = streams.source_peers();
+    let streams_w = streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let gap_threshold = Duration::from_millis(gap_threshold_ms);
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        let health = Rc::new(RefCell::new(HashMap::new()));
+        let health_w = health.clone();
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(t, w, _)| {
+                    health_w.borrow_mut().entry(*w).or_insert_with(WorkerHealth::default).observe(*t, gap_threshold);
+                });
+        });
+
+        while worker.step() { }
+
+        for (w, stats) in health.borrow_mut().drain() {
+            output_send.send((w, stats)).expect("failed to send output to mpsc channel");
+        }
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop checking the log streams");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut health: HashMap<usize, WorkerHealth> = HashMap::new();
+    for (w, stats) in output_recv.into_iter() {
+        health.entry(w).or_insert_with(WorkerHealth::default).merge(stats);
+    }
+
+    let mut problems = Vec::new();
+
+    println!("Worker\tEvents\tFirst (ms)\tLast (ms)\tGaps\tLongest gap (ms)\tRegressions");
+    let workers = std::cmp::max(source_peers.unwrap_or(0), health.keys().map(|w| w + 1).max().unwrap_or(0));
+    for w in 0..workers {
+        let stats = health.remove(&w).unwrap_or_default();
+        if let Some(report) = config.reports() {
+            report.record("worker_health", vec![
+                ("worker", w.into()),
+                ("events", stats.events.into()),
+                ("gaps", stats.gaps.into()),
+                ("longest_gap_ms", (stats.longest_gap.as_millis() as u64).into()),
+                ("regressions", stats.regressions.into()),
+            ]);
+        }
+        println!("{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            w,
+            stats.events,
+            stats.first.map_or("-".to_string(), |t| t.as_millis().to_string()),
+            stats.last.map_or("-".to_string(), |t| t.as_millis().to_string()),
+            stats.gaps,
+            stats.longest_gap.as_millis(),
+            stats.regressions);
+
+        if let Some(source_peers) = source_peers {
+            if w >= source_peers {
+                problems.push(format!("worker {} does not exist in the source computation (corrupt stream?)", w));
+            } else if stats.events == 0 {
+                problems.push(format!("no events from worker {}", w));
+            }
+        }
+        if stats.gaps > 0 {
+            problems.push(format!("{} gap(s) longer than {}ms in the events of worker {}", stats.gaps, gap_threshold_ms, w));
+        }
+        if stats.regressions > 0 {
+            problems.push(format!("{} timestamp regression(s) in the events of worker {}", stats.regressions, w));
+        }
+    }
+
+    println!();
+    if problems.is_empty() {
+        println!("Verdict: clean");
+    } else {
+        println!("Verdict: suspect");
+        for problem in problems.into_iter() {
+            println!("  - {}", problem);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Worker × operator heatmaps, shared by the subcommands that can
+//! produce one.
+//!
+//! This module includes `heatmap/heatmap.html` as a static resource.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::DiagError;
+
+static HEATMAP_HTML: &str = include_str!("heatmap/heatmap.html");
+
+/// Writes a self-contained html file rendering `values` (keyed by
+/// (source worker, column)) as a table with one row per worker and one
+/// column per entry of `columns`, with cells colored by value.
+pub fn write_heatmap(
+    output_path: &std::path::Path,
+    title: &str,
+    unit: &str,
+    columns: &[(usize, String)],
+    values: &HashMap<(usize, usize), f64>) -> Result<(), DiagError> {
+
+    let workers = values.keys().map(|&(w, _)| w + 1).max().unwrap_or(0);
+
+    let mut file = std::fs::File::create(output_path)
+        .map_err(|e| DiagError::io(format!("cannot create {}", output_path.display()), e))?;
+
+    writeln!(file, "<body>")?;
+    writeln!(file, "{}", HEATMAP_HTML)?;
+    writeln!(file, "<script type=\"text/javascript\">")?;
+
+    writeln!(file, "let columns = [")?;
+    for (_, name) in columns.iter() {
+        writeln!(file, "{:?},", name)?;
+    }
+    writeln!(file, "];")?;
+
+    writeln!(file, "let rows = [")?;
+    for worker in 0..workers {
+        writeln!(
+            file,
+            "{{ \"worker\": {}, \"values\": [{}] }},",
+            worker,
+            columns.iter()
+                .map(|(key, _)| format!("{}, ", values.get(&(worker, *key)).cloned().unwrap_or(0.0)))
+                .collect::<Vec<_>>().concat())?;
+    }
+    writeln!(file, "];")?;
+
+    writeln!(file, "run({:?}, {:?}, columns, rows);", title, unit)?;
+    writeln!(file, "</script>")?;
+
+    crate::status::info("heatmap_written", &format!("Heatmap generated in file://{}", std::fs::canonicalize(output_path).expect("invalid path").to_string_lossy()));
+
+    Ok(())
+}
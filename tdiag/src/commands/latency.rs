@@ -0,0 +1,309 @@
+//! "latency" subcommand: estimates the end-to-end latency between two
+//! operators, by following messages along the channels connecting them.
+//!
+//! Timely's log events don't identify the records, so latency is
+//! estimated from the messages themselves: a message sent by the source
+//! operator is matched with its receipt (by channel, workers and
+//! sequence number), the receiving operator is taken to have processed
+//! it by the end of its next activation, and to have forwarded it with
+//! the first message it sent on the next channel during that activation;
+//! and so on until the sink operator has processed it.
+
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::{StartStop, WorkerIdentifier};
+use timely::logging::TimelyEvent::{Operates, Channels, Schedule, Messages};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// The events the latency is estimated from.
+#[derive(Default)]
+struct Events {
+    /// (addr, name), by operator id (logged by `structure_worker`).
+    operators: HashMap<usize, (Vec<usize>, String)>,
+    /// (scope addr, source, target), by channel id (logged by
+    /// `structure_worker`).
+    channels: HashMap<usize, (Vec<usize>, (usize, usize), (usize, usize))>,
+    /// Sends: (time, target worker, sequence number), by (channel, source
+    /// worker).
+    sends: HashMap<(usize, WorkerIdentifier), Vec<(Duration, WorkerIdentifier, usize)>>,
+    /// Receipts, by (channel, source worker, target worker, sequence
+    /// number).
+    receipts: HashMap<(usize, WorkerIdentifier, WorkerIdentifier, usize), Duration>,
+    /// Ends of activations, by (source worker, operator id).
+    activations: HashMap<(WorkerIdentifier, usize), Vec<Duration>>,
+}
+
+impl Events {
+    fn merge(&mut self, other: Events) {
+        self.operators.extend(other.operators);
+        self.channels.extend(other.channels);
+        for (key, sends) in other.sends {
+            self.sends.entry(key).or_insert_with(Vec::new).extend(sends);
+        }
+        self.receipts.extend(other.receipts);
+        for (key, ends) in other.activations {
+            self.activations.entry(key).or_insert_with(Vec::new).extend(ends);
+        }
+    }
+}
+
+/// The first item of a sorted slice at or after `t`.
+fn first_from<T, K: Fn(&T) -> Duration>(sorted: &[T], t: Duration, key: K) -> Option<&T> {
+    let index = sorted.binary_search_by(|x| key(x).cmp(&t).then(std::cmp::Ordering::Greater)).unwrap_err();
+    sorted.get(index)
+}
+
+/// The channels along the shortest path from the operator at `from` to
+/// the operator at `to`.
+///
+/// A channel into or out of a scope connects to the scope itself, so
+/// paths go through scopes as single operators unless an endpoint is
+/// inside them.
+fn path(events: &Events, from: &[usize], to: &[usize]) -> Option<Vec<usize>> {
+    let endpoint = |scope_addr: &[usize], node: usize| {
+        let mut addr = scope_addr.to_vec();
+        if node != 0 {
+            addr.push(node);
+        }
+        addr
+    };
+    let mut edges: HashMap<Vec<usize>, Vec<(usize, Vec<usize>)>> = HashMap::new();
+    for (id, (scope_addr, source, target)) in events.channels.iter() {
+        edges.entry(endpoint(scope_addr, source.0)).or_insert_with(Vec::new)
+            .push((*id, endpoint(scope_addr, target.0)));
+    }
+    for targets in edges.values_mut() {
+        targets.sort();
+    }
+
+    // Breadth-first, remembering the channel each operator was reached by.
+    let mut reached: HashMap<Vec<usize>, (usize, Vec<usize>)> = HashMap::new();
+    let mut visited: HashSet<Vec<usize>> = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(from.to_vec());
+    queue.push_back(from.to_vec());
+    while let Some(addr) = queue.pop_front() {
+        if addr == to {
+            let mut channels = Vec::new();
+            let mut current = addr;
+            while let Some((channel, previous)) = reached.get(&current) {
+                channels.push(*channel);
+                current = previous.clone();
+            }
+            channels.reverse();
+            return Some(channels);
+        }
+        for (channel, next) in edges.get(&addr).into_iter().flatten() {
+            if visited.insert(next.clone()) {
+                reached.insert(next.clone(), (*channel, addr.clone()));
+                queue.push_back(next.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Prints, for each window of `window_ms`, the number of messages sent
+/// by the operator at `from` that could be followed to the operator at
+/// `to`, and the percentiles of their estimated latency (from the send
+/// to the end of the activation of `to` that processed them).
+///
+/// Channels without any message events on the path (e.g. into scopes)
+/// are passed through. Timestamps of different source processes are
+/// assumed to be comparable.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to collect the operators, channels,
+/// message and schedule events;
+/// 3. finds the shortest path of channels from `from` to `to`, follows
+/// each message along it, and prints the per-window percentiles.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    from: &[usize],
+    to: &[usize],
+    window_ms: u64) -> Result<(), DiagError> {
+
+    if from == to {
+        return Err(DiagError::InvalidArgs("--from-addr and --to-addr must be different operators".to_string()));
+    }
+
+    let streams_w = streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        let events = Rc::new(RefCell::new(Events::default()));
+        let events_w = events.clone();
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(t, w, x)| {
+                    let mut events = events_w.borrow_mut();
+                    match x {
+                        Operates(event) if *w == structure_worker => {
+                            events.operators.insert(event.id, (event.addr.clone(), event.name.clone()));
+                        }
+                        Channels(event) if *w == structure_worker => {
+                            events.channels.insert(event.id, (event.scope_addr.clone(), event.source, event.target));
+                        }
+                        Messages(event) if event.is_send => {
+                            events.sends.entry((event.channel, event.source)).or_insert_with(Vec::new)
+                                .push((*t, event.target, event.seq_no));
+                        }
+                        Messages(event) => {
+                            events.receipts.insert((event.channel, event.source, event.target, event.seq_no), *t);
+                        }
+                        Schedule(event) => if let StartStop::Stop = event.start_stop {
+                            events.activations.entry((*w, event.id)).or_insert_with(Vec::new).push(*t);
+                        },
+                        _ => (),
+                    }
+                });
+        });
+
+        while worker.step() { }
+
+        let events = std::mem::replace(&mut *events.borrow_mut(), Events::default());
+        output_send.send(events).expect("failed to send output to mpsc channel");
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting message data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut events = Events::default();
+    for worker_events in output_recv {
+        events.merge(worker_events);
+    }
+    for sends in events.sends.values_mut() {
+        sends.sort();
+    }
+    for ends in events.activations.values_mut() {
+        ends.sort();
+    }
+
+    let name_map = config.names();
+    let ids = events.operators.iter().map(|(id, (addr, _))| (addr.clone(), *id)).collect::<HashMap<_, _>>();
+    let label = |addr: &[usize]| match ids.get(addr).and_then(|id| events.operators.get(id)) {
+        Some((addr, name)) => format!("{} ({:?})", name_map.label(addr, name), addr),
+        None => format!("{:?}", addr),
+    };
+    for addr in [from, to].iter() {
+        if !ids.contains_key(*addr) {
+            return Err(DiagError::Logs(format!("No operator at address {:?}", addr)));
+        }
+    }
+    let channels = path(&events, from, to)
+        .ok_or_else(|| DiagError::Logs(format!("No channels lead from {} to {}", label(from), label(to))))?;
+    // Each channel of the path, and the address of the operator at its end.
+    let hops = channels.iter().map(|channel| {
+        let (scope_addr, _, target) = &events.channels[channel];
+        let mut addr = scope_addr.clone();
+        if target.0 != 0 {
+            addr.push(target.0);
+        }
+        (*channel, addr)
+    }).collect::<Vec<_>>();
+    crate::status::info("latency_path", &format!("Following messages along {}",
+        std::iter::once(label(from))
+            .chain(hops.iter().map(|(channel, addr)| format!("[channel {}] {}", channel, label(addr))))
+            .collect::<Vec<_>>().join(" -> ")));
+
+    let has_messages = events.sends.keys().map(|(channel, _)| *channel).collect::<HashSet<_>>();
+
+    // Latencies, by window index.
+    let mut windows: BTreeMap<u64, Vec<Duration>> = BTreeMap::new();
+    let mut unmatched = 0;
+    let first_channel = hops[0].0;
+    for ((channel, source), sends) in events.sends.iter() {
+        if *channel != first_channel {
+            continue;
+        }
+        'messages: for (sent, target, seq_no) in sends.iter() {
+            // Where the message is (worker, time) after each hop.
+            let (mut worker, mut t) = (*source, *sent);
+            let mut next_send = Some((*target, *seq_no));
+            for (channel, addr) in hops.iter() {
+                if has_messages.contains(channel) {
+                    let (target, seq_no) = match next_send.take() {
+                        Some(message) => message,
+                        // The first message the previous operator sent on
+                        // this channel once it received the message.
+                        None => match events.sends.get(&(*channel, worker)).and_then(|sends| first_from(sends, t, |x| x.0)) {
+                            Some((_, target, seq_no)) => (*target, *seq_no),
+                            None => {
+                                unmatched += 1;
+                                continue 'messages;
+                            }
+                        },
+                    };
+                    let received = events.receipts.get(&(*channel, worker, target, seq_no)).cloned().unwrap_or(t);
+                    worker = target;
+                    t = std::cmp::max(t, received);
+                }
+                if let Some(operator) = ids.get(addr) {
+                    if let Some(end) = events.activations.get(&(worker, *operator)).and_then(|ends| first_from(ends, t, |x| *x)) {
+                        t = *end;
+                    }
+                }
+            }
+            windows.entry(sent.as_millis() as u64 / window_ms).or_insert_with(Vec::new)
+                .push(if t > *sent { t - *sent } else { Duration::from_secs(0) });
+        }
+    }
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!("Window (ms)\tMessages\tp50 (ms)\tp95 (ms)\tp99 (ms)\tMax (ms)");
+    for (window, mut latencies) in windows {
+        latencies.sort();
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+        let (p50, p95, p99, max) = (percentile(0.5), percentile(0.95), percentile(0.99), latencies[latencies.len() - 1]);
+        if let Some(report) = config.reports() {
+            report.record("latency", vec![
+                ("window_ms", (window * window_ms).into()),
+                ("messages", latencies.len().into()),
+                ("p50_ms", ms(p50).into()),
+                ("p95_ms", ms(p95).into()),
+                ("p99_ms", ms(p99).into()),
+                ("max_ms", ms(max).into()),
+            ]);
+        }
+        println!("{}\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}",
+            window * window_ms,
+            latencies.len(),
+            ms(p50),
+            ms(p95),
+            ms(p99),
+            ms(max));
+    }
+
+    if unmatched > 0 {
+        crate::status::warn("unmatched_messages", &format!("{} message(s) couldn't be followed to {}", unmatched, label(to)));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! "lifetimes" subcommand: reports when dataflows and operators are
+//! created and dropped, to confirm that the transient dataflows of a
+//! long-running computation are actually cleaned up.
+
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::{Map, capture::{Capture, extract::Extract}};
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// The creation or shutdown of an operator on a source worker.
+#[derive(Clone, Debug)]
+enum Lifecycle {
+    /// (time, worker, operator id, address, name)
+    Created(Duration, WorkerIdentifier, usize, Vec<usize>, String),
+    /// (time, worker, operator id)
+    Dropped(Duration, WorkerIdentifier, usize),
+}
+
+/// When an operator (or dataflow) was created and dropped.
+struct Lifetime {
+    name: String,
+    /// The first time it was created on a source worker.
+    created: Duration,
+    /// The source workers it was created on.
+    workers: usize,
+    /// The last time it was dropped, and on how many source workers.
+    dropped: Option<(Duration, usize)>,
+}
+
+impl Lifetime {
+    /// Whether it was dropped on all the source workers it was created on.
+    fn is_dropped(&self) -> bool {
+        self.dropped.map_or(false, |(_, workers)| workers >= self.workers)
+    }
+
+    fn dropped_ms(&self) -> Option<u64> {
+        if self.is_dropped() {
+            self.dropped.map(|(t, _)| t.as_millis() as u64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Prints when each dataflow was created and dropped, and how many of
+/// its operators were dropped, followed by the operators themselves if
+/// `operators` is set.
+///
+/// Timely logs an `Operates` event when an operator is created, and a
+/// `Shutdown` event when it's dropped. Operators are matched across
+/// source workers by address, and count as dropped once they're
+/// dropped on every worker that created them. A dataflow counts as
+/// dropped once all its operators are.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to collect the `Operates` and
+/// `Shutdown` events;
+/// 3. prints the lifetime of each dataflow, in the order in which they
+/// were created.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    operators: bool) -> Result<(), crate::DiagError> {
+
+    let name_map = config.names();
+    let streams_w = streams.clone();
+
+    let (events_send, events_recv) = ::std::sync::mpsc::channel();
+    let events_send = Arc::new(Mutex::new(events_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let events_send: std::sync::mpsc::Sender<_> = events_send.lock().expect("cannot lock events_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .flat_map(|(t, w, x)| match x {
+                    TimelyEvent::Operates(event) => Some(Lifecycle::Created(t, w, event.id, event.addr, event.name)),
+                    TimelyEvent::Shutdown(event) => Some(Lifecycle::Dropped(t, w, event.id)),
+                    _ => None,
+                })
+                .capture_into(events_send);
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting lifetimes");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut events = events_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<Vec<_>>();
+    // Operators are created before they're dropped, but the events of
+    // different workers may be captured in any order.
+    events.sort_by_key(|event| match event {
+        Lifecycle::Created(t, ..) => (*t, false),
+        Lifecycle::Dropped(t, ..) => (*t, true),
+    });
+
+    // (worker, operator id) -> address
+    let mut addrs = HashMap::new();
+    let mut lifetimes: BTreeMap<Vec<usize>, Lifetime> = BTreeMap::new();
+    let mut unknown = 0;
+    for event in events.into_iter() {
+        match event {
+            Lifecycle::Created(t, worker, id, addr, name) => {
+                let lifetime = lifetimes.entry(addr.clone()).or_insert_with(|| Lifetime {
+                    name: name_map.label(&addr, &name).to_string(),
+                    created: t,
+                    workers: 0,
+                    dropped: None,
+                });
+                lifetime.workers += 1;
+                addrs.insert((worker, id), addr);
+            }
+            Lifecycle::Dropped(t, worker, id) => match addrs.get(&(worker, id)).and_then(|addr| lifetimes.get_mut(addr)) {
+                Some(lifetime) => {
+                    let (last, workers) = lifetime.dropped.get_or_insert((t, 0));
+                    *last = std::cmp::max(*last, t);
+                    *workers += 1;
+                }
+                // Created before the log stream was opened.
+                None => unknown += 1,
+            }
+        }
+    }
+
+    println!("Dataflow\tName\tCreated (ms)\tDropped (ms)\tLifetime (ms)\tOperators\tDropped operators");
+    let mut dataflows = lifetimes.iter().filter(|(addr, _)| addr.len() == 1).collect::<Vec<_>>();
+    dataflows.sort_by_key(|(addr, lifetime)| (lifetime.created, (*addr).clone()));
+    let mut alive = 0;
+    for (addr, dataflow) in dataflows.iter() {
+        let contained = lifetimes.range((*addr).clone()..)
+            .take_while(|(op_addr, _)| op_addr.starts_with(addr))
+            .filter(|(op_addr, _)| op_addr.len() > 1)
+            .map(|(_, lifetime)| lifetime)
+            .collect::<Vec<_>>();
+        let dropped_operators = contained.iter().filter(|lifetime| lifetime.is_dropped()).count();
+        // Timely may not log the shutdown of the dataflow itself.
+        let dropped_ms = dataflow.dropped_ms().or_else(|| if !contained.is_empty() && dropped_operators == contained.len() {
+            contained.iter().filter_map(|lifetime| lifetime.dropped_ms()).max()
+        } else {
+            None
+        });
+        if dropped_ms.is_none() {
+            alive += 1;
+        }
+        let created_ms = dataflow.created.as_millis() as u64;
+
+        if let Some(report) = config.reports() {
+            report.record("dataflow_lifetime", vec![
+                ("dataflow", addr[0].into()),
+                ("name", dataflow.name.as_str().into()),
+                ("created_ms", created_ms.into()),
+                ("dropped_ms", dropped_ms.map_or(crate::report::Value::Null, crate::report::Value::from)),
+                ("operators", contained.len().into()),
+                ("dropped_operators", dropped_operators.into()),
+            ]);
+        }
+        println!("{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            addr[0],
+            dataflow.name,
+            created_ms,
+            dropped_ms.map_or("-".to_string(), |ms| ms.to_string()),
+            dropped_ms.map_or("-".to_string(), |ms| ms.saturating_sub(created_ms).to_string()),
+            contained.len(),
+            dropped_operators);
+    }
+    println!("{} dataflows created, {} dropped, {} still alive", dataflows.len(), dataflows.len() - alive, alive);
+    if unknown > 0 {
+        println!("{} operators created before the log streams were opened were dropped", unknown);
+    }
+
+    if operators {
+        println!();
+        println!("Addr\tName\tCreated (ms)\tDropped (ms)\tLifetime (ms)");
+        for (addr, lifetime) in lifetimes.iter().filter(|(addr, _)| addr.len() > 1) {
+            let created_ms = lifetime.created.as_millis() as u64;
+            let dropped_ms = lifetime.dropped_ms();
+            if let Some(report) = config.reports() {
+                report.record("operator_lifetime", vec![
+                    ("addr", format!("{:?}", addr).into()),
+                    ("name", lifetime.name.as_str().into()),
+                    ("created_ms", created_ms.into()),
+                    ("dropped_ms", dropped_ms.map_or(crate::report::Value::Null, crate::report::Value::from)),
+                ]);
+            }
+            println!("{:?}\t{}\t{}\t{}\t{}",
+                addr,
+                lifetime.name,
+                created_ms,
+                dropped_ms.map_or("-".to_string(), |ms| ms.to_string()),
+                dropped_ms.map_or("-".to_string(), |ms| ms.saturating_sub(created_ms).to_string()));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! The statistics of the source computation's operators, channels and
+//! arrangements, as the subcommands that show them while it runs
+//! ("serve", "top" and "export prometheus") receive them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+use crate::source::LogStreams;
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::WorkerIdentifier;
+use timely::logging::TimelyEvent::{Operates, Channels, Messages};
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// What the diagnostic workers report, batch by batch.
+pub(crate) enum Update {
+    /// An operator was created: (id, addr, name).
+    Operator(usize, Vec<usize>, String),
+    /// A channel was created: (id, scope_addr, source, target).
+    Channel(usize, Vec<usize>, (usize, usize), (usize, usize)),
+    /// Time spent in operators (in ns), by operator id.
+    Elapsed(Vec<(usize, u64)>),
+    /// (messages, records) sent over channels, by channel id.
+    Sent(Vec<(usize, (u64, u64))>),
+    /// Changes to the number of tuples in arrangements, by (source
+    /// worker, operator id).
+    Tuples(Vec<((WorkerIdentifier, usize), i64)>),
+}
+
+/// Replays `timely_streams` (and `differential_streams`, if any) until
+/// `is_running` is cleared, sending the `Update`s they tell of to the
+/// returned receiver. Operators and channels are the ones logged by
+/// `structure_worker`.
+pub(crate) fn spawn(
+    config: &crate::config::DiagConfig,
+    timely_streams: &LogStreams,
+    differential_streams: Option<&LogStreams>,
+    is_running: Arc<AtomicBool>) -> Result<(timely::communication::WorkerGuards<()>, Receiver<Update>), DiagError> {
+
+    let timely_streams = timely_streams.clone();
+    let differential_streams = differential_streams.cloned();
+
+    let (updates_send, updates_recv) = ::std::sync::mpsc::channel();
+    let updates_send = Arc::new(Mutex::new(updates_send));
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let updates: std::sync::mpsc::Sender<_> = updates_send.lock().expect("cannot lock updates_send").clone();
+        let (updates_a, updates_d) = (updates.clone(), updates.clone());
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let timely_replayer = timely_streams.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open timely readers");
+        let differential_replayer = differential_streams.as_ref().map(|streams| streams
+            .readers::<Duration, (Duration, WorkerIdentifier, DifferentialEvent)>(worker.index(), worker.peers())
+            .expect("failed to open differential readers"));
+
+        worker.dataflow::<Duration, _, _>(|scope| {
+            let events = timely_replayer.replay_with_shutdown_into(scope, is_running.clone());
+
+            crate::commands::profile::activations(&events)
+                .inspect_batch(move |_, data| {
+                    let mut elapsed = HashMap::new();
+                    for ((_worker, id), _start, duration) in data.iter() {
+                        *elapsed.entry(*id).or_insert(0) += duration.as_nanos() as u64;
+                    }
+                    if !elapsed.is_empty() {
+                        let _ = updates_a.send(Update::Elapsed(elapsed.into_iter().collect()));
+                    }
+                });
+
+            events
+                .inspect_batch(move |_, data| {
+                    let mut sent = HashMap::new();
+                    for (_, worker, event) in data.iter() {
+                        match event {
+                            Operates(event) if *worker == structure_worker => {
+                                let _ = updates.send(Update::Operator(event.id, event.addr.clone(), event.name.clone()));
+                            }
+                            Channels(event) if *worker == structure_worker => {
+                                let _ = updates.send(Update::Channel(event.id, event.scope_addr.clone(), event.source, event.target));
+                            }
+                            Messages(event) if event.is_send => {
+                                let entry = sent.entry(event.channel).or_insert((0, 0));
+                                entry.0 += 1;
+                                entry.1 += event.length as u64;
+                            }
+                            _ => (),
+                        }
+                    }
+                    if !sent.is_empty() {
+                        let _ = updates.send(Update::Sent(sent.into_iter().collect()));
+                    }
+                });
+
+            if let Some(differential_replayer) = differential_replayer {
+                differential_replayer.replay_with_shutdown_into(scope, is_running.clone())
+                    .inspect_batch(move |_, data| {
+                        let mut tuples = HashMap::new();
+                        for (_, worker, event) in data.iter() {
+                            let (operator, diff) = match event {
+                                DifferentialEvent::Batch(x) => (x.operator, x.length as i64),
+                                DifferentialEvent::Merge(x) => match x.complete {
+                                    Some(complete) => (x.operator, complete as i64 - (x.length1 + x.length2) as i64),
+                                    None => continue,
+                                },
+                                DifferentialEvent::Drop(x) => (x.operator, -(x.length as i64)),
+                                _ => continue,
+                            };
+                            *tuples.entry((*worker, operator)).or_insert(0) += diff;
+                        }
+                        if !tuples.is_empty() {
+                            let _ = updates_d.send(Update::Tuples(tuples.into_iter().collect()));
+                        }
+                    });
+            }
+        })
+    }).map_err(DiagError::Timely)?;
+
+    Ok((worker_handles, updates_recv))
+}
@@ -0,0 +1,81 @@
+//! "merge" subcommand: combines recordings (e.g. captured by several
+//! tdiag instances, or separate timely and differential captures) into
+//! a single recording.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::DiagError;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::Event;
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::recording::{self, EventFileReader, Recording, RecordedStream, StreamKind};
+
+/// Copies the streams of all `inputs` into a new recording at `output`,
+/// numbering the streams of each kind in the order of the inputs.
+///
+/// Fails if the events of a source worker appear in more than one
+/// stream of the same kind, as the merged streams would then describe
+/// different computations (or the same one twice).
+pub fn merge(inputs: &[PathBuf], output: &Path) -> Result<(), DiagError> {
+    let recordings = inputs.iter()
+        .map(|input| Recording::open(input))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // (kind, worker) -> the input and stream containing its events
+    let mut owners: HashMap<(StreamKind, WorkerIdentifier), (&Path, usize)> = HashMap::new();
+    for (input, recording) in inputs.iter().zip(recordings.iter()) {
+        for stream in recording.streams.iter() {
+            let workers = match stream.kind {
+                StreamKind::Timely => workers::<TimelyEvent>(&stream.path)?,
+                StreamKind::Differential => workers::<DifferentialEvent>(&stream.path)?,
+            };
+            for worker in workers.into_iter() {
+                if let Some((other_input, other_index)) = owners.insert((stream.kind, worker), (input.as_path(), stream.index)) {
+                    return Err(DiagError::Logs(format!(
+                        "events of worker {} appear in {} stream {} of {} and in {} stream {} of {}",
+                        worker,
+                        stream.kind.name(), other_index, other_input.display(),
+                        stream.kind.name(), stream.index, input.display())));
+                }
+            }
+        }
+    }
+
+    recording::create_dir(output)?;
+
+    let mut next_index = HashMap::new();
+    let mut streams = Vec::new();
+    for (input, recording) in inputs.iter().zip(recordings.iter()) {
+        for stream in recording.streams.iter() {
+            let index = next_index.entry(stream.kind).or_insert(0);
+            let path = output.join(recording::stream_file_name(stream.kind, *index));
+            std::fs::copy(&stream.path, &path)?;
+            println!("{} stream {} of {} -> {} stream {}", stream.kind.name(), stream.index, input.display(), stream.kind.name(), index);
+            streams.push(RecordedStream { kind: stream.kind, index: *index, path });
+            *index += 1;
+        }
+    }
+
+    recording::write_manifest(output, &streams)?;
+
+    Ok(())
+}
+
+/// The source workers whose events appear in a recorded stream.
+fn workers<E: Abomonation + Clone>(path: &Path) -> Result<BTreeSet<WorkerIdentifier>, DiagError> {
+    let mut reader = EventFileReader::<Duration, (Duration, WorkerIdentifier, E)>::open(path)?;
+    let mut workers = BTreeSet::new();
+    while let Some(event) = reader.next_event()? {
+        if let Event::Messages(_, data) = event {
+            workers.extend(data.into_iter().map(|(_, worker, _)| worker));
+        }
+    }
+    Ok(workers)
+}
@@ -0,0 +1,198 @@
+//! "differential merges" subcommand: summarizes the merges of each
+//! arrangement's spine, to diagnose compaction falling behind.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::{TimelyEvent::Operates, WorkerIdentifier};
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Merge statistics gathered for a single operator.
+#[derive(Default, Clone, Debug)]
+struct OperatorMerges {
+    /// Start of the merges in progress, by (source worker, scale).
+    started: HashMap<(WorkerIdentifier, usize), Duration>,
+    merges: usize,
+    merging: Duration,
+    longest_merge: Duration,
+    /// Largest merge, as (input tuples, output tuples).
+    largest_merge: (usize, usize),
+    shortfalls: usize,
+    shortfall: usize,
+}
+
+impl OperatorMerges {
+    fn observe(&mut self, t: Duration, worker: WorkerIdentifier, event: &DifferentialEvent) {
+        match event {
+            DifferentialEvent::Merge(x) => match x.complete {
+                None => {
+                    self.started.insert((worker, x.scale), t);
+                }
+                Some(complete) => {
+                    let duration = match self.started.remove(&(worker, x.scale)) {
+                        Some(start) if t > start => t - start,
+                        _ => Duration::from_secs(0),
+                    };
+                    self.merges += 1;
+                    self.merging += duration;
+                    self.longest_merge = std::cmp::max(self.longest_merge, duration);
+                    self.largest_merge = std::cmp::max(self.largest_merge, (x.length1 + x.length2, complete));
+                }
+            },
+            DifferentialEvent::MergeShortfall(x) => {
+                self.shortfalls += 1;
+                self.shortfall += x.shortfall;
+            }
+            _ => (),
+        }
+    }
+
+    fn merge(&mut self, other: OperatorMerges) {
+        self.started.extend(other.started);
+        self.merges += other.merges;
+        self.merging += other.merging;
+        self.longest_merge = std::cmp::max(self.longest_merge, other.longest_merge);
+        self.largest_merge = std::cmp::max(self.largest_merge, other.largest_merge);
+        self.shortfalls += other.shortfalls;
+        self.shortfall += other.shortfall;
+    }
+}
+
+/// Prints, for each operator maintaining an arrangement, the number and
+/// duration of the merges of its spine, the merges still in progress,
+/// the merge shortfalls (merges that didn't get enough effort to finish
+/// in time), and the largest merge.
+///
+/// A merge's duration is the time between its `Merge` events without
+/// and with `complete` (i.e. the span over which differential spread its
+/// merge effort). The statistics are summed over the source workers.
+///
+/// 1. Listens to incoming connections from a differential-dataflow
+/// program with timely and differential logging enabled;
+/// 2. runs a timely-dataflow program to match the `Merge` events of each
+/// source worker, and count `MergeShortfall` events;
+/// 3. prints the per-operator totals, operators with shortfalls and the
+/// longest merges first.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: crate::source::LogStreams) -> Result<(), DiagError> {
+
+    let timely_streams_w = timely_streams.clone();
+    let differential_streams_w = differential_streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let timely_replayer = timely_streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open timely readers");
+        let differential_replayer = differential_streams_w
+            .readers::<Duration, (Duration, WorkerIdentifier, DifferentialEvent)>(worker.index(), worker.peers())
+            .expect("failed to open differential readers");
+
+        let operators = Rc::new(RefCell::new(HashMap::new()));
+        let operators_w = operators.clone();
+        let merges = Rc::new(RefCell::new(HashMap::new()));
+        let merges_w = merges.clone();
+
+        worker.dataflow::<Duration, _, _>(|scope| {
+            timely_replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(_, w, x)| {
+                    if let Operates(event) = x {
+                        if *w == structure_worker {
+                            operators_w.borrow_mut().insert(event.id, (event.addr.clone(), event.name.clone()));
+                        }
+                    }
+                });
+
+            differential_replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(t, w, x)| {
+                    let operator = match x {
+                        DifferentialEvent::Merge(x) => x.operator,
+                        DifferentialEvent::MergeShortfall(x) => x.operator,
+                        _ => return,
+                    };
+                    merges_w.borrow_mut().entry(operator).or_insert_with(OperatorMerges::default).observe(*t, *w, x);
+                });
+        });
+
+        while worker.step() { }
+
+        let operators = operators.borrow_mut().drain().collect::<Vec<_>>();
+        let merges = merges.borrow_mut().drain().collect::<Vec<_>>();
+        output_send.send((operators, merges)).expect("failed to send output to mpsc channel");
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&timely_streams, &differential_streams], "Press enter to stop collecting merge data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut operators = HashMap::new();
+    let mut merges: HashMap<usize, OperatorMerges> = HashMap::new();
+    for (worker_operators, worker_merges) in output_recv {
+        operators.extend(worker_operators);
+        for (operator, stats) in worker_merges {
+            merges.entry(operator).or_insert_with(OperatorMerges::default).merge(stats);
+        }
+    }
+
+    let mut merges = merges.into_iter().collect::<Vec<_>>();
+    merges.sort_by_key(|(operator, stats)| (std::cmp::Reverse(stats.shortfalls), std::cmp::Reverse(stats.longest_merge), *operator));
+
+    let name_map = config.names();
+    println!("Op. Id\tName\tMerges\tMean (ms)\tLongest (ms)\tIn progress\tShortfalls\tShortfall (tuples)\tLargest merge (tuples in -> out)");
+    for (operator, stats) in merges {
+        let name = match operators.get(&operator) {
+            Some((addr, name)) => format!("{} ({:?})", name_map.label(addr, name), addr),
+            None => "?".to_string(),
+        };
+        let mean = if stats.merges > 0 { stats.merging.as_secs_f64() * 1000.0 / stats.merges as f64 } else { 0.0 };
+        if let Some(report) = config.reports() {
+            report.record("arrangement_merges", vec![
+                ("operator", operator.into()),
+                ("name", name.as_str().into()),
+                ("merges", stats.merges.into()),
+                ("mean_ms", mean.into()),
+                ("longest_ms", (stats.longest_merge.as_secs_f64() * 1000.0).into()),
+                ("in_progress", stats.started.len().into()),
+                ("shortfalls", stats.shortfalls.into()),
+                ("shortfall", stats.shortfall.into()),
+                ("largest_in", stats.largest_merge.0.into()),
+                ("largest_out", stats.largest_merge.1.into()),
+            ]);
+        }
+        println!("{}\t{}\t{}\t{:.3}\t{:.3}\t{}\t{}\t{}\t{} -> {}",
+            operator,
+            name,
+            stats.merges,
+            mean,
+            stats.longest_merge.as_secs_f64() * 1000.0,
+            stats.started.len(),
+            stats.shortfalls,
+            stats.shortfall,
+            stats.largest_merge.0,
+            stats.largest_merge.1);
+    }
+
+    Ok(())
+}
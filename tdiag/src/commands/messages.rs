@@ -0,0 +1,217 @@
+//! "messages" subcommand: reports the records sent over each channel of
+//! the dataflow over time, labelled with the operators at either end.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+use crate::filter::OperatorFilter;
+use crate::names::NameMap;
+
+use timely::dataflow::operators::{Filter, Inspect, Map};
+
+use differential_dataflow::collection::AsCollection;
+use differential_dataflow::operators::Consolidate;
+
+use timely::logging::TimelyEvent::{Operates, Channels, Messages};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// What the diagnostic workers hand to the printer.
+enum Record {
+    /// An operator was created: (addr, name).
+    Operator(Vec<usize>, String),
+    /// A channel was created: (id, scope_addr, source, target).
+    Channel(usize, Vec<usize>, (usize, usize), (usize, usize)),
+    /// Records were sent over a channel in an interval: (channel, end
+    /// of the interval, records).
+    Records(usize, Duration, isize),
+}
+
+/// Prints the number of records sent over each channel, and the rate,
+/// every `output_interval_ms`, followed by the totals once the session
+/// ends.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a differential-dataflow program to sum the length of all
+/// sent messages, keyed by channel, in intervals of
+/// `output_interval_ms`;
+/// 3. prints each interval's sums as soon as it's complete, with the
+/// channels' endpoints.
+///
+/// Only the channels from or to an operator matching the configured
+/// filter are printed (see `DiagConfig::operator_filter`).
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    output_interval_ms: u64) -> Result<(), DiagError> {
+
+    let streams_w = streams.clone();
+
+    let (records_send, records_recv) = ::std::sync::mpsc::channel();
+    let records_send = Arc::new(Mutex::new(records_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let records: std::sync::mpsc::Sender<_> = records_send.lock().expect("cannot lock records_send").clone();
+        let records_w = records.clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
+
+            stream
+                .filter(move |(_, worker, _)| *worker == structure_worker)
+                .inspect(move |(_, _, event)| match event {
+                    Operates(event) => {
+                        let _ = records.send(Record::Operator(event.addr.clone(), event.name.clone()));
+                    }
+                    Channels(event) => {
+                        let _ = records.send(Record::Channel(event.id, event.scope_addr.clone(), event.source, event.target));
+                    }
+                    _ => (),
+                });
+
+            stream
+                .flat_map(move |(t, _, x)| if let Messages(event) = x {
+                    if event.is_send {
+                        let window_idx = (t.as_millis() as u64 / output_interval_ms) + 1;
+                        Some((event.channel, Duration::from_millis(window_idx * output_interval_ms), event.length as isize))
+                    } else {
+                        None
+                    }
+                } else { None })
+                .as_collection()
+                // Emits each interval once it's complete.
+                .consolidate()
+                .inner
+                .inspect(move |(channel, t, records)| {
+                    let _ = records_w.send(Record::Records(*channel, *t, *records));
+                });
+        })
+    }).map_err(DiagError::Timely)?;
+
+    let name_map = config.names().clone();
+    let filter = config.filter().clone();
+    let report = config.reports().cloned();
+    let printer = std::thread::Builder::new()
+        .name("tdiag-messages".to_string())
+        .spawn(move || print_records(records_recv, output_interval_ms, &name_map, &filter, report.as_ref()))?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting message data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    printer.join().map_err(|_| DiagError::Internal("the printer thread panicked".to_string()))?;
+
+    Ok(())
+}
+
+/// Prints the records of each interval as they arrive, then the totals
+/// per channel once the senders are gone.
+fn print_records(
+    records: Receiver<Record>,
+    output_interval_ms: u64,
+    name_map: &NameMap,
+    filter: &OperatorFilter,
+    report: Option<&crate::report::Report>) {
+
+    let mut operators = HashMap::new();
+    // The operators matching the filter.
+    let mut matching = HashSet::new();
+    let mut channels = HashMap::new();
+    let mut totals = HashMap::new();
+    let mut last_ms = 0;
+
+    println!("ms\tChannel\tFrom\tTo\t# of records\tRecords/s");
+    for record in records {
+        match record {
+            Record::Operator(addr, name) => {
+                let label = name_map.label(&addr, &name).to_string();
+                if filter.matches(&addr, &name, name_map) {
+                    matching.insert(addr.clone());
+                }
+                operators.insert(addr, label);
+            }
+            Record::Channel(id, scope_addr, source, target) => {
+                // Index 0 stands for the scope itself.
+                let endpoint = |index: usize| {
+                    let mut addr = scope_addr.clone();
+                    if index != 0 {
+                        addr.push(index);
+                    }
+                    addr
+                };
+                if filter.is_empty() || matching.contains(&endpoint(source.0)) || matching.contains(&endpoint(target.0)) {
+                    channels.insert(id, (scope_addr, source, target));
+                }
+            }
+            Record::Records(channel, _, _) if !channels.contains_key(&channel) && !filter.is_empty() => (),
+            Record::Records(channel, t, count) => {
+                let (from, to) = endpoints(&operators, channels.get(&channel));
+                let rate = count as f64 * 1000.0 / output_interval_ms as f64;
+                println!("{}\t{}\t{}\t{}\t{}\t{:.1}", t.as_millis(), channel, from, to, count, rate);
+                if let Some(report) = report {
+                    report.record("channel_rate", vec![
+                        ("t_ms", (t.as_millis() as u64).into()),
+                        ("channel", channel.into()),
+                        ("from", from.into()),
+                        ("to", to.into()),
+                        ("records", count.into()),
+                        ("records_per_s", rate.into()),
+                    ]);
+                }
+                *totals.entry(channel).or_insert(0) += count;
+                last_ms = std::cmp::max(last_ms, t.as_millis() as u64);
+            }
+        }
+    }
+
+    let mut totals = totals.into_iter().collect::<Vec<_>>();
+    totals.sort_unstable_by_key(|&(channel, records)| (std::cmp::Reverse(records), channel));
+
+    println!();
+    println!("Channel\tFrom\tTo\t# of records\tMean records/s");
+    for (channel, records) in totals.into_iter() {
+        let (from, to) = endpoints(&operators, channels.get(&channel));
+        let rate = if last_ms > 0 { records as f64 * 1000.0 / last_ms as f64 } else { 0.0 };
+        println!("{}\t{}\t{}\t{}\t{:.1}", channel, from, to, records, rate);
+    }
+}
+
+/// Labels the ends of a channel with the operators' names and ports.
+/// Index 0 in a scope stands for the scope's own inputs (as a source)
+/// or outputs (as a target).
+fn endpoints(
+    operators: &HashMap<Vec<usize>, String>,
+    channel: Option<&(Vec<usize>, (usize, usize), (usize, usize))>) -> (String, String) {
+
+    let endpoint = |scope_addr: &Vec<usize>, (index, port): (usize, usize), boundary: &str| {
+        if index == 0 {
+            let scope = operators.get(scope_addr).map(|name| name.as_str()).unwrap_or("scope");
+            format!("{} {} {} {:?}", scope, boundary, port, scope_addr)
+        } else {
+            let mut addr = scope_addr.clone();
+            addr.push(index);
+            let name = operators.get(&addr).map(|name| name.as_str()).unwrap_or("-");
+            format!("{}:{} {:?}", name, port, addr)
+        }
+    };
+
+    match channel {
+        Some((scope_addr, source, target)) => (endpoint(scope_addr, *source, "input"), endpoint(scope_addr, *target, "output")),
+        None => ("-".to_string(), "-".to_string()),
+    }
+}
@@ -4,6 +4,41 @@
 //!
 //! Subfolders in the source tree contain resource files.
 
+pub mod all;
 pub mod graph;
+pub mod graph_svg;
+pub mod graph_diff;
 pub mod profile;
+pub mod profile_diff;
 pub mod arrangements;
+pub mod merges;
+pub mod skew;
+pub mod channels;
+pub mod batches;
+pub mod messages;
+pub mod heatmap;
+pub mod flamegraph;
+pub mod vega;
+pub(crate) mod live;
+pub mod epochs;
+pub mod frontier_lag;
+pub mod frontiers;
+pub mod stuck;
+pub mod lifetimes;
+pub mod progress;
+pub mod park;
+pub mod latency;
+pub mod health;
+pub mod serve;
+pub mod prometheus;
+pub mod otlp;
+pub mod top;
+pub mod trace;
+pub mod tee;
+pub mod record;
+pub mod replay;
+pub mod validate_recording;
+pub mod trim;
+pub mod merge;
+pub mod anonymize;
+pub mod head;
@@ -0,0 +1,353 @@
+//! "export otlp" subcommand: converts the source computation's operator
+//! activations into OpenTelemetry spans, and its per-operator scheduled
+//! time into OpenTelemetry metrics, and pushes them to an OTLP endpoint
+//! (e.g. an OpenTelemetry collector, Jaeger or Tempo) while it runs.
+//!
+//! They're sent with OTLP/HTTP's JSON encoding over a plain HTTP
+//! connection, which needs no additional dependencies; endpoints that
+//! require TLS are reached through a local collector.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{DiagError, LoggingTuple};
+use crate::names::NameMap;
+use crate::status::json_string;
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::{StartStop, WorkerIdentifier};
+use timely::logging::TimelyEvent::{Operates, Schedule};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// How often spans and metrics are pushed.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An OTLP/HTTP endpoint, e.g. `http://127.0.0.1:4318`.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    /// The `host:port` to connect to.
+    host: String,
+    /// The path the signal paths (`/v1/traces`, `/v1/metrics`) are
+    /// appended to.
+    prefix: String,
+}
+
+impl Endpoint {
+    /// Parses an `http://host:port[/prefix]` URL.
+    pub fn parse(url: &str) -> Result<Self, DiagError> {
+        let invalid = |reason: &str| DiagError::Parse {
+            context: format!("Invalid OTLP endpoint {} ({})", url, reason),
+            source: None,
+        };
+        if url.starts_with("https://") {
+            return Err(invalid("https is not supported, export to a local collector instead"));
+        }
+        if !url.starts_with("http://") {
+            return Err(invalid("expected http://host:port"));
+        }
+        let rest = &url["http://".len()..];
+        let (host, prefix) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:4318", host) };
+        Ok(Endpoint { host, prefix: prefix.to_string() })
+    }
+}
+
+/// An operator activation, timed in ns since the Unix epoch.
+struct Span {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    worker: WorkerIdentifier,
+    id: usize,
+    start_ns: u64,
+    end_ns: u64,
+}
+
+/// What the diagnostic workers report to the exporter.
+enum Update {
+    /// An operator was created: (addr, name), by (source worker, id).
+    Operator((WorkerIdentifier, usize), Vec<usize>, String),
+    /// Activations long enough to be exported.
+    Spans(Vec<Span>),
+    /// Time spent in operators (in ns), by (source worker, id).
+    Elapsed(Vec<((WorkerIdentifier, usize), u64)>),
+}
+
+/// Pushes the operator activations as spans, and the time each operator
+/// was scheduled as the `timely.operator.scheduled` metric, to `endpoint`
+/// every second until the user stops the session.
+///
+/// Operators nest: an activation of an operator within a scope is a
+/// child span of the scope's activation, and each activation of a
+/// top-level scope starts a trace. Activations shorter than `min_span`
+/// are only counted in the metrics, to keep the number of spans
+/// manageable. Timestamps are anchored at the time tdiag receives the
+/// first event of each source worker.
+pub fn export(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    endpoint: Endpoint,
+    service_name: &str,
+    min_span: Duration) -> Result<(), DiagError> {
+
+    let streams_w = streams.clone();
+
+    let (updates_send, updates_recv) = ::std::sync::mpsc::channel();
+    let updates_send = Arc::new(Mutex::new(updates_send));
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    // Makes the ids of this session differ from those of earlier ones.
+    let seed = unix_ns(SystemTime::now());
+    let min_span_ns = min_span.as_nanos() as u64;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let updates: std::sync::mpsc::Sender<_> = updates_send.lock().expect("cannot lock updates_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        let mut next_id = ((worker.index() as u64) << 48) + 1;
+        let mut ids = move || {
+            next_id += 1;
+            seed.wrapping_add(next_id)
+        };
+
+        worker.dataflow(|scope| {
+            // The Unix time (in ns) of each source worker's log start.
+            let mut bases: HashMap<WorkerIdentifier, u64> = HashMap::new();
+            // The activations in progress on each source worker, innermost
+            // last: (id, trace id, span id, start).
+            let mut stacks: HashMap<WorkerIdentifier, Vec<(usize, u128, u64, u64)>> = HashMap::new();
+
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect_batch(move |_, data| {
+                    let mut spans = Vec::new();
+                    let mut elapsed = HashMap::new();
+                    for (ts, worker, event) in data.iter() {
+                        let ts_ns = ts.as_nanos() as u64;
+                        let base = *bases.entry(*worker).or_insert_with(|| unix_ns(SystemTime::now()).saturating_sub(ts_ns));
+                        match event {
+                            Operates(event) => {
+                                let _ = updates.send(Update::Operator((*worker, event.id), event.addr.clone(), event.name.clone()));
+                            }
+                            Schedule(event) => {
+                                let stack = stacks.entry(*worker).or_insert_with(Vec::new);
+                                match event.start_stop {
+                                    StartStop::Start => {
+                                        let trace_id = match stack.last() {
+                                            Some((_, trace_id, _, _)) => *trace_id,
+                                            None => (u128::from(ids()) << 64) | u128::from(ids()),
+                                        };
+                                        stack.push((event.id, trace_id, ids(), base + ts_ns));
+                                    }
+                                    StartStop::Stop => {
+                                        // Unmatched events (e.g. when connecting to a
+                                        // running computation) are skipped.
+                                        if stack.last().map(|(id, _, _, _)| *id) != Some(event.id) {
+                                            continue;
+                                        }
+                                        let (id, trace_id, span_id, start_ns) = stack.pop().expect("checked above");
+                                        let end_ns = base + ts_ns;
+                                        *elapsed.entry((*worker, id)).or_insert(0) += end_ns.saturating_sub(start_ns);
+                                        if end_ns.saturating_sub(start_ns) >= min_span_ns {
+                                            spans.push(Span {
+                                                trace_id,
+                                                span_id,
+                                                parent_id: stack.last().map(|(_, _, parent_id, _)| *parent_id),
+                                                worker: *worker,
+                                                id,
+                                                start_ns,
+                                                end_ns,
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                    if !spans.is_empty() {
+                        let _ = updates.send(Update::Spans(spans));
+                    }
+                    if !elapsed.is_empty() {
+                        let _ = updates.send(Update::Elapsed(elapsed.into_iter().collect()));
+                    }
+                });
+        })
+    }).map_err(DiagError::Timely)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_e = stop.clone();
+    let name_map = config.names().clone();
+    let service_name = service_name.to_string();
+    let endpoint_e = endpoint.clone();
+    let exporter = std::thread::Builder::new()
+        .name("tdiag-otlp".to_string())
+        .spawn(move || run_exporter(&endpoint_e, &service_name, updates_recv, &stop_e, &name_map))?;
+
+    crate::status::info("exporting", &format!("Exporting spans and metrics to http://{}{}", endpoint.host, endpoint.prefix));
+
+    crate::source::await_stop(&[&streams], "Press enter to stop exporting");
+
+    is_running.store(false, Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    // Pushes the last spans and metrics.
+    stop.store(true, Ordering::Release);
+    exporter.join().map_err(|_| DiagError::Internal("the OTLP exporter panicked".to_string()))?;
+
+    Ok(())
+}
+
+fn unix_ns(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+/// Pushes the spans received since the last export, and the current
+/// metrics, every `EXPORT_INTERVAL` until `stop` is set.
+fn run_exporter(endpoint: &Endpoint, service_name: &str, updates: Receiver<Update>, stop: &AtomicBool, name_map: &NameMap) {
+    let started_ns = unix_ns(SystemTime::now());
+    let resource = format!("{{\"attributes\":[{}]}}", attribute("service.name", &json_string(service_name)));
+    let scope = "{\"name\":\"tdiag\"}";
+
+    let mut operators: HashMap<(WorkerIdentifier, usize), (Vec<usize>, String)> = HashMap::new();
+    let mut elapsed: HashMap<(WorkerIdentifier, usize), u64> = HashMap::new();
+    let mut spans = Vec::new();
+    let mut last_export = Instant::now();
+
+    loop {
+        let stopping = stop.load(Ordering::Acquire);
+
+        while let Ok(update) = updates.try_recv() {
+            match update {
+                Update::Operator(key, addr, name) => { operators.insert(key, (addr, name)); }
+                Update::Spans(new_spans) => spans.extend(new_spans),
+                Update::Elapsed(new_elapsed) => for (key, ns) in new_elapsed {
+                    *elapsed.entry(key).or_insert(0) += ns;
+                },
+            }
+        }
+
+        if stopping || last_export.elapsed() >= EXPORT_INTERVAL {
+            let operator_attributes = |key: &(WorkerIdentifier, usize)| {
+                let mut attributes = vec![
+                    attribute("timely.worker", &int_value(key.0 as u64)),
+                    attribute("timely.operator.id", &int_value(key.1 as u64)),
+                ];
+                if let Some((addr, name)) = operators.get(key) {
+                    attributes.push(attribute("timely.operator.addr", &json_string(&format!("{:?}", addr))));
+                    attributes.push(attribute("timely.operator.name", &json_string(name_map.label(addr, name))));
+                }
+                attributes.join(",")
+            };
+
+            if !spans.is_empty() {
+                let encoded = spans.drain(..).map(|span| {
+                    let name = match operators.get(&(span.worker, span.id)) {
+                        Some((addr, name)) => name_map.label(addr, name).to_string(),
+                        None => format!("operator {}", span.id),
+                    };
+                    format!("{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",{}\"name\":{},\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}]}}",
+                            span.trace_id, span.span_id,
+                            span.parent_id.map(|id| format!("\"parentSpanId\":\"{:016x}\",", id)).unwrap_or_default(),
+                            json_string(&name), span.start_ns, span.end_ns,
+                            operator_attributes(&(span.worker, span.id)))
+                }).collect::<Vec<_>>();
+                let body = format!("{{\"resourceSpans\":[{{\"resource\":{},\"scopeSpans\":[{{\"scope\":{},\"spans\":[{}]}}]}}]}}",
+                                   resource, scope, encoded.join(","));
+                if let Err(e) = post(endpoint, "/v1/traces", &body) {
+                    crate::status::warn("export_failed", &format!("Dropped {} span(s): {}", encoded.len(), e));
+                }
+            }
+
+            if !elapsed.is_empty() {
+                let now_ns = unix_ns(SystemTime::now());
+                let points = elapsed.iter().map(|(key, ns)| {
+                    format!("{{\"attributes\":[{}],\"startTimeUnixNano\":\"{}\",\"timeUnixNano\":\"{}\",\"asDouble\":{}}}",
+                            operator_attributes(key), started_ns, now_ns, *ns as f64 / 1_000_000_000f64)
+                }).collect::<Vec<_>>();
+                let body = format!("{{\"resourceMetrics\":[{{\"resource\":{},\"scopeMetrics\":[{{\"scope\":{},\"metrics\":[{{\"name\":\"timely.operator.scheduled\",\"unit\":\"s\",\"sum\":{{\"aggregationTemporality\":2,\"isMonotonic\":true,\"dataPoints\":[{}]}}}}]}}]}}]}}",
+                                   resource, scope, points.join(","));
+                if let Err(e) = post(endpoint, "/v1/metrics", &body) {
+                    crate::status::warn("export_failed", &format!("Cannot push the metrics: {}", e));
+                }
+            }
+
+            last_export = Instant::now();
+        }
+
+        if stopping {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// An OTLP/JSON attribute, with an already encoded string value.
+fn attribute(key: &str, value: &str) -> String {
+    format!("{{\"key\":{},\"value\":{{{}}}}}", json_string(key), value)
+}
+
+fn int_value(value: u64) -> String {
+    format!("\"intValue\":\"{}\"", value)
+}
+
+/// Sends `body` to the endpoint, and checks that it was accepted.
+fn post(endpoint: &Endpoint, path: &str, body: &str) -> std::io::Result<()> {
+    let failed = |reason: String| std::io::Error::new(std::io::ErrorKind::Other, reason);
+    let timeout = Duration::from_secs(5);
+    let addr = endpoint.host.to_socket_addrs()?.next()
+        .ok_or_else(|| failed(format!("cannot resolve {}", endpoint.host)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write!(stream, "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           endpoint.prefix, path, endpoint.host, body.len(), body)?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(failed(format!("the endpoint answered {}", status_line))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoint() {
+        let endpoint = Endpoint::parse("http://127.0.0.1:4318").expect("valid endpoint");
+        assert_eq!((endpoint.host.as_str(), endpoint.prefix.as_str()), ("127.0.0.1:4318", ""));
+        let endpoint = Endpoint::parse("http://collector/otlp/").expect("valid endpoint");
+        assert_eq!((endpoint.host.as_str(), endpoint.prefix.as_str()), ("collector:4318", "/otlp"));
+    }
+
+    #[test]
+    fn parse_invalid_endpoint() {
+        for url in &["https://collector:4318", "collector:4318", "grpc://collector", "http://", "http:///v1"] {
+            assert!(Endpoint::parse(url).is_err(), "{:?} should be invalid", url);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! "park" subcommand: reports, for each source worker, how long it was
+//! parked (idle) versus busy, to tell starved workers from saturated
+//! ones.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::{ParkEvent, TimelyEvent::Park};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Park statistics gathered for the events of a single source worker.
+#[derive(Default, Clone, Debug)]
+struct WorkerPark {
+    first: Option<Duration>,
+    last: Option<Duration>,
+    parked_since: Option<Duration>,
+    parks: usize,
+    parked: Duration,
+    longest_park: Duration,
+}
+
+impl WorkerPark {
+    fn observe(&mut self, t: Duration, event: Option<&ParkEvent>) {
+        self.first = Some(self.first.map_or(t, |first| std::cmp::min(first, t)));
+        self.last = Some(self.last.map_or(t, |last| std::cmp::max(last, t)));
+        match event {
+            Some(ParkEvent::Park(_)) => {
+                self.parked_since = Some(t);
+            },
+            Some(ParkEvent::Unpark) => {
+                if let Some(since) = self.parked_since.take() {
+                    let park = if t > since { t - since } else { Duration::from_secs(0) };
+                    self.parks += 1;
+                    self.parked += park;
+                    self.longest_park = std::cmp::max(self.longest_park, park);
+                }
+            },
+            None => { },
+        }
+    }
+
+    /// The time between the worker's first and last event.
+    fn span(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) if last > first => last - first,
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+/// Prints, for each source worker, the time spent parked (waiting for
+/// work) and busy, and the number of times it parked.
+///
+/// A worker's events are all read by the same tdiag worker, so parks
+/// can be matched with the following unpark without exchanging data.
+/// The busy time is the time between the worker's first and last event
+/// that wasn't spent parked; a park still in progress when the session
+/// ends isn't counted.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to match the `Park` events of
+/// each source worker;
+/// 3. prints the per-worker totals, and the share of time parked.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams) -> Result<(), crate::DiagError> {
+
+    let source_peers = streams.source_peers();
+    let streams_w = streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        let parks = Rc::new(RefCell::new(HashMap::new()));
+        let parks_w = parks.clone();
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(t, w, x)| {
+                    let event = if let Park(event) = x { Some(event) } else { None };
+                    parks_w.borrow_mut().entry(*w).or_insert_with(WorkerPark::default).observe(*t, event);
+                });
+        });
+
+        while worker.step() { }
+
+        for (w, stats) in parks.borrow_mut().drain() {
+            output_send.send((w, stats)).expect("failed to send output to mpsc channel");
+        }
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting park data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut parks = output_recv.into_iter().collect::<HashMap<usize, WorkerPark>>();
+
+    println!("Worker\tParks\tParked (ms)\tBusy (ms)\tParked (%)\tLongest park (ms)");
+    let workers = std::cmp::max(source_peers.unwrap_or(0), parks.keys().map(|w| w + 1).max().unwrap_or(0));
+    for w in 0..workers {
+        let stats = parks.remove(&w).unwrap_or_default();
+        let span = stats.span();
+        let parked = std::cmp::min(stats.parked, span);
+        let busy = span - parked;
+        let parked_share = if span > Duration::from_secs(0) { 100.0 * parked.as_secs_f64() / span.as_secs_f64() } else { 0.0 };
+        if let Some(report) = config.reports() {
+            report.record("worker_park", vec![
+                ("worker", w.into()),
+                ("parks", stats.parks.into()),
+                ("parked_ms", (parked.as_secs_f64() * 1000.0).into()),
+                ("busy_ms", (busy.as_secs_f64() * 1000.0).into()),
+                ("longest_park_ms", (stats.longest_park.as_secs_f64() * 1000.0).into()),
+            ]);
+        }
+        println!("{}\t{}\t{:.3}\t{:.3}\t{:.1}\t{:.3}",
+            w,
+            stats.parks,
+            parked.as_secs_f64() * 1000.0,
+            busy.as_secs_f64() * 1000.0,
+            parked_share,
+            stats.longest_park.as_secs_f64() * 1000.0);
+    }
+
+    Ok(())
+}
@@ -1,20 +1,117 @@
 //! "profile" subcommand: reports aggregate runtime for each
 //! scope/operator.
 
+use std::io::Write;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use crate::{DiagError, LoggingTuple};
 
-use timely::dataflow::operators::{Map, Filter, generic::Operator};
+use timely::dataflow::ProbeHandle;
+use timely::dataflow::operators::{Map, Filter, Inspect, Probe, generic::Operator, aggregation::Aggregate, capture::{Capture, extract::Extract}};
 
+use differential_dataflow::Collection;
 use differential_dataflow::trace::TraceReader;
+use differential_dataflow::trace::implementations::ord::OrdKeySpine;
 use differential_dataflow::collection::AsCollection;
-use differential_dataflow::operators::{Join, reduce::Threshold, Consolidate, arrange::{Arrange, Arranged}};
+use differential_dataflow::operators::{Join, reduce::Threshold, Consolidate, arrange::{Arrange, Arranged, TraceAgent}};
 
-use timely::logging::TimelyEvent::{Operates, Schedule};
+use timely::logging::TimelyEvent::{Operates, Schedule, GuardedMessage, GuardedProgress};
 
 use tdiag_connect::receive::ReplayWithShutdown;
 
+/// What the diagnostic workers hand to the interval printer.
+enum Interval {
+    /// An operator was created: (id, addr, name).
+    Operator(usize, Vec<usize>, String),
+    /// The time spent in each operator in an interval: (end of the
+    /// interval, (operator id, ns)).
+    Complete(std::time::Duration, Vec<(usize, isize)>),
+}
+
+/// How to aggregate the time spent in operators.
+#[derive(Clone, Debug)]
+pub enum GroupBy {
+    /// Sum operators by the first `n` components of their address.
+    PrefixLength(usize),
+    /// Sum all operators under each of these scope addresses.
+    Scopes(Vec<Vec<usize>>),
+}
+
+/// How `listen_and_profile` prints its results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileFormat {
+    /// Human-readable tables.
+    Text,
+    /// A Vega-Lite spec of the time spent in each operator per second.
+    Vega,
+    /// A record per operator, for scripts.
+    Json,
+}
+
+impl ProfileFormat {
+    /// Parses a format name, as passed to `profile --format`.
+    pub fn parse(name: &str) -> Result<Self, DiagError> {
+        match name {
+            "text" => Ok(ProfileFormat::Text),
+            "vega" => Ok(ProfileFormat::Vega),
+            "json" => Ok(ProfileFormat::Json),
+            _ => Err(DiagError::InvalidArgs(format!("Invalid --format {} (expected text, vega or json)", name))),
+        }
+    }
+}
+
+/// What `listen_and_profile` reports besides the time spent in each
+/// operator, and how.
+#[derive(Clone, Debug)]
+pub struct ProfileOptions {
+    /// Also write a worker × operator heatmap to this html file.
+    pub heatmap_path: Option<PathBuf>,
+    /// Also report every activation that took at least this many ms.
+    pub long_activation_ms: Option<u64>,
+    /// Sum the time of operators by address.
+    pub group_by: Option<GroupBy>,
+    /// How to print the results.
+    pub format: ProfileFormat,
+    /// Print the results to this file rather than stdout.
+    pub out_path: Option<PathBuf>,
+    /// Also write a flamegraph of the time spent in each scope and
+    /// operator to this svg file.
+    pub flamegraph_path: Option<PathBuf>,
+    /// Print the time spent in each operator on each worker (text
+    /// format only), to spot skew.
+    pub per_worker: bool,
+    /// Also print, for each operator, the time spent in user message
+    /// handlers versus progress logic (text format only).
+    pub guarded: bool,
+    /// Also print the operators that were scheduled the longest in each
+    /// interval of this many ms, while the computation runs.
+    pub output_interval_ms: Option<u64>,
+    /// How many operators to print for each interval.
+    pub top: usize,
+    /// Also print, for each operator, percentiles of the duration of its
+    /// individual activations (text format only).
+    pub histograms: bool,
+}
+
+impl Default for ProfileOptions {
+    fn default() -> Self {
+        ProfileOptions {
+            heatmap_path: None,
+            long_activation_ms: None,
+            group_by: None,
+            format: ProfileFormat::Text,
+            out_path: None,
+            flamegraph_path: None,
+            per_worker: false,
+            guarded: false,
+            output_interval_ms: None,
+            top: 10,
+            histograms: false,
+        }
+    }
+}
+
 /// Prints aggregate time spent in each scope/operator.
 ///
 /// 1. Listens to incoming connections from a timely-dataflow program
@@ -22,149 +119,1185 @@ use tdiag_connect::receive::ReplayWithShutdown;
 /// 2. runs a differential-dataflow program to track scheduling events
 /// and derive runtime for each operator;
 /// 3. prints the resulting measurements alongside operator names and
-/// scope names;
+/// scope names, aggregated as specified by `group_by`, or (with
+/// `per_worker`) for each source worker;
+/// 4. optionally writes a worker × operator heatmap of the time spent
+/// in each operator to `heatmap_path`;
+/// 5. optionally reports every activation that took at least
+/// `long_activation_ms`, since long non-yielding activations hurt the
+/// latency of all other operators on the same worker;
+/// 6. optionally writes a flamegraph to `flamegraph_path`;
+/// 7. optionally (with `guarded`) breaks down the time spent in each
+/// operator into message handling and progress logic, as reported by
+/// `GuardedMessage` and `GuardedProgress` events;
+/// 8. optionally (with `output_interval_ms`) prints the `top` operators
+/// by time scheduled in each interval, as soon as the interval is
+/// complete, before the totals;
+/// 9. optionally (with `histograms`) prints percentiles of the duration
+/// of each operator's individual activations, which tell an operator
+/// with a few long activations from one with many short ones.
+///
+/// Each operator's number of activations, and the mean time per
+/// activation, are printed alongside its total: operators scheduled
+/// millions of times for tiny amounts of work call for different fixes
+/// than ones with a few long activations.
+///
+/// With `ProfileFormat::Vega`, prints a Vega-Lite spec of the time spent
+/// in each operator per second instead of the table; with
+/// `ProfileFormat::Json`, a JSON array with a record per operator. The
+/// output goes to `out_path` if provided, or stdout.
 pub fn listen_and_profile(
-    timely_configuration: timely::Configuration,
-    sockets: Vec<Option<std::net::TcpStream>>) -> Result<(), crate::DiagError> {
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    options: &ProfileOptions) -> Result<(), crate::DiagError> {
+
+    let heatmap_path = options.heatmap_path.as_deref();
+    let long_activation_ms = options.long_activation_ms;
+    let group_by = options.group_by.as_ref();
+    let format = options.format;
+    let out_path = options.out_path.as_deref();
+
+    // Before listening, so that an unusable path is reported right away.
+    let mut out: Box<dyn Write> = match out_path {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)
+            .map_err(|e| DiagError::io(format!("cannot create {}", path.display()), e))?)),
+        None => Box::new(std::io::stdout()),
+    };
+
+    let name_map = config.names();
+    let Profile { data, per_second, sections, long, histograms, activations } = profile(config, streams, options)?;
+
+    if let Some(heatmap_path) = heatmap_path {
+        let columns = data.iter()
+            .filter(|((_, _, _, is_scope), _)| !is_scope)
+            .map(|((id, addr, name, _), _)| (*id, format!("{} ({:?})", name_map.label(addr, name), addr)))
+            .collect::<Vec<_>>();
+        let mut values = std::collections::HashMap::new();
+        for (key, _t, ns) in per_second.iter() {
+            *values.entry(*key).or_insert(0f64) += (*ns as f64) / 1_000_000_000f64;
+        }
+        crate::commands::heatmap::write_heatmap(heatmap_path, "Time spent in each operator", "s", &columns, &values)?;
+    }
+
+    if let Some(flamegraph_path) = options.flamegraph_path.as_deref() {
+        let frames = data.iter()
+            .map(|((_, addr, name, _), ns)| (addr.clone(), name_map.label(addr, name).to_string(), *ns as u64))
+            .collect::<Vec<_>>();
+        crate::commands::flamegraph::write_flamegraph(flamegraph_path, "Time spent in each scope and operator", &frames)?;
+        crate::status::info("flamegraph_written", &format!("Flamegraph written to {}", flamegraph_path.display()));
+    }
+
+    // The operators' address and label (see `NameMap::label`) by id, as
+    // every table below prints them.
+    let operators = data.iter()
+        .map(|((id, addr, name, _), _)| (*id, (addr.clone(), name_map.label(addr, name).to_string())))
+        .collect::<std::collections::HashMap<_, _>>();
+
+    record(config, &data, &activations);
+
+    match format {
+        ProfileFormat::Vega => print_chart(&mut out, &per_second, &data, &operators)?,
+        ProfileFormat::Json => print_json(&mut out, &data, &activations, name_map)?,
+        ProfileFormat::Text => {
+            match group_by {
+                None if options.per_worker => print_per_worker(&mut out, &per_second, &data, &operators)?,
+                None => {
+                    for ((id, addr, name, is_scope), ns) in data.into_iter() {
+                        let count = activations.get(&id).cloned().unwrap_or(0);
+                        writeln!(out, "{}\t{}\t(id={}, addr={:?}):\t{:e} s\t{} activations\t{:e} s/activation",
+                            if is_scope { "[scope]" } else { "" },
+                            name_map.label(&addr, &name),
+                            id,
+                            addr,
+                            (ns as f64) / 1_000_000_000f64,
+                            count,
+                            (ns as f64) / 1_000_000_000f64 / std::cmp::max(count, 1) as f64)?;
+                    }
+                }
+                Some(group_by) => print_groups(&mut out, group_by, &data, &operators)?,
+            }
+
+            if options.guarded {
+                print_guarded(&mut out, &sections, &data, &operators)?;
+            }
+
+            if options.histograms {
+                print_histograms(&mut out, &histograms, &data, &operators, config.reports())?;
+            }
+
+            if let Some(long_activation_ms) = long_activation_ms {
+                print_long_activations(&mut out, long_activation_ms, &long, &operators)?;
+            }
+        }
+    }
+
+    out.flush()?;
+
+    if let Some(out_path) = out_path {
+        crate::status::info("profile_written", &format!("Profile written to {}", out_path.display()));
+    }
+
+    Ok(())
+}
+
+/// The time spent in each scope/operator of the source computation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProfileEntry {
+    /// The operator's id.
+    pub id: usize,
+    /// The operator's address.
+    pub addr: Vec<usize>,
+    /// The operator's name, as logged (see `NameMap::label`).
+    pub name: String,
+    /// Whether the operator is a scope, whose time includes that of the
+    /// operators in it.
+    pub is_scope: bool,
+    /// The time spent in the operator, over all workers.
+    pub elapsed_ns: isize,
+    /// The number of times the operator was scheduled, over all workers.
+    pub activations: u64,
+}
+
+/// Measures the time spent in each scope/operator, as
+/// `listen_and_profile` does without options, and returns it (hottest
+/// first) once the analysis stops (see `await_stop`).
+pub fn listen_and_collect(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams) -> Result<Vec<ProfileEntry>, crate::DiagError> {
+
+    let Profile { data, activations, .. } = profile(config, streams, &ProfileOptions::default())?;
+    Ok(entries(config, data, &activations))
+}
+
+/// The `ProfileEntry`s of the operators in `data`, hottest first, keeping
+/// the operators matching the configured filter.
+pub(crate) fn entries(
+    config: &crate::config::DiagConfig,
+    mut data: Vec<(ProfileKey, isize)>,
+    activations: &std::collections::HashMap<usize, u64>) -> Vec<ProfileEntry> {
+
+    data.sort_unstable_by_key(|&(_, ns)| std::cmp::Reverse(ns));
+    if !config.filter().is_empty() {
+        data.retain(|((_, addr, name, _), _)| config.filter().matches(addr, name, config.names()));
+    }
+    data.into_iter()
+        .map(|((id, addr, name, is_scope), elapsed_ns)| {
+            let activations = activations.get(&id).cloned().unwrap_or(0);
+            ProfileEntry { id, addr, name, is_scope, elapsed_ns, activations }
+        })
+        .collect()
+}
+
+/// What `profile` measured.
+struct Profile {
+    /// ((operator id, addr, name, is_scope), ns), hottest first.
+    data: Vec<(ProfileKey, isize)>,
+    /// ((worker, operator id), end of the second, ns), if needed for the
+    /// heatmap, the chart or the per-worker table.
+    per_second: Vec<((usize, usize), std::time::Duration, isize)>,
+    /// operator id -> (message handlers ns, progress logic ns), with
+    /// `guarded`.
+    sections: std::collections::HashMap<usize, (isize, isize)>,
+    /// The long activations, ((worker, operator id), start, elapsed), by
+    /// start, with `long_activation_ms`.
+    long: Vec<((usize, usize), std::time::Duration, std::time::Duration)>,
+    /// operator id -> durations of its activations, over all workers,
+    /// with `histograms`.
+    histograms: std::collections::HashMap<usize, Histogram>,
+    /// operator id -> number of activations, over all workers.
+    activations: std::collections::HashMap<usize, u64>,
+}
+
+/// Runs the profile's dataflow until the analysis stops, printing the
+/// intervals as they complete (with `output_interval_ms`), and keeps
+/// the operators matching the configured filter.
+fn profile(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    options: &ProfileOptions) -> Result<Profile, crate::DiagError> {
+
+    let heatmap_path = options.heatmap_path.as_deref();
+    let long_activation_ms = options.long_activation_ms;
+    let format = options.format;
+    let name_map = config.names();
+    let streams_w = streams.clone();
 
-    let sockets = Arc::new(Mutex::new(sockets));
 
     let (output_send, output_recv) = ::std::sync::mpsc::channel();
     let output_send = Arc::new(Mutex::new(output_send));
 
+    let (heatmap_send, heatmap_recv) = ::std::sync::mpsc::channel();
+    let heatmap_send = Arc::new(Mutex::new(heatmap_send));
+    // Time spent per second (and worker) is needed for the heatmap, the
+    // chart and the per-worker table.
+    let per_second = heatmap_path.is_some() || format == ProfileFormat::Vega || options.per_worker;
+
+    let (long_send, long_recv) = ::std::sync::mpsc::channel();
+    let long_send = Arc::new(Mutex::new(long_send));
+    let long_activation = long_activation_ms.map(std::time::Duration::from_millis);
+
+    let (guarded_send, guarded_recv) = ::std::sync::mpsc::channel();
+    let guarded_send = Arc::new(Mutex::new(guarded_send));
+    let guarded = options.guarded;
+
+    let (intervals_send, intervals_recv) = ::std::sync::mpsc::channel();
+    let intervals_send = Arc::new(Mutex::new(intervals_send));
+    let output_interval_ms = options.output_interval_ms;
+
+    let (histograms_send, histograms_recv) = ::std::sync::mpsc::channel();
+    let histograms_send = Arc::new(Mutex::new(histograms_send));
+    let histograms = options.histograms;
+
+    let (counts_send, counts_recv) = ::std::sync::mpsc::channel();
+    let counts_send = Arc::new(Mutex::new(counts_send));
+
     let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
     let is_running_w = is_running.clone();
 
-    let worker_handles = timely::execute(timely_configuration, move |worker| {
-        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+    let resources = config.resources().clone();
 
-        let sockets = sockets.clone();
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+        let heatmap_send: std::sync::mpsc::Sender<_> = heatmap_send.lock().expect("cannot lock heatmap_send").clone();
+        let long_send: std::sync::mpsc::Sender<_> = long_send.lock().expect("cannot lock long_send").clone();
+        let guarded_send: std::sync::mpsc::Sender<_> = guarded_send.lock().expect("cannot lock guarded_send").clone();
+        let intervals_o: std::sync::mpsc::Sender<_> = intervals_send.lock().expect("cannot lock intervals_send").clone();
+        let intervals_c = intervals_o.clone();
+        let histograms_send: std::sync::mpsc::Sender<_> = histograms_send.lock().expect("cannot lock histograms_send").clone();
+        let counts_send: std::sync::mpsc::Sender<_> = counts_send.lock().expect("cannot lock counts_send").clone();
+        let resources_l = resources.clone();
+        let resources_s = resources.clone();
 
         // create replayer from disjoint partition of source worker identifiers.
-        let replayer = tdiag_connect::receive::make_readers::<std::time::Duration, LoggingTuple>(
-            tdiag_connect::receive::ReplaySource::Tcp(sockets), worker.index(), worker.peers())
-            .expect("failed to open tcp readers");
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
 
-        let profile_trace = worker.dataflow(|scope| {
+        let mut probe = ProbeHandle::new();
+
+        let mut profile_trace = worker.dataflow(|scope| {
             let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
 
-            let operates = stream
-                .filter(|(_, w, _)| *w== 0)
-                .flat_map(|(t, _, x)| if let Operates(event) = x { Some((event, t, 1 as isize)) } else { None })
-                .as_collection();
-
-            let schedule = stream
-                .flat_map(|(t, w, x)| if let Schedule(event) = x { Some((t, w, event)) } else { None })
-                .unary(timely::dataflow::channels::pact::Pipeline, "Schedules", |_,_| {
-                    let mut map = std::collections::HashMap::new();
-                    let mut vec = Vec::new();
-                    move |input, output| {
-                        input.for_each(|time, data| {
-                            data.swap(&mut vec);
-                            let mut session = output.session(&time);
-                            for (ts, worker, event) in vec.drain(..) {
-                                let key = (worker, event.id);
-                                match event.start_stop {
-                                    timely::logging::StartStop::Start => {
-                                        assert!(!map.contains_key(&key));
-                                        map.insert(key, ts);
-                                    },
-                                    timely::logging::StartStop::Stop => {
-                                        assert!(map.contains_key(&key));
-                                        let end = map.remove(&key).unwrap();
-                                        let ts_clip = std::time::Duration::from_secs(ts.as_secs() + 1);
-                                        let elapsed = ts - end;
-                                        let elapsed_ns = (elapsed.as_secs() as isize) * 1_000_000_000 + (elapsed.subsec_nanos() as isize);
-                                        session.give((key.1, ts_clip, elapsed_ns));
-                                    }
+            let activations = activations(&stream);
+
+            if let Some(long_activation) = long_activation {
+                activations
+                    // Only the longest ones when approaching the memory budget.
+                    .filter(move |(_, _, elapsed)| *elapsed >= long_activation * resources_l.coarsening() as u32)
+                    .capture_into(long_send);
+            }
+
+            if guarded {
+                guarded_sections(&stream)
+                    .map(|(key, t, elapsed)| {
+                        let ts_clip = std::time::Duration::from_secs(t.as_secs() + 1);
+                        let elapsed_ns = (elapsed.as_secs() as isize) * 1_000_000_000 + (elapsed.subsec_nanos() as isize);
+                        (key, ts_clip, elapsed_ns)
+                    })
+                    .as_collection()
+                    .consolidate()
+                    .inner
+                    .capture_into(guarded_send);
+            }
+
+            if histograms {
+                schedule_histograms(&activations).capture_into(histograms_send);
+            }
+
+            activation_counts(&activations).capture_into(counts_send);
+
+            if let Some(output_interval_ms) = output_interval_ms {
+                stream
+                    .filter(move |(_, w, _)| *w == structure_worker)
+                    .inspect(move |(_, _, x)| if let Operates(event) = x {
+                        let _ = intervals_o.send(Interval::Operator(event.id, event.addr.clone(), event.name.clone()));
+                    });
+
+                activations
+                    .map(move |((_, id), start, elapsed)| {
+                        let window_idx = ((start + elapsed).as_millis() as u64 / output_interval_ms) + 1;
+                        let elapsed_ns = (elapsed.as_secs() as isize) * 1_000_000_000 + (elapsed.subsec_nanos() as isize);
+                        (id, std::time::Duration::from_millis(window_idx * output_interval_ms), elapsed_ns)
+                    })
+                    .as_collection()
+                    .consolidate()
+                    .inner
+                    // All of an interval on the first worker, once it's complete.
+                    .map(|(id, t, ns)| ((), (id, t, ns)))
+                    .aggregate::<_, Vec<_>, _, _, _>(
+                        |_, (id, t, ns), interval| interval.push((id, t, ns)),
+                        |_, interval| interval,
+                        |_| 0)
+                    .inspect(move |interval| if let Some((_, t, _)) = interval.first() {
+                        let totals = interval.iter().map(|(id, _, ns)| (*id, *ns)).collect();
+                        let _ = intervals_c.send(Interval::Complete(*t, totals));
+                    });
+            }
+
+            let per_worker = schedule_per_worker(&activations, resources_s);
+
+            if per_second {
+                per_worker.inner.capture_into(heatmap_send);
+            }
+
+            let schedule = per_worker.map(|(_, id)| id); // (operator_id)
+
+            arrange_totals(&stream, &schedule, structure_worker, &mut probe)
+        });
+
+        let mut frontier = Vec::new();
+        while worker.step() {
+            compact_totals(&mut profile_trace, &probe, &mut frontier);
+        }
+
+        for total in read_totals(profile_trace) {
+            output_send.send(total).expect("failed to send output to mpsc channel");
+        }
+
+    }).map_err(DiagError::Timely)?;
+
+    let printer = match output_interval_ms {
+        Some(output_interval_ms) => {
+            let name_map = name_map.clone();
+            let filter = config.filter().clone();
+            let top = options.top;
+            Some(std::thread::Builder::new()
+                .name("tdiag-profile".to_string())
+                .spawn(move || print_intervals(intervals_recv, output_interval_ms, top, &name_map, &filter))?)
+        }
+        None => {
+            drop(intervals_recv);
+            None
+        }
+    };
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting profile data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    if let Some(printer) = printer {
+        printer.join().map_err(|_| DiagError::Internal("the printer thread panicked".to_string()))?;
+        println!();
+    }
+
+    let mut data = output_recv.into_iter().collect::<Vec<_>>();
+    data.sort_unstable_by_key(|&(_, ns)| std::cmp::Reverse(ns));
+
+    // ((worker, operator id), end of the second, ns)
+    let per_second = if per_second {
+        heatmap_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+
+    // operator id -> (message handlers ns, progress logic ns)
+    let mut sections = std::collections::HashMap::new();
+    for ((id, is_progress), _t, ns) in guarded_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        let entry = sections.entry(id).or_insert((0, 0));
+        if is_progress { entry.1 += ns } else { entry.0 += ns }
+    }
+
+    let mut long = long_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<Vec<_>>();
+    long.sort_unstable_by_key(|&((worker, id), start, _)| (start, worker, id));
+
+    // operator id -> durations of its activations, over all workers
+    let mut histograms = std::collections::HashMap::new();
+    for (id, histogram) in histograms_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        histograms.entry(id).or_insert_with(Histogram::default).merge(&histogram);
+    }
+
+    // operator id -> number of activations, over all workers
+    let mut activations = std::collections::HashMap::new();
+    for (id, count) in counts_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        *activations.entry(id).or_insert(0) += count;
+    }
+
+    let mut profile = Profile { data, per_second, sections, long, histograms, activations };
+    if !config.filter().is_empty() {
+        let filter = config.filter();
+        profile.data.retain(|((_, addr, name, _), _)| filter.matches(addr, name, name_map));
+        let kept = profile.data.iter().map(|((id, _, _, _), _)| *id).collect::<std::collections::HashSet<_>>();
+        profile.per_second.retain(|((_, id), _, _)| kept.contains(id));
+        profile.sections.retain(|id, _| kept.contains(id));
+        profile.long.retain(|((_, id), _, _)| kept.contains(id));
+        profile.histograms.retain(|id, _| kept.contains(id));
+        profile.activations.retain(|id, _| kept.contains(id));
+    }
+
+    Ok(profile)
+}
+
+/// An operator (or scope) the time spent in is measured: (operator id,
+/// addr, name, is_scope).
+pub(crate) type ProfileKey = (usize, Vec<usize>, String, bool);
+
+/// The time spent in each operator (as the diff of its `ProfileKey`).
+pub(crate) type ProfileTrace = TraceAgent<OrdKeySpine<ProfileKey, std::time::Duration, isize>>;
+
+/// Sums the time spent in each operator on each source worker in
+/// buckets of a second (wider when approaching the memory budget):
+/// ((worker, operator id), end of the bucket, ns).
+pub(crate) fn schedule_per_worker<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    activations: &timely::dataflow::Stream<G, ((usize, usize), std::time::Duration, std::time::Duration)>,
+    resources: crate::resources::Resources) -> Collection<G, (usize, usize), isize> {
+
+    activations
+        .map(move |(key, start, elapsed)| {
+            // Larger buckets when approaching the memory budget.
+            let bucket_s = resources.coarsening();
+            let ts_clip = std::time::Duration::from_secs(((start + elapsed).as_secs() / bucket_s + 1) * bucket_s);
+            let elapsed_ns = (elapsed.as_secs() as isize) * 1_000_000_000 + (elapsed.subsec_nanos() as isize);
+            (key, ts_clip, elapsed_ns)
+        })
+        .as_collection().consolidate() // (worker, operator_id)
+}
+
+/// Arranges the time spent in each operator, given the time `schedule`d
+/// by operator id, with the operators logged by `structure_worker`.
+///
+/// The trace should be compacted as the `probe`d frontier advances (see
+/// `compact_totals`), and read once the streams end (see `read_totals`).
+pub(crate) fn arrange_totals<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    stream: &timely::dataflow::Stream<G, LoggingTuple>,
+    schedule: &Collection<G, usize, isize>,
+    structure_worker: usize,
+    probe: &mut ProbeHandle<std::time::Duration>) -> ProfileTrace {
+
+    let operates = stream
+        .filter(move |(_, w, _)| *w == structure_worker)
+        .flat_map(|(t, _, x)| if let Operates(event) = x { Some((event, t, 1 as isize)) } else { None })
+        .as_collection();
+
+    // FIXME
+    // == Re-construct the dataflow graph (re-wire channels crossing a scope boundary) ==
+    //
+    // A timely dataflow graph has a hierarchical structure: a "scope" looks like an
+    // operator to the outside but can contain a subgraph of operators (and other scopes)
+    //
+    // We flatten this hierarchy to display it as a simple directed graph, but preserve the
+    // information on scope boundaries so that they can be drawn as graph cuts.
+
+    let operates = operates.map(|event| (event.addr, (event.id, event.name)));
+
+    // Addresses of potential scopes (excluding leaf operators)
+    let scopes = operates.map(|(mut addr, _)| {
+        addr.pop();
+        addr
+    }).distinct();
+
+    // Exclusively leaf operators
+    let operates_without_subg = operates.antijoin(&scopes).map(|(addr, (id, name))| (id, (addr, name, false)));
+    let subg = operates.semijoin(&scopes).map(|(addr, (id, name))| (id, (addr, name, true)));
+
+    let all_operators = operates_without_subg.concat(&subg).distinct();
+
+    let Arranged { trace, stream } = all_operators.semijoin(schedule)
+        .map(|(id, (addr, name, is_scope))| (id, addr, name, is_scope))
+        .consolidate()
+        .arrange::<OrdKeySpine<_, _, _>>();
+    stream.probe_with(probe);
+
+    trace
+}
+
+/// Lets the trace compact the measurements up to the analysed frontier,
+/// to be called as the worker steps.
+///
+/// Only the total time spent in each operator is read, once the streams
+/// end: compacting as the frontier advances keeps a single entry per
+/// operator, rather than one per operator and second of the run.
+pub(crate) fn compact_totals(trace: &mut ProfileTrace, probe: &ProbeHandle<std::time::Duration>, frontier: &mut Vec<std::time::Duration>) {
+    probe.with_frontier(|f| {
+        frontier.clear();
+        frontier.extend(f.iter().cloned());
+    });
+    // An empty frontier would let the trace discard its contents.
+    if !frontier.is_empty() {
+        trace.advance_by(frontier);
+        trace.distinguish_since(frontier);
+    }
+}
+
+/// The total time spent in each operator, once the streams ended.
+pub(crate) fn read_totals(mut trace: ProfileTrace) -> Vec<(ProfileKey, isize)> {
+    trace.distinguish_since(&[]);
+
+    let (mut cursor, storage) = trace.cursor();
+
+    let mut totals = Vec::new();
+    use differential_dataflow::trace::cursor::Cursor;
+    while cursor.key_valid(&storage) {
+        let key = cursor.key(&storage);
+        if cursor.val_valid(&storage) {
+            let mut ns = 0;
+            cursor.map_times(&storage, |_, r| ns += r);
+            totals.push((key.clone(), ns));
+        }
+        cursor.step_key(&storage);
+    }
+    totals
+}
+
+/// Pairs the `Schedule` start and stop events of each operator on each
+/// source worker into activations: ((worker, operator id), start,
+/// elapsed).
+///
+/// Each source worker's events are read by a single worker, in order,
+/// so a pipeline suffices. A stop without a start is skipped, and a
+/// start without a stop replaced by the next start.
+pub(crate) fn activations<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    stream: &timely::dataflow::Stream<G, LoggingTuple>)
+    -> timely::dataflow::Stream<G, ((usize, usize), std::time::Duration, std::time::Duration)> {
+
+    stream
+        .flat_map(|(t, w, x)| if let Schedule(event) = x { Some((t, w, event)) } else { None })
+        .unary(timely::dataflow::channels::pact::Pipeline, "Schedules", |_,_| {
+            let mut map = std::collections::HashMap::new();
+            let mut vec = Vec::new();
+            move |input, output| {
+                input.for_each(|time, data| {
+                    data.swap(&mut vec);
+                    let mut session = output.session(&time);
+                    for (ts, worker, event) in vec.drain(..) {
+                        let key = (worker, event.id);
+                        // Unmatched events (e.g. when connecting to a running
+                        // computation, or after a lost connection) are skipped.
+                        match event.start_stop {
+                            timely::logging::StartStop::Start => {
+                                map.insert(key, ts);
+                            },
+                            timely::logging::StartStop::Stop => {
+                                if let Some(start) = map.remove(&key) {
+                                    session.give((key, start, ts - start));
                                 }
                             }
-                        });
+                        }
                     }
-                }).as_collection().consolidate(); // (operator_id)
-
-            // FIXME
-            // == Re-construct the dataflow graph (re-wire channels crossing a scope boundary) ==
-            //
-            // A timely dataflow graph has a hierarchical structure: a "scope" looks like an
-            // operator to the outside but can contain a subgraph of operators (and other scopes)
-            //
-            // We flatten this hierarchy to display it as a simple directed graph, but preserve the
-            // information on scope boundaries so that they can be drawn as graph cuts.
-
-            let operates = operates.map(|event| (event.addr, (event.id, event.name)));
-
-            // Addresses of potential scopes (excluding leaf operators)
-            let scopes = operates.map(|(mut addr, _)| {
-                addr.pop();
-                addr
-            }).distinct();
-
-            // Exclusively leaf operators
-            let operates_without_subg = operates.antijoin(&scopes).map(|(addr, (id, name))| (id, (addr, name, false)));
-            let subg = operates.semijoin(&scopes).map(|(addr, (id, name))| (id, (addr, name, true)));
-
-            let all_operators = operates_without_subg.concat(&subg).distinct();
-
-            use differential_dataflow::trace::implementations::ord::OrdKeySpine;
-            let Arranged { trace: profile_trace, .. } = all_operators.semijoin(&schedule)
-                .map(|(id, (addr, name, is_scope))| (id, addr, name, is_scope))
-                .consolidate()
-                .arrange::<OrdKeySpine<_, _, _>>();
-
-            profile_trace
-        });
+                });
+            }
+        }) // ((worker, operator_id), start, elapsed)
+}
 
-        while worker.step() { }
+/// Folds the durations of each operator's activations into a `T` per
+/// operator, and emits them once the input is complete.
+fn fold_activations<G, T, F>(
+    activations: &timely::dataflow::Stream<G, ((usize, usize), std::time::Duration, std::time::Duration)>,
+    name: &str,
+    fold: F) -> timely::dataflow::Stream<G, (usize, T)>
+where
+    G: timely::dataflow::Scope<Timestamp=std::time::Duration>,
+    T: timely::Data + Default,
+    F: Fn(&mut T, std::time::Duration) + 'static,
+{
+    activations
+        .unary_frontier(timely::dataflow::channels::pact::Pipeline, name, |_,_| {
+            let mut folded = std::collections::HashMap::<usize, T>::new();
+            let mut capability: Option<timely::dataflow::operators::Capability<std::time::Duration>> = None;
+            let mut vec = Vec::new();
+            move |input, output| {
+                input.for_each(|time, data| {
+                    // The results are emitted at the earliest time seen.
+                    if capability.as_ref().map_or(true, |capability| time.time() < capability.time()) {
+                        capability = Some(time.retain());
+                    }
+                    data.swap(&mut vec);
+                    for ((_worker, id), _start, elapsed) in vec.drain(..) {
+                        fold(folded.entry(id).or_insert_with(T::default), elapsed);
+                    }
+                });
+                if input.frontier().frontier().is_empty() {
+                    if let Some(capability) = capability.take() {
+                        let mut session = output.session(&capability);
+                        for result in folded.drain() {
+                            session.give(result);
+                        }
+                    }
+                }
+            }
+        }) // (operator id, result)
+}
 
-        let mut profile_trace = profile_trace;
+/// Records the duration of each operator's activations into a histogram
+/// per operator, and emits the histograms once the input is complete.
+fn schedule_histograms<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    activations: &timely::dataflow::Stream<G, ((usize, usize), std::time::Duration, std::time::Duration)>)
+    -> timely::dataflow::Stream<G, (usize, Histogram)> {
 
-        profile_trace.distinguish_since(&[]);
+    fold_activations(activations, "ScheduleHistograms", |histogram: &mut Histogram, elapsed| histogram.record(elapsed))
+}
+
+/// Counts the activations of each operator, and emits the counts once
+/// the input is complete.
+pub(crate) fn activation_counts<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    activations: &timely::dataflow::Stream<G, ((usize, usize), std::time::Duration, std::time::Duration)>)
+    -> timely::dataflow::Stream<G, (usize, u64)> {
+
+    fold_activations(activations, "ActivationCounts", |count: &mut u64, _elapsed| *count += 1)
+}
 
-        let (mut cursor, storage) = profile_trace.cursor();
+/// How many bits of each duration a `Histogram` keeps: durations are
+/// recorded with a relative error of at most 2^-PRECISION_BITS (< 1%).
+const PRECISION_BITS: u32 = 7;
+
+/// A histogram of durations, HDR-histogram style: durations (in ns) are
+/// counted in buckets whose width is proportional to their magnitude, so
+/// it stays small (a few hundred buckets at most) whatever the range of
+/// durations, with a bounded relative error.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Histogram {
+    /// bucket -> count
+    counts: std::collections::BTreeMap<u32, u64>,
+    total: u64,
+    max_ns: u64,
+}
+
+impl Histogram {
+    /// The bucket of `ns`: exact below 2^(PRECISION_BITS + 1), then
+    /// 2^PRECISION_BITS buckets for each power of two.
+    fn bucket(ns: u64) -> u32 {
+        if ns < 1 << (PRECISION_BITS + 1) {
+            ns as u32
+        } else {
+            let shift = 63 - ns.leading_zeros() - PRECISION_BITS;
+            (shift << PRECISION_BITS) + (ns >> shift) as u32
+        }
+    }
+
+    /// The largest duration (in ns) counted in `bucket`.
+    fn highest(bucket: u32) -> u64 {
+        if bucket < 1 << (PRECISION_BITS + 1) {
+            u64::from(bucket)
+        } else {
+            let shift = (bucket >> PRECISION_BITS) - 1;
+            let mantissa = u64::from(bucket - (shift << PRECISION_BITS));
+            ((mantissa + 1) << shift) - 1
+        }
+    }
+
+    pub(crate) fn record(&mut self, elapsed: std::time::Duration) {
+        let ns = elapsed.as_nanos().min(u128::from(u64::max_value())) as u64;
+        *self.counts.entry(Self::bucket(ns)).or_insert(0) += 1;
+        self.total += 1;
+        self.max_ns = std::cmp::max(self.max_ns, ns);
+    }
+
+    pub(crate) fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in other.counts.iter() {
+            *self.counts.entry(*bucket).or_insert(0) += count;
+        }
+        self.total += other.total;
+        self.max_ns = std::cmp::max(self.max_ns, other.max_ns);
+    }
+
+    /// The number of durations recorded.
+    pub(crate) fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// The longest duration recorded, exactly.
+    pub(crate) fn max(&self) -> std::time::Duration {
+        std::time::Duration::from_nanos(self.max_ns)
+    }
 
-        use differential_dataflow::trace::cursor::Cursor;
-        while cursor.key_valid(&storage) {
-            let key = cursor.key(&storage);
-            if cursor.val_valid(&storage) {
-                let mut ns = 0;
-                cursor.map_times(&storage, |_, r| ns += r);
-                output_send.send((key.clone(), ns)).expect("failed to send output to mpsc channel");
+    /// The duration below which a fraction `q` (in [0, 1]) of the
+    /// durations recorded are, within the histogram's precision.
+    pub(crate) fn quantile(&self, q: f64) -> std::time::Duration {
+        let rank = std::cmp::max((q * self.total as f64).ceil() as u64, 1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter() {
+            seen += count;
+            if seen >= rank {
+                return std::time::Duration::from_nanos(std::cmp::min(Self::highest(*bucket), self.max_ns));
             }
-            cursor.step_key(&storage);
         }
+        self.max()
+    }
+}
 
-    }).map_err(|x| DiagError(format!("error in the timely computation: {}", x)))?;
+/// Prints, for each (leaf) operator, the number of activations and
+/// percentiles of their duration.
+fn print_histograms<W: Write>(
+    out: &mut W,
+    histograms: &std::collections::HashMap<usize, Histogram>,
+    data: &[((usize, Vec<usize>, String, bool), isize)],
+    operators: &std::collections::HashMap<usize, (Vec<usize>, String)>,
+    report: Option<&crate::report::Report>) -> Result<(), DiagError> {
 
-    {
-        use std::io;
-        use std::io::prelude::*;
+    let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+    writeln!(out)?;
+    writeln!(out, "Name\tAddr\tActivations\tp50 (ms)\tp95 (ms)\tp99 (ms)\tMax (ms)")?;
+    // In the order of `data`, from the most to the least time spent.
+    for ((id, _, _, _), _) in data.iter().filter(|((_, _, _, is_scope), _)| !is_scope) {
+        let ((addr, name), histogram) = match (operators.get(id), histograms.get(id)) {
+            (Some(operator), Some(histogram)) => (operator, histogram),
+            _ => continue,
+        };
+        let (p50, p95, p99, max) = (histogram.quantile(0.5), histogram.quantile(0.95), histogram.quantile(0.99), histogram.max());
+        if let Some(report) = report {
+            report.record("schedule_histogram", vec![
+                ("id", (*id).into()),
+                ("addr", format!("{:?}", addr).into()),
+                ("name", name.as_str().into()),
+                ("activations", histogram.count().into()),
+                ("p50_ms", ms(p50).into()),
+                ("p95_ms", ms(p95).into()),
+                ("p99_ms", ms(p99).into()),
+                ("max_ms", ms(max).into()),
+            ]);
+        }
+        writeln!(out, "{}\t{:?}\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}",
+            name, addr, histogram.count(), ms(p50), ms(p95), ms(p99), ms(max))?;
+    }
+    Ok(())
+}
+
+/// Prints the `top` (leaf) operators matching `filter` by time scheduled
+/// in each interval as it arrives, until the senders are gone.
+fn print_intervals(
+    intervals: std::sync::mpsc::Receiver<Interval>,
+    output_interval_ms: u64,
+    top: usize,
+    name_map: &crate::names::NameMap,
+    filter: &crate::filter::OperatorFilter) {
 
-        let mut stdin = io::stdin();
-        let mut stdout = io::stdout();
+    let mut operators = std::collections::HashMap::new();
+    let mut scopes = std::collections::HashSet::new();
+    let mut excluded = std::collections::HashSet::new();
 
-        write!(stdout, "Press enter to stop collecting profile data (this will crash the source computation if it hasn't terminated).")
-            .expect("failed to write to stdout");
-        stdout.flush().unwrap();
+    for interval in intervals {
+        match interval {
+            Interval::Operator(id, addr, name) => {
+                let mut scope = addr.clone();
+                scope.pop();
+                scopes.insert(scope);
+                let label = name_map.label(&addr, &name).to_string();
+                if !filter.matches(&addr, &name, name_map) {
+                    excluded.insert(id);
+                }
+                operators.insert(id, (addr, label));
+            }
+            Interval::Complete(t, mut totals) => {
+                // The time of a scope includes the time of the operators it contains.
+                totals.retain(|(id, _)| !excluded.contains(id) && operators.get(id).map_or(true, |(addr, _)| !scopes.contains(addr)));
+                totals.sort_unstable_by_key(|&(id, ns)| (std::cmp::Reverse(ns), id));
+                let interval_ns = (output_interval_ms as f64) * 1_000_000f64;
 
-        // Read a single byte and discard
-        let _ = stdin.read(&mut [0u8]).expect("failed to read from stdin");
+                println!("ms\tName\tAddr\tTime (s)\tShare of the interval (%)");
+                for (id, ns) in totals.into_iter().take(top) {
+                    let (addr, name) = operators.get(&id)
+                        .map(|(addr, name)| (format!("{:?}", addr), name.as_str()))
+                        .unwrap_or_else(|| ("-".to_string(), "<unknown>"));
+                    println!("{}\t{}\t{}\t{:.6}\t{:.1}",
+                        t.as_millis(), name, addr, (ns as f64) / 1_000_000_000f64, 100.0 * (ns as f64) / interval_ns);
+                }
+                println!();
+            }
+        }
     }
+}
 
-    is_running.store(false, std::sync::atomic::Ordering::Release);
+/// Matches the `GuardedMessage` and `GuardedProgress` events of each
+/// source worker, and attributes them to the operator being scheduled
+/// at the time (the innermost, as scopes schedule their operators).
+///
+/// Each source worker's events are read by a single worker, in order,
+/// so a pipeline suffices.
+fn guarded_sections<G: timely::dataflow::Scope<Timestamp=std::time::Duration>>(
+    stream: &timely::dataflow::Stream<G, LoggingTuple>)
+    -> timely::dataflow::Stream<G, ((usize, bool), std::time::Duration, std::time::Duration)> {
 
-    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+    stream
+        .unary(timely::dataflow::channels::pact::Pipeline, "GuardedSections", |_,_| {
+            // worker -> (ids of the operators being scheduled, start of the current section)
+            let mut state = std::collections::HashMap::<usize, (Vec<usize>, Option<std::time::Duration>)>::new();
+            let mut vec = Vec::new();
+            move |input, output| {
+                input.for_each(|time, data| {
+                    data.swap(&mut vec);
+                    let mut session = output.session(&time);
+                    for (ts, worker, event) in vec.drain(..) {
+                        let (scheduled, section_start) = state.entry(worker).or_insert_with(Default::default);
+                        let (is_progress, is_start) = match event {
+                            Schedule(event) => {
+                                match event.start_stop {
+                                    timely::logging::StartStop::Start => { scheduled.push(event.id); },
+                                    timely::logging::StartStop::Stop => { scheduled.pop(); },
+                                }
+                                continue;
+                            },
+                            GuardedMessage(event) => (false, event.is_start),
+                            GuardedProgress(event) => (true, event.is_start),
+                            _ => continue,
+                        };
+                        if is_start {
+                            *section_start = Some(ts);
+                        } else if let (Some(start), Some(id)) = (section_start.take(), scheduled.last()) {
+                            let elapsed = if ts > start { ts - start } else { std::time::Duration::from_secs(0) };
+                            session.give(((*id, is_progress), ts, elapsed));
+                        }
+                    }
+                });
+            }
+        }) // ((operator id, is_progress), end, elapsed)
+}
 
-    let mut data = output_recv.into_iter().collect::<Vec<_>>();
-    data.sort_unstable_by_key(|&(_, ns)| std::cmp::Reverse(ns));
-    for ((id, addr, name, is_scope), ns) in data.into_iter() {
-        println!("{}\t{}\t(id={}, addr={:?}):\t{:e} s",
-            if is_scope { "[scope]" } else { "" },
+/// Prints, for each (leaf) operator, the time spent in user message
+/// handlers, in progress logic, and in neither (e.g. in the operator's
+/// own scheduling logic).
+fn print_guarded<W: Write>(
+    out: &mut W,
+    sections: &std::collections::HashMap<usize, (isize, isize)>,
+    data: &[((usize, Vec<usize>, String, bool), isize)],
+    operators: &std::collections::HashMap<usize, (Vec<usize>, String)>) -> Result<(), DiagError> {
+
+    writeln!(out)?;
+    writeln!(out, "Name\tAddr\tTotal (s)\tMessage handlers (s)\tProgress logic (s)\tOther (s)")?;
+    // In the order of `data`, from the most to the least time spent.
+    for ((id, _, _, _), ns) in data.iter().filter(|((_, _, _, is_scope), _)| !is_scope) {
+        let (addr, name) = match operators.get(id) {
+            Some(operator) => operator,
+            None => continue,
+        };
+        let (message_ns, progress_ns) = sections.get(id).cloned().unwrap_or((0, 0));
+        let other_ns = std::cmp::max(*ns - message_ns - progress_ns, 0);
+        writeln!(out, "{}\t{:?}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
             name,
+            addr,
+            (*ns as f64) / 1_000_000_000f64,
+            (message_ns as f64) / 1_000_000_000f64,
+            (progress_ns as f64) / 1_000_000_000f64,
+            (other_ns as f64) / 1_000_000_000f64)?;
+    }
+    Ok(())
+}
+
+/// Reports the time spent in each operator, through the `metrics` facade
+/// and to the configured report.
+fn record(
+    config: &crate::config::DiagConfig,
+    data: &[(ProfileKey, isize)],
+    activations: &std::collections::HashMap<usize, u64>) {
+
+    let name_map = config.names();
+    for ((id, addr, name, is_scope), ns) in data.iter() {
+        crate::telemetry::operator_scheduled(*id, addr, name_map.label(addr, name), *is_scope, *ns);
+        if let Some(report) = config.reports() {
+            report.record("operator_time", vec![
+                ("id", (*id).into()),
+                ("addr", format!("{:?}", addr).into()),
+                ("name", name_map.label(addr, name).into()),
+                ("scope", (*is_scope).into()),
+                ("seconds", ((*ns as f64) / 1_000_000_000f64).into()),
+                ("activations", activations.get(id).cloned().unwrap_or(0).into()),
+            ]);
+        }
+    }
+}
+
+/// Writes the `ProfileEntry`s collected by a `SessionBuilder` to `out`
+/// as `listen_and_profile` does with `ProfileFormat::Json`, and reports
+/// them likewise.
+pub(crate) fn write_entries<W: Write>(
+    out: &mut W,
+    config: &crate::config::DiagConfig,
+    entries: &[ProfileEntry]) -> Result<(), DiagError> {
+
+    let data = entries.iter()
+        .map(|entry| ((entry.id, entry.addr.clone(), entry.name.clone(), entry.is_scope), entry.elapsed_ns))
+        .collect::<Vec<_>>();
+    let activations = entries.iter().map(|entry| (entry.id, entry.activations)).collect();
+    record(config, &data, &activations);
+    print_json(out, &data, &activations, config.names())
+}
+
+/// Prints a JSON array with a record per operator (or scope), from the
+/// most to the least time spent.
+fn print_json<W: Write>(
+    out: &mut W,
+    data: &[((usize, Vec<usize>, String, bool), isize)],
+    activations: &std::collections::HashMap<usize, u64>,
+    name_map: &crate::names::NameMap) -> Result<(), DiagError> {
+
+    writeln!(out, "[")?;
+    for (i, ((id, addr, name, is_scope), ns)) in data.iter().enumerate() {
+        let separator = if i + 1 < data.len() { "," } else { "" };
+        let count = activations.get(id).cloned().unwrap_or(0);
+        writeln!(out, "  {{\"id\": {}, \"addr\": [{}], \"name\": {}, \"is_scope\": {}, \"elapsed_ns\": {}, \"activations\": {}, \"mean_activation_ns\": {}}}{}",
             id,
+            addr.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "),
+            crate::status::json_string(name_map.label(addr, name)),
+            is_scope,
+            ns,
+            count,
+            *ns as u64 / std::cmp::max(count, 1),
+            separator)?;
+    }
+    writeln!(out, "]")?;
+    Ok(())
+}
+
+/// Prints a Vega-Lite spec of a stacked bar chart of the time spent in
+/// each (leaf) operator per second, over all workers.
+fn print_chart<W: Write>(
+    out: &mut W,
+    per_second: &[((usize, usize), std::time::Duration, isize)],
+    data: &[((usize, Vec<usize>, String, bool), isize)],
+    operators: &std::collections::HashMap<usize, (Vec<usize>, String)>) -> Result<(), DiagError> {
+
+    // The time of a scope includes the time of the operators it contains.
+    let scopes = data.iter()
+        .filter(|((_, _, _, is_scope), _)| *is_scope)
+        .map(|((id, _, _, _), _)| *id)
+        .collect::<std::collections::HashSet<_>>();
+
+    // (end of the second, operator id) -> ns
+    let mut totals = std::collections::BTreeMap::new();
+    for ((_worker, id), t, ns) in per_second.iter() {
+        *totals.entry((*t, *id)).or_insert(0) += *ns;
+    }
+
+    let rows = totals.into_iter()
+        .filter(|((_, id), _)| !scopes.contains(id))
+        .filter_map(|((t, id), ns)| operators.get(&id).map(|(addr, name)| vec![
+            t.as_secs().into(),
+            format!("{} ({:?})", name, addr).into(),
+            ((ns as f64) / 1_000_000_000f64).into(),
+        ]))
+        .collect::<Vec<_>>();
+
+    crate::commands::vega::write_spec(
+        out,
+        "Time spent in each operator",
+        r#"{"type": "bar"}"#,
+        r#"{
+    "x": {"field": "second", "type": "ordinal", "title": "Time (s)"},
+    "y": {"field": "seconds", "type": "quantitative", "aggregate": "sum", "title": "Time spent (s)"},
+    "color": {"field": "operator", "type": "nominal"}
+  }"#,
+        &["second", "operator", "seconds"],
+        &rows)
+}
+
+/// Prints a matrix of the time spent in each (leaf) operator on each
+/// source worker, with the ratio of the slowest worker to the mean to
+/// spot skew.
+fn print_per_worker<W: Write>(
+    out: &mut W,
+    per_second: &[((usize, usize), std::time::Duration, isize)],
+    data: &[((usize, Vec<usize>, String, bool), isize)],
+    operators: &std::collections::HashMap<usize, (Vec<usize>, String)>) -> Result<(), DiagError> {
+
+    // (operator id, worker) -> ns
+    let mut totals = std::collections::HashMap::new();
+    for ((worker, id), _t, ns) in per_second.iter() {
+        *totals.entry((*id, *worker)).or_insert(0) += *ns;
+    }
+    let workers = totals.keys().map(|&(_, worker)| worker + 1).max().unwrap_or(0);
+
+    write!(out, "Name\tAddr")?;
+    for worker in 0..workers {
+        write!(out, "\tworker {} (s)", worker)?;
+    }
+    writeln!(out, "\tmax/mean")?;
+
+    // In the order of `data`, from the most to the least time spent.
+    for ((id, _, _, _), _) in data.iter().filter(|((_, _, _, is_scope), _)| !is_scope) {
+        let (addr, name) = match operators.get(id) {
+            Some(operator) => operator,
+            None => continue,
+        };
+        let values = (0..workers)
+            .map(|worker| totals.get(&(*id, worker)).cloned().unwrap_or(0) as f64 / 1_000_000_000f64)
+            .collect::<Vec<_>>();
+        let mean = values.iter().sum::<f64>() / workers.max(1) as f64;
+        let max = values.iter().cloned().fold(0f64, f64::max);
+
+        write!(out, "{}\t{:?}", name, addr)?;
+        for value in values.iter() {
+            write!(out, "\t{:.3}", value)?;
+        }
+        if mean > 0.0 {
+            writeln!(out, "\t{:.2}", max / mean)?;
+        } else {
+            writeln!(out, "\t-")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the time spent in the operators of each group.
+///
+/// Only leaf operators are summed, as the time of a scope includes the
+/// time of the operators it contains.
+fn print_groups<W: Write>(
+    out: &mut W,
+    group_by: &GroupBy,
+    data: &[((usize, Vec<usize>, String, bool), isize)],
+    operators: &std::collections::HashMap<usize, (Vec<usize>, String)>) -> Result<(), DiagError> {
+
+    let names = operators.values().cloned().collect::<std::collections::HashMap<_, _>>();
+
+    // group address -> (# of operators, ns)
+    let mut groups = std::collections::HashMap::new();
+    if let GroupBy::Scopes(scopes) = group_by {
+        for scope in scopes.iter() {
+            groups.insert(scope.clone(), (0, 0));
+        }
+    }
+
+    for ((_, addr, _, _), ns) in data.iter().filter(|((_, _, _, is_scope), _)| !is_scope) {
+        let keys = match group_by {
+            GroupBy::PrefixLength(n) => vec![addr[..std::cmp::min(*n, addr.len())].to_vec()],
+            GroupBy::Scopes(scopes) => scopes.iter().filter(|scope| addr.starts_with(scope)).cloned().collect(),
+        };
+        for key in keys.into_iter() {
+            let entry = groups.entry(key).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += *ns;
+        }
+    }
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_unstable_by_key(|&(ref addr, (_, ns))| (std::cmp::Reverse(ns), addr.clone()));
+
+    for (addr, (count, ns)) in groups.into_iter() {
+        writeln!(out, "{}\t(addr={:?}, {} operators):\t{:e} s",
+            names.get(&addr).map(|name| name.as_str()).unwrap_or("-"),
             addr,
-            (ns as f64) / 1_000_000_000f64);
+            count,
+            (ns as f64) / 1_000_000_000f64)?;
     }
+    Ok(())
+}
+
+/// Prints every long activation, followed by a summary per operator and
+/// the worst offenders.
+fn print_long_activations<W: Write>(
+    out: &mut W,
+    long_activation_ms: u64,
+    long: &[((usize, usize), std::time::Duration, std::time::Duration)],
+    operators: &std::collections::HashMap<usize, (Vec<usize>, String)>) -> Result<(), DiagError> {
 
+    let describe = |id: &usize| operators.get(id)
+        .map(|(addr, name)| format!("{}\t{:?}", name, addr))
+        .unwrap_or_else(|| format!("(id={})\t-", id));
+
+    writeln!(out)?;
+    writeln!(out, "Activations longer than {}ms: {}", long_activation_ms, long.len())?;
+    if long.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Start (ms)\tWorker\tName\tAddr\tDuration (ms)")?;
+    for ((worker, id), start, elapsed) in long.iter() {
+        writeln!(out, "{}\t{}\t{}\t{:.3}", start.as_millis(), worker, describe(id), elapsed.as_secs_f64() * 1000.0)?;
+    }
+
+    // operator_id -> (# of long activations, longest)
+    let mut per_operator = std::collections::HashMap::new();
+    for ((_, id), _, elapsed) in long.iter() {
+        let entry = per_operator.entry(*id).or_insert((0, std::time::Duration::default()));
+        entry.0 += 1;
+        entry.1 = std::cmp::max(entry.1, *elapsed);
+    }
+    let mut per_operator = per_operator.into_iter().collect::<Vec<_>>();
+    per_operator.sort_unstable_by_key(|&(id, (count, longest))| (std::cmp::Reverse(count), std::cmp::Reverse(longest), id));
+
+    writeln!(out)?;
+    writeln!(out, "Name\tAddr\t# of long activations\tLongest (ms)")?;
+    for (id, (count, longest)) in per_operator.into_iter() {
+        writeln!(out, "{}\t{}\t{:.3}", describe(&id), count, longest.as_secs_f64() * 1000.0)?;
+    }
+
+    let mut worst = long.to_vec();
+    worst.sort_unstable_by_key(|&(_, _, elapsed)| std::cmp::Reverse(elapsed));
+
+    writeln!(out)?;
+    writeln!(out, "Worst offenders:")?;
+    writeln!(out, "Duration (ms)\tWorker\tName\tAddr\tStart (ms)")?;
+    for ((worker, id), start, elapsed) in worst.into_iter().take(10) {
+        writeln!(out, "{:.3}\t{}\t{}\t{}", elapsed.as_secs_f64() * 1000.0, worker, describe(&id), start.as_millis())?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use timely::dataflow::operators::ToStream;
+    use timely::logging::{ScheduleEvent, StartStop};
+
+    #[test]
+    fn activations_skip_unmatched_events() {
+        let schedule = |time, id, start_stop| (Duration::from_millis(time), 0, Schedule(ScheduleEvent { id, start_stop }));
+        let events = vec![
+            // Its start was logged before the stream was cut.
+            schedule(1, 3, StartStop::Stop),
+            schedule(2, 3, StartStop::Start),
+            schedule(5, 3, StartStop::Stop),
+            // Never stopped.
+            schedule(6, 4, StartStop::Start),
+        ];
+        let captured = timely::execute_directly(move |worker| {
+            worker.dataflow::<Duration, _, _>(|scope| activations(&events.to_stream(scope)).capture())
+        });
+        let activations = captured.extract().into_iter().flat_map(|(_, data)| data).collect::<Vec<_>>();
+        assert_eq!(activations, vec![((0, 3), Duration::from_millis(2), Duration::from_millis(3))]);
+    }
+
+    #[test]
+    fn histogram_buckets_are_exact_for_short_durations() {
+        for ns in 0..1 << (PRECISION_BITS + 1) {
+            assert_eq!(Histogram::highest(Histogram::bucket(ns)), ns);
+        }
+    }
+
+    #[test]
+    fn histogram_buckets_round_trip_within_precision() {
+        for &ns in &[256, 257, 1_000, 123_456, 999_999_999, 1 << 40, (1 << 50) + 12_345] {
+            let bucket = Histogram::bucket(ns);
+            let highest = Histogram::highest(bucket);
+            assert!(highest >= ns, "{} is above its bucket's highest {}", ns, highest);
+            assert_eq!(Histogram::bucket(highest), bucket);
+            assert!((highest - ns) as f64 <= ns as f64 / f64::from(1u32 << PRECISION_BITS));
+        }
+    }
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), Duration::from_millis(100));
+        assert_eq!(histogram.quantile(1.0), Duration::from_millis(100));
+        for &(q, ms) in &[(0.0, 1), (0.5, 50), (0.95, 95), (0.99, 99)] {
+            let quantile = histogram.quantile(q).as_nanos() as f64;
+            let expected = ms as f64 * 1e6;
+            assert!(quantile >= expected && quantile <= expected * (1.0 + 1.0 / f64::from(1u32 << PRECISION_BITS)),
+                "quantile {} is {}ns, expected about {}ms", q, quantile, ms);
+        }
+        assert_eq!(Histogram::default().quantile(0.5), Duration::from_nanos(0));
+    }
+
+    #[test]
+    fn histogram_merge() {
+        let (mut all, mut odd, mut even) = (Histogram::default(), Histogram::default(), Histogram::default());
+        for us in 1..=1_000 {
+            let elapsed = Duration::from_micros(us * 37);
+            all.record(elapsed);
+            if us % 2 == 1 { odd.record(elapsed) } else { even.record(elapsed) }
+        }
+        odd.merge(&even);
+        assert_eq!(odd, all);
+    }
+}
@@ -0,0 +1,95 @@
+//! "profile-diff" subcommand: compares two profiles written by
+//! `profile --format json`, to quantify performance changes between
+//! versions of a program.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::DiagError;
+use crate::json::Json;
+
+/// An operator's name, whether it's a scope, and the time spent in it
+/// (in ns), by address.
+type Profile = BTreeMap<Vec<usize>, (String, bool, f64)>;
+
+fn read(path: &Path) -> Result<Profile, DiagError> {
+    let invalid = |what: &str| DiagError::Parse {
+        context: format!("{} is not a profile written by profile --format json ({})", path.display(), what),
+        source: None,
+    };
+
+    let mut profile = BTreeMap::new();
+    for entry in Json::read(path)?.as_array().ok_or_else(|| invalid("not an array"))? {
+        let addr = entry.get("addr").and_then(Json::as_addr).ok_or_else(|| invalid("operator without addr"))?;
+        let name = entry.get("name").and_then(Json::as_str).ok_or_else(|| invalid("operator without name"))?;
+        let is_scope = entry.get("is_scope") == Some(&Json::Bool(true));
+        let elapsed_ns = entry.get("elapsed_ns").and_then(Json::as_f64).ok_or_else(|| invalid("operator without elapsed_ns"))?;
+        profile.insert(addr, (name.to_string(), is_scope, elapsed_ns));
+    }
+    Ok(profile)
+}
+
+/// Prints the time spent in each operator before and after, with the
+/// difference and the relative change, largest regression first.
+///
+/// Operators are matched by address; those only in one profile count
+/// as having taken no time in the other. Scopes are marked, as their
+/// time includes that of their operators. Only operators whose time
+/// changed by at least `min_change_ns` are printed.
+pub fn diff(before: &Path, after: &Path, min_change_ns: f64, report: Option<&crate::report::Report>) -> Result<(), DiagError> {
+    let before = read(before)?;
+    let after = read(after)?;
+
+    let mut rows = before.keys().chain(after.keys())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .map(|addr| {
+            let old = before.get(addr);
+            let new = after.get(addr);
+            let (name, is_scope) = new.or(old).map(|(name, is_scope, _)| (name.as_str(), *is_scope)).expect("operator in neither profile");
+            let old_ns = old.map_or(0.0, |(_, _, ns)| *ns);
+            let new_ns = new.map_or(0.0, |(_, _, ns)| *ns);
+            (addr, name, is_scope, old_ns, new_ns)
+        })
+        .filter(|(_, _, _, old_ns, new_ns)| (new_ns - old_ns).abs() >= min_change_ns)
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| (b.4 - b.3).partial_cmp(&(a.4 - a.3)).unwrap_or(std::cmp::Ordering::Equal));
+
+    let seconds = |ns: f64| ns / 1_000_000_000f64;
+    let change = |old_ns: f64, new_ns: f64| if old_ns > 0.0 {
+        format!("{:+.1}%", (new_ns - old_ns) * 100.0 / old_ns)
+    } else if new_ns > 0.0 {
+        "new".to_string()
+    } else {
+        "-".to_string()
+    };
+
+    println!("Delta (s)\tChange\tBefore (s)\tAfter (s)\tOperator");
+    for (addr, name, is_scope, old_ns, new_ns) in rows.iter() {
+        if let Some(report) = report {
+            report.record("profile_change", vec![
+                ("addr", format!("{:?}", addr).into()),
+                ("name", (*name).into()),
+                ("scope", (*is_scope).into()),
+                ("before_seconds", seconds(*old_ns).into()),
+                ("after_seconds", seconds(*new_ns).into()),
+            ]);
+        }
+        println!("{:+.6}\t{}\t{:.6}\t{:.6}\t{}{} {:?}",
+            seconds(new_ns - old_ns),
+            if *new_ns <= 0.0 && *old_ns > 0.0 { "gone".to_string() } else { change(*old_ns, *new_ns) },
+            seconds(*old_ns),
+            seconds(*new_ns),
+            if *is_scope { "[scope] " } else { "" },
+            name,
+            addr);
+    }
+
+    // Scopes include their operators' time: only sum leaf operators.
+    let total = |profile: &Profile| profile.values().filter(|(_, is_scope, _)| !is_scope).map(|(_, _, ns)| ns).sum::<f64>();
+    let (old_total, new_total) = (total(&before), total(&after));
+    println!("{:+.6}\t{}\t{:.6}\t{:.6}\ttotal (operators)",
+        seconds(new_total - old_total), change(old_total, new_total), seconds(old_total), seconds(new_total));
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! "progress" subcommand: reports the progress updates generated for
+//! each operator, to diagnose progress-tracking overhead.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::{Map, Filter, capture::{Capture, extract::Extract}};
+
+use differential_dataflow::collection::AsCollection;
+use differential_dataflow::operators::Consolidate;
+
+use timely::logging::TimelyEvent::{Operates, Progress, PushProgress};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+// What a count is about, as part of the key of each count.
+
+/// Changes to the messages outstanding at an operator's inputs.
+const MESSAGE_UPDATES: u8 = 0;
+/// Capabilities acquired at an operator's outputs.
+const ACQUIRED: u8 = 1;
+/// Capabilities released at an operator's outputs.
+const RELEASED: u8 = 2;
+/// Progress batches sent in a scope (keyed by the scope's address).
+const BATCHES: u8 = 3;
+
+/// Prints, for each operator, the number of progress updates about its
+/// inputs and outputs, the capability churn (acquired and released
+/// capabilities), and the number of times its progress was pushed,
+/// followed by the number of progress batches per scope.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a differential-dataflow program to count the updates in the
+/// `Progress` events sent by each worker, keyed by operator address,
+/// and the `PushProgress` events, keyed by operator id;
+/// 3. prints the counts, sorted by the total number of updates.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams) -> Result<(), crate::DiagError> {
+
+    let name_map = config.names();
+    let streams_w = streams.clone();
+
+    let (operators_send, operators_recv) = ::std::sync::mpsc::channel();
+    let operators_send = Arc::new(Mutex::new(operators_send));
+
+    let (counts_send, counts_recv) = ::std::sync::mpsc::channel();
+    let counts_send = Arc::new(Mutex::new(counts_send));
+
+    let (pushes_send, pushes_recv) = ::std::sync::mpsc::channel();
+    let pushes_send = Arc::new(Mutex::new(pushes_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let operators_send: std::sync::mpsc::Sender<_> = operators_send.lock().expect("cannot lock operators_send").clone();
+        let counts_send: std::sync::mpsc::Sender<_> = counts_send.lock().expect("cannot lock counts_send").clone();
+        let pushes_send: std::sync::mpsc::Sender<_> = pushes_send.lock().expect("cannot lock pushes_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            let stream = replayer.replay_with_shutdown_into(scope, is_running_w.clone());
+
+            stream
+                .filter(move |(_, w, _)| *w == structure_worker)
+                .flat_map(|(_, _, x)| if let Operates(event) = x { Some((event.id, (event.addr, event.name))) } else { None })
+                .capture_into(operators_send);
+
+            // Only updates as sent are counted, rather than once more by
+            // each receiver.
+            stream
+                .flat_map(|(t, _, x)| match x {
+                    Progress(event) if event.is_send => {
+                        let ts_clip = Duration::from_secs(t.as_secs() + 1);
+                        let addr = event.addr;
+                        let node_addr = |node: usize| {
+                            let mut node_addr = addr.clone();
+                            node_addr.push(node);
+                            node_addr
+                        };
+                        let mut counts = Vec::with_capacity(event.messages.len() + event.internal.len() + 1);
+                        for (node, _port, _time, _diff) in event.messages.iter() {
+                            counts.push(((node_addr(*node), MESSAGE_UPDATES), ts_clip, 1));
+                        }
+                        for (node, _port, _time, diff) in event.internal.iter() {
+                            let counter = if *diff > 0 { ACQUIRED } else { RELEASED };
+                            counts.push(((node_addr(*node), counter), ts_clip, diff.abs() as isize));
+                        }
+                        counts.push(((addr.clone(), BATCHES), ts_clip, 1));
+                        counts
+                    },
+                    _ => Vec::new(),
+                })
+                .as_collection()
+                .consolidate()
+                .inner
+                .capture_into(counts_send);
+
+            stream
+                .flat_map(|(t, _, x)| if let PushProgress(event) = x {
+                    Some((event.op_id, Duration::from_secs(t.as_secs() + 1), 1))
+                } else { None })
+                .as_collection()
+                .consolidate()
+                .inner
+                .capture_into(pushes_send);
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop collecting progress data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let operators = operators_recv.extract().into_iter().flat_map(|(_t, v)| v).collect::<HashMap<_, _>>();
+    let names = operators.values().cloned().collect::<HashMap<_, _>>();
+    let label = |addr: &Vec<usize>| names.get(addr).map(|n| name_map.label(addr, n)).unwrap_or("<unknown>");
+
+    let mut counts = HashMap::new();
+    for (key, _t, count) in counts_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        *counts.entry(key).or_insert(0) += count;
+    }
+
+    // addr -> (message updates, acquired, released, pushes)
+    let mut per_operator: HashMap<Vec<usize>, (isize, isize, isize, isize)> = HashMap::new();
+    let mut batches = Vec::new();
+    for ((addr, counter), count) in counts.into_iter() {
+        let entry = per_operator.entry(addr.clone()).or_insert((0, 0, 0, 0));
+        match counter {
+            MESSAGE_UPDATES => entry.0 += count,
+            ACQUIRED => entry.1 += count,
+            RELEASED => entry.2 += count,
+            _ => batches.push((addr, count)),
+        }
+    }
+    for (id, _t, count) in pushes_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+        if let Some((addr, _)) = operators.get(&id) {
+            per_operator.entry(addr.clone()).or_insert((0, 0, 0, 0)).3 += count;
+        }
+    }
+    // Scopes only appear because of their batches.
+    per_operator.retain(|_, (messages, acquired, released, pushes)| *messages + *acquired + *released + *pushes > 0);
+
+    let mut data = per_operator.into_iter().collect::<Vec<_>>();
+    data.sort_unstable_by_key(|(addr, (messages, acquired, released, _))| (std::cmp::Reverse(messages + acquired + released), addr.clone()));
+
+    println!("Name\tAddr\tMessage updates\tCapabilities acquired\tCapabilities released\tProgress pushes");
+    for (addr, (messages, acquired, released, pushes)) in data.into_iter() {
+        if let Some(report) = config.reports() {
+            report.record("progress", vec![
+                ("name", label(&addr).into()),
+                ("addr", format!("{:?}", addr).into()),
+                ("message_updates", messages.into()),
+                ("capabilities_acquired", acquired.into()),
+                ("capabilities_released", released.into()),
+                ("progress_pushes", pushes.into()),
+            ]);
+        }
+        println!("{}\t{:?}\t{}\t{}\t{}\t{}", label(&addr), addr, messages, acquired, released, pushes);
+    }
+
+    batches.sort_unstable_by_key(|(addr, count)| (std::cmp::Reverse(*count), addr.clone()));
+
+    println!();
+    println!("Scope\tAddr\tProgress batches sent");
+    for (addr, count) in batches.into_iter() {
+        println!("{}\t{:?}\t{}", label(&addr), addr, count);
+    }
+
+    Ok(())
+}
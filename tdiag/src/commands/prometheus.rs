@@ -0,0 +1,298 @@
+//! "export prometheus" subcommand: exposes the source computation's
+//! operator, channel and arrangement statistics as Prometheus metrics,
+//! updated while the computation runs.
+//!
+//! The metrics are the ones of `telemetry`, recorded by a `metrics`
+//! recorder installed for the session, so that they're named and
+//! labelled like the ones other subcommands emit. Like the dashboard of
+//! `serve`, they're served with a minimal HTTP responder, in Prometheus'
+//! text exposition format, which needs no additional dependencies.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::DiagError;
+use crate::commands::live::Update;
+use crate::names::NameMap;
+
+use metrics::{Counter, CounterFn, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+/// Serves the metrics on `addr` (at `/metrics`) until the user stops the
+/// session:
+///
+/// * `tdiag_operator_scheduled_seconds`: the time each operator was
+///   scheduled, summed over the source workers;
+/// * `tdiag_channel_messages_total` and `tdiag_channel_records_total`:
+///   the messages and records sent over each channel;
+/// * `tdiag_arrangement_tuples` (with `differential_streams`): the tuples
+///   in the arrangements of each operator, on each source worker.
+///
+/// Fails if another `metrics` recorder is already installed.
+pub fn serve(
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: Option<crate::source::LogStreams>,
+    addr: SocketAddr) -> Result<(), DiagError> {
+
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| DiagError::io(format!("cannot listen on {}", addr), e))?;
+    listener.set_nonblocking(true)?;
+
+    let recorder = PrometheusRecorder::default();
+    metrics::set_global_recorder(recorder.clone())
+        .map_err(|_| DiagError::InvalidArgs("Cannot export Prometheus metrics: another metrics recorder is already installed".to_string()))?;
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let (worker_handles, updates_recv) = crate::commands::live::spawn(config, &timely_streams, differential_streams.as_ref(), is_running.clone())?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_s = stop.clone();
+    let name_map = config.names().clone();
+    let server = std::thread::Builder::new()
+        .name("tdiag-prometheus".to_string())
+        .spawn(move || run_server(listener, updates_recv, &stop_s, name_map, &recorder))?;
+
+    crate::status::info("serving", &format!("Serving Prometheus metrics on http://{}/metrics", addr));
+
+    let mut streams = vec![&timely_streams];
+    streams.extend(differential_streams.iter());
+    crate::source::await_stop(&streams, "Press enter to stop exporting metrics");
+
+    is_running.store(false, Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    stop.store(true, Ordering::Release);
+    server.join().map_err(|_| DiagError::Internal("the metrics server panicked".to_string()))?;
+
+    Ok(())
+}
+
+/// The exported state, maintained from the workers' updates.
+#[derive(Default)]
+struct Metrics {
+    operators: BTreeMap<usize, (Vec<usize>, String)>,
+    /// The addresses of the scopes, i.e. of the operators enclosing
+    /// others.
+    scopes: HashSet<Vec<usize>>,
+    channels: BTreeMap<usize, (Vec<usize>, (usize, usize), (usize, usize))>,
+    /// Time scheduled (in ns), by operator id.
+    elapsed: HashMap<usize, u64>,
+    /// (messages, records) sent, by channel id.
+    sent: HashMap<usize, (u64, u64)>,
+    /// Tuples in the arrangements of each operator, by (worker, operator
+    /// id).
+    tuples: BTreeMap<(usize, usize), i64>,
+}
+
+impl Metrics {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Operator(id, addr, name) => {
+                self.scopes.extend((1..addr.len()).map(|depth| addr[..depth].to_vec()));
+                self.operators.insert(id, (addr, name));
+            }
+            Update::Channel(id, scope_addr, source, target) => { self.channels.insert(id, (scope_addr, source, target)); }
+            Update::Elapsed(elapsed) => for (id, ns) in elapsed {
+                *self.elapsed.entry(id).or_insert(0) += ns;
+            },
+            Update::Sent(sent) => for (id, (messages, records)) in sent {
+                let entry = self.sent.entry(id).or_insert((0, 0));
+                entry.0 += messages;
+                entry.1 += records;
+            },
+            Update::Tuples(tuples) => for (key, diff) in tuples {
+                *self.tuples.entry(key).or_insert(0) += diff;
+            },
+        }
+    }
+
+    /// Records the current state through `telemetry`.
+    fn publish(&self, name_map: &NameMap) {
+        for (id, (addr, name)) in self.operators.iter() {
+            let is_scope = self.scopes.contains(addr);
+            let ns = self.elapsed.get(id).cloned().unwrap_or(0);
+            crate::telemetry::operator_scheduled(*id, addr, name_map.label(addr, name), is_scope, ns as isize);
+        }
+        for (id, (scope_addr, source, target)) in self.channels.iter() {
+            let (messages, records) = self.sent.get(id).cloned().unwrap_or((0, 0));
+            crate::telemetry::channel_sent(*id, scope_addr, *source, *target, messages, records);
+        }
+        for ((worker, operator), tuples) in self.tuples.iter() {
+            // Named once the operator is known.
+            if let Some((addr, name)) = self.operators.get(operator) {
+                crate::telemetry::arrangement_size(*worker, *operator, name_map.label(addr, name), *tuples as isize);
+            }
+        }
+    }
+}
+
+/// A counter's or gauge's value, as the bits of an `f64`.
+#[derive(Default)]
+struct Value(AtomicU64);
+
+impl Value {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Acquire))
+    }
+
+    fn update(&self, f: impl Fn(f64) -> f64) {
+        let _ = self.0.fetch_update(Ordering::AcqRel, Ordering::Acquire, |bits| Some(f(f64::from_bits(bits)).to_bits()));
+    }
+}
+
+impl CounterFn for Value {
+    fn increment(&self, value: u64) {
+        self.update(|current| current + value as f64);
+    }
+
+    fn absolute(&self, value: u64) {
+        self.update(|current| current.max(value as f64));
+    }
+}
+
+impl GaugeFn for Value {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+/// A `metrics` recorder keeping the last value of each counter and
+/// gauge, to render them in Prometheus' text exposition format.
+/// Histograms aren't recorded (`telemetry` has none).
+#[derive(Clone, Default)]
+struct PrometheusRecorder {
+    counters: Arc<Mutex<HashMap<Key, Arc<Value>>>>,
+    gauges: Arc<Mutex<HashMap<Key, Arc<Value>>>>,
+}
+
+impl PrometheusRecorder {
+    fn value(values: &Mutex<HashMap<Key, Arc<Value>>>, key: &Key) -> Arc<Value> {
+        values.lock().expect("cannot lock the metrics").entry(key.clone()).or_default().clone()
+    }
+
+    /// The recorded metrics in Prometheus' text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (kind, values) in [("counter", &self.counters), ("gauge", &self.gauges)].iter() {
+            let mut samples = values.lock().expect("cannot lock the metrics").iter()
+                .map(|(key, value)| {
+                    let labels = key.labels()
+                        .map(|label| format!("{}=\"{}\"", label.key(), escape(label.value())))
+                        .collect::<Vec<_>>();
+                    (key.name().to_string(), labels.join(","), value.get())
+                })
+                .collect::<Vec<_>>();
+            samples.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+            let mut previous: Option<String> = None;
+            for (name, labels, value) in samples {
+                if previous.as_ref() != Some(&name) {
+                    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                }
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+                previous = Some(name);
+            }
+        }
+        out
+    }
+}
+
+impl Recorder for PrometheusRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        Counter::from_arc(Self::value(&self.counters, key))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(Self::value(&self.gauges, key))
+    }
+
+    fn register_histogram(&self, _key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Accepts scrapes and answers them with the current metrics, until
+/// `stop` is set.
+fn run_server(listener: TcpListener, updates: Receiver<Update>, stop: &AtomicBool, name_map: NameMap, recorder: &PrometheusRecorder) {
+    let mut metrics = Metrics::default();
+
+    while !stop.load(Ordering::Acquire) {
+        while let Ok(update) = updates.try_recv() {
+            metrics.apply(update);
+        }
+
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    metrics.publish(&name_map);
+                    respond(stream, recorder);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    crate::status::warn("serve_failed", &format!("Cannot accept a metrics connection: {}", e));
+                    break;
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Answers a scrape (or any other request).
+fn respond(mut stream: TcpStream, recorder: &PrometheusRecorder) {
+    let timeout = Some(Duration::from_secs(1));
+    if stream.set_nonblocking(false).and_then(|()| stream.set_read_timeout(timeout)).and_then(|()| stream.set_write_timeout(timeout)).is_err() {
+        return;
+    }
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let _ = match path {
+        "/metrics" => {
+            let body = recorder.render();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   body.len(), body)
+        }
+        _ => write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+}
@@ -0,0 +1,133 @@
+//! "record" subcommand: saves the log streams to a recording directory,
+//! so that they can be analysed later without re-running the source
+//! computation.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::DiagError;
+use crate::source::{LogEvent, LogStreams};
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::EventWriter;
+use timely::dataflow::operators::capture::event::{Event, EventIterator, EventPusher};
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+use timely::progress::ChangeBatch;
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::receive::DecodedEvents;
+use tdiag_connect::recording::{self, RecordedStream, StreamKind};
+
+type Recorded<E> = DecodedEvents<Duration, (Duration, WorkerIdentifier, E)>;
+
+/// Writes each timely log stream (and each differential log stream, with
+/// `differential`) to its own file in the new recording directory `dir`,
+/// until the streams end or the user presses enter.
+///
+/// Streams that are cut short still make a complete recording: the
+/// capabilities held at that point are released.
+pub fn record(config: &crate::config::DiagConfig, dir: &Path, differential: bool) -> Result<(), DiagError> {
+    // Before waiting for connections, so that an unusable directory is
+    // reported right away.
+    recording::create_dir(dir)?;
+
+    let (timely_streams, differential_streams) = if differential {
+        let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
+        (timely_streams, Some(differential_streams))
+    } else {
+        (config.timely_streams()?, None)
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut writers = spawn_writers::<TimelyEvent>(&timely_streams, StreamKind::Timely, dir, &stop)?;
+    if let Some(differential_streams) = &differential_streams {
+        writers.extend(spawn_writers::<DifferentialEvent>(differential_streams, StreamKind::Differential, dir, &stop)?);
+    }
+
+    crate::status::info("recording", &format!("Recording {} streams to {}", writers.len(), dir.display()));
+
+    let all_streams = std::iter::once(&timely_streams).chain(differential_streams.iter()).collect::<Vec<_>>();
+    crate::source::await_stop(&all_streams, "Press enter to stop recording");
+
+    stop.store(true, Ordering::Release);
+
+    let mut streams = Vec::new();
+    for writer in writers {
+        let (stream, records) = writer.join()
+            .map_err(|_| DiagError::Internal("a recording thread panicked".to_string()))?;
+        crate::status::info("stream_recorded", &format!("{} stream {}: recorded {} records", stream.kind.name(), stream.index, records));
+        streams.push(stream);
+    }
+
+    recording::write_manifest(dir, &streams)?;
+
+    Ok(())
+}
+
+/// Starts a thread per stream, writing it to a file in `dir` until it
+/// ends or `stop` is set.
+fn spawn_writers<E>(
+    streams: &LogStreams,
+    kind: StreamKind,
+    dir: &Path,
+    stop: &Arc<AtomicBool>) -> Result<Vec<JoinHandle<(RecordedStream, usize)>>, DiagError>
+where E: LogEvent + Abomonation + Clone + Send + 'static {
+
+    // All streams are read here, rather than shared among diagnostic workers.
+    streams.readers::<Duration, (Duration, WorkerIdentifier, E)>(0, 1)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, events)| -> Result<_, DiagError> {
+            let path = dir.join(recording::stream_file_name(kind, index));
+            let file = File::create(&path)
+                .map_err(|e| DiagError::io(format!("cannot create {}", path.display()), e))?;
+            let stop = stop.clone();
+            let writer = std::thread::Builder::new()
+                .name("tdiag-record".to_string())
+                .spawn(move || {
+                    let records = write_stream::<E>(events, file, &stop);
+                    (RecordedStream { kind, index, path }, records)
+                })?;
+            Ok(writer)
+        })
+        .collect()
+}
+
+/// Copies the events of a stream to `file`, returning the number of
+/// records written.
+fn write_stream<E: Abomonation + Clone>(mut events: Recorded<E>, file: File, stop: &AtomicBool) -> usize {
+    let mut writer = EventWriter::<Duration, (Duration, WorkerIdentifier, E), _>::new(BufWriter::new(file));
+
+    // Every stream implicitly starts with a capability at the minimal time.
+    let mut capabilities = ChangeBatch::new_from(Duration::default(), 1);
+    let mut records = 0;
+
+    loop {
+        match events.next().cloned() {
+            Some(Event::Progress(updates)) => {
+                capabilities.extend(updates.iter().cloned());
+                writer.push(Event::Progress(updates));
+            }
+            Some(Event::Messages(time, data)) => {
+                records += data.len();
+                writer.push(Event::Messages(time, data));
+            }
+            None if events.ended() || stop.load(Ordering::Acquire) => break,
+            None => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+
+    let remaining = capabilities.drain().map(|(t, diff)| (t, -diff)).collect::<Vec<_>>();
+    if !remaining.is_empty() {
+        writer.push(Event::Progress(remaining));
+    }
+
+    records
+}
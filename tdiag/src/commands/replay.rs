@@ -0,0 +1,155 @@
+//! "replay" subcommand: sends recorded log streams to a tdiag instance,
+//! as if they came from a running source computation.
+
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::capture::EventWriter;
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+use timely::progress::ChangeBatch;
+
+use tdiag_connect::recording::{EventFileReader, Recording, StreamKind};
+
+/// Replays each of the timely streams in the `recordings`
+/// (recording directories, or files captured with `tee`) over its own
+/// connection to `addr`.
+///
+/// With `looped`, the recordings are replayed over and over, with
+/// timestamps rebased at each cycle so that time keeps moving forward.
+/// With `realtime`, events are sent at the pace at which they were
+/// recorded rather than as fast as possible.
+pub fn replay(recordings: &[PathBuf], addr: SocketAddr, looped: bool, realtime: bool) -> Result<(), DiagError> {
+    let mut paths = Vec::new();
+    for recording in recordings.iter() {
+        let recording = Recording::open(recording)?;
+        paths.extend(recording.streams_of(StreamKind::Timely).into_iter().map(|s| s.path.clone()));
+    }
+
+    // Each cycle starts after the last timestamp of all recordings, so
+    // that the streams stay aligned.
+    let mut span = Duration::from_millis(1);
+    for path in paths.iter() {
+        read_events(path, |event| {
+            let latest = match event {
+                Event::Progress(updates) => updates.into_iter().map(|(t, _)| t).max(),
+                Event::Messages(t, data) => data.into_iter().map(|(ts, _, _)| ts).chain(Some(t)).max(),
+            };
+            if let Some(latest) = latest {
+                span = std::cmp::max(span, latest + Duration::from_millis(1));
+            }
+            Ok(())
+        })?;
+    }
+
+    let started = Instant::now();
+
+    let handles = paths.iter().map(|path| {
+        let path_r = path.clone();
+        let stream = TcpStream::connect(addr)?;
+        let handle = std::thread::Builder::new()
+            .name("tdiag-replay".to_string())
+            .spawn(move || replay_stream(&path_r, stream, span, looped, realtime, started))?;
+        Ok((path, handle))
+    }).collect::<Result<Vec<_>, DiagError>>()?;
+
+    crate::status::info("replaying", &format!("Replaying {} stream(s) to {}", paths.len(), addr));
+
+    for (path, handle) in handles.into_iter() {
+        // `EventWriter` panics when it can't write, e.g. once tdiag
+        // closed the connection.
+        handle.join().map_err(|_| DiagError::Internal(format!(
+            "the thread replaying {} panicked, did tdiag close the connection?", path.display())))??;
+    }
+
+    Ok(())
+}
+
+/// Replays a single recorded stream over `stream`.
+fn replay_stream(
+    path: &Path,
+    stream: TcpStream,
+    span: Duration,
+    looped: bool,
+    realtime: bool,
+    started: Instant) -> Result<(), DiagError> {
+
+    let mut writer = EventWriter::<Duration, LoggingTuple, _>::new(stream);
+
+    // The capabilities held by the stream, as seen by the receiving end.
+    // Every stream implicitly starts with a capability at the minimal time.
+    let mut capabilities = ChangeBatch::new_from(Duration::default(), 1);
+
+    let mut offset = Duration::default();
+    loop {
+        let next_offset = offset + span;
+
+        read_events(path, |event| {
+            match event {
+                Event::Progress(updates) => {
+                    let mut updates = updates.into_iter().map(|(t, diff)| (t + offset, diff)).collect::<Vec<_>>();
+                    capabilities.extend(updates.iter().cloned());
+                    if looped && capabilities.is_empty() {
+                        // Hold on to a capability for the next cycle,
+                        // otherwise the receiving end considers the
+                        // stream complete.
+                        updates.push((next_offset, 1));
+                        capabilities.update(next_offset, 1);
+                    }
+                    writer.push(Event::Progress(updates));
+                },
+                Event::Messages(t, data) => {
+                    let t = t + offset;
+                    if realtime {
+                        let due = started + t;
+                        let now = Instant::now();
+                        if due > now {
+                            std::thread::sleep(due - now);
+                        }
+                    }
+                    let data = data.into_iter().map(|(ts, worker, event)| (ts + offset, worker, event)).collect();
+                    writer.push(Event::Messages(t, data));
+                },
+            }
+            Ok(())
+        })?;
+
+        if !looped {
+            break;
+        }
+
+        // Like any stream, the next cycle starts with a single capability
+        // at its minimal time.
+        let mut handover = ChangeBatch::new();
+        handover.extend(capabilities.drain().map(|(t, diff)| (t, -diff)));
+        handover.update(next_offset, 1);
+        let handover = handover.into_inner();
+        if !handover.is_empty() {
+            writer.push(Event::Progress(handover));
+        }
+        capabilities.update(next_offset, 1);
+
+        offset = next_offset;
+    }
+
+    // Release any capabilities left over by a truncated recording, so
+    // that the receiving end sees a complete stream.
+    let remaining = capabilities.drain().map(|(t, diff)| (t, -diff)).collect::<Vec<_>>();
+    if !remaining.is_empty() {
+        writer.push(Event::Progress(remaining));
+    }
+
+    Ok(())
+}
+
+/// Decodes all events in a recorded stream, in order.
+fn read_events<F>(path: &Path, mut logic: F) -> Result<(), DiagError>
+where F: FnMut(Event<Duration, LoggingTuple>) -> Result<(), DiagError> {
+    let mut reader = EventFileReader::<Duration, LoggingTuple>::open(path)?;
+    while let Some(event) = reader.next_event()? {
+        logic(event)?;
+    }
+    Ok(())
+}
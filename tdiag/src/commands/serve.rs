@@ -0,0 +1,199 @@
+//! "serve" subcommand: a live dashboard of the source computation's
+//! operators and channels, served over HTTP and updated every second
+//! while the computation runs.
+//!
+//! The browser receives the updates as server-sent events (a plain
+//! HTTP response that stays open), which need no additional
+//! dependencies on either side, unlike websockets.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::DiagError;
+use crate::commands::live::Update;
+use crate::names::NameMap;
+use crate::status::json_string;
+
+static DASHBOARD_HTML: &str = include_str!("serve/dashboard.html");
+
+/// How often the dashboard is updated.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serves a dashboard on `addr` that shows the time spent in each
+/// operator and the records sent over each channel, in total and over
+/// the last second, until the user stops the session.
+///
+/// This module includes `serve/dashboard.html` as a static resource.
+pub fn serve(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    addr: SocketAddr) -> Result<(), DiagError> {
+
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| DiagError::io(format!("cannot listen on {}", addr), e))?;
+    listener.set_nonblocking(true)?;
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    // Without differential streams, arrangements aren't shown.
+    let (worker_handles, updates_recv) = crate::commands::live::spawn(config, &streams, None, is_running.clone())?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_s = stop.clone();
+    let name_map = config.names().clone();
+    let server = std::thread::Builder::new()
+        .name("tdiag-serve".to_string())
+        .spawn(move || run_server(listener, updates_recv, &stop_s, name_map))?;
+
+    crate::status::info("serving", &format!("Serving the dashboard on http://{}/", addr));
+
+    crate::source::await_stop(&[&streams], "Press enter to stop serving the dashboard");
+
+    is_running.store(false, Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    // Sends a final update with the last events.
+    stop.store(true, Ordering::Release);
+    server.join().map_err(|_| DiagError::Internal("the dashboard server panicked".to_string()))?;
+
+    Ok(())
+}
+
+/// The dashboard's state, maintained from the workers' updates.
+#[derive(Default)]
+struct Dashboard {
+    operators: BTreeMap<usize, (Vec<usize>, String)>,
+    channels: BTreeMap<usize, (Vec<usize>, (usize, usize), (usize, usize))>,
+    /// (total, since the last update), by operator id.
+    elapsed: HashMap<usize, (u64, u64)>,
+    /// (total, since the last update), by channel id.
+    records: HashMap<usize, (u64, u64)>,
+}
+
+impl Dashboard {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Operator(id, addr, name) => { self.operators.insert(id, (addr, name)); }
+            Update::Channel(id, scope_addr, source, target) => { self.channels.insert(id, (scope_addr, source, target)); }
+            Update::Elapsed(elapsed) => for (id, ns) in elapsed {
+                let entry = self.elapsed.entry(id).or_insert((0, 0));
+                entry.0 += ns;
+                entry.1 += ns;
+            },
+            Update::Sent(sent) => for (id, (_messages, records)) in sent {
+                let entry = self.records.entry(id).or_insert((0, 0));
+                entry.0 += records;
+                entry.1 += records;
+            },
+            Update::Tuples(_) => (),
+        }
+    }
+
+    /// The current state as JSON; starts a new update interval.
+    fn snapshot(&mut self, name_map: &NameMap) -> String {
+        let addr = |addr: &[usize]| format!("[{}]", addr.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(","));
+
+        let operators = self.operators.iter().map(|(id, (operator_addr, name))| {
+            let (total, recent) = self.elapsed.get(id).cloned().unwrap_or((0, 0));
+            format!("{{\"id\":{},\"addr\":{},\"name\":{},\"total_ns\":{},\"recent_ns\":{}}}",
+                    id, addr(operator_addr), json_string(name_map.label(operator_addr, name)), total, recent)
+        }).collect::<Vec<_>>();
+
+        let channels = self.channels.iter().map(|(id, (scope_addr, source, target))| {
+            let (total, recent) = self.records.get(id).cloned().unwrap_or((0, 0));
+            format!("{{\"id\":{},\"scope_addr\":{},\"source\":[{},{}],\"target\":[{},{}],\"records\":{},\"recent_records\":{}}}",
+                    id, addr(scope_addr), source.0, source.1, target.0, target.1, total, recent)
+        }).collect::<Vec<_>>();
+
+        for (_, recent) in self.elapsed.values_mut().chain(self.records.values_mut()) {
+            *recent = 0;
+        }
+
+        format!("{{\"operators\":[{}],\"channels\":[{}]}}", operators.join(","), channels.join(","))
+    }
+}
+
+/// Accepts browser connections and pushes the dashboard's state to
+/// them every `UPDATE_INTERVAL`, until `stop` is set.
+fn run_server(listener: TcpListener, updates: Receiver<Update>, stop: &AtomicBool, name_map: NameMap) {
+    let mut dashboard = Dashboard::default();
+    let mut clients: Vec<TcpStream> = Vec::new();
+    let mut last_update = Instant::now();
+
+    loop {
+        let stopping = stop.load(Ordering::Acquire);
+
+        while let Ok(update) = updates.try_recv() {
+            dashboard.apply(update);
+        }
+
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => if let Some(client) = respond(stream) {
+                    clients.push(client);
+                },
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    crate::status::warn("serve_failed", &format!("Cannot accept a dashboard connection: {}", e));
+                    break;
+                }
+            }
+        }
+
+        if stopping || last_update.elapsed() >= UPDATE_INTERVAL {
+            let event = format!("data: {}\n\n", dashboard.snapshot(&name_map));
+            // Clients that went away are dropped.
+            clients = clients.into_iter()
+                .filter_map(|mut client| client.write_all(event.as_bytes()).and_then(|()| client.flush()).ok().map(|()| client))
+                .collect();
+            last_update = Instant::now();
+        }
+
+        if stopping {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Answers a browser's request: the page, or the stream of updates (in
+/// which case the connection is returned to push them).
+fn respond(mut stream: TcpStream) -> Option<TcpStream> {
+    let timeout = Some(Duration::from_secs(1));
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(timeout).ok()?;
+    stream.set_write_timeout(timeout).ok()?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        let read = stream.read(&mut buf).ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    match path {
+        "/" | "/index.html" => {
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           DASHBOARD_HTML.len(), DASHBOARD_HTML);
+            None
+        }
+        "/events" => {
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n").ok()?;
+            Some(stream)
+        }
+        _ => {
+            let _ = write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            None
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! "differential skew" subcommand: estimates how evenly the updates to
+//! each arrangement are spread over the source workers, to help find hot
+//! keys and skewed joins.
+//!
+//! Differential's log events don't identify keys, so skew is estimated
+//! from the sizes of the batches each worker adds to an arrangement: a
+//! hot key shows up as one worker receiving most of the updates.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::{TimelyEvent::Operates, WorkerIdentifier};
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Tuples added to an arrangement, by (operator id, window index) and
+/// source worker.
+type Updates = HashMap<(usize, u64), HashMap<WorkerIdentifier, u64>>;
+
+/// The ratio of the largest to the mean number of tuples per worker (1.0
+/// when evenly spread, `workers` when a single worker gets them all).
+fn skew(per_worker: &HashMap<WorkerIdentifier, u64>, workers: usize) -> f64 {
+    let total: u64 = per_worker.values().sum();
+    let max = per_worker.values().cloned().max().unwrap_or(0);
+    if total == 0 || workers == 0 {
+        1.0
+    } else {
+        max as f64 * workers as f64 / total as f64
+    }
+}
+
+/// Prints, for each arrangement, its share of all updates, the worker
+/// receiving most of its updates and that worker's share, and its skew
+/// (the largest number of tuples added by a worker over the mean), over
+/// the whole session and per window of `window_ms`.
+///
+/// Arrangements whose skew reaches `threshold` (over the session, or in
+/// at least a window) are flagged. Arrangements are listed by number of
+/// tuples, so that the ones dominating the growth come first.
+///
+/// 1. Listens to incoming connections from a differential-dataflow
+/// program with timely and differential logging enabled;
+/// 2. runs a timely-dataflow program to sum the lengths of the `Batch`
+/// events of each source worker, by arrangement and window;
+/// 3. prints the per-arrangement distributions.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: crate::source::LogStreams,
+    window_ms: u64,
+    threshold: f64) -> Result<(), DiagError> {
+
+    let timely_streams_w = timely_streams.clone();
+    let differential_streams_w = differential_streams.clone();
+
+    let (output_send, output_recv) = ::std::sync::mpsc::channel();
+    let output_send = Arc::new(Mutex::new(output_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let output_send: std::sync::mpsc::Sender<_> = output_send.lock().expect("cannot lock output_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let timely_replayer = timely_streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open timely readers");
+        let differential_replayer = differential_streams_w
+            .readers::<Duration, (Duration, WorkerIdentifier, DifferentialEvent)>(worker.index(), worker.peers())
+            .expect("failed to open differential readers");
+
+        let operators = Rc::new(RefCell::new(HashMap::new()));
+        let operators_w = operators.clone();
+        let updates: Rc<RefCell<Updates>> = Rc::new(RefCell::new(HashMap::new()));
+        let updates_w = updates.clone();
+
+        worker.dataflow::<Duration, _, _>(|scope| {
+            timely_replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(_, w, x)| {
+                    if let Operates(event) = x {
+                        if *w == structure_worker {
+                            operators_w.borrow_mut().insert(event.id, (event.addr.clone(), event.name.clone()));
+                        }
+                    }
+                });
+
+            differential_replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect(move |(t, w, x)| {
+                    if let DifferentialEvent::Batch(x) = x {
+                        let window = t.as_millis() as u64 / window_ms;
+                        *updates_w.borrow_mut()
+                            .entry((x.operator, window)).or_insert_with(HashMap::new)
+                            .entry(*w).or_insert(0) += x.length as u64;
+                    }
+                });
+        });
+
+        while worker.step() { }
+
+        let operators = operators.borrow_mut().drain().collect::<Vec<_>>();
+        let updates = updates.borrow_mut().drain().collect::<Vec<_>>();
+        output_send.send((operators, updates)).expect("failed to send output to mpsc channel");
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&timely_streams, &differential_streams], "Press enter to stop collecting batch data");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    let mut operators = HashMap::new();
+    let mut windows: Updates = HashMap::new();
+    for (worker_operators, worker_updates) in output_recv {
+        operators.extend(worker_operators);
+        for (key, per_worker) in worker_updates {
+            let entry = windows.entry(key).or_insert_with(HashMap::new);
+            for (w, tuples) in per_worker {
+                *entry.entry(w).or_insert(0) += tuples;
+            }
+        }
+    }
+
+    let workers = std::cmp::max(
+        differential_streams.source_peers().unwrap_or(0),
+        windows.values().flat_map(|per_worker| per_worker.keys()).map(|w| w + 1).max().unwrap_or(0));
+
+    // (tuples by worker, windows, skewed windows), by operator id.
+    let mut arrangements: HashMap<usize, (HashMap<WorkerIdentifier, u64>, usize, usize)> = HashMap::new();
+    for ((operator, _window), per_worker) in windows.iter() {
+        let (totals, count, skewed) = arrangements.entry(*operator).or_insert_with(Default::default);
+        for (w, tuples) in per_worker.iter() {
+            *totals.entry(*w).or_insert(0) += tuples;
+        }
+        *count += 1;
+        if skew(per_worker, workers) >= threshold {
+            *skewed += 1;
+        }
+    }
+    let all_tuples: u64 = arrangements.values().flat_map(|(totals, _, _)| totals.values()).sum();
+
+    let mut arrangements = arrangements.into_iter().collect::<Vec<_>>();
+    arrangements.sort_by_key(|(operator, (totals, _, _))| (std::cmp::Reverse(totals.values().sum::<u64>()), *operator));
+
+    let name_map = config.names();
+    let mut flagged = 0;
+    println!("Op. Id\tName\tTuples\tShare (%)\tHottest worker\tWorker share (%)\tSkew\tSkewed windows\tFlagged");
+    for (operator, (totals, count, skewed)) in arrangements {
+        let name = match operators.get(&operator) {
+            Some((addr, name)) => format!("{} ({:?})", name_map.label(addr, name), addr),
+            None => "?".to_string(),
+        };
+        let tuples: u64 = totals.values().sum();
+        let share = if all_tuples > 0 { 100.0 * tuples as f64 / all_tuples as f64 } else { 0.0 };
+        let (hottest, hottest_tuples) = totals.iter().max_by_key(|(w, tuples)| (**tuples, std::cmp::Reverse(**w)))
+            .map(|(w, tuples)| (*w, *tuples)).unwrap_or((0, 0));
+        let hottest_share = if tuples > 0 { 100.0 * hottest_tuples as f64 / tuples as f64 } else { 0.0 };
+        let session_skew = skew(&totals, workers);
+        let is_flagged = session_skew >= threshold || skewed > 0;
+        if is_flagged {
+            flagged += 1;
+        }
+        if let Some(report) = config.reports() {
+            report.record("arrangement_skew", vec![
+                ("operator", operator.into()),
+                ("name", name.as_str().into()),
+                ("tuples", tuples.into()),
+                ("share", share.into()),
+                ("hottest_worker", hottest.into()),
+                ("hottest_worker_share", hottest_share.into()),
+                ("skew", session_skew.into()),
+                ("windows", count.into()),
+                ("skewed_windows", skewed.into()),
+                ("flagged", is_flagged.into()),
+            ]);
+        }
+        println!("{}\t{}\t{}\t{:.1}\t{}\t{:.1}\t{:.2}\t{}/{}\t{}",
+            operator,
+            name,
+            tuples,
+            share,
+            hottest,
+            hottest_share,
+            session_skew,
+            skewed,
+            count,
+            if is_flagged { "yes" } else { "" });
+    }
+
+    if flagged > 0 {
+        crate::status::warn("skewed_arrangements", &format!("{} arrangement(s) are skewed (skew of at least {} over the session or in a window of {}ms)", flagged, threshold, window_ms));
+    }
+
+    Ok(())
+}
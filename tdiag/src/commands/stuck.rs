@@ -0,0 +1,309 @@
+//! "stuck" subcommand: watches the progress updates while the source
+//! computation runs, and alerts when operators' frontiers stop
+//! advancing, to pinpoint deadlocked or stalled subgraphs.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::{DiagError, LoggingTuple};
+use crate::commands::frontiers::Timestamp;
+use crate::names::NameMap;
+use crate::report::Report;
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::TimelyEvent::{Operates, Channels, Progress};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// How often stalls are checked for.
+const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A location in a scope: (scope addr, node, port).
+type Location = (Vec<usize>, usize, usize);
+
+/// What the diagnostic workers report to the watcher.
+enum Update {
+    /// An operator was created: (addr, name).
+    Operator(Vec<usize>, String),
+    /// A channel was created: (id, scope addr, source, target).
+    Channel(usize, Vec<usize>, (usize, usize), (usize, usize)),
+    /// Changes to the messages outstanding at inputs (true) and the
+    /// capabilities held at outputs (false), logged at a time.
+    Pointstamps(Duration, Vec<(Location, bool, String, i64)>),
+    /// The latest time of any event.
+    Time(Duration),
+}
+
+/// Watches the operators' frontiers until the user stops the session,
+/// and reports the operators whose frontier hasn't advanced for
+/// `stall_after` (while they still have messages outstanding at their
+/// inputs, or hold capabilities), along with the channels with messages
+/// outstanding.
+///
+/// A frontier is stalled for the time between the last progress update
+/// that changed it and the latest event of the source computation, plus
+/// the time since that event was received: this detects stalls in a
+/// running computation that stopped logging altogether, as well as in
+/// recordings.
+///
+/// 1. Listens to incoming connections from a timely-dataflow program
+/// with logging enabled;
+/// 2. runs a timely-dataflow program to forward the progress updates
+/// sent by each worker;
+/// 3. maintains the per-operator frontiers, and prints the stalled
+/// operators and outstanding channels when a stall is detected, and a
+/// status message once it resolves.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    stall_after: Duration) -> Result<(), DiagError> {
+
+    let streams_w = streams.clone();
+
+    let (updates_send, updates_recv) = ::std::sync::mpsc::channel();
+    let updates_send = Arc::new(Mutex::new(updates_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let name_map = config.names().clone();
+    let report = config.reports().cloned();
+    let watcher = std::thread::Builder::new()
+        .name("tdiag-stuck".to_string())
+        .spawn(move || watch(updates_recv, stall_after, &name_map, report.as_ref()))?;
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let updates: std::sync::mpsc::Sender<_> = updates_send.lock().expect("cannot lock updates_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect_batch(move |_, data| {
+                    let mut latest = None;
+                    for (t, w, x) in data.iter() {
+                        latest = std::cmp::max(latest, Some(*t));
+                        match x {
+                            Operates(event) if *w == structure_worker => {
+                                let _ = updates.send(Update::Operator(event.addr.clone(), event.name.clone()));
+                            }
+                            Channels(event) if *w == structure_worker => {
+                                let _ = updates.send(Update::Channel(event.id, event.scope_addr.clone(), event.source, event.target));
+                            }
+                            // Each update is logged once by its sender, and once
+                            // by each of its receivers.
+                            Progress(event) if event.is_send => {
+                                let messages = event.messages.iter().map(|x| (x, true));
+                                let internal = event.internal.iter().map(|x| (x, false));
+                                let pointstamps = messages.chain(internal)
+                                    .map(|((node, port, time, diff), is_message)| ((event.addr.clone(), *node, *port), is_message, time.clone(), *diff))
+                                    .collect::<Vec<_>>();
+                                let _ = updates.send(Update::Pointstamps(*t, pointstamps));
+                            }
+                            _ => (),
+                        }
+                    }
+                    if let Some(latest) = latest {
+                        let _ = updates.send(Update::Time(latest));
+                    }
+                });
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::status::info("watching", &format!("Watching for frontiers that don't advance for {:?}", stall_after));
+
+    crate::source::await_stop(&[&streams], "Press enter to stop watching for stalls");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    watcher.join().map_err(|_| DiagError::Internal("the watcher thread panicked".to_string()))?;
+
+    Ok(())
+}
+
+/// The frontiers, and when they last changed.
+struct Watch {
+    names: HashMap<Vec<usize>, String>,
+    /// Channels, by their target location.
+    channels: HashMap<Location, (usize, (usize, usize))>,
+    /// Outstanding messages and capabilities, by operator addr and time.
+    counts: HashMap<Vec<usize>, BTreeMap<Timestamp, i64>>,
+    /// Outstanding messages, by input location and time.
+    messages: HashMap<Location, BTreeMap<Timestamp, i64>>,
+    /// The frontier of each operator with outstanding work, and the time
+    /// at which it last changed.
+    frontiers: HashMap<Vec<usize>, (Timestamp, Duration)>,
+    /// The latest event time, and when it was received.
+    latest: (Duration, Instant),
+}
+
+/// Adds `diff` to the count of `time`, forgetting it once it's zero.
+fn update_count(counts: &mut BTreeMap<Timestamp, i64>, time: &Timestamp, diff: i64) {
+    let count = counts.entry(time.clone()).or_insert(0);
+    *count += diff;
+    if *count == 0 {
+        counts.remove(time);
+    }
+}
+
+/// The earliest time with a positive count.
+fn earliest(counts: &BTreeMap<Timestamp, i64>) -> Option<(&Timestamp, i64)> {
+    counts.iter().find(|(_, count)| **count > 0).map(|(time, count)| (time, *count))
+}
+
+impl Watch {
+    fn new() -> Self {
+        Watch {
+            names: HashMap::new(),
+            channels: HashMap::new(),
+            counts: HashMap::new(),
+            messages: HashMap::new(),
+            frontiers: HashMap::new(),
+            latest: (Duration::from_secs(0), Instant::now()),
+        }
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Operator(addr, name) => { self.names.insert(addr, name); }
+            Update::Channel(id, scope_addr, source, target) => {
+                self.channels.insert((scope_addr, target.0, target.1), (id, source));
+            }
+            Update::Pointstamps(t, pointstamps) => {
+                let mut touched = BTreeSet::new();
+                for (location, is_message, time, diff) in pointstamps {
+                    let time = Timestamp::parse(&time);
+                    // Node 0 is the scope's own inputs and outputs.
+                    if location.1 != 0 {
+                        let mut addr = location.0.clone();
+                        addr.push(location.1);
+                        update_count(self.counts.entry(addr.clone()).or_insert_with(BTreeMap::new), &time, diff);
+                        touched.insert(addr);
+                    }
+                    if is_message {
+                        update_count(self.messages.entry(location).or_insert_with(BTreeMap::new), &time, diff);
+                    }
+                }
+                for addr in touched {
+                    match self.counts.get(&addr).and_then(earliest).map(|(time, _)| time.clone()) {
+                        Some(frontier) => {
+                            if self.frontiers.get(&addr).map_or(true, |(previous, _)| *previous != frontier) {
+                                self.frontiers.insert(addr, (frontier, t));
+                            }
+                        }
+                        None => { self.frontiers.remove(&addr); }
+                    }
+                }
+                self.advance(t);
+            }
+            Update::Time(t) => self.advance(t),
+        }
+    }
+
+    fn advance(&mut self, t: Duration) {
+        if t >= self.latest.0 {
+            self.latest = (t, Instant::now());
+        }
+    }
+
+    /// The operators whose frontier hasn't changed for at least
+    /// `stall_after`, with how long it hasn't.
+    fn stalled(&self, stall_after: Duration) -> BTreeMap<Vec<usize>, (Timestamp, Duration)> {
+        let (latest, received) = self.latest;
+        let idle = received.elapsed();
+        self.frontiers.iter()
+            .map(|(addr, (frontier, since))| (addr, frontier, latest.checked_sub(*since).unwrap_or_default() + idle))
+            .filter(|(_, _, stalled)| *stalled >= stall_after)
+            .map(|(addr, frontier, stalled)| (addr.clone(), (frontier.clone(), stalled)))
+            .collect()
+    }
+
+    /// The channels with outstanding messages, as (channel id, scope
+    /// addr, source, target, earliest time, messages at that time).
+    fn outstanding(&self) -> Vec<(usize, &[usize], (usize, usize), (usize, usize), &Timestamp, i64)> {
+        let mut outstanding = self.messages.iter()
+            .filter_map(|(location, counts)| {
+                let (time, count) = earliest(counts)?;
+                let (id, source) = self.channels.get(location)?;
+                Some((*id, location.0.as_slice(), *source, (location.1, location.2), time, count))
+            })
+            .collect::<Vec<_>>();
+        outstanding.sort_by_key(|(id, ..)| *id);
+        outstanding
+    }
+}
+
+/// Applies the updates received from `updates` until all senders are
+/// dropped, reporting stalls as they're detected.
+fn watch(updates: Receiver<Update>, stall_after: Duration, name_map: &NameMap, report: Option<&Report>) {
+    let mut watch = Watch::new();
+    // The stalled operators already reported.
+    let mut reported: BTreeSet<Vec<usize>> = BTreeSet::new();
+    let mut last_check = Instant::now();
+
+    loop {
+        match updates.recv_timeout(CHECK_INTERVAL) {
+            Ok(update) => {
+                watch.apply(update);
+                if last_check.elapsed() < CHECK_INTERVAL {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        last_check = Instant::now();
+
+        let stalled = watch.stalled(stall_after);
+        if stalled.is_empty() {
+            if !reported.is_empty() {
+                crate::status::info("stall_resolved", "All frontiers advance again");
+                reported.clear();
+            }
+            continue;
+        }
+        if stalled.keys().all(|addr| reported.contains(addr)) {
+            continue;
+        }
+
+        crate::status::warn("stalled", &format!("{} operator(s) didn't advance their frontier for at least {:?}", stalled.len(), stall_after));
+        println!("Stalled operators");
+        println!("Addr\tName\tFrontier\tStalled (s)");
+        for (addr, (frontier, duration)) in stalled.iter() {
+            let name = watch.names.get(addr).map(|name| name_map.label(addr, name)).unwrap_or("<unknown>");
+            if let Some(report) = report {
+                report.record("stalled_operator", vec![
+                    ("addr", format!("{:?}", addr).into()),
+                    ("name", name.into()),
+                    ("frontier", frontier.text.as_str().into()),
+                    ("stalled_s", duration.as_secs_f64().into()),
+                ]);
+            }
+            println!("{:?}\t{}\t{}\t{:.1}", addr, name, frontier.text, duration.as_secs_f64());
+        }
+        println!("Channels with outstanding messages");
+        println!("Channel\tScope\tSource\tTarget\tEarliest time\tMessages");
+        for (id, scope_addr, source, target, time, count) in watch.outstanding() {
+            if let Some(report) = report {
+                report.record("outstanding_channel", vec![
+                    ("channel", id.into()),
+                    ("scope_addr", format!("{:?}", scope_addr).into()),
+                    ("source", format!("{:?}", source).into()),
+                    ("target", format!("{:?}", target).into()),
+                    ("time", time.text.as_str().into()),
+                    ("messages", count.into()),
+                ]);
+            }
+            println!("{}\t{:?}\t{:?}\t{:?}\t{}\t{}", id, scope_addr, source, target, time.text, count);
+        }
+        reported = stalled.into_iter().map(|(addr, _)| addr).collect();
+    }
+}
@@ -0,0 +1,44 @@
+//! "tee" subcommand: forwards the log streams to stdout, so that they
+//! can be piped into another tdiag (with `--from-stdin`) or into
+//! compression/storage tools.
+
+use std::sync::Arc;
+
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::operators::capture::{Capture, EventWriter};
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// Re-encodes all log streams into a single event stream written to
+/// stdout.
+///
+/// This always runs on a single diagnostic worker, so that the events
+/// of all source workers end up in one stream. The output can be read
+/// back with `--from-stdin`.
+pub fn listen(streams: crate::source::LogStreams) -> Result<(), crate::DiagError> {
+
+    let streams_w = streams.clone();
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let worker_handles = timely::execute(timely::Configuration::Thread, move |worker| {
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .capture_into(EventWriter::new(std::io::stdout()));
+        })
+    }).map_err(DiagError::Timely)?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop forwarding the log streams");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    Ok(())
+}
@@ -0,0 +1,367 @@
+//! "top" subcommand: a live, sortable table of the source computation's
+//! operators in the terminal, refreshed every second while the
+//! computation runs.
+//!
+//! The terminal is driven with ANSI escape sequences, and switched to
+//! unbuffered input with `stty` so that keys take effect without enter,
+//! which needs no additional dependencies (on Unix-like systems; keys
+//! need enter elsewhere).
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::DiagError;
+use crate::commands::live::Update;
+use crate::names::NameMap;
+
+/// How often the table is refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of rows used when the terminal's size is unknown.
+const DEFAULT_ROWS: usize = 24;
+
+/// The column the table is sorted by, in decreasing order.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortBy {
+    Total,
+    Recent,
+    Records,
+    Tuples,
+}
+
+/// Shows a table of the operators of the source computation, with the
+/// time they were scheduled (in total and over the last second), the
+/// records they received and sent, and (with `differential_streams`)
+/// the tuples in their arrangements, until the user quits.
+///
+/// Keys: `t`, `r`, `m` and `a` sort by total time, recent time, records
+/// and arrangement size; `/` filters operators by name or address
+/// (enter applies, escape clears); `q` quits.
+pub fn top(
+    config: &crate::config::DiagConfig,
+    timely_streams: crate::source::LogStreams,
+    differential_streams: Option<crate::source::LogStreams>) -> Result<(), DiagError> {
+
+    if timely_streams.uses_stdin() {
+        return Err(DiagError::InvalidArgs("top reads keys from stdin, and does not support --from-stdin".to_string()));
+    }
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let (worker_handles, updates_recv) = crate::commands::live::spawn(config, &timely_streams, differential_streams.as_ref(), is_running.clone())?;
+
+    let quit = Arc::new(AtomicBool::new(false));
+    let quit_t = quit.clone();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_t = stop.clone();
+    let name_map = config.names().clone();
+    let terminal = std::thread::Builder::new()
+        .name("tdiag-top".to_string())
+        .spawn(move || run_terminal(updates_recv, &quit_t, &stop_t, &name_map))?;
+
+    crate::signals::arm();
+    let mut streams = vec![&timely_streams];
+    streams.extend(differential_streams.iter());
+    let until_end = streams.iter().any(|s| s.is_recorded());
+    while !quit.load(Ordering::Acquire) {
+        if until_end && streams.iter().all(|s| s.exhausted()) {
+            break;
+        }
+        if streams.iter().any(|s| s.memory_exhausted() || s.timed_out()) || crate::signals::interrupted() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    stop.store(true, Ordering::Release);
+    terminal.join().map_err(|_| DiagError::Internal("the terminal thread panicked".to_string()))??;
+
+    is_running.store(false, Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    Ok(())
+}
+
+/// The table's state, maintained from the workers' updates.
+#[derive(Default)]
+struct Table {
+    operators: BTreeMap<usize, (Vec<usize>, String)>,
+    channels: HashMap<usize, (Vec<usize>, (usize, usize), (usize, usize))>,
+    /// (total, in the last refresh interval, in the current one), by
+    /// operator id.
+    elapsed: HashMap<usize, (u64, u64, u64)>,
+    /// Records sent over each channel.
+    records: HashMap<usize, u64>,
+    /// Tuples in the arrangements of each operator.
+    tuples: HashMap<usize, i64>,
+}
+
+/// A row of the table.
+struct Row {
+    id: usize,
+    addr: Vec<usize>,
+    name: String,
+    total_ns: u64,
+    recent_ns: u64,
+    records_in: u64,
+    records_out: u64,
+    tuples: Option<i64>,
+}
+
+impl Table {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Operator(id, addr, name) => { self.operators.insert(id, (addr, name)); }
+            Update::Channel(id, scope_addr, source, target) => { self.channels.insert(id, (scope_addr, source, target)); }
+            Update::Elapsed(elapsed) => for (id, ns) in elapsed {
+                let entry = self.elapsed.entry(id).or_insert((0, 0, 0));
+                entry.0 += ns;
+                entry.2 += ns;
+            },
+            Update::Sent(sent) => for (id, (_messages, records)) in sent {
+                *self.records.entry(id).or_insert(0) += records;
+            },
+            // Summed over the source workers.
+            Update::Tuples(tuples) => for ((_worker, id), diff) in tuples {
+                *self.tuples.entry(id).or_insert(0) += diff;
+            },
+        }
+    }
+
+    /// Starts a new refresh interval, whose time is shown as recent
+    /// once it's complete.
+    fn tick(&mut self) {
+        for (_, last, current) in self.elapsed.values_mut() {
+            *last = std::mem::replace(current, 0);
+        }
+    }
+
+    /// The rows matching `filter`, sorted by `sort_by`.
+    fn rows(&self, name_map: &NameMap, sort_by: SortBy, filter: &str) -> Vec<Row> {
+        // Records are attributed to the operators at either end of each
+        // channel (index 0 is the scope itself).
+        let by_addr = self.operators.iter().map(|(id, (addr, _))| (addr.clone(), *id)).collect::<HashMap<_, _>>();
+        let mut records_in = HashMap::new();
+        let mut records_out = HashMap::new();
+        for (channel, (scope_addr, source, target)) in self.channels.iter() {
+            let records = self.records.get(channel).cloned().unwrap_or(0);
+            let endpoint = |index: usize| {
+                let mut addr = scope_addr.clone();
+                if index != 0 {
+                    addr.push(index);
+                }
+                by_addr.get(&addr).cloned()
+            };
+            if let Some(id) = endpoint(source.0) {
+                *records_out.entry(id).or_insert(0) += records;
+            }
+            if let Some(id) = endpoint(target.0) {
+                *records_in.entry(id).or_insert(0) += records;
+            }
+        }
+
+        let filter = filter.to_lowercase();
+        let mut rows = self.operators.iter()
+            .map(|(id, (addr, name))| {
+                let (total_ns, recent_ns, _) = self.elapsed.get(id).cloned().unwrap_or((0, 0, 0));
+                Row {
+                    id: *id,
+                    addr: addr.clone(),
+                    name: name_map.label(addr, name).to_string(),
+                    total_ns,
+                    recent_ns,
+                    records_in: records_in.get(id).cloned().unwrap_or(0),
+                    records_out: records_out.get(id).cloned().unwrap_or(0),
+                    tuples: self.tuples.get(id).cloned(),
+                }
+            })
+            .filter(|row| filter.is_empty()
+                || row.name.to_lowercase().contains(&filter)
+                || format!("{:?}", row.addr).contains(&filter))
+            .collect::<Vec<_>>();
+
+        match sort_by {
+            SortBy::Total => rows.sort_by_key(|row| (std::cmp::Reverse(row.total_ns), row.id)),
+            SortBy::Recent => rows.sort_by_key(|row| (std::cmp::Reverse(row.recent_ns), row.id)),
+            SortBy::Records => rows.sort_by_key(|row| (std::cmp::Reverse(row.records_in + row.records_out), row.id)),
+            SortBy::Tuples => rows.sort_by_key(|row| (std::cmp::Reverse(row.tuples.unwrap_or(0)), row.id)),
+        }
+
+        rows
+    }
+}
+
+/// Restores the terminal's settings when dropped.
+struct RawMode {
+    saved: Option<String>,
+}
+
+impl RawMode {
+    /// Switches the terminal to unbuffered input without echo, if `stty`
+    /// is available; it's restored on drop, or if a signal exits tdiag
+    /// first.
+    fn enable() -> Self {
+        let saved = stty(&["-g"]).filter(|_| stty(&["-icanon", "-echo", "min", "1"]).is_some());
+        if let Some(saved) = saved.clone() {
+            crate::signals::on_exit(Box::new(move || { let _ = stty(&[saved.trim()]); }));
+        }
+        RawMode { saved }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            crate::signals::clear_on_exit();
+            let _ = stty(&[saved.trim()]);
+        }
+    }
+}
+
+/// Runs `stty` on the terminal, returning its output if it succeeded.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        None
+    }
+}
+
+/// The number of rows of the terminal.
+fn terminal_rows() -> usize {
+    stty(&["size"])
+        .and_then(|size| size.split_whitespace().next().and_then(|rows| rows.parse().ok()))
+        .or_else(|| std::env::var("LINES").ok().and_then(|rows| rows.parse().ok()))
+        .unwrap_or(DEFAULT_ROWS)
+}
+
+/// Handles keys and refreshes the table every `REFRESH_INTERVAL`, until
+/// the user quits (which sets `quit`) or `stop` is set.
+fn run_terminal(updates: Receiver<Update>, quit: &AtomicBool, stop: &AtomicBool, name_map: &NameMap) -> Result<(), DiagError> {
+    let _raw_mode = RawMode::enable();
+
+    let (keys_send, keys_recv) = std::sync::mpsc::channel();
+    // Left blocked on stdin once the table is gone, until the process exits.
+    std::thread::Builder::new()
+        .name("tdiag-top-keys".to_string())
+        .spawn(move || {
+            let mut stdin = std::io::stdin();
+            let mut byte = [0u8];
+            while let Ok(1) = stdin.read(&mut byte) {
+                if keys_send.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    let mut table = Table::default();
+    let mut sort_by = SortBy::Total;
+    let mut filter = String::new();
+    // The filter being typed, if any.
+    let mut editing: Option<String> = None;
+    let mut last_refresh: Option<Instant> = None;
+    let started = Instant::now();
+
+    let stdout = std::io::stdout();
+    loop {
+        while let Ok(update) = updates.try_recv() {
+            table.apply(update);
+        }
+
+        let mut redraw = false;
+        while let Ok(key) = keys_recv.try_recv() {
+            redraw = true;
+            match editing.as_mut() {
+                Some(typed) => match key {
+                    b'\n' | b'\r' => filter = editing.take().unwrap_or_default(),
+                    // Escape
+                    0x1b => {
+                        editing = None;
+                        filter.clear();
+                    }
+                    // Backspace or delete
+                    0x08 | 0x7f => { typed.pop(); }
+                    key if key.is_ascii_graphic() || key == b' ' => typed.push(key as char),
+                    _ => (),
+                },
+                None => match key {
+                    b't' => sort_by = SortBy::Total,
+                    b'r' => sort_by = SortBy::Recent,
+                    b'm' => sort_by = SortBy::Records,
+                    b'a' => sort_by = SortBy::Tuples,
+                    b'/' => editing = Some(String::new()),
+                    b'q' => quit.store(true, Ordering::Release),
+                    _ => (),
+                },
+            }
+        }
+
+        if quit.load(Ordering::Acquire) || stop.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        // Keys redraw the table without starting a new interval.
+        let tick = last_refresh.map_or(true, |last| last.elapsed() >= REFRESH_INTERVAL);
+        if tick {
+            table.tick();
+            last_refresh = Some(Instant::now());
+        }
+        if redraw || tick {
+            let rows = table.rows(name_map, sort_by, &filter);
+            let mut out = stdout.lock();
+            draw(&mut out, &rows, sort_by, &filter, editing.as_ref().map(|typed| typed.as_str()), started.elapsed())?;
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Draws the table, as many rows as fit in the terminal.
+fn draw<W: Write>(
+    out: &mut W,
+    rows: &[Row],
+    sort_by: SortBy,
+    filter: &str,
+    editing: Option<&str>,
+    elapsed: Duration) -> Result<(), DiagError> {
+
+    let header = |column: SortBy, title: &str| if column == sort_by { format!("[{}]", title) } else { title.to_string() };
+
+    // Clear the screen and move the cursor to the top left.
+    write!(out, "\x1b[2J\x1b[H")?;
+    write!(out, "tdiag top - {}s - {} operators", elapsed.as_secs(), rows.len())?;
+    match editing {
+        Some(typed) => write!(out, " - filter: {}_", typed)?,
+        None if !filter.is_empty() => write!(out, " - filter: {}", filter)?,
+        None => (),
+    }
+    write!(out, "\r\n")?;
+    write!(out, "keys: t/r/m/a sort by total/recent time, records, tuples; / filter; q quit\r\n\r\n")?;
+    write!(out, "{:>6}  {:<16}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}  {}\r\n",
+        "Id", "Addr", header(SortBy::Total, "Total (s)"), header(SortBy::Recent, "Last 1s (ms)"),
+        header(SortBy::Records, "Records in"), "Records out", header(SortBy::Tuples, "Tuples"), "Name")?;
+
+    let fit = terminal_rows().saturating_sub(5);
+    for row in rows.iter().take(fit) {
+        write!(out, "{:>6}  {:<16}  {:>12.3}  {:>12.1}  {:>12}  {:>12}  {:>12}  {}\r\n",
+            row.id,
+            format!("{:?}", row.addr),
+            row.total_ns as f64 / 1_000_000_000f64,
+            row.recent_ns as f64 / 1_000_000f64,
+            row.records_in,
+            row.records_out,
+            row.tuples.map_or("-".to_string(), |tuples| tuples.to_string()),
+            row.name)?;
+    }
+    out.flush()?;
+    Ok(())
+}
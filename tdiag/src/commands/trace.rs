@@ -0,0 +1,156 @@
+//! "trace" subcommand: exports operator activations in the Chrome Trace
+//! Event format, to inspect them on a zoomable timeline in
+//! chrome://tracing or Perfetto.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::{DiagError, LoggingTuple};
+use crate::names::NameMap;
+use crate::status::json_string;
+
+use timely::dataflow::operators::Inspect;
+use timely::logging::TimelyEvent::Operates;
+
+use tdiag_connect::receive::ReplayWithShutdown;
+
+/// What the diagnostic workers hand to the trace writer.
+enum Record {
+    /// An operator was created: (id, addr, name).
+    Operator(usize, Vec<usize>, String),
+    /// An operator ran: (worker, operator id, start, elapsed).
+    Activation(usize, usize, Duration, Duration),
+}
+
+/// Writes every operator activation to `out_path` as a Chrome trace,
+/// with a process per source worker and a track (thread) per operator.
+///
+/// Activations are written as they're replayed rather than held in
+/// memory; operator names are added at the end of the session.
+pub fn listen(
+    config: &crate::config::DiagConfig,
+    streams: crate::source::LogStreams,
+    out_path: &Path) -> Result<(), DiagError> {
+
+    let file = File::create(out_path)
+        .map_err(|e| DiagError::io(format!("cannot create {}", out_path.display()), e))?;
+
+    let streams_w = streams.clone();
+
+    let (records_send, records_recv) = ::std::sync::mpsc::channel();
+    let records_send = Arc::new(Mutex::new(records_send));
+
+    let is_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let is_running_w = is_running.clone();
+
+    let structure_worker = config.structure_worker()?;
+
+    let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+        let records: std::sync::mpsc::Sender<_> = records_send.lock().expect("cannot lock records_send").clone();
+
+        // create replayer from disjoint partition of source worker identifiers.
+        let replayer = streams_w.readers::<std::time::Duration, LoggingTuple>(worker.index(), worker.peers())
+            .expect("failed to open readers");
+
+        worker.dataflow(|scope| {
+            let records_o = records.clone();
+            // Operators are named before their activations are written.
+            let events = replayer.replay_with_shutdown_into(scope, is_running_w.clone())
+                .inspect_batch(move |_, data| {
+                    for (_, worker, event) in data.iter() {
+                        if let Operates(event) = event {
+                            if *worker == structure_worker {
+                                let _ = records_o.send(Record::Operator(event.id, event.addr.clone(), event.name.clone()));
+                            }
+                        }
+                    }
+                });
+
+            crate::commands::profile::activations(&events)
+                .inspect_batch(move |_, data| {
+                    for ((worker, id), start, elapsed) in data.iter() {
+                        let _ = records.send(Record::Activation(*worker, *id, *start, *elapsed));
+                    }
+                });
+        })
+    }).map_err(DiagError::Timely)?;
+
+    let name_map = config.names().clone();
+    let writer = std::thread::Builder::new()
+        .name("tdiag-trace".to_string())
+        .spawn(move || write_trace(BufWriter::new(file), records_recv, &name_map))?;
+
+    crate::source::await_stop(&[&streams], "Press enter to stop tracing");
+
+    is_running.store(false, std::sync::atomic::Ordering::Release);
+
+    worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().expect("Timely error");
+
+    // The workers are gone, which ends the records.
+    let activations = writer.join()
+        .map_err(|_| DiagError::Internal("the trace writer panicked".to_string()))?
+        .map_err(|e| DiagError::io(format!("cannot write {}", out_path.display()), e))?;
+
+    crate::status::info("trace_written", &format!(
+        "Wrote {} activations to {}, open it in chrome://tracing or https://ui.perfetto.dev",
+        activations, out_path.display()));
+
+    Ok(())
+}
+
+/// Writes the activations in `records` as complete ("X") events until
+/// the senders are gone, followed by the names of the workers and
+/// operators; returns the number of activations.
+fn write_trace<W: Write>(mut out: W, records: Receiver<Record>, name_map: &NameMap) -> std::io::Result<usize> {
+    let mut operators = BTreeMap::new();
+    let mut tracks = BTreeSet::new();
+    let mut activations = 0;
+
+    writeln!(out, "{{\"displayTimeUnit\": \"ns\", \"traceEvents\": [")?;
+    for record in records {
+        match record {
+            Record::Operator(id, addr, name) => {
+                operators.insert(id, (addr, name));
+            }
+            Record::Activation(worker, id, start, elapsed) => {
+                tracks.insert((worker, id));
+                activations += 1;
+                // Timestamps are in microseconds.
+                writeln!(out, "{{\"ph\": \"X\", \"pid\": {}, \"tid\": {}, \"ts\": {:.3}, \"dur\": {:.3}, \"name\": {}}},",
+                    worker, id,
+                    start.as_nanos() as f64 / 1000.0,
+                    elapsed.as_nanos() as f64 / 1000.0,
+                    json_string(&operator_label(&operators, name_map, id)))?;
+            }
+        }
+    }
+
+    let workers = tracks.iter().map(|(worker, _)| *worker).collect::<BTreeSet<_>>();
+    for worker in workers.iter() {
+        writeln!(out, "{{\"ph\": \"M\", \"pid\": {}, \"name\": \"process_name\", \"args\": {{\"name\": \"worker {}\"}}}},", worker, worker)?;
+    }
+    for (worker, id) in tracks.iter() {
+        // Tracks are sorted by operator id.
+        writeln!(out, "{{\"ph\": \"M\", \"pid\": {}, \"tid\": {}, \"name\": \"thread_sort_index\", \"args\": {{\"sort_index\": {}}}}},", worker, id, id)?;
+        writeln!(out, "{{\"ph\": \"M\", \"pid\": {}, \"tid\": {}, \"name\": \"thread_name\", \"args\": {{\"name\": {}}}}},",
+            worker, id, json_string(&operator_label(&operators, name_map, *id)))?;
+    }
+    // Closes the array without a trailing comma.
+    writeln!(out, "{{\"ph\": \"M\", \"pid\": 0, \"name\": \"process_labels\", \"args\": {{\"labels\": \"tdiag\"}}}}")?;
+    writeln!(out, "]}}")?;
+    out.flush()?;
+
+    Ok(activations)
+}
+
+fn operator_label(operators: &BTreeMap<usize, (Vec<usize>, String)>, name_map: &NameMap, id: usize) -> String {
+    match operators.get(&id) {
+        Some((addr, name)) => format!("{} {:?}", name_map.label(addr, name), addr),
+        None => format!("operator {}", id),
+    }
+}
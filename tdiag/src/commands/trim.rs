@@ -0,0 +1,151 @@
+//! "trim" subcommand: cuts a time range out of a recording, to share a
+//! short slice rather than the whole capture.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::DiagError;
+use crate::source::{LogEvent, Window};
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::EventWriter;
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+use timely::progress::ChangeBatch;
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::recording::{self, EventFileReader, Recording, RecordedStream, StreamKind};
+
+/// Writes a copy of the recording at `input` to `output` that only
+/// contains the records logged between `from` (inclusive) and `to`
+/// (exclusive), for each stream.
+///
+/// The `Operates` and `Channels` events logged before `to` are kept
+/// regardless, as they're needed to name operators and to reconstruct
+/// the dataflow graph. Events that close what was opened before `from`
+/// (e.g. the stop of an activation) are dropped (see `Window`).
+pub fn trim(input: &Path, output: &Path, from: Duration, to: Duration) -> Result<(), DiagError> {
+    if from >= to {
+        return Err(DiagError::InvalidArgs("--from must be before --to".to_string()));
+    }
+
+    let recording = Recording::open(input)?;
+    recording::create_dir(output)?;
+
+    let mut streams = Vec::new();
+    for stream in recording.streams.iter() {
+        let path = output.join(recording::stream_file_name(stream.kind, stream.index));
+        let (kept, total) = match stream.kind {
+            StreamKind::Timely => trim_stream::<TimelyEvent>(&stream.path, &path, from, to)?,
+            StreamKind::Differential => trim_stream::<DifferentialEvent>(&stream.path, &path, from, to)?,
+        };
+        println!("{} stream {}: kept {} of {} records", stream.kind.name(), stream.index, kept, total);
+        streams.push(RecordedStream { kind: stream.kind, index: stream.index, path });
+    }
+
+    recording::write_manifest(output, &streams)?;
+
+    Ok(())
+}
+
+/// Copies the records of a stream in the time range (see `Window`), and
+/// its prelude (see `LogEvent::is_prelude`) from before it, returning the
+/// number of records kept and the total number of records.
+///
+/// Progress updates are copied as they are, which keeps them consistent
+/// with the remaining records. Capabilities still held at the end of
+/// the stream are released, so that the trimmed stream is complete.
+fn trim_stream<E>(input: &Path, output: &Path, from: Duration, to: Duration) -> Result<(usize, usize), DiagError>
+where E: Abomonation + Clone + LogEvent {
+    let mut reader = EventFileReader::<Duration, (Duration, WorkerIdentifier, E)>::open(input)?;
+    let file = File::create(output).map_err(|e| DiagError::io(format!("cannot create {}", output.display()), e))?;
+    let mut writer = EventWriter::<Duration, (Duration, WorkerIdentifier, E), _>::new(BufWriter::new(file));
+
+    // Every stream implicitly starts with a capability at the minimal time.
+    let mut capabilities = ChangeBatch::new_from(Duration::default(), 1);
+    let mut window = Window::new(Some(from), Some(to));
+    let mut kept = 0;
+    let mut total = 0;
+
+    while let Some(event) = reader.next_event()? {
+        match event {
+            Event::Progress(updates) => {
+                capabilities.extend(updates.iter().cloned());
+                writer.push(Event::Progress(updates));
+            }
+            Event::Messages(time, data) => {
+                total += data.len();
+                let data = data.into_iter()
+                    .filter(|record| window.keep(record))
+                    .collect::<Vec<_>>();
+                if !data.is_empty() {
+                    kept += data.len();
+                    writer.push(Event::Messages(time, data));
+                }
+            }
+        }
+    }
+
+    let remaining = capabilities.drain().map(|(t, diff)| (t, -diff)).collect::<Vec<_>>();
+    if !remaining.is_empty() {
+        writer.push(Event::Progress(remaining));
+    }
+
+    Ok((kept, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use timely::logging::{OperatesEvent, ScheduleEvent, StartStop};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn schedule(time: u64, start_stop: StartStop) -> (Duration, WorkerIdentifier, TimelyEvent) {
+        (ms(time), 0, TimelyEvent::Schedule(ScheduleEvent { id: 3, start_stop }))
+    }
+
+    #[test]
+    fn trim_keeps_whole_activations_in_the_window() {
+        let dir = std::env::temp_dir().join(format!("tdiag-trim-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("cannot create the test directory");
+        let (input, output) = (dir.join("input"), dir.join("output"));
+
+        {
+            let file = File::create(&input).expect("cannot create the input");
+            let mut writer = EventWriter::<Duration, (Duration, WorkerIdentifier, TimelyEvent), _>::new(BufWriter::new(file));
+            writer.push(Event::Messages(ms(0), vec![
+                (ms(1), 0, TimelyEvent::Operates(OperatesEvent { id: 3, addr: vec![0, 3], name: "Map".to_string() })),
+                // Cut by the start of the window.
+                schedule(5, StartStop::Start),
+                schedule(12, StartStop::Stop),
+                schedule(13, StartStop::Start),
+                schedule(14, StartStop::Stop),
+                // Cut by its end.
+                schedule(19, StartStop::Start),
+                schedule(21, StartStop::Stop),
+            ]));
+            writer.push(Event::Progress(vec![(Duration::default(), -1)]));
+        }
+
+        let (kept, total) = trim_stream::<TimelyEvent>(&input, &output, ms(10), ms(20)).expect("cannot trim");
+        assert_eq!((kept, total), (4, 7));
+
+        let mut reader = EventFileReader::<Duration, (Duration, WorkerIdentifier, TimelyEvent)>::open(&output).expect("cannot open the output");
+        let mut times = Vec::new();
+        while let Some(event) = reader.next_event().expect("cannot read the output") {
+            if let Event::Messages(_, data) = event {
+                times.extend(data.into_iter().map(|(t, _, _)| t));
+            }
+        }
+        std::fs::remove_dir_all(&dir).expect("cannot remove the test directory");
+        assert_eq!(times, vec![ms(1), ms(13), ms(14), ms(19)]);
+    }
+}
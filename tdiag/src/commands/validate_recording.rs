@@ -0,0 +1,201 @@
+//! "validate-recording" subcommand: checks that a recording can be
+//! replayed, before archiving or sharing it.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::DiagError;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::Event;
+use timely::logging::{TimelyEvent, WorkerIdentifier};
+use timely::progress::ChangeBatch;
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::recording::{EventFileReader, Recording, StreamKind, MANIFEST};
+
+/// What was found in a recorded stream.
+#[derive(Default)]
+struct StreamReport {
+    bytes: u64,
+    events: usize,
+    records: usize,
+    workers: BTreeSet<WorkerIdentifier>,
+    first: Option<Duration>,
+    last: Option<Duration>,
+    complete: bool,
+    problems: Vec<String>,
+}
+
+/// Decodes every event of every stream in the recording at `path`,
+/// and prints a summary of each stream followed by a verdict.
+///
+/// Checks that
+/// - the manifest lists existing, distinct files, and all event files
+///   in the recording's directory;
+/// - every event decodes, up to the end of each file;
+/// - records are only sent at times the stream holds a capability for,
+///   and capabilities are never released more often than acquired;
+/// - each source worker's timestamps don't regress;
+/// - no source worker appears in more than one stream of a kind.
+///
+/// Returns an error if any check fails.
+pub fn validate(path: &Path) -> Result<(), DiagError> {
+    let recording = Recording::open(path)?;
+
+    let mut problems = Vec::new();
+
+    // == Manifest consistency ==
+    let mut seen = HashSet::new();
+    for stream in recording.streams.iter() {
+        if !seen.insert((stream.kind, stream.index)) {
+            problems.push(format!("{} stream {} is listed more than once", stream.kind.name(), stream.index));
+        }
+        if !stream.path.is_file() {
+            problems.push(format!("{} is listed in the manifest but does not exist", stream.path.display()));
+        }
+    }
+    if let Some(dir) = &recording.dir {
+        let listed = recording.streams.iter().map(|s| s.path.clone()).collect::<HashSet<_>>();
+        for entry in std::fs::read_dir(dir)? {
+            let entry_path = entry?.path();
+            let is_events = entry_path.extension().map_or(false, |e| e == "events");
+            if is_events && !listed.contains(&entry_path) {
+                problems.push(format!("{} is not listed in the {}", entry_path.display(), MANIFEST));
+            }
+        }
+    }
+
+    // == Streams ==
+    let mut reports = Vec::new();
+    for stream in recording.streams.iter().filter(|s| s.path.is_file()) {
+        let mut report = match stream.kind {
+            StreamKind::Timely => check_stream::<TimelyEvent>(&stream.path),
+            StreamKind::Differential => check_stream::<DifferentialEvent>(&stream.path),
+        };
+        report.bytes = std::fs::metadata(&stream.path)?.len();
+        for problem in report.problems.iter() {
+            problems.push(format!("{}: {}", stream.path.display(), problem));
+        }
+        reports.push((stream, report));
+    }
+
+    // == Worker collisions ==
+    let mut owners: HashMap<(StreamKind, WorkerIdentifier), usize> = HashMap::new();
+    for (stream, report) in reports.iter() {
+        for worker in report.workers.iter() {
+            if let Some(other) = owners.insert((stream.kind, *worker), stream.index) {
+                problems.push(format!("events of worker {} appear in {} streams {} and {}",
+                    worker, stream.kind.name(), other, stream.index));
+            }
+        }
+    }
+
+    println!("Kind\tIndex\tFile\tBytes\tEvents\tRecords\tWorkers\tFirst (ms)\tLast (ms)\tComplete");
+    for (stream, report) in reports.iter() {
+        println!("{}\t{}\t{}\t{}\t{}\t{}\t{:?}\t{}\t{}\t{}",
+            stream.kind.name(),
+            stream.index,
+            stream.path.display(),
+            report.bytes,
+            report.events,
+            report.records,
+            report.workers,
+            report.first.map_or("-".to_string(), |t| t.as_millis().to_string()),
+            report.last.map_or("-".to_string(), |t| t.as_millis().to_string()),
+            if report.complete { "yes" } else { "no" });
+    }
+
+    println!();
+    if problems.is_empty() {
+        println!("Verdict: valid");
+        Ok(())
+    } else {
+        println!("Verdict: invalid");
+        for problem in problems.iter() {
+            println!("  - {}", problem);
+        }
+        Err(DiagError::CheckFailed(format!("{} has {} problem(s)", path.display(), problems.len())))
+    }
+}
+
+/// Decodes and checks all events in a recorded stream.
+fn check_stream<E: Abomonation + Clone>(path: &Path) -> StreamReport {
+    let mut report = StreamReport::default();
+
+    let mut reader = match EventFileReader::<Duration, (Duration, WorkerIdentifier, E)>::open(path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            report.problems.push(crate::DiagError::from(e).to_string());
+            return report;
+        }
+    };
+
+    // Every stream implicitly starts with a capability at the minimal time.
+    let mut capabilities = ChangeBatch::new_from(Duration::default(), 1);
+    let mut latest = HashMap::new();
+    let mut uncovered = 0;
+    let mut released_twice = 0;
+    let mut regressions = 0;
+
+    loop {
+        let offset = reader.offset();
+        match reader.next_event() {
+            Ok(Some(Event::Progress(updates))) => {
+                report.events += 1;
+                capabilities.extend(updates.into_iter());
+                if capabilities.iter().any(|(_, diff)| *diff < 0) {
+                    if released_twice == 0 {
+                        report.problems.push(format!("capabilities released more often than acquired at offset {}", offset));
+                    }
+                    released_twice += 1;
+                }
+            }
+            Ok(Some(Event::Messages(time, data))) => {
+                report.events += 1;
+                if !capabilities.iter().any(|(t, diff)| *diff > 0 && *t <= time) {
+                    if uncovered == 0 {
+                        report.problems.push(format!("records at time {:?} without a capability at offset {}", time, offset));
+                    }
+                    uncovered += 1;
+                }
+                for (t, worker, _) in data.into_iter() {
+                    report.records += 1;
+                    report.workers.insert(worker);
+                    let last = latest.entry(worker).or_insert(t);
+                    if t < *last {
+                        regressions += 1;
+                    }
+                    *last = std::cmp::max(*last, t);
+                    report.first = Some(report.first.map_or(t, |first| std::cmp::min(first, t)));
+                    report.last = Some(report.last.map_or(t, |last| std::cmp::max(last, t)));
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                report.problems.push(crate::DiagError::from(e).to_string());
+                break;
+            }
+        }
+    }
+
+    if uncovered > 1 {
+        report.problems.push(format!("{} more batches of records without a capability", uncovered - 1));
+    }
+    if released_twice > 1 {
+        report.problems.push(format!("{} more progress updates releasing missing capabilities", released_twice - 1));
+    }
+    if regressions > 0 {
+        report.problems.push(format!("{} timestamp regression(s)", regressions));
+    }
+
+    // A stream that doesn't release all its capabilities was cut short
+    // (e.g. the recording stopped before the source computation did);
+    // it can still be replayed.
+    report.complete = capabilities.is_empty();
+
+    report
+}
@@ -0,0 +1,70 @@
+//! Vega-Lite chart specs with inlined data, shared by the subcommands
+//! that can produce one (`--format vega`).
+//!
+//! Render a spec with e.g. `vl-convert vl2svg -i chart.vl.json -o chart.svg`.
+
+use std::io::Write;
+
+use crate::DiagError;
+use crate::report::Value;
+
+/// How `arrangements` prints its results (`profile` has its own
+/// `ProfileFormat`, without CSV).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable tables.
+    Text,
+    /// A Vega-Lite spec with the data inlined.
+    Vega,
+    /// Structured records, for scripts.
+    Json,
+    /// Comma-separated values, for spreadsheets and data frames.
+    Csv,
+}
+
+impl OutputFormat {
+    /// Parses a format name, as passed to `--format`.
+    pub fn parse(name: &str) -> Result<Self, DiagError> {
+        match name {
+            "text" => Ok(OutputFormat::Text),
+            "vega" => Ok(OutputFormat::Vega),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(DiagError::InvalidArgs(format!("Invalid --format {} (expected text, vega, json or csv)", name))),
+        }
+    }
+}
+
+/// Writes a Vega-Lite spec to `out`, where `rows` (with values for
+/// `fields`) are inlined as the data, and `mark` and `encoding` are
+/// JSON snippets.
+pub fn write_spec<W: Write>(
+    out: &mut W,
+    title: &str,
+    mark: &str,
+    encoding: &str,
+    fields: &[&str],
+    rows: &[Vec<Value>]) -> Result<(), DiagError> {
+
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"$schema\": \"https://vega.github.io/schema/vega-lite/v5.json\",")?;
+    writeln!(out, "  \"title\": {},", Value::from(title).to_json())?;
+    writeln!(out, "  \"width\": 800,")?;
+    writeln!(out, "  \"height\": 400,")?;
+    writeln!(out, "  \"data\": {{")?;
+    writeln!(out, "    \"values\": [")?;
+    for (i, row) in rows.iter().enumerate() {
+        let fields = fields.iter().zip(row.iter())
+            .map(|(field, value)| format!("{}: {}", Value::from(*field).to_json(), value.to_json()))
+            .collect::<Vec<_>>();
+        let separator = if i + 1 < rows.len() { "," } else { "" };
+        writeln!(out, "      {{{}}}{}", fields.join(", "), separator)?;
+    }
+    writeln!(out, "    ]")?;
+    writeln!(out, "  }},")?;
+    writeln!(out, "  \"mark\": {},", mark)?;
+    writeln!(out, "  \"encoding\": {}", encoding)?;
+    writeln!(out, "}}")?;
+
+    Ok(())
+}
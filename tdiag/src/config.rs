@@ -0,0 +1,842 @@
+//! Configuration shared by all commands: where to get the log streams
+//! from, how to analyse them, and how to report.
+//!
+//! `main.rs` builds a `DiagConfig` from the command line; embedders can
+//! build one directly:
+//!
+//! ```ignore
+//! let config = tdiag::config::DiagConfig::new()
+//!     .port(51317)
+//!     .source_peers(4)
+//!     .diag_workers(2);
+//! let streams = config.timely_streams()?;
+//! tdiag::commands::profile::listen_and_profile(&config, streams, &Default::default())?;
+//! ```
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::DiagError;
+use crate::filter::OperatorFilter;
+use crate::names::NameMap;
+use crate::report::Report;
+use crate::resources::Resources;
+use crate::source::{IdleTimeout, LogStreams, Session};
+use crate::status;
+
+use tdiag_connect::ConnectError;
+use tdiag_connect::compression::Compression;
+use tdiag_connect::handshake::Reply;
+use tdiag_connect::receive::{Connection, ReadAhead, Reconnections, Throttle};
+#[cfg(feature = "tls")]
+use tdiag_connect::receive::TlsConfig;
+use tdiag_connect::recording::{Recording, StreamKind};
+use tdiag_connect::send::FilterSpec;
+
+/// Configuration shared by all commands.
+#[derive(Clone)]
+pub struct DiagConfig {
+    interface: IpAddr,
+    port: u16,
+    differential_port: u16,
+    source_peers: Option<usize>,
+    session_peers: Option<Vec<usize>>,
+    settle: Option<Duration>,
+    reconnect_timeout: Option<Duration>,
+    source_filter: Option<FilterSpec>,
+    compression: Compression,
+    from_stdin: bool,
+    from_files: Option<PathBuf>,
+    diag_workers: usize,
+    name_map: NameMap,
+    operator_filter: OperatorFilter,
+    workers: Option<BTreeSet<usize>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    throttle: Throttle,
+    idle_timeout: Option<IdleTimeout>,
+    report: Option<Report>,
+    verbose: bool,
+    resources: Resources,
+    detach: bool,
+    exit_on_eof: bool,
+    duration: Option<Duration>,
+    tee: Option<PathBuf>,
+    time_range: (Option<Duration>, Option<Duration>),
+    read_ahead: Option<ReadAhead>,
+    multiplex: bool,
+}
+
+impl Default for DiagConfig {
+    fn default() -> Self {
+        DiagConfig {
+            interface: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 51317,
+            differential_port: 51318,
+            source_peers: None,
+            session_peers: None,
+            settle: None,
+            reconnect_timeout: None,
+            source_filter: None,
+            compression: Compression::None,
+            from_stdin: false,
+            from_files: None,
+            diag_workers: 1,
+            name_map: NameMap::default(),
+            operator_filter: OperatorFilter::default(),
+            workers: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            throttle: Throttle::default(),
+            idle_timeout: None,
+            report: None,
+            verbose: false,
+            resources: Resources::default(),
+            detach: false,
+            exit_on_eof: false,
+            duration: None,
+            tee: None,
+            time_range: (None, None),
+            read_ahead: None,
+            multiplex: false,
+        }
+    }
+}
+
+impl DiagConfig {
+    /// The default configuration: listens on 127.0.0.1:51317 (and
+    /// 51318 for differential log streams), with a single diagnostic
+    /// worker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the interface (ip address) to listen on.
+    pub fn interface(mut self, interface: IpAddr) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// Sets the port to listen on for timely log streams.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets the port to listen on for differential log streams.
+    pub fn differential_port(mut self, port: u16) -> Self {
+        self.differential_port = port;
+        self
+    }
+
+    /// Sets the number of workers in the source computation (one log
+    /// stream per worker).
+    pub fn source_peers(mut self, source_peers: usize) -> Self {
+        self.source_peers = Some(source_peers);
+        self
+    }
+
+    /// Accepts connections from source workers until none arrives for
+    /// `settle` after the first one, instead of waiting for a known
+    /// number of them (see `source_peers`).
+    pub fn auto_source_peers(mut self, settle: Duration) -> Self {
+        self.settle = Some(settle);
+        self
+    }
+
+    /// Accepts the log streams of several source computations (sessions),
+    /// with `session_peers[i]` workers in the `i`-th one, instead of a
+    /// single one. Each session connects to its own port, counting up
+    /// from the configured ports (e.g. 51317, 51318, ... for timely
+    /// log streams), and the worker identifiers of each session are
+    /// shifted past those of the previous sessions.
+    pub fn session_peers(mut self, session_peers: Vec<usize>) -> Self {
+        self.session_peers = Some(session_peers);
+        self
+    }
+
+    /// Reads a single log stream from stdin instead of listening for
+    /// connections.
+    pub fn from_stdin(mut self, from_stdin: bool) -> Self {
+        self.from_stdin = from_stdin;
+        self
+    }
+
+    /// Replays the log streams of the recording at `path` (a directory,
+    /// or a file captured with `tee`) instead of listening for
+    /// connections.
+    pub fn from_files(mut self, path: PathBuf) -> Self {
+        self.from_files = Some(path);
+        self
+    }
+
+    /// Only analyses the records of the recording logged between `start`
+    /// (inclusive) and `end` (exclusive), since the start of the source
+    /// computation (see `LogStreams::only_between`).
+    pub fn time_range(mut self, start: Option<Duration>, end: Option<Duration>) -> Self {
+        self.time_range = (start, end);
+        self
+    }
+
+    /// Sets the number of worker threads for the diagnostic computation.
+    pub fn diag_workers(mut self, diag_workers: usize) -> Self {
+        self.diag_workers = diag_workers;
+        self
+    }
+
+    /// Sets the labels to use for operators in the output.
+    pub fn name_map(mut self, name_map: NameMap) -> Self {
+        self.name_map = name_map;
+        self
+    }
+
+    /// Restricts the graph, profile and messages analyses to the
+    /// operators matching `filter`.
+    pub fn operator_filter(mut self, filter: OperatorFilter) -> Self {
+        self.operator_filter = filter;
+        self
+    }
+
+    /// Restricts all analyses to the events of the source workers
+    /// `workers` (with several sessions, the identifiers are global, see
+    /// `session_peers`).
+    pub fn workers(mut self, workers: BTreeSet<usize>) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Accepts TLS-encrypted log streams.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Whether the log streams are accepted over TLS.
+    #[cfg(feature = "tls")]
+    fn uses_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Whether the log streams are accepted over TLS.
+    #[cfg(not(feature = "tls"))]
+    fn uses_tls(&self) -> bool {
+        false
+    }
+
+    /// Limits the rate (in bytes per second) at which the log streams
+    /// are read, across all connections and per connection.
+    pub fn rate_limits(mut self, global: Option<u64>, per_connection: Option<u64>) -> Self {
+        self.throttle = Throttle::new(global, per_connection);
+        self
+    }
+
+    /// Reports (and optionally closes) connections that send no bytes
+    /// for a while, and stops waiting for source workers that don't
+    /// connect.
+    pub fn idle_timeout(mut self, idle_timeout: IdleTimeout) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Limits tdiag's memory usage to `max_bytes`: analyses aggregate
+    /// more coarsely as the budget is approached, and stop reading the
+    /// log streams once it's exceeded. Enforced by the resources reporter
+    /// (see `Resources::spawn_reporter`).
+    pub fn max_memory(mut self, max_bytes: u64) -> Self {
+        self.resources = self.resources.with_memory_budget(max_bytes);
+        self
+    }
+
+    /// Keeps listening once the source workers connected, and waits for
+    /// up to `timeout` for a worker whose connection drops to reconnect
+    /// (see `tdiag_connect::send::ReconnectingSender`), resuming its log
+    /// stream. Not supported with TLS.
+    pub fn reconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.reconnect_timeout = Some(timeout);
+        self
+    }
+
+    /// Asks the source workers that connect to apply `spec` to their
+    /// timely log stream (see `tdiag_connect::send::FilterSpec`), instead
+    /// of their own, by answering their header with it. Not supported
+    /// with TLS, whose connections have no header.
+    pub fn source_filter(mut self, spec: FilterSpec) -> Self {
+        self.source_filter = Some(spec);
+        self
+    }
+
+    /// Decompresses the log streams received from the source computation
+    /// (over connections or stdin, but not recordings, which tdiag
+    /// writes uncompressed).
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Keeps draining the source computation's log streams once the
+    /// analysis stops, so that it isn't disconnected (see
+    /// `await_detached`).
+    pub fn detach(mut self, detach: bool) -> Self {
+        self.detach = detach;
+        self
+    }
+
+    /// Stops the analysis once the source computation closes all its log
+    /// streams, rather than waiting for the user to press enter, e.g. for
+    /// scripted runs.
+    pub fn exit_on_eof(mut self, exit_on_eof: bool) -> Self {
+        self.exit_on_eof = exit_on_eof;
+        self
+    }
+
+    /// Stops the analysis once `duration` has elapsed since the log
+    /// streams were opened, e.g. for unattended runs.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Also copies the log streams received from the source computation
+    /// to a new recording in `dir` while analysing them, so that they
+    /// can be analysed again later (with `--from-files`).
+    pub fn tee(mut self, dir: PathBuf) -> Self {
+        self.tee = Some(dir);
+        self
+    }
+
+    /// Reads each live log stream on a dedicated thread into a buffer of
+    /// up to `capacity` bytes, so that TCP backpressure doesn't slow down
+    /// the source computation while the analysis catches up (once a
+    /// buffer is full, the stream is read only as fast as it's analysed).
+    pub fn read_ahead(mut self, capacity: usize) -> Self {
+        self.read_ahead = Some(ReadAhead::new(capacity));
+        self
+    }
+
+    /// Like `read_ahead`, but appends the log streams to segment files
+    /// in `dir` and replays them from there, so that the source
+    /// computation is never slowed down however far behind the analysis
+    /// is, and sessions of any length fit.
+    pub fn buffer_dir(mut self, dir: PathBuf) -> Self {
+        self.read_ahead = Some(ReadAhead::on_disk(dir));
+        self
+    }
+
+    /// Receives the timely and differential log streams on the same
+    /// port (`port`), telling them apart by the header the source
+    /// workers send when they connect with `TDIAG_LOG_ADDR` (see
+    /// `tdiag_connect::handshake`). Not supported with TLS or
+    /// `reconnect_timeout`.
+    pub fn multiplex(mut self, multiplex: bool) -> Self {
+        self.multiplex = multiplex;
+        self
+    }
+
+    /// Appends the commands' results to `report` as they're derived.
+    pub fn report(mut self, report: Report) -> Self {
+        self.report = Some(report);
+        self
+    }
+
+    /// Prints tdiag's own resource usage periodically.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// The configuration of the diagnostic computation.
+    pub fn timely_configuration(&self) -> timely::Configuration {
+        match self.diag_workers {
+            1 => timely::Configuration::Thread,
+            n => timely::Configuration::Process(n),
+        }
+    }
+
+    /// The labels to use for operators in the output.
+    pub fn names(&self) -> &NameMap {
+        &self.name_map
+    }
+
+    /// The operators to restrict the analyses to.
+    pub fn filter(&self) -> &OperatorFilter {
+        &self.operator_filter
+    }
+
+    /// The source worker whose events describe the dataflow's structure
+    /// (every worker logs the same operators and channels): the first of
+    /// the selected workers, or worker 0.
+    ///
+    /// Fails if the selected workers belong to several sessions (see
+    /// `session_peers`): their operators have the same ids but describe
+    /// different dataflows, so per-operator results would mix them up.
+    pub fn structure_worker(&self) -> Result<usize, DiagError> {
+        if let Some(session_peers) = self.session_peers.as_ref().filter(|peers| peers.len() > 1) {
+            let mut first = 0;
+            let sessions = session_peers.iter()
+                .map(|peers| {
+                    let range = first..first + peers;
+                    first += peers;
+                    range
+                })
+                .filter(|range| self.workers.as_ref().map_or(true, |workers| workers.iter().any(|w| range.contains(w))))
+                .count();
+            if sessions > 1 {
+                return Err(DiagError::InvalidArgs(
+                    "Per-operator analyses need the workers of a single session: select them with --workers".to_string()));
+            }
+        }
+        Ok(self.workers.as_ref().and_then(|workers| workers.iter().next().cloned()).unwrap_or(0))
+    }
+
+    /// Where to append the commands' results, if anywhere.
+    pub fn reports(&self) -> Option<&Report> {
+        self.report.as_ref()
+    }
+
+    /// Whether to print tdiag's own resource usage.
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    /// The queues of the streams opened with this configuration, to
+    /// report tdiag's own resource usage, and the memory budget.
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    /// Blocks until the source computation closed the log streams that
+    /// are drained after the analysis stopped (with `detach`).
+    pub fn await_detached(&self) {
+        if !self.detach || self.resources.all_closed() {
+            return;
+        }
+        // Interrupting tdiag now disconnects the source computation.
+        crate::signals::disarm();
+        status::info("draining", "Draining the log streams until the source computation closes them, so that it keeps running (interrupt tdiag to disconnect it)");
+        while !self.resources.all_closed() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    /// The rate limits, shared by all streams opened with this
+    /// configuration.
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    /// The statistics of the read-ahead buffers, if enabled.
+    pub fn read_ahead_stats(&self) -> Option<&ReadAhead> {
+        self.read_ahead.as_ref()
+    }
+
+    /// Waits for the source computation's timely log streams, or opens
+    /// stdin or a recording.
+    pub fn timely_streams(&self) -> Result<LogStreams, DiagError> {
+        if let Some(recording) = self.recording()? {
+            return self.recorded_streams(&recording, StreamKind::Timely);
+        }
+        self.create_tee_dir()?;
+        if self.from_stdin {
+            status::info("reading_stdin", "Reading a log stream from stdin");
+            let streams = self.timed(self.selected(self.read_ahead_of(self.teed(LogStreams::stdin(), StreamKind::Timely))
+                .compressed(self.compression)
+                .throttled(self.throttle.clone())
+                .monitored(self.resources.clone(), "timely")));
+            self.write_tee_manifest(&[&streams])?;
+            return Ok(streams);
+        }
+
+        let streams = if self.multiplex {
+            let (timely_streams, _) = self.accept_multiplexed(self.bind("Timely", self.port)?, false)?;
+            timely_streams
+        } else {
+            self.accept(self.bind("Timely", self.port)?)?
+        };
+        let streams = self.teed(streams, StreamKind::Timely)
+            .monitored(self.resources.clone(), "timely");
+        status::info("connected", "Trace sources connected");
+        self.write_tee_manifest(&[&streams])?;
+        Ok(streams)
+    }
+
+    /// Waits for the source computation's timely and differential log
+    /// streams.
+    pub fn timely_and_differential_streams(&self) -> Result<(LogStreams, LogStreams), DiagError> {
+        if let Some(recording) = self.recording()? {
+            return Ok((
+                self.recorded_streams(&recording, StreamKind::Timely)?,
+                self.recorded_streams(&recording, StreamKind::Differential)?,
+            ));
+        }
+        if self.from_stdin {
+            return Err(DiagError::InvalidArgs("--from-stdin is not supported for differential diagnostics, which read two log streams".to_string()));
+        }
+        self.create_tee_dir()?;
+        if self.multiplex {
+            let (timely_streams, differential_streams) = self.accept_multiplexed(self.bind("Timely and Differential", self.port)?, true)?;
+            let timely_streams = self.teed(timely_streams, StreamKind::Timely)
+                .monitored(self.resources.clone(), "timely");
+            let differential_streams = self.teed(differential_streams.expect("differential streams accepted"), StreamKind::Differential)
+                .monitored(self.resources.clone(), "differential");
+            status::info("connected", "Trace sources connected");
+            self.write_tee_manifest(&[&timely_streams, &differential_streams])?;
+            return Ok((timely_streams, differential_streams));
+        }
+        let sessions = self.require_sessions()?.len() as u32;
+        let (first, last) = (std::cmp::min(self.port, self.differential_port), std::cmp::max(self.port, self.differential_port));
+        if u32::from(first) + sessions > u32::from(last) {
+            return Err(DiagError::InvalidArgs(format!(
+                "The ports of the {} sessions' Timely ({}) and Differential ({}) log streams overlap, pass a --differential-port further apart",
+                sessions, self.port, self.differential_port)));
+        }
+
+        // It's crucial that we bind to both listening addresses first,
+        // before waiting for connections. Otherwise we will open up the
+        // potential for a race condition in the source computation.
+
+        let timely_listeners = self.bind("Timely", self.port)?;
+        let mut differential_listeners = self.bind("Differential", self.differential_port)?;
+
+        let timely_streams = self.teed(self.accept(timely_listeners)?, StreamKind::Timely)
+            .monitored(self.resources.clone(), "timely");
+        // Once detected, the source workers are expected to connect
+        // their differential log streams as well.
+        for (_, peers) in differential_listeners.iter_mut().filter(|(_, peers)| peers.is_none()) {
+            *peers = timely_streams.source_peers();
+        }
+        let differential_streams = self.teed(self.accept(differential_listeners)?, StreamKind::Differential)
+            .monitored(self.resources.clone(), "differential");
+
+        status::info("connected", "Trace sources connected");
+        self.write_tee_manifest(&[&timely_streams, &differential_streams])?;
+        Ok((timely_streams, differential_streams))
+    }
+
+    /// Waits for `source_peers` source workers to connect their log
+    /// streams of `kind` to `listener`, and reads them as configured,
+    /// like `timely_streams` does on its own listener. Call
+    /// `write_tee_manifest` once all kinds are opened.
+    pub(crate) fn accepted_streams(&self, listener: TcpListener, source_peers: usize, kind: StreamKind) -> Result<LogStreams, DiagError> {
+        self.create_tee_dir()?;
+        let streams = self.accept(vec![(listener, Some(source_peers))])?;
+        Ok(self.teed(streams, kind).monitored(self.resources.clone(), kind.name()))
+    }
+
+    /// Reads the log streams of `kind` from `sockets`, already connected
+    /// to the source computation's workers, as configured (see
+    /// `accepted_streams`).
+    pub(crate) fn connected_streams(&self, sockets: Vec<TcpStream>, kind: StreamKind) -> Result<LogStreams, DiagError> {
+        if self.uses_tls() {
+            return Err(DiagError::InvalidArgs("Connected sockets can't be read over TLS".to_string()));
+        }
+        self.create_tee_dir()?;
+        let sockets = sockets.into_iter()
+            .map(|socket| {
+                // As `await_sockets` leaves them.
+                socket.set_nonblocking(true)?;
+                Ok(Some(socket))
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()
+            .map_err(|e| DiagError::io("cannot configure a log stream socket", e))?;
+        let sessions = vec![Session { peers: sockets.len(), connected: sockets.len(), worker_offset: 0 }];
+        let streams = self.accepted(LogStreams::tcp(sockets), sessions)?;
+        Ok(self.teed(streams, kind).monitored(self.resources.clone(), kind.name()))
+    }
+
+    fn recording(&self) -> Result<Option<Recording>, DiagError> {
+        match &self.from_files {
+            Some(path) => {
+                if self.from_stdin {
+                    return Err(DiagError::InvalidArgs("--from-files and --from-stdin are mutually exclusive".to_string()));
+                }
+                if self.tee.is_some() {
+                    return Err(DiagError::InvalidArgs("--tee only copies live log streams, not --from-files".to_string()));
+                }
+                if self.read_ahead.is_some() {
+                    return Err(DiagError::InvalidArgs("--read-ahead and --buffer-dir only buffer live log streams, not --from-files".to_string()));
+                }
+                Ok(Some(Recording::open(path)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Opens the recorded streams of a kind, ignoring `--source-peers`.
+    fn recorded_streams(&self, recording: &Recording, kind: StreamKind) -> Result<LogStreams, DiagError> {
+        let streams = recording.streams_of(kind).len();
+        if streams == 0 {
+            return Err(DiagError::Logs(format!("The recording has no {} streams", kind.name())));
+        }
+        status::info("reading_recording", &format!("Replaying {} recorded {} streams", streams, kind.name()));
+        Ok(self.timed(self.selected(LogStreams::recorded(recording, kind)
+            .only_between(self.time_range.0, self.time_range.1)
+            .throttled(self.throttle.clone())
+            .monitored(self.resources.clone(), kind.name()))))
+    }
+
+    /// Creates the recording directory set with `tee`, if any, before
+    /// waiting for connections so that an unusable directory is
+    /// reported right away.
+    fn create_tee_dir(&self) -> Result<(), DiagError> {
+        if let Some(dir) = &self.tee {
+            if self.reconnect_timeout.is_some() {
+                // A reconnected source worker starts a new stream, which
+                // can't be appended to the recorded one.
+                return Err(DiagError::InvalidArgs("--tee is not supported with --reconnect-timeout".to_string()));
+            }
+            tdiag_connect::recording::create_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Copies `streams` to the recording directory set with `tee`, if
+    /// any.
+    fn teed(&self, streams: LogStreams, kind: StreamKind) -> LogStreams {
+        match &self.tee {
+            Some(dir) => streams.tee(dir, kind),
+            None => streams,
+        }
+    }
+
+    /// Reads `streams` ahead as set with `read_ahead` or `buffer_dir`, if
+    /// at all.
+    fn read_ahead_of(&self, streams: LogStreams) -> LogStreams {
+        match &self.read_ahead {
+            Some(read_ahead) => streams.read_ahead(read_ahead.clone()),
+            None => streams,
+        }
+    }
+
+    /// Lists the copied streams in the recording's manifest, so that it
+    /// can be replayed even if tdiag doesn't stop cleanly.
+    pub(crate) fn write_tee_manifest(&self, streams: &[&LogStreams]) -> Result<(), DiagError> {
+        if let Some(dir) = &self.tee {
+            let recorded = streams.iter().flat_map(|streams| streams.tee_streams()).collect::<Vec<_>>();
+            tdiag_connect::recording::write_manifest(dir, &recorded)?;
+            status::info("teeing", &format!("Copying {} streams to {}", recorded.len(), dir.display()));
+        }
+        Ok(())
+    }
+
+    /// Drops the events of the source workers that aren't selected, if
+    /// any are.
+    fn selected(&self, streams: LogStreams) -> LogStreams {
+        match &self.workers {
+            Some(workers) => streams.only_workers(workers.clone()),
+            None => streams,
+        }
+    }
+
+    /// Starts the capture duration, if any.
+    fn timed(&self, streams: LogStreams) -> LogStreams {
+        match self.duration {
+            Some(duration) => streams.stop_after(duration),
+            None => streams,
+        }
+    }
+
+    fn require_source_peers(&self) -> Result<usize, DiagError> {
+        self.source_peers.ok_or_else(|| DiagError::InvalidArgs("--source-peers is required".to_string()))
+    }
+
+    /// The number of workers in each source computation: those set
+    /// with `session_peers`, or a single one with `source_peers` (`None`
+    /// if detected with `auto_source_peers`).
+    fn require_sessions(&self) -> Result<Vec<Option<usize>>, DiagError> {
+        match (&self.session_peers, self.settle) {
+            (Some(session_peers), _) => Ok(session_peers.iter().cloned().map(Some).collect()),
+            (None, Some(_)) => Ok(vec![None]),
+            (None, None) => Ok(vec![Some(self.require_source_peers()?)]),
+        }
+    }
+
+    /// Listens for the `kind` log streams of each session, on
+    /// consecutive ports starting at `port`.
+    fn bind(&self, kind: &str, port: u16) -> Result<Vec<(TcpListener, Option<usize>)>, DiagError> {
+        let sessions = self.require_sessions()?;
+        let several = sessions.len() > 1;
+        sessions.into_iter().enumerate().map(|(index, peers)| {
+            let port = port.checked_add(index as u16)
+                .ok_or_else(|| DiagError::InvalidArgs(format!("No port left for session {}", index)))?;
+            let session = if several { format!(" from session {}", index) } else { String::new() };
+            let peers_s = peers.map_or(String::new(), |peers| format!("{} ", peers));
+            status::info("listening", &format!("Listening for {}{} connections{} on {}:{}", peers_s, kind, session, self.interface, port));
+            Ok((tdiag_connect::receive::bind(self.interface, port)?, peers))
+        }).collect()
+    }
+
+    /// What to answer the headers of the source workers' connections
+    /// with (see `tdiag_connect::handshake`).
+    fn reply(&self) -> Reply {
+        Reply { filter: self.source_filter.as_ref().map(|spec| spec.to_string()) }
+    }
+
+    /// Waits for the workers of each session to connect to its listener,
+    /// over TLS if enabled.
+    fn accept(&self, listeners: Vec<(TcpListener, Option<usize>)>) -> Result<LogStreams, DiagError> {
+        let timeout = self.idle_timeout.map(|idle| idle.timeout);
+        let mut sessions = Vec::with_capacity(listeners.len());
+        let mut worker_offset = 0;
+        let mut session = |peers: Option<usize>, connected: usize| {
+            let peers = peers.unwrap_or(connected);
+            sessions.push(Session { peers, connected, worker_offset });
+            worker_offset += peers;
+        };
+
+        let rejected = |addr: SocketAddr, err: ConnectError| {
+            status::warn("connection_rejected", &format!("Rejected connection from {}: {}", addr, DiagError::from(err)));
+        };
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.tls {
+                if self.reconnect_timeout.is_some() {
+                    return Err(DiagError::InvalidArgs("--reconnect-timeout is not supported with TLS".to_string()));
+                }
+                if self.source_filter.is_some() {
+                    return Err(DiagError::InvalidArgs("--source-filter is not supported with TLS".to_string()));
+                }
+                let acceptor = tls.acceptor()?;
+                let mut all = Vec::new();
+                for (listener, peers) in listeners {
+                    let streams = match (peers, self.settle) {
+                        (None, Some(settle)) => tdiag_connect::receive::await_tls_sockets_settle(listener, settle, &acceptor, timeout, rejected)?,
+                        _ => tdiag_connect::receive::await_tls_sockets(listener, peers.unwrap_or(0), &acceptor, timeout, rejected)?,
+                    };
+                    session(peers, streams.len());
+                    all.extend(streams);
+                }
+                return self.accepted(LogStreams::tls(all), sessions);
+            }
+        }
+
+        let reply = self.reply();
+        let mut all = Vec::new();
+        let mut headers = Vec::new();
+        let mut reconnections = Vec::new();
+        for (listener, peers) in listeners {
+            let reconnect_listener = match self.reconnect_timeout {
+                Some(_) => Some(listener.try_clone()?),
+                None => None,
+            };
+            let connections = match (peers, self.settle) {
+                (None, Some(settle)) => tdiag_connect::receive::await_connections_settle(listener, settle, timeout, &reply, rejected)?,
+                (peers, _) => tdiag_connect::receive::await_connections(listener, peers.unwrap_or(0), timeout, &reply, rejected)?,
+            };
+            report_connections(&connections);
+            session(peers, connections.len());
+            for connection in connections {
+                headers.push(connection.header);
+                all.push(Some(connection.socket));
+            }
+            if let Some(listener) = reconnect_listener {
+                reconnections.push(Reconnections::new(listener, reply.clone())?);
+            }
+        }
+        let streams = match self.reconnect_timeout {
+            Some(timeout) => LogStreams::tcp(all).with_reconnections(reconnections, headers, timeout),
+            None => LogStreams::tcp(all),
+        };
+
+        self.accepted(streams, sessions)
+    }
+
+    /// Waits for the workers of each session to connect to its listener,
+    /// sending both their timely and (with `differential`) differential
+    /// log streams, told apart by their header.
+    fn accept_multiplexed(&self, listeners: Vec<(TcpListener, Option<usize>)>, differential: bool) -> Result<(LogStreams, Option<LogStreams>), DiagError> {
+        if self.uses_tls() {
+            return Err(DiagError::InvalidArgs("--multiplex is not supported with TLS".to_string()));
+        }
+        if self.reconnect_timeout.is_some() {
+            return Err(DiagError::InvalidArgs("--multiplex is not supported with --reconnect-timeout".to_string()));
+        }
+        let timeout = self.idle_timeout.map(|idle| idle.timeout);
+        let (mut timely, mut differentials) = (Vec::new(), Vec::new());
+        let (mut timely_sessions, mut differential_sessions) = (Vec::new(), Vec::new());
+        let mut worker_offset = 0;
+        for (listener, peers) in listeners {
+            let peers = peers.ok_or_else(|| DiagError::InvalidArgs("--multiplex needs the number of source workers, not --source-peers auto".to_string()))?;
+            let rejected = |addr: SocketAddr, err: ConnectError| {
+                status::warn("connection_rejected", &format!("Rejected connection from {}: {}", addr, DiagError::from(err)));
+            };
+            let sockets = tdiag_connect::receive::await_multiplexed_sockets(listener, peers, differential, timeout, &self.reply(), rejected)?;
+            report_connections(&sockets.timely);
+            report_connections(&sockets.differential);
+            timely_sessions.push(Session { peers, connected: sockets.timely.len(), worker_offset });
+            differential_sessions.push(Session { peers, connected: sockets.differential.len(), worker_offset });
+            worker_offset += peers;
+            timely.extend(sockets.timely.into_iter().map(|connection| Some(connection.socket)));
+            differentials.extend(sockets.differential.into_iter().map(|connection| Some(connection.socket)));
+        }
+        let timely_streams = self.accepted(LogStreams::tcp(timely), timely_sessions)?;
+        let differential_streams = if differential {
+            Some(self.accepted(LogStreams::tcp(differentials), differential_sessions)?)
+        } else {
+            None
+        };
+        Ok((timely_streams, differential_streams))
+    }
+
+    /// Reads the streams accepted from the workers of `sessions` as
+    /// configured.
+    fn accepted(&self, mut streams: LogStreams, sessions: Vec<Session>) -> Result<LogStreams, DiagError> {
+        if let (None, Some(settle)) = (&self.session_peers, self.settle) {
+            let connected = sessions.iter().map(|s| s.connected).sum::<usize>();
+            if connected == 0 {
+                return Err(DiagError::Logs("No source workers connected within the idle timeout".to_string()));
+            }
+            status::info("peers_detected", &format!("Detected {} source workers (no new connections for {}ms)", connected, settle.as_millis()));
+        }
+
+        let several = sessions.len() > 1;
+        for (index, session) in sessions.iter().enumerate() {
+            if session.connected < session.peers {
+                let of_session = if several { format!(" of session {}", index) } else { String::new() };
+                status::warn("sources_missing", &format!("Only {} of {} source workers{} connected within the idle timeout, proceeding without the others", session.connected, session.peers, of_session));
+            }
+        }
+        if self.session_peers.is_some() {
+            for (index, session) in sessions.iter().enumerate() {
+                status::info("session", &format!("Session {}: source workers {}-{} (logged as workers 0-{})",
+                    index, session.worker_offset, session.worker_offset + session.peers - 1, session.peers - 1));
+            }
+            streams = streams.with_sessions(sessions);
+        }
+        if let Some(idle) = self.idle_timeout {
+            streams = streams.with_idle_timeout(idle);
+        }
+        streams = self.read_ahead_of(streams.compressed(self.compression));
+        if self.detach {
+            streams = streams.detached();
+        }
+        if self.exit_on_eof {
+            streams = streams.exit_on_eof();
+        }
+        Ok(self.timed(self.selected(streams.throttled(self.throttle.clone()))))
+    }
+}
+
+/// Reports what the header of each connection (of a single listener)
+/// says about its source worker, and warns about workers that connected
+/// more than once, e.g. two source computations logging to the same
+/// port.
+fn report_connections(connections: &[Connection]) {
+    let mut workers = BTreeSet::new();
+    for connection in connections {
+        match &connection.header {
+            Some(header) => {
+                status::info("source_connected", &format!("Source worker {} connected from {} ({} log stream, timely {}, protocol version {})",
+                    header.worker, connection.addr, header.kind.name(), header.timely_version, header.version));
+                if !workers.insert((header.kind, header.worker)) {
+                    status::warn("source_duplicate", &format!("Source worker {} connected more than once for its {} log stream, are several source computations logging to the same port?",
+                        header.worker, header.kind.name()));
+                }
+            }
+            None => status::info("source_connected", &format!("A source worker connected from {} (without a header, its versions can't be checked)", connection.addr)),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Restricting analyses to some operators.
+//!
+//! Very large dataflows make for noisy graphs and profiles. An operator
+//! filter keeps the operators matching an address pattern or a name:
+//!
+//! - address patterns are addresses (`0.1.3` or `[0, 1, 3]`) where `*`
+//! stands for any index; a trailing `*` stands for any number (at least
+//! one) of trailing indices, so that `0.1.*` matches all the operators
+//! in the scope at `0.1`, however deeply nested;
+//! - names match if they contain the given string, regardless of case,
+//! either as logged or as labelled by the name map.
+//!
+//! An operator matches if it matches any of the address patterns (if
+//! any) and any of the names (if any).
+
+use crate::DiagError;
+use crate::names::NameMap;
+
+/// A component of an address pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Component {
+    Index(usize),
+    Any,
+}
+
+/// Selects operators by address and name; matches all operators when
+/// empty.
+#[derive(Clone, Default, Debug)]
+pub struct OperatorFilter {
+    addrs: Vec<Vec<Component>>,
+    /// Lowercase.
+    names: Vec<String>,
+}
+
+impl OperatorFilter {
+    /// A filter matching all operators.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also matches the operators whose address matches `pattern` (e.g.
+    /// `0.1.*`).
+    pub fn addr(mut self, pattern: &str) -> Result<Self, DiagError> {
+        let components = pattern.trim().trim_start_matches('[').trim_end_matches(']')
+            .split(|c| c == '.' || c == ',')
+            .map(|x| match x.trim() {
+                "*" => Ok(Component::Any),
+                x => x.parse::<usize>().map(Component::Index)
+                    .map_err(|e| DiagError::parse(format!("Invalid operator address pattern {}", pattern), e)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.addrs.push(components);
+        Ok(self)
+    }
+
+    /// Also matches the operators whose name contains `name`, regardless
+    /// of case.
+    pub fn name(mut self, name: &str) -> Self {
+        self.names.push(name.to_lowercase());
+        self
+    }
+
+    /// Whether the filter matches all operators.
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty() && self.names.is_empty()
+    }
+
+    /// Whether the operator at `addr`, logged as `name`, matches.
+    pub fn matches(&self, addr: &[usize], name: &str, name_map: &NameMap) -> bool {
+        let addr_matches = self.addrs.is_empty() || self.addrs.iter().any(|pattern| matches_pattern(pattern, addr));
+        let name_matches = self.names.is_empty() || {
+            let (name, label) = (name.to_lowercase(), name_map.label(addr, name).to_lowercase());
+            self.names.iter().any(|filter| name.contains(filter.as_str()) || label.contains(filter.as_str()))
+        };
+        addr_matches && name_matches
+    }
+}
+
+fn matches_pattern(pattern: &[Component], addr: &[usize]) -> bool {
+    let matches_at = |(component, index): (&Component, &usize)| match component {
+        Component::Index(i) => i == index,
+        Component::Any => true,
+    };
+    match pattern.split_last() {
+        Some((Component::Any, prefix)) if addr.len() > prefix.len() =>
+            prefix.iter().zip(addr.iter()).all(matches_at),
+        _ => pattern.len() == addr.len() && pattern.iter().zip(addr.iter()).all(matches_at),
+    }
+}
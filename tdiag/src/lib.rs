@@ -3,23 +3,221 @@
 #![deny(missing_docs)]
 
 pub mod commands;
+pub mod config;
+pub mod filter;
+mod json;
+pub mod names;
+pub mod report;
+pub mod resources;
+pub mod session;
+pub mod signals;
+pub mod source;
+pub mod statsd;
+pub mod status;
+pub mod telemetry;
+
+pub use commands::arrangements::ArrangementSize;
+pub use commands::graph::{ChannelEdge, DataflowGraph, Divergence, OperatorNode, ScopeNode};
+pub use commands::profile::ProfileEntry;
+pub use session::{Session, SessionBuilder, SessionResults, StopHandle};
 
 /// An error generated by one of the commands of this tool.
-pub struct DiagError(pub String);
+///
+/// The message of each variant already includes its cause, which
+/// `source` also returns for inspection.
+#[derive(Debug)]
+pub enum DiagError {
+    /// Reading or writing a file or socket failed.
+    Io {
+        /// What was being done, e.g. `cannot create out.html`.
+        context: String,
+        /// The underlying error.
+        source: std::io::Error,
+    },
+    /// Receiving the log streams failed (including TLS and recordings).
+    Connect(tdiag_connect::ConnectError),
+    /// The timely computation running the analysis failed.
+    Timely(String),
+    /// A value (a command line argument, a name map, ...) is malformed.
+    Parse {
+        /// What was malformed, e.g. `Invalid --port`.
+        context: String,
+        /// The underlying parse error, if any.
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    /// The arguments are well-formed but invalid, or don't fit together.
+    InvalidArgs(String),
+    /// The log streams don't allow the analysis, e.g. no source worker
+    /// connected or a recording lacks a stream.
+    Logs(String),
+    /// A check asked for on the command line failed, e.g. an unexpected
+    /// graph fingerprint or a recording with problems.
+    CheckFailed(String),
+    /// One of tdiag's own threads failed.
+    Internal(String),
+}
+
+impl DiagError {
+    /// An I/O error, with what was being done.
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        DiagError::Io { context: context.into(), source }
+    }
+
+    /// A parse error, with what was being parsed.
+    pub fn parse(context: impl Into<String>, source: impl std::error::Error + Send + Sync + 'static) -> Self {
+        DiagError::Parse { context: context.into(), source: Some(Box::new(source)) }
+    }
+
+    /// The exit status of the command line tool for this error:
+    ///
+    /// * 2: invalid arguments (`Parse`, `InvalidArgs`);
+    /// * 3: an I/O error (`Io`);
+    /// * 4: no usable log streams (`Connect`, `Logs`);
+    /// * 5: a requested check failed (`CheckFailed`);
+    /// * 1: any other failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DiagError::Parse { .. } | DiagError::InvalidArgs(_) => 2,
+            DiagError::Io { .. } => 3,
+            DiagError::Connect(_) | DiagError::Logs(_) => 4,
+            DiagError::CheckFailed(_) => 5,
+            DiagError::Timely(_) | DiagError::Internal(_) => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for DiagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DiagError::Io { context, source } => write!(f, "{}: {}", context, source),
+            DiagError::Connect(e) => write!(f, "{}", e),
+            DiagError::Timely(e) => write!(f, "error in the timely computation: {}", e),
+            DiagError::Parse { context, source: Some(source) } => write!(f, "{}: {}", context, source),
+            DiagError::Parse { context, source: None } => write!(f, "{}", context),
+            DiagError::InvalidArgs(e)
+            | DiagError::Logs(e)
+            | DiagError::CheckFailed(e)
+            | DiagError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DiagError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiagError::Io { source, .. } => Some(source),
+            DiagError::Connect(e) => Some(e),
+            DiagError::Parse { source: Some(source), .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for DiagError {
     fn from(error: std::io::Error) -> Self {
-        DiagError(format!("io error: {}", error.to_string()))
+        DiagError::io("io error", error)
     }
 }
 
 impl From<tdiag_connect::ConnectError> for DiagError {
     fn from(error: tdiag_connect::ConnectError) -> Self {
-        match error {
-            tdiag_connect::ConnectError::IoError(e) => DiagError(format!("io error: {}", e)),
-            tdiag_connect::ConnectError::Other(e) => DiagError(e),
+        DiagError::Connect(error)
+    }
+}
+
+/// Parses a duration such as `1500ms`, `30s`, `2m`, `1h` or `2m30s`
+/// (plain numbers are seconds).
+pub fn parse_duration(duration: &str) -> Result<std::time::Duration, DiagError> {
+    let invalid = || DiagError::Parse {
+        context: format!("Invalid duration {} (e.g. 1500ms, 30s, 2m30s, 1h)", duration),
+        source: None,
+    };
+
+    let mut rest = duration.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut secs = 0f64;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or_else(|| rest.len());
+        let value: f64 = rest[..split].parse().map_err(|_| invalid())?;
+        rest = &rest[split..];
+        let unit_length = rest.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or_else(|| rest.len());
+        secs += match &rest[..unit_length] {
+            "ms" => value / 1000.0,
+            "" | "s" => value,
+            "m" => value * 60.0,
+            "h" => value * 3600.0,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_length..];
+    }
+    // `Duration::from_secs_f64` panics on durations it can't represent.
+    if !secs.is_finite() || secs >= u64::max_value() as f64 {
+        return Err(DiagError::Parse {
+            context: format!("Invalid duration {}: too long", duration),
+            source: None,
+        });
+    }
+    Ok(std::time::Duration::from_secs_f64(secs))
+}
+
+/// Parses a number of bytes such as `512M`, `2G` or `1500000` (with
+/// binary suffixes `K`, `M` and `G`).
+pub fn parse_bytes(bytes: &str) -> Result<u64, DiagError> {
+    let invalid = || DiagError::Parse {
+        context: format!("Invalid size {} (e.g. 1500000, 512M, 2G)", bytes),
+        source: None,
+    };
+
+    let trimmed = bytes.trim();
+    let (number, unit) = match trimmed.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&trimmed[..i], c.to_ascii_uppercase()),
+        _ => (trimmed, 'B'),
+    };
+    let multiplier: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    let value: u64 = number.parse().map_err(|_| invalid())?;
+    value.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// The number of source workers `parse_workers` accepts identifiers
+/// for, far more than any source computation runs.
+pub const MAX_WORKERS: usize = 1 << 16;
+
+/// Parses a set of source worker identifiers such as `0,3-5` (ranges
+/// are inclusive), each below `MAX_WORKERS`.
+pub fn parse_workers(workers: &str) -> Result<std::collections::BTreeSet<usize>, DiagError> {
+    let invalid = || DiagError::Parse {
+        context: format!("Invalid workers {} (e.g. 0,3-5)", workers),
+        source: None,
+    };
+
+    let mut set = std::collections::BTreeSet::new();
+    for part in workers.split(',') {
+        let (first, last) = match part.trim().find('-') {
+            Some(dash) => (&part.trim()[..dash], &part.trim()[dash + 1..]),
+            None => (part.trim(), part.trim()),
+        };
+        let first: usize = first.trim().parse().map_err(|_| invalid())?;
+        let last: usize = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        if last >= MAX_WORKERS {
+            return Err(DiagError::Parse {
+                context: format!("Invalid workers {}: worker identifiers must be below {}", workers, MAX_WORKERS),
+                source: None,
+            });
         }
+        set.extend(first..=last);
     }
+    Ok(set)
 }
 
 type LoggingTuple = (std::time::Duration, timely::logging::WorkerIdentifier, timely::logging::TimelyEvent);
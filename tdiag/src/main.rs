@@ -4,18 +4,29 @@
 //! See the README and --help for usage information.
 
 use tdiag::*;
+use tdiag::config::DiagConfig;
+
+/// Whether a flag was passed, or its environment variable is set to
+/// `1` or `true` (clap's env support only covers options with values).
+fn flag(args: &clap::ArgMatches, name: &str, env: &str) -> bool {
+    args.is_present(name) || std::env::var(env)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
 
 fn run() -> Result<(), DiagError> {
-    let args = clap::App::new("tdiag")
+    let app = clap::App::new("tdiag")
         .about(
 "Diagostic tools for timely-dataflow programs.
 Run the timely program to inspect with `env TIMELY_WORKER_LOG_ADDR=127.0.0.1:51317 cargo run ...`.
 You can customize the interface and port for the receiver (this program) with --interface and --port.
+All options can also be set through TDIAG_* environment variables (e.g. TDIAG_PORT, TDIAG_SOURCE_PEERS); flags are enabled by setting theirs to 1.
 "
         )
         .arg(clap::Arg::with_name("interface")
              .short("i")
              .long("interface")
+             .env("TDIAG_INTERFACE")
              .value_name("INTERFACE")
              .help("Interface (ip address) to listen on; defaults to 127.0.0.1")
              .default_value("127.0.0.1")
@@ -23,6 +34,7 @@ You can customize the interface and port for the receiver (this program) with --
         .arg(clap::Arg::with_name("port")
              .short("p")
              .long("port")
+             .env("TDIAG_PORT")
              .value_name("PORT")
              .help("Port to listen on; defaults to 51317")
              .default_value("51317")
@@ -30,27 +42,640 @@ You can customize the interface and port for the receiver (this program) with --
         .arg(clap::Arg::with_name("source_peers")
              .short("s")
              .long("source-peers")
+             .env("TDIAG_SOURCE_PEERS")
              .value_name("PEERS")
-             .help("Number of workers in the source computation")
-             .required(true))
+             .help("Number of workers in the source computation, or `auto` to accept connections until none arrives for --settle-ms; required unless reading from stdin or a recording"))
+        .arg(clap::Arg::with_name("settle_ms")
+             .long("settle-ms")
+             .env("TDIAG_SETTLE_MS")
+             .value_name("MS")
+             .help("With --source-peers auto, stop accepting connections once none has arrived for this many milliseconds")
+             .default_value("2000")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("session_peers")
+             .long("session-peers")
+             .env("TDIAG_SESSION_PEERS")
+             .value_name("PEERS,...")
+             .help("Number of workers in each of several source computations, inspected together; each connects to its own port, counting up from --port (and --differential-port)")
+             .conflicts_with("source_peers")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("from_stdin")
+             .long("from-stdin")
+             .help("Read a single worker's log stream from stdin instead of listening for connections; stops at the end of the stream [env: TDIAG_FROM_STDIN]"))
+        .arg(clap::Arg::with_name("from_files")
+             .long("from-files")
+             .env("TDIAG_FROM_FILES")
+             .value_name("DIR")
+             .help("Replay the log streams of a recording (e.g. made with record) instead of listening for connections; stops at the end of the recording")
+             .conflicts_with("from_stdin")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("start")
+             .long("start")
+             .env("TDIAG_START")
+             .value_name("TIME")
+             .help("With --from-files, skip the records logged before this time (e.g. 90, 2m30s; since the start of the source computation)")
+             .requires("from_files")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("end")
+             .long("end")
+             .env("TDIAG_END")
+             .value_name("TIME")
+             .help("With --from-files, skip the records logged from this time on (e.g. 90, 2m30s; since the start of the source computation)")
+             .requires("from_files")
+             .takes_value(true))
         .arg(clap::Arg::with_name("diag_workers")
              .short("w")
              .long("diag-workers")
+             .env("TDIAG_DIAG_WORKERS")
              .value_name("WORKERS")
              .help("Number of worker threads for the diagnostic tool")
              .default_value("1"))
+        .arg(clap::Arg::with_name("name_map")
+             .long("name-map")
+             .env("TDIAG_NAME_MAP")
+             .value_name("PATH")
+             .help("A toml file mapping operator addresses or names to labels to use in the output")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("workers")
+             .long("workers")
+             .env("TDIAG_WORKERS")
+             .value_name("WORKERS")
+             .help("Restrict all analyses to the events of these source workers (e.g. 0,3-5); the dataflow's structure is taken from the first one")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("filter_addr")
+             .long("filter-addr")
+             .env("TDIAG_FILTER_ADDR")
+             .value_name("PATTERN")
+             .help("Restrict graph, profile and messages to the operators at addresses matching this pattern, where * stands for any index (e.g. 0.1.* for all operators in scope 0.1; may be repeated)")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(clap::Arg::with_name("filter_name")
+             .long("filter-name")
+             .env("TDIAG_FILTER_NAME")
+             .value_name("NAME")
+             .help("Restrict graph, profile and messages to the operators whose name (or label) contains this, regardless of case (may be repeated)")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(clap::Arg::with_name("rate-limit")
+             .long("rate-limit")
+             .env("TDIAG_RATE_LIMIT")
+             .value_name("BYTES_PER_SEC")
+             .help("Maximum rate at which to read the log streams, across all connections")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("connection-rate-limit")
+             .long("connection-rate-limit")
+             .env("TDIAG_CONNECTION_RATE_LIMIT")
+             .value_name("BYTES_PER_SEC")
+             .help("Maximum rate at which to read each log stream")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("idle_timeout")
+             .long("idle-timeout")
+             .env("TDIAG_IDLE_TIMEOUT")
+             .value_name("SECS")
+             .help("Report source workers that don't connect, or connections that send no bytes, within this many seconds, and proceed without them")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("close_idle")
+             .long("close-idle")
+             .help("Close connections reported by --idle-timeout, treating their stream as complete [env: TDIAG_CLOSE_IDLE]")
+             .requires("idle_timeout"))
+        .arg(clap::Arg::with_name("reconnect_timeout")
+             .long("reconnect-timeout")
+             .env("TDIAG_RECONNECT_TIMEOUT")
+             .value_name("SECS")
+             .help("Wait this many seconds for source workers whose connection drops to reconnect, and resume their log stream (they need to send it with tdiag_connect::send::connect_reconnecting)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("source_filter")
+             .long("source-filter")
+             .env("TDIAG_SOURCE_FILTER")
+             .value_name("SPEC")
+             .help("Ask the source workers to only send these timely events, e.g. types=operates,channels,schedule;sample=schedule:10 (they need to send their log streams with tdiag_connect::send)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("multiplex")
+             .long("multiplex")
+             .help("Receive the timely and differential log streams on the same --port, told apart by the header the source workers send when connecting with tdiag_connect::send and TDIAG_LOG_ADDR; the differential --port is then unused [env: TDIAG_MULTIPLEX]"))
+        .arg(clap::Arg::with_name("status_format")
+             .long("status-format")
+             .env("TDIAG_STATUS_FORMAT")
+             .value_name("FORMAT")
+             .help("Format of tdiag's own status messages: text, or json (one object per line, on stderr)")
+             .possible_values(&["text", "json"])
+             .default_value("text"))
+        .arg(clap::Arg::with_name("verbose")
+             .short("v")
+             .long("verbose")
+             .help("Print tdiag's own memory usage, queue depths and records/s every few seconds [env: TDIAG_VERBOSE]"))
+        .arg(clap::Arg::with_name("report")
+             .long("report")
+             .env("TDIAG_REPORT")
+             .value_name("PATH")
+             .help("Append the results to this file as JSON lines (tagged with their kind) as soon as they're derived"))
+        .arg(clap::Arg::with_name("tee")
+             .long("tee")
+             .env("TDIAG_TEE")
+             .value_name("DIR")
+             .help("Also copy the live log streams to a new recording in this directory while analysing them, to replay them later with --from-files")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("detach")
+             .long("detach")
+             .help("Once the analysis stops, keep draining the log streams until the source computation closes them, rather than disconnecting (and crashing) it [env: TDIAG_DETACH]"))
+        .arg(clap::Arg::with_name("exit_on_eof")
+             .long("exit-on-eof")
+             .help("Stop the analysis and write its results once the source computation closes all its log streams, rather than waiting for enter [env: TDIAG_EXIT_ON_EOF]"))
+        .arg(clap::Arg::with_name("duration")
+             .long("duration")
+             .env("TDIAG_DURATION")
+             .value_name("DURATION")
+             .help("Stop the analysis and write its results this long (e.g. 90, 30s, 2m30s, 1h; plain numbers are seconds) after the log streams were opened")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("max_memory")
+             .long("max-memory")
+             .env("TDIAG_MAX_MEMORY")
+             .value_name("BYTES")
+             .help("Memory budget (e.g. 512M, 2G): aggregate more coarsely when approaching it, stop reading the log streams when exceeding it")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("read_ahead")
+             .long("read-ahead")
+             .env("TDIAG_READ_AHEAD")
+             .value_name("BYTES")
+             .help("Read each live log stream on a dedicated thread into a buffer of up to this size (e.g. 64M), so that the source computation isn't slowed down while the analysis catches up")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("buffer_dir")
+             .long("buffer-dir")
+             .env("TDIAG_BUFFER_DIR")
+             .value_name("DIR")
+             .conflicts_with("read_ahead")
+             .help("Like --read-ahead, but append the live log streams to segment files in this directory and replay them from there, so that the source computation is never slowed down")
+             .takes_value(true))
+        .subcommand(clap::SubCommand::with_name("all")
+            .about("Run several analyses over a single capture of the log streams")
+            .arg(clap::Arg::with_name("graph")
+                .long("graph")
+                .env("TDIAG_ALL_GRAPH")
+                .value_name("PATH")
+                .help("Render the dataflow graph to this path, as graph --out does")
+                .takes_value(true))
+            .arg(clap::Arg::with_name("graph_format")
+                .long("graph-format")
+                .env("TDIAG_ALL_GRAPH_FORMAT")
+                .value_name("FORMAT")
+                .help("How to write the graph: html, dot, json or svg, as graph --format")
+                .possible_values(&["html", "dot", "json", "svg"])
+                .default_value("html"))
+            .arg(clap::Arg::with_name("profile")
+                .long("profile")
+                .env("TDIAG_ALL_PROFILE")
+                .value_name("PATH")
+                .help("Write the time spent in each operator to this path, as profile --format json does")
+                .takes_value(true))
+            .arg(clap::Arg::with_name("arrangements")
+                .long("arrangements")
+                .env("TDIAG_ALL_ARRANGEMENTS")
+                .value_name("PATH")
+                .help("Write the arrangement sizes to this path, as differential arrangements --format json does (also reads the Differential log streams)")
+                .takes_value(true))
+            .arg(clap::Arg::with_name("differential_port")
+                .long("differential-port")
+                .env("TDIAG_ALL_DIFFERENTIAL_PORT")
+                .value_name("PORT")
+                .help("Port to listen on for Differential log streams, with --arrangements; defaults to 51318")
+                .default_value("51318"))
+            .arg(clap::Arg::with_name("output-interval")
+                .long("output-interval")
+                .env("TDIAG_ALL_OUTPUT_INTERVAL")
+                .value_name("MS")
+                .help("Interval (in ms) at which arrangement sizes are measured, with --arrangements; defaults to 1000ms")
+                .default_value("1000"))
+        )
         .subcommand(clap::SubCommand::with_name("graph")
             .about("Render a computation's dataflow graph")
             .arg(clap::Arg::with_name("output_path")
                 .short("o")
                 .long("out")
+                .env("TDIAG_GRAPH_OUT")
                 .value_name("PATH")
-                .help("The output path for the generated html file (don't forget the .html extension)")
+                .help("The output path for the generated file (don't forget the .html, .dot, .json or .svg extension); the html file fetches its data from <name>-data.json next to it")
+                .required(true))
+            .arg(clap::Arg::with_name("format")
+                .long("format")
+                .env("TDIAG_GRAPH_FORMAT")
+                .value_name("FORMAT")
+                .help("html, dot for a Graphviz file with a cluster per scope, json for the operators and channels as a JSON document, or svg for an image laid out without a browser")
+                .possible_values(&["html", "dot", "json", "svg"])
+                .default_value("html"))
+            .arg(clap::Arg::with_name("data_only")
+                .long("data-only")
+                .help("Only write the data file the html file fetches, to render a new capture with an existing html file or vice versa [env: TDIAG_GRAPH_DATA_ONLY]"))
+            .arg(clap::Arg::with_name("with_profile")
+                .long("with-profile")
+                .help("Also sum the time spent in each operator, and shade the operators by it [env: TDIAG_GRAPH_WITH_PROFILE]"))
+            .arg(clap::Arg::with_name("all_workers")
+                .long("all-workers")
+                .help("Build the graph from the operators and channels of all source workers, and report those only some of them logged [env: TDIAG_GRAPH_ALL_WORKERS]"))
+            .arg(clap::Arg::with_name("expect_fingerprint")
+                .long("expect-fingerprint")
+                .env("TDIAG_GRAPH_EXPECT_FINGERPRINT")
+                .value_name("FINGERPRINT")
+                .help("Fail if the structural fingerprint of the graph doesn't match this one")
+                .takes_value(true))
+        )
+        .subcommand(clap::SubCommand::with_name("graph-diff")
+            .about("Compare two dataflow graphs written by graph --format json, and print the operators and channels added, removed or renamed")
+            .arg(clap::Arg::with_name("fail_on_changes")
+                .long("fail-on-changes")
+                .help("Fail if the graphs differ [env: TDIAG_GRAPH_DIFF_FAIL_ON_CHANGES]"))
+            .arg(clap::Arg::with_name("before")
+                .value_name("BEFORE")
+                .help("The graph before the change (a JSON graph, or the data file of an html graph)")
+                .required(true))
+            .arg(clap::Arg::with_name("after")
+                .value_name("AFTER")
+                .help("The graph after the change")
+                .required(true))
+        )
+        .subcommand(clap::SubCommand::with_name("profile-diff")
+            .about("Compare two profiles written by profile --format json, largest regression first")
+            .arg(clap::Arg::with_name("min_change")
+                .long("min-change")
+                .env("TDIAG_PROFILE_DIFF_MIN_CHANGE")
+                .value_name("DURATION")
+                .help("Only print the operators whose time changed by at least this much (e.g. 10ms); defaults to 0s")
+                .default_value("0s"))
+            .arg(clap::Arg::with_name("before")
+                .value_name("BEFORE")
+                .help("The profile before the change")
+                .required(true))
+            .arg(clap::Arg::with_name("after")
+                .value_name("AFTER")
+                .help("The profile after the change")
                 .required(true))
         )
         .subcommand(
             clap::SubCommand::with_name("profile")
                 .about("Print total time spent running each operator")
+                .arg(clap::Arg::with_name("heatmap")
+                     .long("heatmap")
+                     .env("TDIAG_PROFILE_HEATMAP")
+                     .value_name("PATH")
+                     .help("Also write a worker × operator heatmap of the time spent in each operator to an html file")
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("long-activations")
+                     .long("long-activations")
+                     .env("TDIAG_PROFILE_LONG_ACTIVATIONS")
+                     .value_name("MS")
+                     .help("Also report every operator activation that took at least this many milliseconds")
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("group-by")
+                     .long("group-by")
+                     .env("TDIAG_PROFILE_GROUP_BY")
+                     .value_name("LEN|ADDR")
+                     .help("Sum the time of operators by the first LEN components of their address, or under the scope at ADDR (e.g. 0.3 or [0, 3]; may be repeated)")
+                     .multiple(true)
+                     .number_of_values(1)
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("format")
+                     .long("format")
+                     .env("TDIAG_PROFILE_FORMAT")
+                     .value_name("FORMAT")
+                     .help("text (the default), vega for a Vega-Lite spec of the time spent in each operator per second, or json for a record per operator ({id, addr, name, is_scope, elapsed_ns}); --long-activations, --group-by, --per-worker, --guarded, --histograms and --output-interval need text")
+                     .possible_values(&["text", "vega", "json"])
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("per-worker")
+                     .long("per-worker")
+                     .help("Print the time spent in each operator on each source worker, to spot skew [env: TDIAG_PROFILE_PER_WORKER]")
+                     .conflicts_with("group-by"))
+                .arg(clap::Arg::with_name("guarded")
+                     .long("guarded")
+                     .help("Also print the time spent in user message handlers versus progress logic in each operator [env: TDIAG_PROFILE_GUARDED]"))
+                .arg(clap::Arg::with_name("histograms")
+                     .long("histograms")
+                     .help("Also print percentiles (p50/p95/p99/max) of the duration of each operator's individual activations [env: TDIAG_PROFILE_HISTOGRAMS]"))
+                .arg(clap::Arg::with_name("output-interval")
+                     .long("output-interval")
+                     .env("TDIAG_PROFILE_OUTPUT_INTERVAL")
+                     .value_name("MS")
+                     .help("Also print the operators scheduled the longest in each interval of this many ms, while the computation runs")
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("top")
+                     .long("top")
+                     .env("TDIAG_PROFILE_TOP")
+                     .value_name("N")
+                     .help("How many operators to print for each interval with --output-interval; defaults to 10")
+                     .default_value("10"))
+                .arg(clap::Arg::with_name("flamegraph")
+                     .long("flamegraph")
+                     .env("TDIAG_PROFILE_FLAMEGRAPH")
+                     .value_name("PATH")
+                     .help("Also write a flamegraph of the time spent in each scope and operator to an svg file")
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("out")
+                     .long("out")
+                     .env("TDIAG_PROFILE_OUT")
+                     .value_name("PATH")
+                     .help("Write the profile to this file instead of stdout")
+                     .takes_value(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("channels")
+                .about("Print every channel with its endpoints and the number of messages and records sent over it")
+                .arg(clap::Arg::with_name("matrix")
+                     .long("matrix")
+                     .help("Print the number of records exchanged between each pair of source workers instead [env: TDIAG_CHANNELS_MATRIX]"))
+                .arg(clap::Arg::with_name("heatmap")
+                     .long("heatmap")
+                     .env("TDIAG_CHANNELS_HEATMAP")
+                     .value_name("PATH")
+                     .help("Also write a worker × channel heatmap of the records sent to an html file")
+                     .takes_value(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("batches")
+                .about("Print the distribution of the sizes of the messages sent over each channel, flagging channels that send many tiny batches")
+                .arg(clap::Arg::with_name("tiny")
+                     .long("tiny")
+                     .env("TDIAG_BATCHES_TINY")
+                     .value_name("RECORDS")
+                     .help("Messages of at most this many records count as tiny")
+                     .default_value("8"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("messages")
+                .about("Print the number of records sent over each channel over time, with the operators at either end")
+                .arg(clap::Arg::with_name("output-interval")
+                     .long("output-interval")
+                     .env("TDIAG_MESSAGES_OUTPUT_INTERVAL")
+                     .value_name("MS")
+                     .help("Interval (in ms) over which to sum the records; defaults to 1000ms")
+                     .default_value("1000"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("epochs")
+                .about("Print the number of records produced for each epoch, and the resulting throughput")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("frontier-lag")
+                .about("Print how long each operator holds back the frontier after its inputs have been drained")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("lifetimes")
+                .about("Print when dataflows (and their operators) are created and dropped, to check that transient dataflows are cleaned up")
+                .arg(clap::Arg::with_name("operators")
+                     .long("operators")
+                     .help("Also print the lifetime of each operator [env: TDIAG_LIFETIMES_OPERATORS]"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("frontiers")
+                .about("Periodically print the operators whose frontier lags behind the frontier of their dataflow's sources")
+                .arg(clap::Arg::with_name("output-interval")
+                     .long("output-interval")
+                     .env("TDIAG_FRONTIERS_OUTPUT_INTERVAL")
+                     .value_name("MS")
+                     .help("Interval (in ms of the source computation's time) at which to print the lagging operators; defaults to 1000ms")
+                     .default_value("1000"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("stuck")
+                .about("Alert when operators' frontiers stop advancing, listing them and the channels with outstanding messages")
+                .arg(clap::Arg::with_name("after")
+                     .long("after")
+                     .env("TDIAG_STUCK_AFTER")
+                     .value_name("DURATION")
+                     .help("How long a frontier may not advance before alerting (e.g. 30s); defaults to 10s")
+                     .default_value("10s"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("progress")
+                .about("Print the progress updates and capability churn generated by each operator")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("park")
+                .about("Print how long each worker was parked (idle) versus busy")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("latency")
+                .about("Estimate the latency from one operator to another, by following messages along the channels between them")
+                .arg(clap::Arg::with_name("from_addr")
+                     .long("from-addr")
+                     .env("TDIAG_LATENCY_FROM_ADDR")
+                     .value_name("ADDR")
+                     .help("Address of the source operator (e.g. 0.3 or [0, 3])")
+                     .required(true))
+                .arg(clap::Arg::with_name("to_addr")
+                     .long("to-addr")
+                     .env("TDIAG_LATENCY_TO_ADDR")
+                     .value_name("ADDR")
+                     .help("Address of the sink operator")
+                     .required(true))
+                .arg(clap::Arg::with_name("window-ms")
+                     .long("window-ms")
+                     .env("TDIAG_LATENCY_WINDOW_MS")
+                     .value_name("MS")
+                     .help("Report percentiles for windows of this length (in ms), by send time; defaults to 1000ms")
+                     .default_value("1000"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("health")
+                .about("Check the log streams for missing workers, gaps and timestamp regressions")
+                .arg(clap::Arg::with_name("gap-ms")
+                     .long("gap-ms")
+                     .env("TDIAG_HEALTH_GAP_MS")
+                     .value_name("MS")
+                     .help("Report periods longer than this (in ms) without events from a worker as gaps; defaults to 1000ms")
+                     .default_value("1000"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("trace")
+                .about("Export operator activations as a Chrome trace, for chrome://tracing or Perfetto")
+                .arg(clap::Arg::with_name("out")
+                     .long("out")
+                     .short("o")
+                     .env("TDIAG_TRACE_OUT")
+                     .value_name("PATH")
+                     .help("The trace file to write (JSON)")
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("serve")
+                .about("Serve a live dashboard of the time spent in each operator and the records sent over each channel")
+                .arg(clap::Arg::with_name("listen")
+                     .long("listen")
+                     .env("TDIAG_SERVE_ADDR")
+                     .value_name("ADDR")
+                     .help("Address to serve the dashboard on; defaults to 127.0.0.1:51319")
+                     .default_value("127.0.0.1:51319"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Export the source computation's statistics to monitoring systems, while it runs")
+                .subcommand(
+                    clap::SubCommand::with_name("prometheus")
+                        .about("Serve per-operator scheduled time, per-channel message counts and per-arrangement sizes as Prometheus metrics")
+                        .arg(clap::Arg::with_name("listen")
+                             .long("listen")
+                             .env("TDIAG_PROMETHEUS_LISTEN")
+                             .value_name("ADDR")
+                             .help("Address to serve the metrics on (at /metrics); defaults to 127.0.0.1:9090")
+                             .default_value("127.0.0.1:9090"))
+                        .arg(clap::Arg::with_name("differential_port")
+                             .long("differential-port")
+                             .env("TDIAG_PROMETHEUS_DIFFERENTIAL_PORT")
+                             .value_name("PORT")
+                             .help("Also export the tuples in each arrangement, from the Differential log streams received on this port")
+                             .takes_value(true))
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("otlp")
+                        .about("Push operator activations as OpenTelemetry spans, and scheduled time as metrics, to an OTLP/HTTP endpoint")
+                        .arg(clap::Arg::with_name("endpoint")
+                             .long("endpoint")
+                             .env("TDIAG_OTLP_ENDPOINT")
+                             .value_name("URL")
+                             .help("The OTLP/HTTP endpoint (e.g. of an OpenTelemetry collector); defaults to http://127.0.0.1:4318")
+                             .default_value("http://127.0.0.1:4318"))
+                        .arg(clap::Arg::with_name("service_name")
+                             .long("service-name")
+                             .env("TDIAG_OTLP_SERVICE_NAME")
+                             .value_name("NAME")
+                             .help("The service.name of the exported spans and metrics; defaults to timely")
+                             .default_value("timely"))
+                        .arg(clap::Arg::with_name("min_span")
+                             .long("min-span")
+                             .env("TDIAG_OTLP_MIN_SPAN")
+                             .value_name("DURATION")
+                             .help("Only export activations at least this long as spans (e.g. 0.5ms, 10ms); defaults to 1ms")
+                             .default_value("1ms"))
+                )
+        )
+        .subcommand(
+            clap::SubCommand::with_name("top")
+                .about("Show a live, sortable table of the operators in the terminal (keys: t/r/m/a to sort, / to filter, q to quit)")
+                .arg(clap::Arg::with_name("differential_port")
+                     .long("differential-port")
+                     .env("TDIAG_TOP_DIFFERENTIAL_PORT")
+                     .value_name("PORT")
+                     .help("Also show the tuples in each arrangement, from the Differential log streams received on this port")
+                     .takes_value(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("tee")
+                .about("Forward the log streams to stdout, re-encoded as a single stream that can be read back with --from-stdin")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("record")
+                .about("Save the log streams to a recording directory, with a file per stream, for offline analysis")
+                .arg(clap::Arg::with_name("differential_port")
+                     .long("differential-port")
+                     .env("TDIAG_RECORD_DIFFERENTIAL_PORT")
+                     .value_name("PORT")
+                     .help("Also record the Differential log streams, listening on this port")
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("dir")
+                     .value_name("DIR")
+                     .help("The recording directory to create (it must not exist, or be empty)")
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("replay")
+                .about("Send recorded log streams (e.g. saved with tee) to another tdiag, as if they came from the source computation")
+                .arg(clap::Arg::with_name("to")
+                     .long("to")
+                     .env("TDIAG_REPLAY_TO")
+                     .value_name("ADDR")
+                     .help("Address of the tdiag to send the streams to; defaults to 127.0.0.1:51317")
+                     .default_value("127.0.0.1:51317"))
+                .arg(clap::Arg::with_name("loop")
+                     .long("loop")
+                     .help("Replay the recordings over and over, rebasing timestamps at each cycle [env: TDIAG_REPLAY_LOOP]"))
+                .arg(clap::Arg::with_name("realtime")
+                     .long("realtime")
+                     .help("Send events at the pace at which they were recorded, rather than as fast as possible [env: TDIAG_REPLAY_REALTIME]"))
+                .arg(clap::Arg::with_name("recordings")
+                     .value_name("PATH")
+                     .help("Recording directories or files captured with tee; each recorded stream is sent over its own connection (the receiving tdiag needs --source-peers set to their number)")
+                     .multiple(true)
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("validate-recording")
+                .about("Check that a recording decodes entirely and is consistent, and print a summary of its streams")
+                .arg(clap::Arg::with_name("recording")
+                     .value_name("PATH")
+                     .help("A recording directory, or a file captured with tee")
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("trim")
+                .about("Write a copy of a recording with only the records logged in a time range")
+                .arg(clap::Arg::with_name("from")
+                     .long("from")
+                     .env("TDIAG_TRIM_FROM")
+                     .value_name("DURATION")
+                     .help("Start of the range, since the start of the source computation (e.g. 90s, 2m); defaults to the start")
+                     .default_value("0s"))
+                .arg(clap::Arg::with_name("to")
+                     .long("to")
+                     .env("TDIAG_TRIM_TO")
+                     .value_name("DURATION")
+                     .help("End of the range, since the start of the source computation (e.g. 5m); defaults to the end")
+                     .takes_value(true))
+                .arg(clap::Arg::with_name("input")
+                     .value_name("IN")
+                     .help("The recording to trim")
+                     .required(true))
+                .arg(clap::Arg::with_name("output")
+                     .value_name("OUT")
+                     .help("The directory to write the trimmed recording to")
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("merge")
+                .about("Combine recordings (e.g. from several tdiag instances, or separate timely and differential captures) into one")
+                .arg(clap::Arg::with_name("output")
+                     .short("o")
+                     .long("out")
+                     .env("TDIAG_MERGE_OUT")
+                     .value_name("DIR")
+                     .help("The directory to write the merged recording to")
+                     .required(true))
+                .arg(clap::Arg::with_name("inputs")
+                     .value_name("RECORDING")
+                     .help("The recordings to merge")
+                     .multiple(true)
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("anonymize")
+                .about("Write a copy of a recording with generated operator names, to share it without revealing the original ones")
+                .arg(clap::Arg::with_name("mapping")
+                     .long("mapping")
+                     .env("TDIAG_ANONYMIZE_MAPPING")
+                     .value_name("PATH")
+                     .help("Where to write the mapping back to the original names (a name map for --name-map); keep it private")
+                     .required(true))
+                .arg(clap::Arg::with_name("input")
+                     .value_name("IN")
+                     .help("The recording to anonymize")
+                     .required(true))
+                .arg(clap::Arg::with_name("output")
+                     .value_name("OUT")
+                     .help("The directory to write the anonymized recording to")
+                     .required(true))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("head")
+                .about("Print a summary of a recording and the first events of each worker")
+                .arg(clap::Arg::with_name("events")
+                     .short("n")
+                     .long("events")
+                     .env("TDIAG_HEAD_EVENTS")
+                     .value_name("N")
+                     .help("Number of events to print for each worker; defaults to 10")
+                     .default_value("10"))
+                .arg(clap::Arg::with_name("recording")
+                     .value_name("PATH")
+                     .help("A recording directory, or a file captured with tee")
+                     .required(true))
         )
         .subcommand(
             clap::SubCommand::with_name("differential")
@@ -58,6 +683,7 @@ You can customize the interface and port for the receiver (this program) with --
                 .arg(clap::Arg::with_name("port")
                      .short("p")
                      .long("port")
+                     .env("TDIAG_DIFFERENTIAL_PORT")
                      .value_name("PORT")
                      .help("Port to listen on for Differential log streams; defaults to 51318")
                      .default_value("51318")
@@ -67,9 +693,56 @@ You can customize the interface and port for the receiver (this program) with --
                         .about("Track the logical size of arrangements over the course of a computation")
                         .arg(clap::Arg::with_name("output-interval")
                              .long("output-interval")
+                             .env("TDIAG_ARRANGEMENTS_OUTPUT_INTERVAL")
                              .value_name("MS")
                              .help("Interval (in ms) at which to print arrangement sizes; defaults to 1000ms")
                              .default_value("1000"))
+                        .arg(clap::Arg::with_name("format")
+                             .long("format")
+                             .env("TDIAG_ARRANGEMENTS_FORMAT")
+                             .value_name("FORMAT")
+                             .help("text or csv, printed as sizes are measured; json, or vega for a Vega-Lite spec of the sizes over time, printed once the streams end")
+                             .possible_values(&["text", "csv", "json", "vega"])
+                             .default_value("text"))
+                        .arg(clap::Arg::with_name("out")
+                             .long("out")
+                             .short("o")
+                             .env("TDIAG_ARRANGEMENTS_OUT")
+                             .value_name("PATH")
+                             .help("Write the sizes to this file instead of stdout")
+                             .takes_value(true))
+                        .arg(clap::Arg::with_name("render")
+                             .long("render")
+                             .env("TDIAG_ARRANGEMENTS_RENDER")
+                             .value_name("PATH")
+                             .help("Also write an html file plotting the sizes over time to this path, once the streams end")
+                             .takes_value(true))
+                        .arg(clap::Arg::with_name("statsd")
+                             .long("statsd")
+                             .env("TDIAG_ARRANGEMENTS_STATSD")
+                             .value_name("ADDR")
+                             .help("Also send the sizes as StatsD gauges to this host:port, as they're measured")
+                             .takes_value(true))
+                        .arg(clap::Arg::with_name("statsd_prefix")
+                             .long("statsd-prefix")
+                             .env("TDIAG_ARRANGEMENTS_STATSD_PREFIX")
+                             .value_name("PREFIX")
+                             .help("The prefix of the StatsD gauge names; defaults to tdiag")
+                             .default_value("tdiag"))
+                        .arg(clap::Arg::with_name("record_bytes")
+                             .long("record-bytes")
+                             .env("TDIAG_ARRANGEMENTS_RECORD_BYTES")
+                             .value_name("OP_ID=BYTES")
+                             .help("The size of the records of the arrangement maintained by an operator (e.g. 18=64, 20=1K), to estimate its size in bytes (may be repeated)")
+                             .multiple(true)
+                             .number_of_values(1)
+                             .takes_value(true))
+                        .arg(clap::Arg::with_name("record_bytes_file")
+                             .long("record-bytes-file")
+                             .env("TDIAG_ARRANGEMENTS_RECORD_BYTES_FILE")
+                             .value_name("PATH")
+                             .help("A toml file with the sizes of the records of arrangements, by operator id (see --record-bytes)")
+                             .takes_value(true))
                         .after_help("
 Add the following snippet to your Differential computation:
 
@@ -84,95 +757,657 @@ if let Ok(addr) = ::std::env::var(\"DIFFERENTIAL_LOG_ADDR\") {
 }
 ```
 
+or call `tdiag_connect::send::connect_differential_logging::<_, DifferentialEvent>(worker)`.
+
 Then start your computation with the DIFFERENTIAL_LOG_ADDR environment
 variable pointing to tdiag's differential port (51318 by default).
 ")
                 )
-        )
-        .get_matches();
+                .subcommand(
+                    clap::SubCommand::with_name("merges")
+                        .about("Summarize the merges of each arrangement's spine: durations, shortfalls and the largest merges")
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("skew")
+                        .about("Estimate how evenly the updates to each arrangement are spread over the workers")
+                        .arg(clap::Arg::with_name("window-ms")
+                             .long("window-ms")
+                             .env("TDIAG_SKEW_WINDOW_MS")
+                             .value_name("MS")
+                             .help("Length (in ms) of the windows over which skew is also estimated; defaults to 1000ms")
+                             .default_value("1000"))
+                        .arg(clap::Arg::with_name("threshold")
+                             .long("threshold")
+                             .env("TDIAG_SKEW_THRESHOLD")
+                             .value_name("RATIO")
+                             .help("Flag arrangements whose busiest worker gets at least this many times the mean number of updates; defaults to 2")
+                             .default_value("2"))
+                )
+        );
+    let args = compression_args(tls_args(app)).get_matches();
+
+    let status_format = match args.value_of("status_format") {
+        Some("json") => status::StatusFormat::Json,
+        _ => status::StatusFormat::Text,
+    };
+    // `tee` writes the log streams to stdout, and so do charts and JSON
+    // profiles (unless written to a file).
+    let chart = args.subcommand_matches("differential").and_then(|d| d.subcommand_matches("arrangements"))
+        .map_or(false, |a| a.value_of("format") != Some("text") && a.value_of("out").is_none());
+    let structured_profile = args.subcommand_matches("profile")
+        .map_or(false, |a| a.value_of("format").map_or(false, |format| format != "text") && a.value_of("out").is_none());
+    let stdout_reserved = args.subcommand_name() == Some("tee") || args.subcommand_name() == Some("top");
+    status::configure(status_format, stdout_reserved || chart || structured_profile);
+    status::lifecycle("session_started", &format!("Running {}", args.subcommand_name().unwrap_or("-")));
+    signals::handle_signals()?;
 
     match args.subcommand() {
-        (_, None) => Err(DiagError("Invalid subcommand".to_string()))?,
+        (_, None) => Err(DiagError::InvalidArgs("Missing subcommand, see tdiag --help".to_string()))?,
         _ => (),
     }
 
     let ip_addr: std::net::IpAddr = args.value_of("interface").expect("error parsing args")
-        .parse().map_err(|e| DiagError(format!("Invalid --interface: {}", e)))?;
+        .parse().map_err(|e| DiagError::parse("Invalid --interface", e))?;
     let port: u16 = args.value_of("port").expect("error parsing args")
-        .parse().map_err(|e| DiagError(format!("Invalid --port: {}", e)))?;
-    let source_peers: usize = args.value_of("source_peers").expect("error parsing args")
-        .parse().map_err(|e| DiagError(format!("Invalid --source-peers: {}", e)))?;
+        .parse().map_err(|e| DiagError::parse("Invalid --port", e))?;
     let diag_workers: usize = args.value_of("diag_workers").expect("error parsing args")
-        .parse().map_err(|e| DiagError(format!("Invalid --diag-workers: {}", e)))?;
+        .parse().map_err(|e| DiagError::parse("Invalid --diag-workers", e))?;
+    if diag_workers == 0 {
+        return Err(DiagError::InvalidArgs("--diag-workers must be at least 1".to_string()));
+    }
+
+    let mut config = DiagConfig::new()
+        .interface(ip_addr)
+        .port(port)
+        .from_stdin(flag(&args, "from_stdin", "TDIAG_FROM_STDIN"))
+        .diag_workers(diag_workers);
 
-    let timely_configuration = match diag_workers {
-        1 => timely::Configuration::Thread,
-        n => timely::Configuration::Process(n),
+    if let Some(dir) = args.value_of("from_files") {
+        config = config.from_files(dir.into());
+    }
+
+    let start = args.value_of("start").map(parse_duration).transpose()?;
+    let end = args.value_of("end").map(parse_duration).transpose()?;
+    if let (Some(start), Some(end)) = (start, end) {
+        if start >= end {
+            return Err(DiagError::InvalidArgs("--start must be before --end".to_string()));
+        }
+    }
+    config = config.time_range(start, end);
+
+    match args.value_of("source_peers") {
+        Some("auto") => {
+            let settle_ms: u64 = args.value_of("settle_ms").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --settle-ms", e))?;
+            config = config.auto_source_peers(std::time::Duration::from_millis(settle_ms));
+        }
+        Some(peers) => {
+            let peers: usize = peers.parse().map_err(|e| DiagError::parse("Invalid --source-peers (expected a number of workers, or auto)", e))?;
+            if peers == 0 {
+                return Err(DiagError::InvalidArgs(
+                    "--source-peers must be at least 1: pass the number of workers of the source computation, or auto".to_string()));
+            }
+            config = config.source_peers(peers);
+        }
+        None => (),
+    }
+
+    if let Some(peers) = args.value_of("session_peers") {
+        let peers = peers.split(',')
+            .map(|p| match p.trim().parse::<usize>() {
+                Ok(0) => Err(DiagError::InvalidArgs("Invalid --session-peers: every session needs at least one worker".to_string())),
+                Ok(p) => Ok(p),
+                Err(e) => Err(DiagError::parse("Invalid --session-peers", e)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        config = config.session_peers(peers);
+    }
+
+    if let Some(path) = args.value_of("name_map") {
+        config = config.name_map(tdiag::names::NameMap::from_file(std::path::Path::new(path))?);
+    }
+
+    if let Some(workers) = args.value_of("workers") {
+        config = config.workers(tdiag::parse_workers(workers)?);
+    }
+
+    let mut filter = tdiag::filter::OperatorFilter::new();
+    for pattern in args.values_of("filter_addr").into_iter().flatten() {
+        filter = filter.addr(pattern)?;
+    }
+    for name in args.values_of("filter_name").into_iter().flatten() {
+        filter = filter.name(name);
+    }
+    config = config.operator_filter(filter);
+
+    #[cfg(feature = "tls")]
+    {
+        if let (Some(cert), Some(key)) = (args.value_of("tls_cert"), args.value_of("tls_key")) {
+            let tls = tdiag_connect::receive::TlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: args.value_of("tls_client_ca").map(Into::into),
+            };
+            // Fail early on unusable certificates, rather than after the
+            // first connection.
+            tls.acceptor()?;
+            config = config.tls(tls);
+        }
+    }
+
+    config = config.verbose(flag(&args, "verbose", "TDIAG_VERBOSE"))
+        .detach(flag(&args, "detach", "TDIAG_DETACH"))
+        .exit_on_eof(flag(&args, "exit_on_eof", "TDIAG_EXIT_ON_EOF"))
+        .multiplex(flag(&args, "multiplex", "TDIAG_MULTIPLEX"));
+    if let Some(duration) = args.value_of("duration") {
+        let duration = parse_duration(duration)?;
+        if duration == std::time::Duration::from_secs(0) {
+            return Err(DiagError::InvalidArgs("--duration must be positive".to_string()));
+        }
+        config = config.duration(duration);
+    }
+    if let Some(max_memory) = args.value_of("max_memory") {
+        config = config.max_memory(tdiag::parse_bytes(max_memory)?);
+    }
+    // Always running, so that the metrics are available to recorders.
+    let _resources_reporter = config.resources().spawn_reporter(std::time::Duration::from_secs(5), config.is_verbose());
+
+    if let Some(path) = args.value_of("report") {
+        config = config.report(tdiag::report::Report::append(std::path::Path::new(path))?);
+    }
+
+    let parse_rate = |arg: &str| -> Result<Option<u64>, DiagError> {
+        match args.value_of(arg).map(|rate| rate.parse()) {
+            Some(Ok(0)) => Err(DiagError::InvalidArgs(format!("--{} must be positive (in bytes per second), leave it out to not limit", arg))),
+            Some(Ok(rate)) => Ok(Some(rate)),
+            Some(Err(e)) => Err(DiagError::parse(format!("Invalid --{}", arg), e)),
+            None => Ok(None),
+        }
     };
+    config = config.rate_limits(parse_rate("rate-limit")?, parse_rate("connection-rate-limit")?);
 
-    match args.subcommand() {
+    if let Some(secs) = args.value_of("idle_timeout") {
+        let secs: u64 = secs.parse().map_err(|e| DiagError::parse("Invalid --idle-timeout", e))?;
+        if secs == 0 {
+            return Err(DiagError::InvalidArgs("--idle-timeout must be at least 1 (in seconds)".to_string()));
+        }
+        config = config.idle_timeout(tdiag::source::IdleTimeout {
+            timeout: std::time::Duration::from_secs(secs),
+            close: flag(&args, "close_idle", "TDIAG_CLOSE_IDLE"),
+        });
+    }
+
+    #[cfg(feature = "compression")]
+    {
+        config = config.compression(tdiag_connect::compression::Compression::parse(
+            args.value_of("compression").expect("error parsing args"))
+            .map_err(|e| DiagError::InvalidArgs(e.to_string()))?);
+    }
+
+    if let Some(secs) = args.value_of("reconnect_timeout") {
+        config = config.reconnect_timeout(std::time::Duration::from_secs(
+            secs.parse().map_err(|e| DiagError::parse("Invalid --reconnect-timeout", e))?));
+    }
+
+    if let Some(spec) = args.value_of("source_filter") {
+        config = config.source_filter(tdiag_connect::send::FilterSpec::parse(spec)
+            .map_err(|e| DiagError::InvalidArgs(e.to_string()))?);
+    }
+
+    if let Some(dir) = args.value_of("tee") {
+        config = config.tee(dir.into());
+    }
+
+    if let Some(read_ahead) = args.value_of("read_ahead") {
+        let bytes = tdiag::parse_bytes(read_ahead)?;
+        if bytes == 0 {
+            return Err(DiagError::InvalidArgs("--read-ahead must be at least 1 byte".to_string()));
+        }
+        config = config.read_ahead(bytes as usize);
+    }
+    if let Some(dir) = args.value_of("buffer_dir") {
+        config = config.buffer_dir(dir.into());
+    }
+
+    let result = match args.subcommand() {
+        ("all", Some(all_args)) => {
+            let differential_port: u16 = all_args.value_of("differential_port").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --differential-port", e))?;
+            let output_interval_ms: u64 = all_args.value_of("output-interval").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --output-interval", e))?;
+            if output_interval_ms == 0 {
+                return Err(DiagError::InvalidArgs("--output-interval must be positive".to_string()));
+            }
+            let options = crate::commands::all::AllOptions {
+                graph_path: all_args.value_of("graph").map(std::path::PathBuf::from),
+                graph_format: crate::commands::graph::GraphFormat::parse(all_args.value_of("graph_format").expect("error parsing args"))?,
+                profile_path: all_args.value_of("profile").map(std::path::PathBuf::from),
+                arrangements_path: all_args.value_of("arrangements").map(std::path::PathBuf::from),
+                output_interval_ms,
+            };
+            crate::commands::all::listen(&config.clone().differential_port(differential_port), options)
+        }
         ("graph", Some(graph_args)) => {
             let output_path = std::path::Path::new(graph_args.value_of("output_path").expect("error parsing args"));
-            println!("Listening for {} connections on {}:{}", source_peers, ip_addr, port);
-            let sockets = tdiag_connect::receive::open_sockets(ip_addr, port, source_peers)?;
-            println!("Trace sources connected");
-            crate::commands::graph::listen_and_render(timely_configuration, sockets, output_path)
+            let format = crate::commands::graph::GraphFormat::parse(graph_args.value_of("format").expect("error parsing args"))?;
+            let streams = config.timely_streams()?;
+            let options = crate::commands::graph::GraphOptions {
+                format,
+                data_only: flag(graph_args, "data_only", "TDIAG_GRAPH_DATA_ONLY"),
+                with_profile: flag(graph_args, "with_profile", "TDIAG_GRAPH_WITH_PROFILE"),
+                all_workers: flag(graph_args, "all_workers", "TDIAG_GRAPH_ALL_WORKERS"),
+                expect_fingerprint: graph_args.value_of("expect_fingerprint").map(String::from),
+            };
+            crate::commands::graph::listen_and_render(&config, streams, output_path, &options)
+        }
+        ("graph-diff", Some(diff_args)) => {
+            let before = std::path::Path::new(diff_args.value_of("before").expect("error parsing args"));
+            let after = std::path::Path::new(diff_args.value_of("after").expect("error parsing args"));
+            let fail_on_changes = flag(diff_args, "fail_on_changes", "TDIAG_GRAPH_DIFF_FAIL_ON_CHANGES");
+            crate::commands::graph_diff::diff(before, after, fail_on_changes, config.reports())
+        }
+        ("profile-diff", Some(diff_args)) => {
+            let before = std::path::Path::new(diff_args.value_of("before").expect("error parsing args"));
+            let after = std::path::Path::new(diff_args.value_of("after").expect("error parsing args"));
+            let min_change = parse_duration(diff_args.value_of("min_change").expect("error parsing args"))?;
+            crate::commands::profile_diff::diff(before, after, min_change.as_nanos() as f64, config.reports())
+        }
+        ("profile", Some(profile_args)) => {
+            let heatmap_path = profile_args.value_of("heatmap").map(std::path::PathBuf::from);
+            let long_activation_ms: Option<u64> = profile_args.value_of("long-activations")
+                .map(|ms| ms.parse().map_err(|e| DiagError::parse("Invalid --long-activations", e)))
+                .transpose()?;
+            let group_by = match profile_args.values_of("group-by") {
+                None => None,
+                Some(values) => {
+                    let values = values.collect::<Vec<_>>();
+                    match values[..] {
+                        [len] if len.parse::<usize>().is_ok() =>
+                            Some(crate::commands::profile::GroupBy::PrefixLength(len.parse().expect("error parsing args"))),
+                        _ => Some(crate::commands::profile::GroupBy::Scopes(
+                            values.into_iter().map(tdiag::names::parse_addr).collect::<Result<Vec<_>, _>>()?)),
+                    }
+                }
+            };
+            let output_interval_ms: Option<u64> = profile_args.value_of("output-interval")
+                .map(|ms| ms.parse().map_err(|e| DiagError::parse("Invalid --output-interval", e)))
+                .transpose()?;
+            if output_interval_ms == Some(0) {
+                return Err(DiagError::InvalidArgs("--output-interval must be positive".to_string()));
+            }
+            let top: usize = profile_args.value_of("top").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --top", e))?;
+            if top == 0 {
+                return Err(DiagError::InvalidArgs("--top must be at least 1".to_string()));
+            }
+            let format = crate::commands::profile::ProfileFormat::parse(profile_args.value_of("format").unwrap_or("text"))?;
+            let options = crate::commands::profile::ProfileOptions {
+                heatmap_path,
+                long_activation_ms,
+                group_by,
+                format,
+                out_path: profile_args.value_of("out").map(std::path::PathBuf::from),
+                flamegraph_path: profile_args.value_of("flamegraph").map(std::path::PathBuf::from),
+                per_worker: flag(profile_args, "per-worker", "TDIAG_PROFILE_PER_WORKER"),
+                guarded: flag(profile_args, "guarded", "TDIAG_PROFILE_GUARDED"),
+                output_interval_ms,
+                top,
+                histograms: flag(profile_args, "histograms", "TDIAG_PROFILE_HISTOGRAMS"),
+            };
+            if options.format != crate::commands::profile::ProfileFormat::Text {
+                let text_only = [
+                    ("--long-activations", options.long_activation_ms.is_some()),
+                    ("--group-by", options.group_by.is_some()),
+                    ("--per-worker", options.per_worker),
+                    ("--guarded", options.guarded),
+                    ("--histograms", options.histograms),
+                    ("--output-interval", options.output_interval_ms.is_some()),
+                ];
+                if let Some((name, _)) = text_only.iter().find(|(_, set)| *set) {
+                    return Err(DiagError::InvalidArgs(format!("{} is only supported with --format text", name)));
+                }
+            }
+            let streams = config.timely_streams()?;
+            crate::commands::profile::listen_and_profile(&config, streams, &options)
+        }
+        ("channels", Some(channels_args)) => {
+            let heatmap_path = channels_args.value_of("heatmap").map(std::path::Path::new);
+            let streams = config.timely_streams()?;
+            crate::commands::channels::listen(&config, streams, flag(channels_args, "matrix", "TDIAG_CHANNELS_MATRIX"), heatmap_path)
+        }
+        ("batches", Some(batches_args)) => {
+            let tiny: usize = batches_args.value_of("tiny").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --tiny", e))?;
+            let streams = config.timely_streams()?;
+            crate::commands::batches::listen(&config, streams, tiny)
+        }
+        ("messages", Some(messages_args)) => {
+            let output_interval_ms: u64 = messages_args.value_of("output-interval").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --output-interval", e))?;
+            if output_interval_ms == 0 {
+                return Err(DiagError::InvalidArgs("--output-interval must be positive".to_string()));
+            }
+            let streams = config.timely_streams()?;
+            crate::commands::messages::listen(&config, streams, output_interval_ms)
+        }
+        ("epochs", Some(_epochs_args)) => {
+            let streams = config.timely_streams()?;
+            crate::commands::epochs::listen(&config, streams)
+        }
+        ("frontier-lag", Some(_frontier_lag_args)) => {
+            let streams = config.timely_streams()?;
+            crate::commands::frontier_lag::listen(&config, streams)
         }
-        ("profile", Some(_profile_args)) => {
-            println!("Listening for {} connections on {}:{}", source_peers, ip_addr, port);
-            let sockets = tdiag_connect::receive::open_sockets(ip_addr, port, source_peers)?;
-            println!("Trace sources connected");
-            crate::commands::profile::listen_and_profile(timely_configuration, sockets)
+        ("lifetimes", Some(lifetimes_args)) => {
+            let streams = config.timely_streams()?;
+            crate::commands::lifetimes::listen(&config, streams, flag(lifetimes_args, "operators", "TDIAG_LIFETIMES_OPERATORS"))
+        }
+        ("frontiers", Some(frontiers_args)) => {
+            let output_interval_ms: u64 = frontiers_args.value_of("output-interval").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --output-interval", e))?;
+            if output_interval_ms == 0 {
+                return Err(DiagError::InvalidArgs("--output-interval must be positive".to_string()));
+            }
+            let streams = config.timely_streams()?;
+            crate::commands::frontiers::listen(&config, streams, output_interval_ms)
+        }
+        ("stuck", Some(stuck_args)) => {
+            let after = parse_duration(stuck_args.value_of("after").expect("error parsing args"))?;
+            if after == std::time::Duration::from_secs(0) {
+                return Err(DiagError::InvalidArgs("--after must be positive".to_string()));
+            }
+            let streams = config.timely_streams()?;
+            crate::commands::stuck::listen(&config, streams, after)
+        }
+        ("progress", Some(_progress_args)) => {
+            let streams = config.timely_streams()?;
+            crate::commands::progress::listen(&config, streams)
+        }
+        ("park", Some(_park_args)) => {
+            let streams = config.timely_streams()?;
+            crate::commands::park::listen(&config, streams)
+        }
+        ("latency", Some(latency_args)) => {
+            let from = tdiag::names::parse_addr(latency_args.value_of("from_addr").expect("error parsing args"))?;
+            let to = tdiag::names::parse_addr(latency_args.value_of("to_addr").expect("error parsing args"))?;
+            let window_ms: u64 = latency_args.value_of("window-ms").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --window-ms", e))?;
+            if window_ms == 0 {
+                return Err(DiagError::InvalidArgs("--window-ms must be positive".to_string()));
+            }
+            let streams = config.timely_streams()?;
+            crate::commands::latency::listen(&config, streams, &from, &to, window_ms)
+        }
+        ("health", Some(health_args)) => {
+            let gap_threshold_ms: u64 = health_args.value_of("gap-ms").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --gap-ms", e))?;
+            let streams = config.timely_streams()?;
+            crate::commands::health::listen(&config, streams, gap_threshold_ms)
+        }
+        ("trace", Some(trace_args)) => {
+            let out_path = std::path::Path::new(trace_args.value_of("out").expect("error parsing args"));
+            let streams = config.timely_streams()?;
+            crate::commands::trace::listen(&config, streams, out_path)
+        }
+        ("serve", Some(serve_args)) => {
+            let addr: std::net::SocketAddr = serve_args.value_of("listen").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --listen", e))?;
+            let streams = config.timely_streams()?;
+            crate::commands::serve::serve(&config, streams, addr)
+        }
+        ("export", Some(export_args)) => match export_args.subcommand() {
+            ("prometheus", Some(prometheus_args)) => {
+                let addr: std::net::SocketAddr = prometheus_args.value_of("listen").expect("error parsing args")
+                    .parse().map_err(|e| DiagError::parse("Invalid --listen", e))?;
+                match prometheus_args.value_of("differential_port") {
+                    Some(differential_port) => {
+                        let differential_port: u16 = differential_port.parse()
+                            .map_err(|e| DiagError::parse("Invalid --differential-port", e))?;
+                        let config = config.clone().differential_port(differential_port);
+                        let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
+                        crate::commands::prometheus::serve(&config, timely_streams, Some(differential_streams), addr)
+                    }
+                    None => {
+                        let streams = config.timely_streams()?;
+                        crate::commands::prometheus::serve(&config, streams, None, addr)
+                    }
+                }
+            }
+            ("otlp", Some(otlp_args)) => {
+                let endpoint = crate::commands::otlp::Endpoint::parse(otlp_args.value_of("endpoint").expect("error parsing args"))?;
+                let min_span = parse_duration(otlp_args.value_of("min_span").expect("error parsing args"))?;
+                let streams = config.timely_streams()?;
+                crate::commands::otlp::export(&config, streams, endpoint, otlp_args.value_of("service_name").expect("error parsing args"), min_span)
+            }
+            _ => Err(DiagError::InvalidArgs("Missing exporter, see tdiag export --help".to_string())),
+        },
+        ("top", Some(top_args)) => {
+            match top_args.value_of("differential_port") {
+                Some(differential_port) => {
+                    let differential_port: u16 = differential_port.parse()
+                        .map_err(|e| DiagError::parse("Invalid --differential-port", e))?;
+                    let config = config.clone().differential_port(differential_port);
+                    let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
+                    crate::commands::top::top(&config, timely_streams, Some(differential_streams))
+                }
+                None => {
+                    let streams = config.timely_streams()?;
+                    crate::commands::top::top(&config, streams, None)
+                }
+            }
+        }
+        ("tee", Some(_tee_args)) => {
+            let streams = config.timely_streams()?;
+            crate::commands::tee::listen(streams)
+        }
+        ("record", Some(record_args)) => {
+            let dir = std::path::Path::new(record_args.value_of("dir").expect("error parsing args"));
+            match record_args.value_of("differential_port") {
+                Some(differential_port) => {
+                    let differential_port: u16 = differential_port.parse()
+                        .map_err(|e| DiagError::parse("Invalid --differential-port", e))?;
+                    crate::commands::record::record(&config.clone().differential_port(differential_port), dir, true)
+                }
+                None => crate::commands::record::record(&config, dir, false),
+            }
+        }
+        ("replay", Some(replay_args)) => {
+            let addr: std::net::SocketAddr = replay_args.value_of("to").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --to", e))?;
+            let paths = replay_args.values_of("recordings").expect("error parsing args")
+                .map(std::path::PathBuf::from)
+                .collect::<Vec<_>>();
+            crate::commands::replay::replay(&paths, addr, flag(replay_args, "loop", "TDIAG_REPLAY_LOOP"), flag(replay_args, "realtime", "TDIAG_REPLAY_REALTIME"))
+        }
+        ("validate-recording", Some(validate_args)) => {
+            let path = std::path::Path::new(validate_args.value_of("recording").expect("error parsing args"));
+            crate::commands::validate_recording::validate(path)
+        }
+        ("trim", Some(trim_args)) => {
+            let from = parse_duration(trim_args.value_of("from").expect("error parsing args"))?;
+            let to = match trim_args.value_of("to") {
+                Some(to) => parse_duration(to)?,
+                None => std::time::Duration::from_secs(u64::max_value()),
+            };
+            let input = std::path::Path::new(trim_args.value_of("input").expect("error parsing args"));
+            let output = std::path::Path::new(trim_args.value_of("output").expect("error parsing args"));
+            crate::commands::trim::trim(input, output, from, to)
+        }
+        ("merge", Some(merge_args)) => {
+            let inputs = merge_args.values_of("inputs").expect("error parsing args")
+                .map(std::path::PathBuf::from)
+                .collect::<Vec<_>>();
+            let output = std::path::Path::new(merge_args.value_of("output").expect("error parsing args"));
+            crate::commands::merge::merge(&inputs, output)
+        }
+        ("anonymize", Some(anonymize_args)) => {
+            let input = std::path::Path::new(anonymize_args.value_of("input").expect("error parsing args"));
+            let output = std::path::Path::new(anonymize_args.value_of("output").expect("error parsing args"));
+            let mapping = std::path::Path::new(anonymize_args.value_of("mapping").expect("error parsing args"));
+            crate::commands::anonymize::anonymize(input, output, mapping)
+        }
+        ("head", Some(head_args)) => {
+            let events: usize = head_args.value_of("events").expect("error parsing args")
+                .parse().map_err(|e| DiagError::parse("Invalid --events", e))?;
+            let path = std::path::Path::new(head_args.value_of("recording").expect("error parsing args"));
+            crate::commands::head::head(path, events)
         }
         ("differential", Some(differential_args)) => {
 
             let differential_port: u16 = differential_args.value_of("port")
                 .expect("error parsing args")
                 .parse()
-                .map_err(|e| DiagError(format!("Invalid --port: {}", e)))?;
-            
+                .map_err(|e| DiagError::parse("Invalid --port", e))?;
+            let config = config.clone().differential_port(differential_port);
+
             match differential_args.subcommand() {
                 ("arrangements", Some(args)) => {
-                    // It's crucial that we bind to both listening
-                    // addresses first, before waiting for
-                    // connections. Otherwise we will open up the
-                    // potential for a race condition in the source
-                    // computation.
-                    
-                    println!("Listening for {} Timely connections on {}:{}", source_peers, ip_addr, port);
-                    let timely_listener = tdiag_connect::receive::bind(ip_addr, port)?;
-
-                    println!("Listening for {} Differential connections on {}:{}", source_peers, ip_addr, differential_port);
-                    let differential_listener = tdiag_connect::receive::bind(ip_addr, differential_port)?;
-
-                    let timely_sockets = tdiag_connect::receive::await_sockets(timely_listener, source_peers)?;
-                    let differential_sockets = tdiag_connect::receive::await_sockets(differential_listener, source_peers)?;
-
                     let output_interval_ms: u64 = args.value_of("output-interval")
                         .expect("error parsing args")
                         .parse()
-                        .expect("error parsing args");
+                        .map_err(|e| DiagError::parse("Invalid --output-interval", e))?;
+                    if output_interval_ms == 0 {
+                        return Err(DiagError::InvalidArgs("--output-interval must be positive".to_string()));
+                    }
+
+                    let format = crate::commands::vega::OutputFormat::parse(args.value_of("format").expect("error parsing args"))?;
+
+                    let statsd = match args.value_of("statsd") {
+                        Some(addr) => {
+                            let addr = std::net::ToSocketAddrs::to_socket_addrs(addr)
+                                .map_err(|e| DiagError::parse("Invalid --statsd (expected host:port)", e))?
+                                .next()
+                                .ok_or_else(|| DiagError::InvalidArgs(format!("Invalid --statsd: {} doesn't resolve to an address", addr)))?;
+                            Some(tdiag::statsd::StatsdSink::connect(addr, args.value_of("statsd_prefix").expect("error parsing args"))?)
+                        }
+                        None => None,
+                    };
+
+                    let mut record_bytes = match args.value_of("record_bytes_file") {
+                        Some(path) => crate::commands::arrangements::RecordBytes::from_file(std::path::Path::new(path))?,
+                        None => Default::default(),
+                    };
+                    for hint in args.values_of("record_bytes").into_iter().flatten() {
+                        record_bytes.parse_hint(hint)?;
+                    }
+
+                    let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
 
-                    println!("Will report every {}ms", output_interval_ms);
+                    status::info("output_interval", &format!("Will report every {}ms", output_interval_ms));
 
-                    println!("Trace sources connected");
-                    crate::commands::arrangements::listen(
-                        timely_configuration,
-                        timely_sockets,
-                        differential_sockets,
+                    let options = crate::commands::arrangements::ArrangementsOptions {
                         output_interval_ms,
-                    )
+                        format,
+                        out_path: args.value_of("out").map(std::path::PathBuf::from),
+                        render_path: args.value_of("render").map(std::path::PathBuf::from),
+                        statsd,
+                        record_bytes,
+                    };
+                    crate::commands::arrangements::listen(&config, timely_streams, differential_streams, options)
                 }
-                _ => panic!("Invalid subcommand for differential diagnostics"),
+                ("merges", Some(_args)) => {
+                    let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
+                    crate::commands::merges::listen(&config, timely_streams, differential_streams)
+                }
+                ("skew", Some(args)) => {
+                    let window_ms: u64 = args.value_of("window-ms").expect("error parsing args")
+                        .parse().map_err(|e| DiagError::parse("Invalid --window-ms", e))?;
+                    if window_ms == 0 {
+                        return Err(DiagError::InvalidArgs("--window-ms must be positive".to_string()));
+                    }
+                    let threshold: f64 = args.value_of("threshold").expect("error parsing args")
+                        .parse().map_err(|e| DiagError::parse("Invalid --threshold", e))?;
+                    if threshold.is_nan() || threshold < 1.0 {
+                        return Err(DiagError::InvalidArgs("--threshold must be at least 1 (the skew of evenly spread updates)".to_string()));
+                    }
+                    let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
+                    crate::commands::skew::listen(&config, timely_streams, differential_streams, window_ms, threshold)
+                }
+                _ => Err(DiagError::InvalidArgs("Missing subcommand for differential diagnostics, see tdiag differential --help".to_string())),
             }
         }
-        _ => panic!("Invalid subcommand"),
+        (name, _) => Err(DiagError::InvalidArgs(format!("Invalid subcommand {}, see tdiag --help", name))),
+    };
+
+    // After the results were printed, so that they're not held back.
+    config.await_detached();
+
+    let throttled_bytes = config.throttle().throttled_bytes();
+    if throttled_bytes > 0 {
+        status::warn("throttled", &format!("Note: the log streams were rate-limited ({} bytes were held back), so the analysis may be based on a delayed view of the source computation", throttled_bytes));
+    }
+
+    if let Some(read_ahead) = config.read_ahead_stats() {
+        if read_ahead.stalls() > 0 {
+            status::warn("read_ahead_full", &format!("Note: the read-ahead buffers filled up {} times (at most {} bytes were buffered), slowing down the source computation; pass a larger --read-ahead", read_ahead.stalls(), read_ahead.peak_bytes()));
+        }
     }
+
+    result
+}
+
+/// Adds the options accepting TLS-encrypted log streams, with the `tls`
+/// feature.
+#[cfg(feature = "tls")]
+fn tls_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app
+        .arg(clap::Arg::with_name("tls_cert")
+             .long("tls-cert")
+             .env("TDIAG_TLS_CERT")
+             .value_name("PATH")
+             .help("Accept TLS-encrypted log streams, presenting the certificate chain in this PEM file")
+             .requires("tls_key")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("tls_key")
+             .long("tls-key")
+             .env("TDIAG_TLS_KEY")
+             .value_name("PATH")
+             .help("PEM file with the private key for --tls-cert")
+             .requires("tls_cert")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("tls_client_ca")
+             .long("tls-client-ca")
+             .env("TDIAG_TLS_CLIENT_CA")
+             .value_name("PATH")
+             .help("Only accept log streams from sources presenting a client certificate signed by a CA in this PEM file")
+             .requires("tls_cert")
+             .takes_value(true))
+}
+
+/// Adds the options accepting TLS-encrypted log streams, with the `tls`
+/// feature.
+#[cfg(not(feature = "tls"))]
+fn tls_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app
+}
+
+/// Adds the option decompressing log streams, with the `compression`
+/// feature.
+#[cfg(feature = "compression")]
+fn compression_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app
+        .arg(clap::Arg::with_name("compression")
+             .long("compression")
+             .env("TDIAG_COMPRESSION")
+             .value_name("COMPRESSION")
+             .help("How the source computation compresses its log streams (with tdiag_connect::compression::CompressingWriter)")
+             .possible_values(&["none", "gzip", "zstd"])
+             .default_value("none"))
+}
+
+/// Adds the option decompressing log streams, with the `compression`
+/// feature.
+#[cfg(not(feature = "compression"))]
+fn compression_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app
 }
 
 fn main() {
     match run() {
-        Ok(()) => (),
-        Err(DiagError(e)) => eprintln!("Error: {}", e),
+        Ok(()) => status::lifecycle("session_ended", "Done"),
+        Err(e) => {
+            status::error("session_failed", &e.to_string());
+            std::process::exit(e.exit_code());
+        }
     }
 }
@@ -0,0 +1,83 @@
+//! User-provided labels for operators.
+//!
+//! Generated dataflows are full of anonymous operators ("Map",
+//! "FlatMap", ...) that are impossible to tell apart. A name map file
+//! assigns meaningful labels to them, either by address or by default
+//! name:
+//!
+//! ```toml
+//! [addresses]
+//! "0.3.1" = "parse edges"
+//! "[0, 3, 4]" = "rank updates"
+//!
+//! [names]
+//! "FlatMap" = "explode"
+//! ```
+//!
+//! Labels given for an address take precedence over labels given for
+//! a name.
+
+use std::collections::HashMap;
+
+use crate::DiagError;
+
+/// Maps operator addresses and default names to user-provided labels.
+#[derive(Clone, Default, Debug)]
+pub struct NameMap {
+    by_addr: HashMap<Vec<usize>, String>,
+    by_name: HashMap<String, String>,
+}
+
+impl NameMap {
+    /// Reads a name map from a toml file.
+    pub fn from_file(path: &std::path::Path) -> Result<Self, DiagError> {
+        let contents = std::fs::read_to_string(path)?;
+        let value = contents.parse::<toml::Value>()
+            .map_err(|e| DiagError::parse(format!("Invalid name map {}", path.display()), e))?;
+
+        let mut name_map = NameMap::default();
+
+        if let Some(addresses) = value.get("addresses").and_then(|v| v.as_table()) {
+            for (addr, label) in addresses.iter() {
+                let label = label.as_str()
+                    .ok_or_else(|| DiagError::Parse {
+                        context: format!("Invalid name map {}: label for {} is not a string", path.display(), addr),
+                        source: None,
+                    })?;
+                name_map.by_addr.insert(parse_addr(addr)?, label.to_string());
+            }
+        }
+
+        if let Some(names) = value.get("names").and_then(|v| v.as_table()) {
+            for (name, label) in names.iter() {
+                let label = label.as_str()
+                    .ok_or_else(|| DiagError::Parse {
+                        context: format!("Invalid name map {}: label for {} is not a string", path.display(), name),
+                        source: None,
+                    })?;
+                name_map.by_name.insert(name.clone(), label.to_string());
+            }
+        }
+
+        Ok(name_map)
+    }
+
+    /// Returns the label for the operator at `addr` with default name
+    /// `name`, which is `name` itself if no label was provided.
+    pub fn label<'a>(&'a self, addr: &[usize], name: &'a str) -> &'a str {
+        self.by_addr.get(addr)
+            .or_else(|| self.by_name.get(name))
+            .map(|label| label.as_str())
+            .unwrap_or(name)
+    }
+}
+
+/// Parses an operator address, either dotted (`0.3.1`) or in the form
+/// it is printed in (`[0, 3, 1]`).
+pub fn parse_addr(addr: &str) -> Result<Vec<usize>, DiagError> {
+    addr.trim().trim_start_matches('[').trim_end_matches(']')
+        .split(|c| c == '.' || c == ',')
+        .map(|x| x.trim().parse::<usize>()
+             .map_err(|e| DiagError::parse(format!("Invalid operator address {}", addr), e)))
+        .collect()
+}
@@ -0,0 +1,100 @@
+//! Incremental reports: the commands' results, appended to a file as
+//! JSON lines as soon as they're derived, so that other tools can tail
+//! the file rather than wait for the end of the session.
+//!
+//! Every record is tagged with its `kind`, and the time (in ms since
+//! the epoch) at which it was written:
+//!
+//! ```text
+//! {"kind":"arrangement_size","time_ms":1571212800000,"t_ms":3000,"worker":0,"operator":12,"name":"ArrangeBySelf","tuples":1024}
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::DiagError;
+use crate::status::json_string;
+
+/// A field's value in a report record.
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// A string.
+    Str(String),
+    /// A signed integer.
+    Int(i64),
+    /// An unsigned integer.
+    UInt(u64),
+    /// A floating point number.
+    Float(f64),
+    /// A boolean.
+    Bool(bool),
+    /// No value, e.g. an estimate that couldn't be made.
+    Null,
+}
+
+impl From<&str> for Value { fn from(v: &str) -> Self { Value::Str(v.to_string()) } }
+impl From<String> for Value { fn from(v: String) -> Self { Value::Str(v) } }
+impl From<i64> for Value { fn from(v: i64) -> Self { Value::Int(v) } }
+impl From<isize> for Value { fn from(v: isize) -> Self { Value::Int(v as i64) } }
+impl From<u64> for Value { fn from(v: u64) -> Self { Value::UInt(v) } }
+impl From<usize> for Value { fn from(v: usize) -> Self { Value::UInt(v as u64) } }
+impl From<f64> for Value { fn from(v: f64) -> Self { Value::Float(v) } }
+impl From<bool> for Value { fn from(v: bool) -> Self { Value::Bool(v) } }
+
+impl Value {
+    /// The value, encoded as JSON.
+    pub fn to_json(&self) -> String {
+        match self {
+            Value::Str(v) => json_string(v),
+            Value::Int(v) => v.to_string(),
+            Value::UInt(v) => v.to_string(),
+            // JSON has no representation for NaN and infinities.
+            Value::Float(v) if v.is_finite() => v.to_string(),
+            Value::Float(_) => "null".to_string(),
+            Value::Bool(v) => v.to_string(),
+            Value::Null => "null".to_string(),
+        }
+    }
+}
+
+/// A file that report records are appended to; clones append to the
+/// same file.
+#[derive(Clone)]
+pub struct Report {
+    out: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Report {
+    /// Appends records to the file at `path`, which is created if
+    /// needed.
+    pub fn append(path: &Path) -> Result<Self, DiagError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| DiagError::io(format!("cannot open report file {}", path.display()), e))?;
+        Ok(Report { out: Arc::new(Mutex::new(BufWriter::new(file))) })
+    }
+
+    /// Appends a record of `kind` with `fields`, and flushes it so that
+    /// readers see complete lines.
+    pub fn record(&self, kind: &str, fields: Vec<(&str, Value)>) {
+        let time_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|t| t.as_millis())
+            .unwrap_or(0);
+
+        let mut line = format!("{{\"kind\":{},\"time_ms\":{}", json_string(kind), time_ms);
+        for (key, value) in fields.iter() {
+            line.push(',');
+            line.push_str(&json_string(key));
+            line.push(':');
+            line.push_str(&value.to_json());
+        }
+        line.push_str("}\n");
+
+        let mut out = self.out.lock().expect("cannot lock the report file");
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            crate::status::warn("report_failed", &format!("Cannot write to the report file: {}", e));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! tdiag's own resource usage: memory, the depth of the queues between
+//! the decoding threads and the analysis, and the records processed
+//! per second, to tell which stage is the bottleneck when an analysis
+//! lags behind.
+//!
+//! With a memory budget, the analyses coarsen their aggregations as the
+//! budget is approached (see `Resources::coarsening`), and stop reading
+//! the log streams once it's exceeded regardless.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tdiag_connect::receive::QueueStats;
+
+/// The queues of the log streams opened by a session, shared by its
+/// clones.
+#[derive(Clone, Default)]
+pub struct Resources {
+    queues: Arc<Mutex<Vec<(String, QueueStats)>>>,
+    budget: Option<Arc<Budget>>,
+}
+
+/// The coarsening factor is doubled up to this value.
+const MAX_COARSENING: u64 = 64;
+
+/// Coarsening starts when this fraction of the budget is used.
+const COARSENING_THRESHOLD: f64 = 0.8;
+
+/// How long to wait for a coarser aggregation to take effect before
+/// coarsening further.
+const COARSENING_COOLDOWN: Duration = Duration::from_secs(10);
+
+struct Budget {
+    max_bytes: u64,
+    coarsening: AtomicU64,
+    exhausted: AtomicBool,
+    last_coarsened: Mutex<Option<Instant>>,
+}
+
+impl Budget {
+    fn check(&self, rss_bytes: u64) {
+        if rss_bytes as f64 >= COARSENING_THRESHOLD * self.max_bytes as f64 {
+            let coarsening = self.coarsening.load(Ordering::Acquire);
+            let mut last_coarsened = self.last_coarsened.lock().expect("cannot lock last_coarsened");
+            let cooled_down = last_coarsened.map_or(true, |t| t.elapsed() >= COARSENING_COOLDOWN);
+            if coarsening < MAX_COARSENING && cooled_down {
+                self.coarsening.store(coarsening * 2, Ordering::Release);
+                *last_coarsened = Some(Instant::now());
+                crate::status::warn("memory_budget", &format!(
+                    "Using {} MiB of the {} MiB memory budget, aggregating {}x more coarsely",
+                    rss_bytes >> 20, self.max_bytes >> 20, coarsening * 2));
+            }
+        }
+        if rss_bytes >= self.max_bytes && !self.exhausted.swap(true, Ordering::AcqRel) {
+            crate::status::warn("memory_budget_exceeded", &format!(
+                "Using {} MiB, more than the {} MiB memory budget: no longer reading the log streams, results will be partial",
+                rss_bytes >> 20, self.max_bytes >> 20));
+        }
+    }
+}
+
+/// A point-in-time view of the resources used by tdiag.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// The resident set size of the process, where supported (Linux).
+    pub rss_bytes: Option<u64>,
+    /// The decoded events waiting to be analysed, per stream.
+    pub queued: Vec<(String, usize)>,
+    /// The records decoded so far, over all streams.
+    pub decoded_records: usize,
+    /// The records analysed so far, over all streams.
+    pub replayed_records: usize,
+}
+
+impl Resources {
+    /// Limits tdiag's memory usage (resident set size) to `max_bytes`;
+    /// only enforced while a reporter runs (see `spawn_reporter`), and
+    /// only where the memory usage is known (Linux).
+    pub fn with_memory_budget(mut self, max_bytes: u64) -> Self {
+        self.budget = Some(Arc::new(Budget {
+            max_bytes,
+            coarsening: AtomicU64::new(1),
+            exhausted: AtomicBool::new(false),
+            last_coarsened: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// The factor by which analyses should coarsen their aggregations
+    /// (e.g. multiply the size of their time buckets), 1 unless the
+    /// memory budget is approached.
+    pub fn coarsening(&self) -> u64 {
+        self.budget.as_ref().map_or(1, |budget| budget.coarsening.load(Ordering::Acquire))
+    }
+
+    /// Whether the memory budget was exceeded, in which case the log
+    /// streams should no longer be read.
+    pub fn memory_exhausted(&self) -> bool {
+        self.budget.as_ref().map_or(false, |budget| budget.exhausted.load(Ordering::Acquire))
+    }
+
+    /// Tracks the queue of a stream.
+    pub fn register(&self, name: String, stats: QueueStats) {
+        self.queues.lock().expect("cannot lock queues").push((name, stats));
+    }
+
+    /// Whether none of the tracked streams is read anymore.
+    pub fn all_closed(&self) -> bool {
+        self.queues.lock().expect("cannot lock queues").iter().all(|(_, stats)| stats.closed())
+    }
+
+    /// The resources used right now.
+    pub fn snapshot(&self) -> Snapshot {
+        let queues = self.queues.lock().expect("cannot lock queues");
+        Snapshot {
+            rss_bytes: rss_bytes(),
+            queued: queues.iter().map(|(name, stats)| (name.clone(), stats.queued())).collect(),
+            decoded_records: queues.iter().map(|(_, stats)| stats.decoded_records()).sum(),
+            replayed_records: queues.iter().map(|(_, stats)| stats.replayed_records()).sum(),
+        }
+    }
+
+    /// Reports the resources used every `interval` as metrics (see
+    /// `telemetry`), and also as status messages if `print`, until the
+    /// returned `Reporter` is dropped. Also enforces the memory budget,
+    /// every second.
+    pub fn spawn_reporter(&self, interval: Duration, print: bool) -> Reporter {
+        let running = Arc::new(AtomicBool::new(true));
+        let running_r = running.clone();
+        let resources = self.clone();
+
+        std::thread::spawn(move || {
+            let tick = std::cmp::min(interval, Duration::from_secs(1));
+            let mut last = (Instant::now(), 0, 0);
+            while running_r.load(Ordering::Acquire) {
+                std::thread::sleep(tick);
+                if let (Some(budget), Some(rss_bytes)) = (&resources.budget, rss_bytes()) {
+                    budget.check(rss_bytes);
+                }
+                if last.0.elapsed() < interval {
+                    continue;
+                }
+
+                let snapshot = resources.snapshot();
+                let (last_time, last_decoded, last_replayed) = last;
+                let elapsed = last_time.elapsed().as_secs_f64();
+                let decoded_per_s = (snapshot.decoded_records - last_decoded) as f64 / elapsed;
+                let replayed_per_s = (snapshot.replayed_records - last_replayed) as f64 / elapsed;
+                last = (Instant::now(), snapshot.decoded_records, snapshot.replayed_records);
+
+                crate::telemetry::resources(&snapshot, decoded_per_s, replayed_per_s);
+
+                if print {
+                    let queued = snapshot.queued.iter()
+                        .filter(|(_, queued)| *queued > 0)
+                        .map(|(name, queued)| format!("{}={}", name, queued))
+                        .collect::<Vec<_>>();
+                    crate::status::info("resources", &format!(
+                        "memory: {}, decoded: {:.0} records/s, analysed: {:.0} records/s, queued events: {}",
+                        snapshot.rss_bytes.map_or("-".to_string(), |b| format!("{:.1} MiB", b as f64 / (1 << 20) as f64)),
+                        decoded_per_s,
+                        replayed_per_s,
+                        if queued.is_empty() { "none".to_string() } else { queued.join(" ") }));
+                }
+            }
+        });
+
+        Reporter { running }
+    }
+}
+
+/// Stops reporting resources when dropped.
+pub struct Reporter {
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+/// The resident set size of this process, from `/proc` on Linux.
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
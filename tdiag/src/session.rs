@@ -0,0 +1,415 @@
+//! Runs several analyses on a single timely instance, for embedding
+//! tdiag in another program.
+//!
+//! Each `listen_and_collect` function runs its own timely instance
+//! over its own log streams; a `SessionBuilder` instead replays the log
+//! streams once per worker into all the enabled analyses:
+//!
+//! ```ignore
+//! let config = tdiag::config::DiagConfig::new()
+//!     .port(51317)
+//!     .source_peers(4);
+//! let session = tdiag::SessionBuilder::new(config)
+//!     .workers(2)
+//!     .graph()
+//!     .profile()
+//!     .start()?;
+//! session.await_stop("Press enter to stop the analyses");
+//! let results = session.finish()?;
+//! ```
+
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::commands::arrangements::{self, ArrangementSize, RecordBytes};
+use crate::commands::graph::{self, Collected, DataflowGraph, Update};
+use crate::commands::profile::{self, ProfileEntry, ProfileKey};
+use crate::config::DiagConfig;
+use crate::resources::Resources;
+use crate::source::LogStreams;
+use crate::{DiagError, LoggingTuple};
+
+use timely::dataflow::ProbeHandle;
+use timely::dataflow::operators::capture::{Capture, Event, extract::Extract};
+use timely::logging::WorkerIdentifier;
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::receive::{ReplayWithShutdown, ShutdownFn};
+use tdiag_connect::recording::StreamKind;
+
+/// Where a session reads one kind of log stream from.
+enum Source {
+    /// Streams opened by the caller.
+    Streams(LogStreams),
+    /// Sockets connected to the source computation's workers (one
+    /// socket per worker).
+    Sockets(Vec<TcpStream>),
+    /// A listener the given number of source workers connect to.
+    Listener(TcpListener, usize),
+}
+
+impl Source {
+    /// Opens the streams of `kind`, as `config` describes (compression,
+    /// read-ahead, selected workers, `--tee`, ...) unless opened by the
+    /// caller.
+    fn open(self, config: &DiagConfig, kind: StreamKind) -> Result<LogStreams, DiagError> {
+        match self {
+            Source::Streams(streams) => Ok(streams),
+            Source::Sockets(sockets) => config.connected_streams(sockets, kind),
+            Source::Listener(listener, source_peers) => config.accepted_streams(listener, source_peers, kind),
+        }
+    }
+}
+
+/// Stops replaying once the session is stopped, or for the reasons the
+/// subcommands stop for: the capture duration elapsed (as measured by
+/// `streams`), tdiag was interrupted, or its memory budget is exceeded.
+fn shutdown(is_running: Arc<AtomicBool>, resources: Resources, streams: LogStreams) -> ShutdownFn<impl Fn() -> bool> {
+    ShutdownFn(move || !is_running.load(Ordering::Acquire)
+        || resources.memory_exhausted()
+        || streams.timed_out()
+        || crate::signals::interrupted())
+}
+
+/// Configures the analyses to run on a single timely instance, and the
+/// log streams they read.
+///
+/// Without explicit streams, sockets or listeners, the log streams are
+/// opened as the `DiagConfig` describes (see
+/// `DiagConfig::timely_streams`).
+pub struct SessionBuilder {
+    config: DiagConfig,
+    timely: Option<Source>,
+    differential: Option<Source>,
+    graph: Option<(bool, bool)>,
+    profile: bool,
+    arrangements: Option<(u64, RecordBytes)>,
+}
+
+impl SessionBuilder {
+    /// A session running no analysis yet, configured by `config`.
+    pub fn new(config: DiagConfig) -> Self {
+        SessionBuilder {
+            config,
+            timely: None,
+            differential: None,
+            graph: None,
+            profile: false,
+            arrangements: None,
+        }
+    }
+
+    /// Runs the analyses on `workers` timely workers (1 by default).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config = self.config.diag_workers(workers);
+        self
+    }
+
+    /// Reads the timely log streams from `streams`.
+    pub fn timely_streams(mut self, streams: LogStreams) -> Self {
+        self.timely = Some(Source::Streams(streams));
+        self
+    }
+
+    /// Reads the timely log streams from sockets already connected to
+    /// the source computation's workers.
+    pub fn timely_sockets(mut self, sockets: Vec<TcpStream>) -> Self {
+        self.timely = Some(Source::Sockets(sockets));
+        self
+    }
+
+    /// Accepts the timely log streams of `source_peers` workers on
+    /// `listener`, once the session starts.
+    pub fn timely_listener(mut self, listener: TcpListener, source_peers: usize) -> Self {
+        self.timely = Some(Source::Listener(listener, source_peers));
+        self
+    }
+
+    /// Reads the differential log streams from `streams`.
+    pub fn differential_streams(mut self, streams: LogStreams) -> Self {
+        self.differential = Some(Source::Streams(streams));
+        self
+    }
+
+    /// Reads the differential log streams from sockets already connected
+    /// to the source computation's workers.
+    pub fn differential_sockets(mut self, sockets: Vec<TcpStream>) -> Self {
+        self.differential = Some(Source::Sockets(sockets));
+        self
+    }
+
+    /// Accepts the differential log streams of `source_peers` workers on
+    /// `listener`, once the session starts (after the timely ones).
+    pub fn differential_listener(mut self, listener: TcpListener, source_peers: usize) -> Self {
+        self.differential = Some(Source::Listener(listener, source_peers));
+        self
+    }
+
+    /// Reconstructs the dataflow graph, as `graph::listen_and_collect`
+    /// does without options.
+    pub fn graph(self) -> Self {
+        self.graph_with(false, false)
+    }
+
+    /// Reconstructs the dataflow graph, also summing the time spent in
+    /// each operator with `with_profile`, and from the events of all
+    /// source workers with `all_workers` (see
+    /// `graph::listen_and_collect`).
+    pub fn graph_with(mut self, with_profile: bool, all_workers: bool) -> Self {
+        self.graph = Some((with_profile, all_workers));
+        self
+    }
+
+    /// Measures the time spent in each operator, as
+    /// `profile::listen_and_collect` does.
+    pub fn profile(mut self) -> Self {
+        self.profile = true;
+        self
+    }
+
+    /// Measures the arrangement sizes every `output_interval_ms`, as
+    /// `arrangements::listen_and_collect` does. Needs the differential
+    /// log streams.
+    pub fn arrangements(mut self, output_interval_ms: u64, record_bytes: RecordBytes) -> Self {
+        self.arrangements = Some((output_interval_ms, record_bytes));
+        self
+    }
+
+    /// Opens the log streams (waiting for the source workers to connect
+    /// if needed) and starts the analyses.
+    pub fn start(self) -> Result<Session, DiagError> {
+        let SessionBuilder { config, timely, differential, graph, profile, arrangements } = self;
+
+        if graph.is_none() && !profile && arrangements.is_none() {
+            return Err(DiagError::InvalidArgs("No analysis enabled in the session".to_string()));
+        }
+        if let Some((0, _)) = arrangements {
+            return Err(DiagError::InvalidArgs("The arrangement sizes' output interval must be positive".to_string()));
+        }
+
+        let (timely_streams, differential_streams) = match (timely, differential) {
+            (None, None) if arrangements.is_some() => {
+                let (timely_streams, differential_streams) = config.timely_and_differential_streams()?;
+                (timely_streams, Some(differential_streams))
+            }
+            (None, None) => (config.timely_streams()?, None),
+            (Some(timely), differential) => {
+                let timely_streams = timely.open(&config, StreamKind::Timely)?;
+                let differential_streams = differential.map(|differential| differential.open(&config, StreamKind::Differential)).transpose()?;
+                let mut opened = vec![&timely_streams];
+                opened.extend(differential_streams.iter());
+                config.write_tee_manifest(&opened)?;
+                (timely_streams, differential_streams)
+            }
+            (None, Some(_)) => return Err(DiagError::InvalidArgs("The session has differential log streams but no timely ones".to_string())),
+        };
+        if arrangements.is_some() && differential_streams.is_none() {
+            return Err(DiagError::InvalidArgs("Measuring arrangement sizes needs the differential log streams".to_string()));
+        }
+
+        let (graph_send, collector) = match graph {
+            Some(_) => {
+                let (updates_send, collector) = graph::spawn_collector()?;
+                (Some(Arc::new(Mutex::new(updates_send))), Some(collector))
+            }
+            None => (None, None),
+        };
+
+        let (totals_send, totals_recv) = ::std::sync::mpsc::channel();
+        let totals_send = Arc::new(Mutex::new(totals_send));
+        let (counts_send, counts_recv) = ::std::sync::mpsc::channel();
+        let counts_send = Arc::new(Mutex::new(counts_send));
+
+        let (sizes_send, sizes_recv) = ::std::sync::mpsc::channel();
+        let sizes_send = Arc::new(Mutex::new(sizes_send));
+        let measurement = arrangements.map(|(output_interval_ms, record_bytes)|
+            arrangements::Measurement::new(&config, output_interval_ms, Arc::new(record_bytes))).transpose()?;
+        let measured = measurement.is_some();
+
+        let is_running = Arc::new(AtomicBool::new(true));
+        let is_running_w = is_running.clone();
+        crate::signals::arm();
+
+        let structure_worker = config.structure_worker()?;
+        let resources = config.resources().clone();
+
+        let timely_streams_w = timely_streams.clone();
+        let differential_streams_w = differential_streams.clone();
+
+        let worker_handles = timely::execute(config.timely_configuration(), move |worker| {
+            let graph_send: Option<Sender<Update>> = graph_send.as_ref().map(|send| send.lock().expect("cannot lock graph_send").clone());
+            let totals_send: Sender<_> = totals_send.lock().expect("cannot lock totals_send").clone();
+            let counts_send: Sender<_> = counts_send.lock().expect("cannot lock counts_send").clone();
+            let sizes_send: Sender<_> = sizes_send.lock().expect("cannot lock sizes_send").clone();
+            let measurement = measurement.clone();
+            let resources_p = resources.clone();
+            let timely_shutdown = shutdown(is_running_w.clone(), resources.clone(), timely_streams_w.clone());
+            let differential_shutdown = shutdown(is_running_w.clone(), resources.clone(), timely_streams_w.clone());
+
+            // create replayers from disjoint partitions of source worker identifiers.
+            let timely_replayer = timely_streams_w.readers::<Duration, LoggingTuple>(worker.index(), worker.peers())
+                .expect("failed to open timely readers");
+            let differential_replayer = match (&differential_streams_w, &measurement) {
+                (Some(streams), Some(_)) => Some(streams
+                    .readers::<Duration, (Duration, WorkerIdentifier, DifferentialEvent)>(worker.index(), worker.peers())
+                    .expect("failed to open differential readers")),
+                _ => None,
+            };
+
+            let mut probe = ProbeHandle::new();
+
+            let mut profile_trace = worker.dataflow::<Duration, _, _>(|scope| {
+                let stream = timely_replayer.replay_with_shutdown_into(scope, timely_shutdown);
+
+                if let (Some((with_profile, all_workers)), Some(graph_send)) = (graph, graph_send) {
+                    graph::build(&stream, structure_worker, with_profile, all_workers, graph_send);
+                }
+
+                if let (Some(measurement), Some(replayer)) = (measurement, differential_replayer) {
+                    let differential = replayer.replay_with_shutdown_into(scope, differential_shutdown);
+                    arrangements::build(&stream, &differential, measurement, sizes_send);
+                }
+
+                if profile {
+                    let activations = profile::activations(&stream);
+                    profile::activation_counts(&activations).capture_into(counts_send);
+                    let schedule = profile::schedule_per_worker(&activations, resources_p).map(|(_, id)| id);
+                    Some(profile::arrange_totals(&stream, &schedule, structure_worker, &mut probe))
+                } else {
+                    None
+                }
+            });
+
+            let mut frontier = Vec::new();
+            while worker.step() {
+                if let Some(trace) = profile_trace.as_mut() {
+                    profile::compact_totals(trace, &probe, &mut frontier);
+                }
+            }
+
+            if let Some(trace) = profile_trace {
+                for total in profile::read_totals(trace) {
+                    totals_send.send(total).expect("failed to send output to mpsc channel");
+                }
+            }
+        }).map_err(DiagError::Timely)?;
+
+        Ok(Session {
+            config,
+            timely_streams,
+            differential_streams,
+            is_running,
+            worker_handles,
+            graph: graph.and_then(|(with_profile, all_workers)| collector.map(|collector| (collector, with_profile, all_workers))),
+            profile: if profile { Some((totals_recv, counts_recv)) } else { None },
+            sizes: if measured { Some(sizes_recv) } else { None },
+        })
+    }
+}
+
+/// The analyses of a `SessionBuilder`, running until stopped.
+pub struct Session {
+    config: DiagConfig,
+    timely_streams: LogStreams,
+    differential_streams: Option<LogStreams>,
+    is_running: Arc<AtomicBool>,
+    worker_handles: timely::communication::WorkerGuards<()>,
+    graph: Option<(JoinHandle<Collected>, bool, bool)>,
+    profile: Option<(Receiver<(ProfileKey, isize)>, Receiver<Event<Duration, (usize, u64)>>)>,
+    sizes: Option<Receiver<ArrangementSize>>,
+}
+
+impl Session {
+    /// The arrangement sizes, as they're measured, if measured. Once
+    /// taken, they're not returned by `finish` anymore; the sizes
+    /// measured after the receiver is dropped are discarded.
+    pub fn arrangement_sizes(&mut self) -> Option<Receiver<ArrangementSize>> {
+        self.sizes.take()
+    }
+
+    /// The log streams the analyses read, e.g. to wait for their end.
+    pub fn streams(&self) -> Vec<&LogStreams> {
+        std::iter::once(&self.timely_streams).chain(self.differential_streams.as_ref()).collect()
+    }
+
+    /// Blocks until the analyses should stop, as the subcommands do (see
+    /// `source::await_stop`), or until the session is stopped (see
+    /// `stop_handle`).
+    pub fn await_stop(&self, prompt: &str) {
+        crate::source::await_stop_or(&self.streams(), prompt, || !self.is_running.load(Ordering::Acquire));
+    }
+
+    /// Stops reading the log streams; the analyses then complete with
+    /// what was read so far.
+    pub fn stop(&self) {
+        self.is_running.store(false, Ordering::Release);
+    }
+
+    /// A handle stopping the session as `stop` does, e.g. from a thread
+    /// consuming `arrangement_sizes` that failed.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.is_running.clone())
+    }
+
+    /// Stops the analyses (if they weren't already) and returns their
+    /// results, keeping the operators matching the configured filter.
+    pub fn finish(self) -> Result<SessionResults, DiagError> {
+        self.stop();
+
+        let Session { config, worker_handles, graph, profile, sizes, .. } = self;
+
+        worker_handles.join().into_iter().collect::<Result<Vec<_>, _>>().map_err(DiagError::Timely)?;
+
+        let graph = match graph {
+            Some((collector, with_profile, all_workers)) => {
+                let collected = collector.join()
+                    .map_err(|_| DiagError::Internal("the graph collector thread panicked".to_string()))?;
+                Some(graph::assemble(&config, collected, with_profile, all_workers))
+            }
+            None => None,
+        };
+
+        let profile = profile.map(|(totals_recv, counts_recv)| {
+            // operator id -> number of activations, over all workers
+            let mut activations = HashMap::new();
+            for (id, count) in counts_recv.extract().into_iter().flat_map(|(_t, v)| v) {
+                *activations.entry(id).or_insert(0) += count;
+            }
+            profile::entries(&config, totals_recv.into_iter().collect(), &activations)
+        });
+
+        let arrangements = sizes.map(|sizes| sizes.into_iter().collect());
+
+        Ok(SessionResults { graph, profile, arrangements })
+    }
+}
+
+/// Stops a `Session` from another thread (see `Session::stop_handle`).
+#[derive(Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// Stops reading the session's log streams.
+    pub fn stop(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// The results of the analyses a session ran.
+#[derive(Clone, Debug, Default)]
+pub struct SessionResults {
+    /// The dataflow graph, if reconstructed.
+    pub graph: Option<DataflowGraph>,
+    /// The time spent in each operator (hottest first), if profiled.
+    pub profile: Option<Vec<ProfileEntry>>,
+    /// The arrangement sizes (in the order in which they were
+    /// measured), if measured and not taken while the session ran (see
+    /// `Session::arrangement_sizes`).
+    pub arrangements: Option<Vec<ArrangementSize>>,
+}
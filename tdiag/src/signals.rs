@@ -0,0 +1,61 @@
+//! Clean shutdown on SIGINT/SIGTERM (e.g. Ctrl-C).
+//!
+//! While an analysis is reading the log streams, the first signal stops
+//! it as pressing enter would (see `source::await_stop`), so that its
+//! results are still written; a second signal, or a signal at any other
+//! time, exits right away, after undoing what was registered with
+//! `on_exit` (e.g. `top`'s terminal settings).
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::DiagError;
+
+/// The exit status after a signal, as shells report it for SIGINT.
+const INTERRUPTED_STATUS: i32 = 130;
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static ON_EXIT: Mutex<Option<Box<dyn Fn() + Send>>> = Mutex::new(None);
+
+/// Installs the signal handler; without it, signals terminate tdiag
+/// as usual.
+pub fn handle_signals() -> Result<(), DiagError> {
+    ctrlc::set_handler(|| {
+        if !ARMED.load(Ordering::Acquire) || INTERRUPTED.swap(true, Ordering::AcqRel) {
+            if let Ok(cleanup) = ON_EXIT.lock() {
+                if let Some(cleanup) = cleanup.as_ref() {
+                    cleanup();
+                }
+            }
+            std::process::exit(INTERRUPTED_STATUS);
+        }
+    }).map_err(|e| DiagError::Internal(format!("cannot install the signal handler: {}", e)))
+}
+
+/// Makes the next signal stop the analysis rather than exit, until
+/// `disarm`.
+pub(crate) fn arm() {
+    ARMED.store(true, Ordering::Release);
+}
+
+/// Makes signals exit right away again.
+pub(crate) fn disarm() {
+    ARMED.store(false, Ordering::Release);
+}
+
+/// Runs `cleanup` before a signal exits tdiag right away, until
+/// `clear_on_exit`: exiting skips destructors.
+pub(crate) fn on_exit(cleanup: Box<dyn Fn() + Send>) {
+    *ON_EXIT.lock().expect("cannot lock the exit cleanup") = Some(cleanup);
+}
+
+/// Forgets the cleanup registered with `on_exit`.
+pub(crate) fn clear_on_exit() {
+    *ON_EXIT.lock().expect("cannot lock the exit cleanup") = None;
+}
+
+/// Whether a signal asked to stop the analysis.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Acquire)
+}
@@ -0,0 +1,734 @@
+//! Where a command's log streams come from, and when to stop reading
+//! them.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use abomonation::Abomonation;
+
+use timely::logging::{StartStop, TimelyEvent, WorkerIdentifier};
+
+use differential_dataflow::logging::DifferentialEvent;
+
+use tdiag_connect::compression::Compression;
+use tdiag_connect::handshake::Header;
+use tdiag_connect::recording::{self, RecordedStream, Recording, StreamKind};
+use tdiag_connect::receive::{ReplaySource, EndOfStreams, DecodedEvents, ReadAhead, Reconnections, Resume, TcpStreamOrFile, Throttle, ThrottledReader};
+#[cfg(feature = "tls")]
+use tdiag_connect::receive::TlsStream;
+
+/// The reader each log stream is decoded from.
+pub type StreamReader = ThrottledReader<IdleReader<TeeReader<TcpStreamOrFile>>>;
+
+/// The log streams replayed by a command.
+///
+/// Clones share the underlying streams: each stream is handed out
+/// to exactly one diagnostic worker by `readers`.
+#[derive(Clone)]
+pub struct LogStreams {
+    source: ReplaySource,
+    streams: usize,
+    source_peers: Option<usize>,
+    end_of_streams: EndOfStreams,
+    throttle: Throttle,
+    idle_timeout: Option<IdleTimeout>,
+    resources: Option<(crate::resources::Resources, &'static str)>,
+    detached: bool,
+    exit_on_eof: bool,
+    deadline: Option<Instant>,
+    sessions: Vec<Session>,
+    reconnect: Option<(Vec<Reconnections>, Vec<Option<Header>>, Duration)>,
+    compression: Compression,
+    workers: Option<Arc<BTreeSet<WorkerIdentifier>>>,
+    tee: Option<(PathBuf, StreamKind)>,
+    time_range: (Option<Duration>, Option<Duration>),
+    read_ahead: Option<ReadAhead>,
+}
+
+/// One of several source computations whose streams are read together
+/// (see `LogStreams::with_sessions`).
+#[derive(Clone, Copy, Debug)]
+pub struct Session {
+    /// The number of workers in the source computation.
+    pub peers: usize,
+    /// The number of its workers that connected.
+    pub connected: usize,
+    /// Added to the worker identifiers in the session's records, so
+    /// that they don't collide with the other sessions' workers.
+    pub worker_offset: usize,
+}
+
+/// A log record that names the source worker that logged it.
+pub trait SourceRecord {
+    /// Shifts the source worker identifier by `offset`.
+    fn offset_worker(&mut self, offset: usize);
+    /// The source worker identifier.
+    fn worker(&self) -> WorkerIdentifier;
+    /// When the record was logged, since the start of the source
+    /// computation.
+    fn time(&self) -> Duration;
+    /// Whether the record describes the dataflow rather than its
+    /// activity, and so is needed to make sense of later records.
+    fn is_prelude(&self) -> bool;
+    /// How the record pairs with the source worker's other records.
+    fn pairing(&self) -> Pairing;
+}
+
+/// How an event pairs with the other events of its source worker, so
+/// that a stream can be cut without keeping half of a pair (see
+/// `Window`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pairing {
+    /// The event stands on its own.
+    Single,
+    /// The event opens `count` of `key`, which later events close.
+    Opens { key: usize, count: u64 },
+    /// The event closes `count` of `key`, which earlier events opened,
+    /// and opens `reopens` of it again.
+    Closes { key: usize, count: u64, reopens: u64 },
+}
+
+/// An event of a log stream (see `SourceRecord`).
+pub trait LogEvent {
+    /// Whether the event describes the dataflow rather than its activity.
+    fn is_prelude(&self) -> bool;
+    /// How the event pairs with the source worker's other events.
+    fn pairing(&self) -> Pairing;
+}
+
+impl LogEvent for TimelyEvent {
+    fn is_prelude(&self) -> bool {
+        // Needed to name operators and to reconstruct the dataflow graph.
+        matches!(self, TimelyEvent::Operates(_) | TimelyEvent::Channels(_))
+    }
+
+    fn pairing(&self) -> Pairing {
+        // An operator's activation, from its start to its stop.
+        match self {
+            TimelyEvent::Schedule(event) => match event.start_stop {
+                StartStop::Start => Pairing::Opens { key: event.id, count: 1 },
+                StartStop::Stop => Pairing::Closes { key: event.id, count: 1, reopens: 0 },
+            },
+            _ => Pairing::Single,
+        }
+    }
+}
+
+impl LogEvent for DifferentialEvent {
+    fn is_prelude(&self) -> bool {
+        false
+    }
+
+    fn pairing(&self) -> Pairing {
+        // The tuples of an arrangement, from the batch that adds them to
+        // the merge or drop that removes them.
+        match self {
+            DifferentialEvent::Batch(x) => Pairing::Opens { key: x.operator, count: x.length as u64 },
+            DifferentialEvent::Merge(x) => match x.complete {
+                Some(complete) => Pairing::Closes { key: x.operator, count: (x.length1 + x.length2) as u64, reopens: complete as u64 },
+                None => Pairing::Single,
+            },
+            DifferentialEvent::Drop(x) => Pairing::Closes { key: x.operator, count: x.length as u64, reopens: 0 },
+            _ => Pairing::Single,
+        }
+    }
+}
+
+impl<E: LogEvent> SourceRecord for (Duration, WorkerIdentifier, E) {
+    fn offset_worker(&mut self, offset: usize) {
+        self.1 += offset;
+    }
+
+    fn worker(&self) -> WorkerIdentifier {
+        self.1
+    }
+
+    fn time(&self) -> Duration {
+        self.0
+    }
+
+    fn is_prelude(&self) -> bool {
+        self.2.is_prelude()
+    }
+
+    fn pairing(&self) -> Pairing {
+        self.2.pairing()
+    }
+}
+
+/// Cuts a stream of records to the window between `start` (inclusive)
+/// and `end` (exclusive), keeping the prelude (see
+/// `SourceRecord::is_prelude`) from before it.
+///
+/// Records that close what earlier records opened (see `Pairing`) are
+/// only kept as far as what they close was opened in the window, so that
+/// an activation that straddles `start` or an arrangement batch logged
+/// before it doesn't leave half a pair behind.
+#[derive(Clone, Debug, Default)]
+pub struct Window {
+    start: Option<Duration>,
+    end: Option<Duration>,
+    /// How much of each key the kept records left open, by source worker.
+    open: HashMap<(WorkerIdentifier, usize), u64>,
+}
+
+impl Window {
+    /// A window between `start` and `end`, either of which may be open.
+    pub fn new(start: Option<Duration>, end: Option<Duration>) -> Self {
+        Window { start, end, open: HashMap::new() }
+    }
+
+    /// Whether to keep `record`, the next record of its stream.
+    pub fn keep<R: SourceRecord>(&mut self, record: &R) -> bool {
+        let time = record.time();
+        if self.end.map_or(false, |end| time >= end) {
+            return false;
+        }
+        match self.start {
+            // Nothing was cut before the record, so it pairs up as logged.
+            None => return true,
+            Some(start) if time < start => return record.is_prelude(),
+            Some(_) => (),
+        }
+        match record.pairing() {
+            Pairing::Single => true,
+            Pairing::Opens { key, count } => {
+                *self.open.entry((record.worker(), key)).or_insert(0) += count;
+                true
+            }
+            Pairing::Closes { key, count, reopens } => match self.open.get_mut(&(record.worker(), key)) {
+                Some(open) if *open >= count => {
+                    *open = *open - count + reopens;
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// What to do with connections that send no bytes for a while.
+#[derive(Clone, Copy, Debug)]
+pub struct IdleTimeout {
+    /// How long a connection may stay silent before it's reported.
+    pub timeout: Duration,
+    /// Whether to close reported connections, treating them as
+    /// having reached the end of their stream.
+    pub close: bool,
+}
+
+impl LogStreams {
+    /// `streams` streams read from `source`, with the defaults.
+    fn new(source: ReplaySource, streams: usize, source_peers: Option<usize>) -> Self {
+        LogStreams {
+            source,
+            streams,
+            source_peers,
+            end_of_streams: EndOfStreams::new(),
+            throttle: Throttle::default(),
+            idle_timeout: None,
+            resources: None,
+            detached: false,
+            exit_on_eof: false,
+            deadline: None,
+            sessions: Vec::new(),
+            reconnect: None,
+            compression: Compression::None,
+            workers: None,
+            tee: None,
+            time_range: (None, None),
+            read_ahead: None,
+        }
+    }
+
+    /// Streams read from sockets connected to the source computation's
+    /// workers (one socket per worker).
+    pub fn tcp(sockets: Vec<Option<TcpStream>>) -> Self {
+        let streams = sockets.len();
+        LogStreams::new(ReplaySource::Tcp(Arc::new(Mutex::new(sockets))), streams, Some(streams))
+    }
+
+    /// Streams read from TLS sessions established with the source
+    /// computation's workers (one session per worker).
+    #[cfg(feature = "tls")]
+    pub fn tls(sessions: Vec<Option<TlsStream>>) -> Self {
+        let streams = sessions.len();
+        LogStreams::new(ReplaySource::Tls(Arc::new(Mutex::new(sessions))), streams, Some(streams))
+    }
+
+    /// The streams of a kind in a recording (one file per stream).
+    pub fn recorded(recording: &Recording, kind: StreamKind) -> Self {
+        let streams = recording.streams_of(kind).len();
+        LogStreams::new(recording.replay_source(kind), streams, Some(streams))
+    }
+
+    /// A single stream piped into stdin.
+    pub fn stdin() -> Self {
+        LogStreams::new(ReplaySource::Stdin, 1, None)
+    }
+
+    /// Limits the rate at which the streams are read.
+    pub fn throttled(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Reports (and optionally closes) connections that send no bytes
+    /// for a while.
+    pub fn with_idle_timeout(mut self, idle_timeout: IdleTimeout) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Keeps draining the streams after the analysis stops, until the
+    /// source computation closes them, so that it isn't disconnected.
+    pub fn detached(mut self) -> Self {
+        self.detached = true;
+        self
+    }
+
+    /// Stops the analysis (see `await_stop`) once all streams reach
+    /// their end, i.e. the source computation finished, rather than
+    /// waiting for the user to press enter.
+    pub fn exit_on_eof(mut self) -> Self {
+        self.exit_on_eof = true;
+        self
+    }
+
+    /// Stops the analysis (see `await_stop`) once `duration` has elapsed
+    /// from now.
+    pub fn stop_after(mut self, duration: Duration) -> Self {
+        self.deadline = Some(Instant::now() + duration);
+        self
+    }
+
+    /// Whether the duration set with `stop_after` has elapsed.
+    pub fn timed_out(&self) -> bool {
+        self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
+    }
+
+    /// Reads the streams of several source computations together: the
+    /// first `sessions[0].connected` streams belong to the first
+    /// session, and so on. Each session's worker identifiers are
+    /// shifted by its `worker_offset`, so that the analyses tell the
+    /// sessions' workers apart.
+    pub fn with_sessions(mut self, sessions: Vec<Session>) -> Self {
+        self.source_peers = Some(sessions.iter().map(|s| s.peers).sum());
+        self.sessions = sessions;
+        self
+    }
+
+    /// The session a (global) source worker identifier belongs to, and
+    /// the worker's identifier within it, if reading several sessions.
+    pub fn session_of_worker(&self, worker: WorkerIdentifier) -> Option<(usize, WorkerIdentifier)> {
+        self.sessions.iter().enumerate()
+            .find(|(_, s)| worker >= s.worker_offset && worker < s.worker_offset + s.peers)
+            .map(|(index, s)| (index, worker - s.worker_offset))
+    }
+
+    /// Decompresses the streams, as sent by a source computation using a
+    /// `tdiag_connect::compression::CompressingWriter`.
+    pub fn compressed(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Waits for up to `timeout` for source workers whose connection
+    /// drops to reconnect, on the listener of their session (one per
+    /// session, a single one without sessions), and resumes their stream.
+    ///
+    /// `headers` are those of the streams' first connections (see
+    /// `tdiag_connect::receive::Connection`), by which reconnections are
+    /// matched to streams.
+    pub fn with_reconnections(mut self, reconnections: Vec<Reconnections>, headers: Vec<Option<Header>>, timeout: Duration) -> Self {
+        self.reconnect = Some((reconnections, headers, timeout));
+        self
+    }
+
+    /// The session the `stream`-th stream belongs to, and its worker
+    /// offset (the first session, and 0, without sessions).
+    fn session_of_stream(&self, stream: usize) -> (usize, usize) {
+        let mut first = 0;
+        for (index, session) in self.sessions.iter().enumerate() {
+            if stream < first + session.connected {
+                return (index, session.worker_offset);
+            }
+            first += session.connected;
+        }
+        (0, 0)
+    }
+
+    /// Drops the records of all source workers but `workers` (global
+    /// identifiers, see `with_sessions`) as they're decoded.
+    pub fn only_workers(mut self, workers: BTreeSet<WorkerIdentifier>) -> Self {
+        self.workers = Some(Arc::new(workers));
+        self
+    }
+
+    /// Drops the records logged before `start` or from `end` on (since
+    /// the start of the source computation) as they're decoded, so that
+    /// only that window is analysed.
+    ///
+    /// The records that describe the dataflow (see
+    /// `SourceRecord::is_prelude`) are kept from before `start`, so that
+    /// operators can still be named, and records that close what was
+    /// opened before `start` are dropped (see `Window`).
+    pub fn only_between(mut self, start: Option<Duration>, end: Option<Duration>) -> Self {
+        self.time_range = (start, end);
+        self
+    }
+
+    /// Reads each stream ahead on a dedicated thread, into a buffer of
+    /// `read_ahead`, so that the source computation isn't slowed down
+    /// while the analysis catches up.
+    pub fn read_ahead(mut self, read_ahead: ReadAhead) -> Self {
+        self.read_ahead = Some(read_ahead);
+        self
+    }
+
+    /// Copies the bytes of each stream (decompressed) to a file in the
+    /// recording directory `dir` as they're read, named after `kind`
+    /// and the connection (see `tee_streams`).
+    pub fn tee(mut self, dir: &Path, kind: StreamKind) -> Self {
+        self.tee = Some((dir.to_path_buf(), kind));
+        self
+    }
+
+    /// The files the streams are copied to with `tee`, for the
+    /// recording's manifest.
+    pub fn tee_streams(&self) -> Vec<RecordedStream> {
+        match &self.tee {
+            Some((dir, kind)) => (0..self.streams)
+                .map(|index| RecordedStream { kind: *kind, index, path: dir.join(recording::stream_file_name(*kind, index)) })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Registers the streams' queues with `resources` when they're
+    /// opened, named after `kind` and the connection.
+    pub fn monitored(mut self, resources: crate::resources::Resources, kind: &'static str) -> Self {
+        self.resources = Some((resources, kind));
+        self
+    }
+
+    /// Opens the readers for the share of the streams assigned to a
+    /// diagnostic worker.
+    ///
+    /// Each stream is read and decoded on its own thread, so that the
+    /// replay operators only hand the decoded events to the analysis.
+    pub fn readers<T, E>(
+        &self,
+        worker_index: usize,
+        worker_peers: usize) -> Result<Vec<DecodedEvents<T, E>>, crate::DiagError>
+    where
+        T: Abomonation + Ord + Default + Clone + Send + 'static,
+        E: SourceRecord + Abomonation + Clone + Send + 'static {
+
+        tdiag_connect::receive::open_streams(self.source.clone(), worker_index, worker_peers)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| -> Result<_, crate::DiagError> {
+                // Streams are handed out round-robin to the diagnostic workers.
+                let connection = worker_index + i * worker_peers;
+                let copy = match &self.tee {
+                    Some((dir, kind)) => {
+                        let path = dir.join(recording::stream_file_name(*kind, connection));
+                        Some(File::create(&path).map_err(|e| crate::DiagError::io(format!("cannot create {}", path.display()), e))?)
+                    }
+                    None => None,
+                };
+                // Read ahead before decompressing, so that the buffers hold
+                // the compressed bytes. Either way, the stream is read on a
+                // thread of its own, which waits for the socket.
+                let s = match &self.read_ahead {
+                    Some(read_ahead) => s.read_ahead(read_ahead)?,
+                    None => s.blocking()?,
+                };
+                let idle = IdleReader::new(TeeReader { reader: s.decompressed(self.compression)?, copy }, connection, self.idle_timeout);
+                let (session, worker_offset) = self.session_of_stream(connection);
+                let on_error = move |e| crate::status::warn("stream_failed", &format!("Stopped reading connection {}: {}", connection, crate::DiagError::from(e)));
+                let workers = self.workers.clone();
+                let mut window = Window::new(self.time_range.0, self.time_range.1);
+                let map = move |record: &mut E| {
+                    if worker_offset > 0 {
+                        record.offset_worker(worker_offset);
+                    }
+                    workers.as_ref().map_or(true, |workers| workers.contains(&record.worker()))
+                        && window.keep(record)
+                };
+                let reconnections = self.reconnect.as_ref()
+                    .and_then(|(reconnections, headers, timeout)| reconnections.get(session)
+                        .map(|r| (r.clone(), headers.get(connection).cloned().flatten(), *timeout)));
+                let decoded = match reconnections {
+                    Some((reconnections, header, timeout)) => {
+                        let (throttle, idle_timeout, compression, read_ahead) = (self.throttle.clone(), self.idle_timeout, self.compression, self.read_ahead.clone());
+                        let resume = Resume {
+                            reconnections,
+                            header,
+                            wrap: Box::new(move |socket| {
+                                let socket = match &read_ahead {
+                                    Some(read_ahead) => TcpStreamOrFile::Tcp(socket).read_ahead(read_ahead).expect("cannot spawn a read-ahead thread"),
+                                    None => TcpStreamOrFile::Tcp(socket).blocking().expect("cannot make the socket blocking"),
+                                };
+                                // Supported, or opening the first connection would have failed.
+                                let socket = socket.decompressed(compression).expect("unsupported compression");
+                                // Not teed, see `DiagConfig::tee`.
+                                throttle.reader(IdleReader::new(TeeReader { reader: socket, copy: None }, connection, idle_timeout))
+                            }),
+                            timeout,
+                            notify: Box::new(move |resumed| if resumed {
+                                crate::status::info("connection_reconnected", &format!("Source connection {} reconnected, resuming its stream", connection));
+                            } else {
+                                crate::status::warn("connection_dropped", &format!("Source connection {} dropped, waiting up to {}s for the worker to reconnect", connection, timeout.as_secs()));
+                            }),
+                        };
+                        tdiag_connect::receive::spawn_resumable_decoder(
+                            self.throttle.reader(idle),
+                            tdiag_connect::receive::DEFAULT_QUEUE_CAPACITY,
+                            Some(self.end_of_streams.clone()),
+                            on_error,
+                            map,
+                            resume)?
+                    }
+                    None => tdiag_connect::receive::spawn_decoder_with(
+                        self.throttle.reader(idle),
+                        tdiag_connect::receive::DEFAULT_QUEUE_CAPACITY,
+                        Some(self.end_of_streams.clone()),
+                        on_error,
+                        map)?,
+                };
+                if let Some((resources, kind)) = &self.resources {
+                    resources.register(format!("{}-{}", kind, connection), decoded.stats());
+                }
+                if self.detached {
+                    decoded.drain_on_drop();
+                }
+                Ok(decoded)
+            })
+            .collect()
+    }
+
+    /// The number of workers in the source computation, if known
+    /// (a stream piped into stdin may come from any worker).
+    pub fn source_peers(&self) -> Option<usize> {
+        self.source_peers
+    }
+
+    /// Whether the streams are read from stdin, which then can't be
+    /// used to interact with the user.
+    pub fn uses_stdin(&self) -> bool {
+        matches!(self.source, ReplaySource::Stdin)
+    }
+
+    /// Whether the streams are read from files, which end by themselves.
+    pub fn is_recorded(&self) -> bool {
+        matches!(self.source, ReplaySource::Files(_))
+    }
+
+    /// Whether all streams have reached their end.
+    pub fn exhausted(&self) -> bool {
+        self.end_of_streams.count() >= self.streams
+    }
+
+    /// Whether tdiag's memory budget was exceeded, so that the streams
+    /// should no longer be read.
+    pub fn memory_exhausted(&self) -> bool {
+        self.resources.as_ref().map_or(false, |(resources, _)| resources.memory_exhausted())
+    }
+}
+
+/// A Read that reports connections that send no bytes for longer than
+/// an `IdleTimeout`, and optionally closes them.
+///
+/// Only readers that time out (i.e. sockets) are affected.
+pub struct IdleReader<R> {
+    reader: R,
+    connection: usize,
+    idle_timeout: Option<IdleTimeout>,
+    last_data: Instant,
+    reported: bool,
+}
+
+impl<R> IdleReader<R> {
+    fn new(reader: R, connection: usize, idle_timeout: Option<IdleTimeout>) -> Self {
+        IdleReader { reader, connection, idle_timeout, last_data: Instant::now(), reported: false }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for IdleReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.reported && self.idle_timeout.map_or(false, |idle| idle.close) {
+            return Ok(0);
+        }
+
+        match self.reader.read(buf) {
+            Ok(read) if read > 0 => {
+                if self.reported {
+                    crate::status::info("connection_resumed", &format!("Source connection {} is sending data again", self.connection));
+                    self.reported = false;
+                }
+                self.last_data = Instant::now();
+                Ok(read)
+            }
+            Err(e) => {
+                let timed_out = e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut;
+                if let (true, Some(idle)) = (timed_out, self.idle_timeout) {
+                    let silent = self.last_data.elapsed();
+                    if !self.reported && silent > idle.timeout {
+                        self.reported = true;
+                        if idle.close {
+                            crate::status::warn("connection_closed", &format!("Source connection {} has sent no bytes for {}s, closing it", self.connection, silent.as_secs()));
+                            return Ok(0);
+                        }
+                        crate::status::warn("connection_idle", &format!("Source connection {} has sent no bytes for {}s", self.connection, silent.as_secs()));
+                    }
+                }
+                Err(e)
+            }
+            other => other,
+        }
+    }
+}
+
+/// A Read that copies the bytes it reads to a file, if any (see
+/// `LogStreams::tee`).
+pub struct TeeReader<R> {
+    reader: R,
+    copy: Option<File>,
+}
+
+impl<R: std::io::Read> std::io::Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::Write;
+        let read = self.reader.read(buf)?;
+        // Unbuffered, so that the copy is complete whenever tdiag stops.
+        let failed = self.copy.as_mut().and_then(|copy| copy.write_all(&buf[..read]).err());
+        if let Some(e) = failed {
+            crate::status::warn("tee_failed", &format!("Stopped copying a log stream: {}", e));
+            self.copy = None;
+        }
+        Ok(read)
+    }
+}
+
+/// Blocks until a command should stop reading its log streams.
+///
+/// Waits for the user to press enter after printing `prompt` (as a
+/// status message, with a note on what happens to the source
+/// computation); if any of the streams is read from stdin, waits for all
+/// streams to reach their end instead. Recorded streams (and streams
+/// with `exit_on_eof`) are read until their end, unless the user presses
+/// enter first. Stops early if the duration set with `stop_after` has
+/// elapsed, if tdiag is interrupted (see `signals`), or if tdiag's memory
+/// budget is exceeded.
+pub fn await_stop(streams: &[&LogStreams], prompt: &str) {
+    await_stop_or(streams, prompt, || false)
+}
+
+/// Like `await_stop`, but also stops early once `stopped` returns true
+/// (e.g. once the analysis failed).
+pub fn await_stop_or(streams: &[&LogStreams], prompt: &str, stopped: impl Fn() -> bool) {
+    let from_stdin = streams.iter().any(|s| s.uses_stdin());
+    let until_end = from_stdin || streams.iter().any(|s| s.is_recorded() || s.exit_on_eof);
+
+    crate::signals::arm();
+
+    let (enter_send, enter_recv) = std::sync::mpsc::channel();
+    if !from_stdin {
+        if streams.iter().any(|s| s.is_recorded()) {
+            crate::status::prompt("Replaying the recording until its end, press enter to stop early.");
+        } else if until_end {
+            crate::status::prompt(&format!("{}, or wait for the source computation to close its log streams.", prompt));
+        } else if streams.iter().all(|s| s.detached) {
+            crate::status::prompt(&format!("{} (the source computation keeps running).", prompt));
+        } else {
+            crate::status::prompt(&format!("{} (this will crash the source computation if it hasn't terminated, see --detach).", prompt));
+        }
+        std::thread::spawn(move || {
+            use std::io::Read;
+            // Read a single byte and discard
+            let _ = std::io::stdin().read(&mut [0u8]);
+            let _ = enter_send.send(());
+        });
+    }
+
+    loop {
+        if until_end && streams.iter().all(|s| s.exhausted()) {
+            return;
+        }
+        if stopped() {
+            return;
+        }
+        if crate::signals::interrupted() {
+            crate::status::info("interrupted", "Interrupted, stopping (interrupt again to exit right away)");
+            return;
+        }
+        if streams.iter().any(|s| s.timed_out()) {
+            crate::status::info("duration_elapsed", "The capture duration elapsed, stopping");
+            return;
+        }
+        if streams.iter().any(|s| s.memory_exhausted()) {
+            return;
+        }
+        match enter_recv.recv_timeout(Duration::from_millis(100)) {
+            Ok(()) => return,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => (),
+            // Only when reading from stdin: there's no one to wait for.
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use timely::logging::{OperatesEvent, ScheduleEvent};
+
+    use differential_dataflow::logging::{BatchEvent, DropEvent, MergeEvent};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn schedule(time: u64, id: usize, start_stop: StartStop) -> (Duration, WorkerIdentifier, TimelyEvent) {
+        (ms(time), 0, TimelyEvent::Schedule(ScheduleEvent { id, start_stop }))
+    }
+
+    #[test]
+    fn window_drops_the_stop_of_an_activation_cut_by_its_start() {
+        let mut window = Window::new(Some(ms(10)), Some(ms(20)));
+        let operates = (ms(1), 0, TimelyEvent::Operates(OperatesEvent { id: 3, addr: vec![0, 3], name: "Map".to_string() }));
+        assert!(window.keep(&operates));
+        assert!(!window.keep(&schedule(5, 3, StartStop::Start)));
+        assert!(!window.keep(&schedule(12, 3, StartStop::Stop)));
+        assert!(window.keep(&schedule(13, 3, StartStop::Start)));
+        assert!(window.keep(&schedule(14, 3, StartStop::Stop)));
+        assert!(!window.keep(&schedule(20, 3, StartStop::Start)));
+    }
+
+    #[test]
+    fn window_keeps_unpaired_records_without_a_start() {
+        let mut window = Window::new(None, Some(ms(20)));
+        assert!(window.keep(&schedule(12, 3, StartStop::Stop)));
+    }
+
+    #[test]
+    fn window_only_removes_tuples_added_in_it() {
+        let mut window = Window::new(Some(ms(10)), None);
+        let record = |time, event| (ms(time), 0, event);
+        assert!(!window.keep(&record(5, DifferentialEvent::Batch(BatchEvent { operator: 7, length: 4 }))));
+        assert!(window.keep(&record(11, DifferentialEvent::Batch(BatchEvent { operator: 7, length: 2 }))));
+        // Merges the batch from before the window with the one in it.
+        let merge = MergeEvent { operator: 7, scale: 1, length1: 4, length2: 2, complete: Some(5) };
+        assert!(!window.keep(&record(12, DifferentialEvent::Merge(merge))));
+        assert!(!window.keep(&record(13, DifferentialEvent::Drop(DropEvent { operator: 7, length: 4 }))));
+        assert!(window.keep(&record(14, DifferentialEvent::Drop(DropEvent { operator: 7, length: 2 }))));
+        assert!(!window.keep(&record(15, DifferentialEvent::Drop(DropEvent { operator: 7, length: 2 }))));
+    }
+}
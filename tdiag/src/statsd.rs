@@ -0,0 +1,70 @@
+//! A StatsD sink, to feed derived values into existing alerting
+//! (StatsD, or Graphite through a StatsD daemon).
+//!
+//! Metrics are sent as plain-text UDP datagrams, which needs no
+//! additional dependencies; like StatsD clients do, send errors are
+//! ignored so that an unavailable daemon doesn't stop the analysis.
+
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::DiagError;
+
+/// Sends gauges to a StatsD daemon.
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdSink {
+    /// A sink sending to `addr`, with metric names starting with
+    /// `prefix` (e.g. `tdiag`).
+    pub fn connect(addr: SocketAddr, prefix: &str) -> Result<Self, DiagError> {
+        let bind: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(addr)
+            .map_err(|e| DiagError::io(format!("cannot send to StatsD at {}", addr), e))?;
+        Ok(StatsdSink { socket, prefix: prefix.trim_end_matches('.').to_string() })
+    }
+
+    /// Sets the gauge `<prefix>.<path>` (whose components are
+    /// sanitized) to `value`.
+    pub fn gauge(&self, path: &[&str], value: u64) {
+        let mut name = self.prefix.clone();
+        for component in path {
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&sanitize(component));
+        }
+        let _ = self.socket.send(format!("{}:{}|g", name, value).as_bytes());
+    }
+}
+
+/// Replaces the characters StatsD and Graphite give a meaning to in
+/// metric names (`.`, `:`, `|`, `@`, whitespace, ...) by a single `_`.
+fn sanitize(component: &str) -> String {
+    let mut sanitized = String::with_capacity(component.len());
+    for c in component.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            sanitized.push(c);
+        } else if !sanitized.is_empty() && !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    sanitized.trim_end_matches('_').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_components() {
+        assert_eq!(sanitize("Join"), "Join");
+        assert_eq!(sanitize("op-1"), "op-1");
+        assert_eq!(sanitize("Join.Reduce"), "Join_Reduce");
+        assert_eq!(sanitize("a: b|c@0.5"), "a_b_c_0_5");
+        assert_eq!(sanitize("..Map.."), "Map");
+        assert_eq!(sanitize("..."), "");
+    }
+}
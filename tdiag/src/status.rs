@@ -0,0 +1,112 @@
+//! tdiag's own status messages (listening, connected, warnings, ...),
+//! as opposed to the output of the commands.
+//!
+//! Messages are printed as plain text by default, or as JSON lines on
+//! stderr so that they can be handled by log aggregators:
+//!
+//! ```text
+//! {"time_ms":1571212800000,"level":"info","event":"connected","message":"Trace sources connected"}
+//! ```
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How status messages are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusFormat {
+    /// Human-readable text.
+    Text,
+    /// One JSON object per line, on stderr.
+    Json,
+}
+
+const TEXT: u8 = 0;
+const TEXT_TO_STDERR: u8 = 1;
+const JSON: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(TEXT);
+
+/// Sets how status messages are printed. With `stdout_reserved`
+/// (e.g. for `tee`, which writes the log streams to stdout), text
+/// messages go to stderr as well.
+pub fn configure(format: StatusFormat, stdout_reserved: bool) {
+    let mode = match format {
+        StatusFormat::Json => JSON,
+        StatusFormat::Text if stdout_reserved => TEXT_TO_STDERR,
+        StatusFormat::Text => TEXT,
+    };
+    MODE.store(mode, Ordering::Release);
+}
+
+/// Reports progress, e.g. that tdiag is listening for connections.
+pub fn info(event: &str, message: &str) {
+    match MODE.load(Ordering::Acquire) {
+        TEXT => println!("{}", message),
+        TEXT_TO_STDERR => eprintln!("{}", message),
+        _ => json("info", event, message),
+    }
+}
+
+/// Reports a change in the session's lifecycle (started, ended), which
+/// is only of interest to machines: it's only printed as JSON.
+pub fn lifecycle(event: &str, message: &str) {
+    if MODE.load(Ordering::Acquire) == JSON {
+        json("info", event, message);
+    }
+}
+
+/// Reports a condition that may affect the results.
+pub fn warn(event: &str, message: &str) {
+    match MODE.load(Ordering::Acquire) {
+        JSON => json("warn", event, message),
+        _ => eprintln!("{}", message),
+    }
+}
+
+/// Reports the error that ended the session.
+pub fn error(event: &str, message: &str) {
+    match MODE.load(Ordering::Acquire) {
+        JSON => json("error", event, message),
+        _ => eprintln!("Error: {}", message),
+    }
+}
+
+/// Asks the user for input, without a trailing newline in text mode.
+pub fn prompt(message: &str) {
+    match MODE.load(Ordering::Acquire) {
+        JSON => json("info", "awaiting_input", message),
+        _ => {
+            // stdout may carry a command's output (e.g. for `tee`).
+            let mut stderr = std::io::stderr();
+            write!(stderr, "{}", message).expect("failed to write to stderr");
+            stderr.flush().unwrap();
+        }
+    }
+}
+
+fn json(level: &str, event: &str, message: &str) {
+    let time_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|t| t.as_millis())
+        .unwrap_or(0);
+    eprintln!("{{\"time_ms\":{},\"level\":{},\"event\":{},\"message\":{}}}",
+        time_ms, json_string(level), json_string(event), json_string(message));
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
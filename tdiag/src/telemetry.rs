@@ -0,0 +1,75 @@
+//! Derived metrics, emitted through the `metrics` facade.
+//!
+//! When tdiag's commands are used as a library inside a larger
+//! operational tool, any recorder installed by that tool (Prometheus,
+//! statsd, ...) picks these up. Without an installed recorder emitting
+//! them is a no-op.
+
+/// Records the total time spent running an operator (or scope).
+pub fn operator_scheduled(id: usize, addr: &[usize], name: &str, is_scope: bool, elapsed_ns: isize) {
+    metrics::gauge!(
+        "tdiag_operator_scheduled_seconds",
+        "id" => id.to_string(),
+        "addr" => format!("{:?}", addr),
+        "name" => name.to_string(),
+        "scope" => is_scope.to_string())
+        .set((elapsed_ns as f64) / 1_000_000_000f64);
+}
+
+/// Records the number of tuples currently maintained by an arrangement.
+pub fn arrangement_size(worker: usize, operator: usize, name: &str, tuples: isize) {
+    metrics::gauge!(
+        "tdiag_arrangement_tuples",
+        "worker" => worker.to_string(),
+        "operator" => operator.to_string(),
+        "name" => name.to_string())
+        .set(tuples as f64);
+}
+
+/// Records the number of batches currently in an arrangement's trace,
+/// and the number of merges it completed so far.
+pub fn arrangement_batches(worker: usize, operator: usize, name: &str, batches: isize, merges: isize) {
+    metrics::gauge!(
+        "tdiag_arrangement_batches",
+        "worker" => worker.to_string(),
+        "operator" => operator.to_string(),
+        "name" => name.to_string())
+        .set(batches as f64);
+    metrics::counter!(
+        "tdiag_arrangement_merges_total",
+        "worker" => worker.to_string(),
+        "operator" => operator.to_string(),
+        "name" => name.to_string())
+        .absolute(merges as u64);
+}
+
+/// Records the messages and records sent over a channel so far, summed
+/// over the source workers.
+pub fn channel_sent(id: usize, scope_addr: &[usize], source: (usize, usize), target: (usize, usize), messages: u64, records: u64) {
+    metrics::counter!(
+        "tdiag_channel_messages_total",
+        "id" => id.to_string(),
+        "scope" => format!("{:?}", scope_addr),
+        "source" => format!("{}:{}", source.0, source.1),
+        "target" => format!("{}:{}", target.0, target.1))
+        .absolute(messages);
+    metrics::counter!(
+        "tdiag_channel_records_total",
+        "id" => id.to_string(),
+        "scope" => format!("{:?}", scope_addr),
+        "source" => format!("{}:{}", source.0, source.1),
+        "target" => format!("{}:{}", target.0, target.1))
+        .absolute(records);
+}
+
+/// Records tdiag's own resource usage.
+pub fn resources(snapshot: &crate::resources::Snapshot, decoded_per_s: f64, replayed_per_s: f64) {
+    if let Some(rss_bytes) = snapshot.rss_bytes {
+        metrics::gauge!("tdiag_self_rss_bytes").set(rss_bytes as f64);
+    }
+    for (stream, queued) in snapshot.queued.iter() {
+        metrics::gauge!("tdiag_self_queued_events", "stream" => stream.clone()).set(*queued as f64);
+    }
+    metrics::gauge!("tdiag_self_decoded_records_per_second").set(decoded_per_s);
+    metrics::gauge!("tdiag_self_analysed_records_per_second").set(replayed_per_s);
+}